@@ -1221,7 +1221,7 @@ pub fn apply_root_change<R: rand::Rng, T: MutTxnT, P: ChangeStore>(
             pos: ChangePosition(0u64.into()),
         };
         let contents = rng
-            .sample_iter(rand::distributions::Standard)
+            .sample_iter(rand::distr::StandardUniform)
             .take(32)
             .collect();
         debug!(
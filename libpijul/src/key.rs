@@ -102,7 +102,7 @@ impl SKey {
     pub fn generate(expires: Option<jiff::Timestamp>) -> Self {
         use rand::RngCore;
         let mut key = [0; 32];
-        rand::thread_rng().fill_bytes(&mut key);
+        rand::rng().fill_bytes(&mut key);
         let secret = ed25519_dalek::SecretKey::from_bytes(&key).unwrap();
         SKey::Ed25519 {
             key: ed25519_dalek::Keypair {
@@ -119,8 +119,8 @@ impl SKey {
                 let mut key = key.to_bytes();
                 let encryption = if let Some(password) = password {
                     use rand::Rng;
-                    let salt = rand::thread_rng()
-                        .sample_iter(&rand::distributions::Alphanumeric)
+                    let salt = rand::rng()
+                        .sample_iter(&rand::distr::Alphanumeric)
                         .take(32)
                         .map(|c| c as char)
                         .collect();
@@ -81,9 +81,11 @@ pub type Hasher = std::collections::hash_map::RandomState;
 pub type HashMap<K, V> = std::collections::HashMap<K, V, Hasher>;
 pub type HashSet<K> = std::collections::HashSet<K, Hasher>;
 
-impl MutTxnTExt for pristine::sanakirja::MutTxn<()> {}
-impl TxnTExt for pristine::sanakirja::MutTxn<()> {}
-impl TxnTExt for pristine::sanakirja::Txn {}
+impl<T: pristine::sanakirja::RawMutTxnT> MutTxnTExt for pristine::sanakirja::MutTxn<T> {}
+impl<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPage> TxnTExt
+    for pristine::sanakirja::GenericTxn<T>
+{
+}
 
 pub fn commit<T: pristine::MutTxnT>(
     txn: std::sync::Arc<std::sync::RwLock<T>>,
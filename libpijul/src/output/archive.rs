@@ -21,7 +21,7 @@ pub struct Tarball<W: std::io::Write> {
     pub umask: u16,
 }
 
-#[cfg(feature = "tarball")]
+#[cfg(any(feature = "tarball", feature = "zip"))]
 pub struct File {
     buf: Vec<u8>,
     path: String,
@@ -29,7 +29,7 @@ pub struct File {
     mtime: u64,
 }
 
-#[cfg(feature = "tarball")]
+#[cfg(any(feature = "tarball", feature = "zip"))]
 impl std::io::Write for File {
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
         self.buf.write(buf)
@@ -71,6 +71,7 @@ impl<W: std::io::Write> Archive for Tarball<W> {
     }
     fn create_dir(&mut self, path: &str, mtime: u64, permissions: u16) -> Result<(), Self::Error> {
         let mut header = tar::Header::new_gnu();
+        header.set_size(0);
         header.set_mode((permissions & !self.umask) as u32);
         header.set_mtime(mtime);
         header.set_entry_type(tar::EntryType::Directory);
@@ -96,6 +97,89 @@ impl<W: std::io::Write> Archive for Tarball<W> {
     }
 }
 
+#[cfg(feature = "zip")]
+pub struct Zip<W: std::io::Write + std::io::Seek> {
+    pub archive: zip::ZipWriter<W>,
+    pub prefix: Option<String>,
+    pub buffer: Vec<u8>,
+    pub umask: u16,
+}
+
+#[cfg(feature = "zip")]
+impl<W: std::io::Write + std::io::Seek> Zip<W> {
+    pub fn new(w: W, prefix: Option<String>, umask: u16) -> Self {
+        Zip {
+            archive: zip::ZipWriter::new(w),
+            buffer: Vec::new(),
+            prefix,
+            umask,
+        }
+    }
+
+    /// Write the central directory, and return the underlying writer.
+    pub fn finish(self) -> Result<W, zip::result::ZipError> {
+        self.archive.finish()
+    }
+
+    fn options(mtime: u64, permissions: u16) -> zip::write::SimpleFileOptions {
+        let utc = jiff::Timestamp::from_second(mtime as i64)
+            .unwrap_or_default()
+            .to_zoned(jiff::tz::TimeZone::UTC)
+            .datetime();
+        // Zip timestamps can't be earlier than 1980.
+        let mtime = zip::DateTime::from_date_and_time(
+            utc.year() as u16,
+            utc.month() as u8,
+            utc.day() as u8,
+            utc.hour() as u8,
+            utc.minute() as u8,
+            utc.second() as u8,
+        )
+        .unwrap_or_default();
+        zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(mtime)
+            .unix_permissions(permissions as u32)
+            .large_file(true)
+    }
+}
+
+#[cfg(feature = "zip")]
+impl<W: std::io::Write + std::io::Seek> Archive for Zip<W> {
+    type File = File;
+    type Error = zip::result::ZipError;
+    fn create_file(&mut self, path: &str, mtime: u64, permissions: u16) -> Self::File {
+        self.buffer.clear();
+        File {
+            buf: std::mem::replace(&mut self.buffer, Vec::new()),
+            path: if let Some(ref prefix) = self.prefix {
+                prefix.clone() + path
+            } else {
+                path.to_string()
+            },
+            mtime,
+            permissions: permissions & !self.umask,
+        }
+    }
+    fn create_dir(&mut self, path: &str, mtime: u64, permissions: u16) -> Result<(), Self::Error> {
+        let options = Self::options(mtime, permissions & !self.umask);
+        if let Some(ref prefix) = self.prefix {
+            self.archive.add_directory(prefix.clone() + path, options)
+        } else {
+            self.archive.add_directory(path, options)
+        }
+    }
+
+    fn close_file(&mut self, file: Self::File) -> Result<(), Self::Error> {
+        use std::io::Write;
+        let options = Self::options(file.mtime, file.permissions);
+        self.archive.start_file(file.path.as_str(), options)?;
+        self.archive.write_all(&file.buf)?;
+        self.buffer = file.buf;
+        Ok(())
+    }
+}
+
 #[derive(Error)]
 pub enum ArchiveError<
     P: std::error::Error + 'static,
@@ -322,3 +406,74 @@ pub(crate) fn archive<
     }
     Ok(conflicts)
 }
+
+#[cfg(all(test, any(feature = "tarball", feature = "zip")))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn fill<A: Archive>(arch: &mut A) {
+        arch.create_dir("a", 1_700_000_000, 0o755).unwrap();
+        let mut f = arch.create_file("a/b", 1_700_000_000, 0o755);
+        f.write_all(b"b\n").unwrap();
+        arch.close_file(f).unwrap();
+        let mut f = arch.create_file("c", 0, 0o644);
+        f.write_all(b"c\n").unwrap();
+        arch.close_file(f).unwrap();
+    }
+
+    #[cfg(feature = "tarball")]
+    #[test]
+    fn tarball() {
+        let mut tarball = Tarball::new(Vec::new(), Some("r/".to_string()), 0o022);
+        fill(&mut tarball);
+        let bytes = tarball.archive.into_inner().unwrap().finish().unwrap();
+
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(&bytes[..]));
+        let mut entries = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            let path = entry.path().unwrap().to_str().unwrap().to_string();
+            entries.push((path, entry.header().mode().unwrap() & 0o777, contents));
+        }
+        assert_eq!(
+            entries,
+            [
+                ("r/a".to_string(), 0o755, String::new()),
+                ("r/a/b".to_string(), 0o755, "b\n".to_string()),
+                ("r/c".to_string(), 0o644, "c\n".to_string()),
+            ]
+        );
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn zip() {
+        let mut archive = Zip::new(
+            std::io::Cursor::new(Vec::new()),
+            Some("r/".to_string()),
+            0o022,
+        );
+        fill(&mut archive);
+        let bytes = archive.finish().unwrap();
+
+        let mut zip = zip::ZipArchive::new(bytes).unwrap();
+        assert_eq!(zip.len(), 3);
+        let dir = zip.by_name("r/a/").unwrap();
+        assert!(dir.is_dir());
+        assert_eq!(dir.unix_mode().unwrap() & 0o777, 0o755);
+        drop(dir);
+        for (name, mode, expected) in [("r/a/b", 0o755, "b\n"), ("r/c", 0o644, "c\n")] {
+            let mut file = zip.by_name(name).unwrap();
+            assert_eq!(file.unix_mode().unwrap() & 0o777, mode);
+            let mut contents = String::new();
+            file.read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, expected);
+        }
+        // Times before 1980 are clamped.
+        let c = zip.by_name("r/c").unwrap();
+        assert_eq!(c.last_modified().unwrap().year(), 1980);
+    }
+}
@@ -106,6 +106,7 @@ impl Merkle {
             return None;
         };
         curve25519_dalek::edwards::CompressedEdwardsY::from_slice(&bytes[..32])
+            .ok()?
             .decompress()
             .map(Merkle::Ed25519)
     }
@@ -132,6 +133,7 @@ impl super::Base32 for Merkle {
         };
         if bytes.len() == 33 && *bytes.last().unwrap() == MerkleAlgorithm::Ed25519 as u8 {
             curve25519_dalek::edwards::CompressedEdwardsY::from_slice(&bytes[..32])
+                .ok()?
                 .decompress()
                 .map(Merkle::Ed25519)
         } else {
@@ -208,6 +210,7 @@ impl<'a> From<&'a SerializedMerkle> for Merkle {
         assert_eq!((m.0)[0], MerkleAlgorithm::Ed25519 as u8);
         Merkle::Ed25519(
             curve25519_dalek::edwards::CompressedEdwardsY::from_slice(&(m.0)[1..])
+                .unwrap()
                 .decompress()
                 .unwrap(),
         )
@@ -219,6 +222,7 @@ impl From<SerializedMerkle> for Merkle {
         assert_eq!((m.0)[0], MerkleAlgorithm::Ed25519 as u8);
         Merkle::Ed25519(
             curve25519_dalek::edwards::CompressedEdwardsY::from_slice(&(m.0)[1..])
+                .unwrap()
                 .decompress()
                 .unwrap(),
         )
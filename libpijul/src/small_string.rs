@@ -311,7 +311,7 @@ impl sanakirja::UnsizedStorable for SmallStr {
     fn size(&self) -> usize {
         1 + self.len as usize
     }
-    unsafe fn write_to_page_alloc<T: sanakirja::AllocPage>(&self, _: &mut T, p: *mut u8) {
+    unsafe fn write_to_page(&self, p: *mut u8) {
         std::ptr::copy(&self.len, p, 1 + self.len as usize);
         debug!(
             "writing {:?}",
@@ -1,4 +1,4 @@
-use crate::pristine::sanakirja::{Channel, MutTxn, SanakirjaError, P, UP};
+use crate::pristine::sanakirja::{Channel, MutTxn0, SanakirjaError, P, UP};
 use crate::pristine::*;
 use crate::HashSet;
 use crate::TxnT;
@@ -151,9 +151,9 @@ pub(crate) const BLOCK_SIZE: usize = 4096;
 
 pub fn restore_channel(
     mut tag: OpenTagFile,
-    txn: &mut MutTxn<()>,
+    txn: &mut MutTxn0,
     name: &str,
-) -> Result<ChannelRef<MutTxn<()>>, TagError> {
+) -> Result<ChannelRef<MutTxn0>, TagError> {
     tag.file.seek(SeekFrom::Start(tag.header.channel))?;
     let mut comp = vec![0; (tag.header.unhashed - tag.header.channel) as usize];
     debug!("tag header {:?}", tag.header);
@@ -284,11 +284,11 @@ pub fn restore_channel(
             name: name.clone(),
             last_modified: 0,
             id: {
-                let mut rng = rand::thread_rng();
+                let mut rng = rand::rng();
                 use rand::Rng;
                 let mut m = crate::pristine::RemoteId([0; 16]);
                 for m in m.0.iter_mut() {
-                    *m = rng.gen()
+                    *m = rng.random()
                 }
                 m
             },
@@ -329,14 +329,14 @@ fn restore<
     F,
 >(
     file_txn: &Txn,
-    txn: &mut crate::pristine::sanakirja::MutTxn<()>,
+    txn: &mut crate::pristine::sanakirja::MutTxn0,
     pending: u64,
     f: F,
 ) -> Result<::sanakirja::btree::Db_<K, V, P>, TxnErr<SanakirjaError>>
 where
     F: Fn(
         &Txn,
-        &mut crate::pristine::sanakirja::MutTxn<()>,
+        &mut crate::pristine::sanakirja::MutTxn0,
         &K,
         &V,
     ) -> Result<(K, V), TxnErr<SanakirjaError>>,
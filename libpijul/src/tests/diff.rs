@@ -1,6 +1,6 @@
 use super::*;
 use crate::alive::retrieve;
-use rand::distributions::Alphanumeric;
+use rand::distr::Alphanumeric;
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 use std::io::Write;
@@ -0,0 +1,330 @@
+//! Audit log.
+//!
+//! Every mutating operation (pushes, tags, repository, access, key
+//! and token changes...) appends a JSON record to the audit log, one
+//! per line. The log is only ever appended to; rotating it is left to
+//! the administrator. Failing to write a record doesn't fail the
+//! operation, which has already happened: the error is logged.
+//!
+//! Channels are created by pushes, recorded as such. The server has
+//! no operation forking, renaming or dropping channels: the scratch
+//! channels some operations use internally are never committed.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::auth::{Access, Caller};
+use crate::config::AuditConfig;
+
+/// Serializes appends, so that records of concurrent operations
+/// don't interleave.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Size of the blocks in which the log is read backwards.
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Kinds of recorded operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Push,
+    TagCreate,
+    RepoCreate,
+    RepoFork,
+    RepoRename,
+    RepoDelete,
+    AccessChange,
+    ProposalCreate,
+    ProposalComment,
+    ProposalMerge,
+    ProposalClose,
+    MirrorSet,
+    MirrorRemove,
+    MirrorSync,
+    PushMirrorCreate,
+    PushMirrorDelete,
+    MirrorPush,
+    GitImport,
+    GitExport,
+    KeyAdd,
+    KeyRevoke,
+    TokenCreate,
+    TokensRevoke,
+    IdentityLink,
+    IdentityUpdate,
+    IdentityRemove,
+    WebhookCreate,
+    WebhookDelete,
+    HostKeyRotate,
+}
+
+/// An audit record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub time: Timestamp,
+    pub action: Action,
+    /// User name, `<admin>` for the admin token
+    pub actor: String,
+    /// Address the operation came from
+    pub addr: Option<IpAddr>,
+    pub repo: Option<String>,
+    pub channel: Option<String>,
+    pub old_state: Option<String>,
+    pub new_state: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<String>,
+    /// What the operation was about: a user, a key fingerprint, a new
+    /// name...
+    pub target: Option<String>,
+}
+
+impl Record {
+    /// A record of `action` by `caller`, other fields empty.
+    pub fn new(action: Action, caller: &Caller) -> Self {
+        Self {
+            time: Timestamp::now(),
+            action,
+            actor: caller.name().to_string(),
+            addr: caller.addr,
+            repo: None,
+            channel: None,
+            old_state: None,
+            new_state: None,
+            changes: Vec::new(),
+            target: None,
+        }
+    }
+
+    /// A record of `action` on the repository of `access`.
+    pub fn on_repo(action: Action, access: &Access) -> Self {
+        Self {
+            time: Timestamp::now(),
+            action,
+            actor: access.caller().to_string(),
+            addr: access.addr(),
+            repo: Some(access.repo().to_string()),
+            channel: None,
+            old_state: None,
+            new_state: None,
+            changes: Vec::new(),
+            target: None,
+        }
+    }
+}
+
+/// Append a record to the audit log, unless it is disabled.
+pub fn append(config: &AuditConfig, record: Record) {
+    if !config.enabled {
+        return;
+    }
+    if let Err(e) = write(&config.path, &record) {
+        error!(
+            path = %config.path.display(),
+            action = ?record.action,
+            actor = %record.actor,
+            error = %e,
+            "Cannot write audit record"
+        );
+    }
+}
+
+fn write(path: &Path, record: &Record) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    let _lock = LOCK.lock().unwrap();
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(&line)?;
+    Ok(())
+}
+
+/// Filters of an audit log query. Records match if they match all the
+/// filters given.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Query {
+    pub actor: Option<String>,
+    pub action: Option<Action>,
+    pub repo: Option<String>,
+    pub channel: Option<String>,
+    /// Records of a change
+    pub change: Option<String>,
+    /// Records at or after this time
+    pub since: Option<Timestamp>,
+    /// Records before this time
+    pub until: Option<Timestamp>,
+}
+
+impl Query {
+    fn matches(&self, record: &Record) -> bool {
+        fn eq(filter: &Option<String>, value: &Option<String>) -> bool {
+            filter.is_none() || filter == value
+        }
+        self.actor.as_ref().is_none_or(|a| *a == record.actor)
+            && self.action.is_none_or(|a| a == record.action)
+            && eq(&self.repo, &record.repo)
+            && eq(&self.channel, &record.channel)
+            && self
+                .change
+                .as_ref()
+                .is_none_or(|c| record.changes.contains(c))
+            && self.since.is_none_or(|t| record.time >= t)
+            && self.until.is_none_or(|t| record.time < t)
+    }
+}
+
+/// The last `limit` records matching `query`, newest first. The log
+/// is read backwards, and only as far as needed.
+pub fn search(config: &AuditConfig, query: &Query, limit: usize) -> anyhow::Result<Vec<Record>> {
+    let file = match File::open(&config.path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = RevLines::new(file)?;
+    let mut records = Vec::new();
+    while records.len() < limit {
+        let Some(line) = lines.next_line()? else {
+            break;
+        };
+        // A partial last line, if a write was interrupted.
+        let Ok(record) = serde_json::from_slice::<Record>(&line) else {
+            continue;
+        };
+        if query.matches(&record) {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+/// The lines of a file, last first.
+struct RevLines {
+    file: File,
+    /// Offset of the part of the file not read yet
+    pos: u64,
+    /// Start of the earliest line read, which may be incomplete
+    partial: Vec<u8>,
+    /// Complete lines read, in file order
+    lines: Vec<Vec<u8>>,
+}
+
+impl RevLines {
+    fn new(mut file: File) -> std::io::Result<Self> {
+        let pos = file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            file,
+            pos,
+            partial: Vec::new(),
+            lines: Vec::new(),
+        })
+    }
+
+    fn next_line(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(line) = self.lines.pop() {
+                return Ok(Some(line));
+            }
+            if self.pos == 0 {
+                if self.partial.is_empty() {
+                    return Ok(None);
+                }
+                return Ok(Some(std::mem::take(&mut self.partial)));
+            }
+            let len = BLOCK_SIZE.min(self.pos);
+            self.pos -= len;
+            let mut block = vec![0; len as usize];
+            self.file.seek(SeekFrom::Start(self.pos))?;
+            self.file.read_exact(&mut block)?;
+            block.append(&mut self.partial);
+            let mut lines = block.split(|&b| b == b'\n');
+            self.partial = lines.next().unwrap_or_default().to_vec();
+            self.lines.extend(lines.map(<[u8]>::to_vec));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AuditConfig {
+            enabled: true,
+            path: dir.path().join("audit.log"),
+        };
+        let alice = Caller {
+            user: Some("alice".to_string()),
+            addr: Some("192.0.2.1".parse().unwrap()),
+            ..Default::default()
+        };
+        append(
+            &config,
+            Record {
+                repo: Some("a".to_string()),
+                ..Record::new(Action::RepoCreate, &alice)
+            },
+        );
+        append(
+            &config,
+            Record {
+                target: Some("bob".to_string()),
+                ..Record::new(Action::TokenCreate, &Caller::admin())
+            },
+        );
+        append(
+            &AuditConfig {
+                enabled: false,
+                ..config.clone()
+            },
+            Record::new(Action::RepoDelete, &alice),
+        );
+
+        let all = search(&config, &Query::default(), 10).unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].action, Action::TokenCreate);
+        assert_eq!(all[0].actor, "<admin>");
+        assert_eq!(all[1].addr, alice.addr);
+
+        let query = Query {
+            actor: Some("alice".to_string()),
+            ..Default::default()
+        };
+        let found = search(&config, &query, 10).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].repo.as_deref(), Some("a"));
+        let query = Query {
+            since: Some(all[0].time),
+            ..Default::default()
+        };
+        assert_eq!(search(&config, &query, 10).unwrap().len(), 1);
+        assert_eq!(search(&config, &Query::default(), 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_rev_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        // Lines spanning several blocks.
+        let lines: Vec<String> = (0..3 * BLOCK_SIZE / 1000)
+            .map(|i| format!("{}{}", i, "x".repeat(1000)))
+            .collect();
+        std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+        let mut rev = RevLines::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(rev.next_line().unwrap(), Some(Vec::new()));
+        for line in lines.iter().rev() {
+            assert_eq!(rev.next_line().unwrap().as_deref(), Some(line.as_bytes()));
+        }
+        assert_eq!(rev.next_line().unwrap(), None);
+    }
+}
@@ -0,0 +1,306 @@
+//! Repository permissions.
+//!
+//! Each repository may have an `access.json` file in its `.pijul`
+//! directory:
+//!
+//! ```json
+//! {
+//!   "public": true,
+//!   "users": { "alice": "admin", "bob": "write" },
+//!   "protected_channels": ["main"],
+//!   "signed_channels": ["main"]
+//! }
+//! ```
+//!
+//! Public repositories can be read by anyone, including anonymous
+//! HTTP clients. Protected channels only accept pushes from
+//! repository admins. Signed channels, or all channels with
+//! `"require_signatures": true`, only accept signed changes (see
+//! [`crate::repo::signatures`]). Server administrators (`auth.admins`) are
+//! admins of every repository. A repository without an access file is
+//! private to server administrators.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ServerConfig;
+use crate::error::{Result, ServerError};
+
+/// Name of the access file, in the repository's `.pijul` directory.
+pub const ACCESS_FILE: &str = "access.json";
+
+/// Role of a user on a repository. Each role includes the previous
+/// ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Clone and pull
+    Read,
+    /// Push to unprotected channels
+    Write,
+    /// Push to protected channels and manage the repository
+    Admin,
+}
+
+/// Permissions stored with a repository.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessList {
+    /// Whether anyone can read the repository
+    pub public: bool,
+    /// Roles of individual users
+    pub users: BTreeMap<String, Role>,
+    /// Channels only admins can push to
+    pub protected_channels: Vec<String>,
+    /// Whether all channels only accept signed changes
+    pub require_signatures: bool,
+    /// Channels only accepting signed changes
+    pub signed_channels: Vec<String>,
+}
+
+impl AccessList {
+    fn path(repo_path: &Path) -> PathBuf {
+        repo_path.join(libpijul::DOT_DIR).join(ACCESS_FILE)
+    }
+
+    /// Load the access list of the repository at `repo_path`.
+    pub fn load(repo_path: &Path) -> Result<Self> {
+        match std::fs::read(Self::path(repo_path)) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| ServerError::repository(format!("Invalid {}: {}", ACCESS_FILE, e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the access list of the repository at `repo_path`.
+    pub fn save(&self, repo_path: &Path) -> Result<()> {
+        let path = Self::path(repo_path);
+        let tmp = path.with_extension("tmp");
+        let contents =
+            serde_json::to_vec_pretty(self).map_err(|e| ServerError::internal(e.to_string()))?;
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+/// The party making a request, as authenticated by SSH keys or HTTP
+/// tokens.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Caller {
+    /// The authenticated user, if any
+    pub user: Option<String>,
+    /// Whether the caller is a server administrator
+    pub is_admin: bool,
+    /// Address the caller connected from, if known
+    pub addr: Option<IpAddr>,
+}
+
+impl Caller {
+    /// An unauthenticated caller.
+    pub fn anonymous() -> Self {
+        Self::default()
+    }
+
+    /// An authenticated user.
+    pub fn user(name: &str, config: &ServerConfig) -> Self {
+        Self {
+            user: Some(name.to_string()),
+            is_admin: config.auth.admins.iter().any(|a| a == name),
+            addr: None,
+        }
+    }
+
+    /// A server administrator authenticated by the admin token.
+    pub fn admin() -> Self {
+        Self {
+            user: None,
+            is_admin: true,
+            addr: None,
+        }
+    }
+
+    /// The same caller, connected from `addr`.
+    pub fn with_addr(self, addr: Option<IpAddr>) -> Self {
+        Self { addr, ..self }
+    }
+
+    /// Name used in logs.
+    pub fn name(&self) -> &str {
+        match self.user {
+            Some(ref user) => user,
+            None if self.is_admin => "<admin>",
+            None => "<anonymous>",
+        }
+    }
+
+    /// Check that the caller is `user` or a server administrator.
+    pub fn require_user(&self, user: &str) -> Result<()> {
+        if self.is_admin || self.user.as_deref() == Some(user) {
+            Ok(())
+        } else {
+            Err(ServerError::auth(format!(
+                "{} cannot manage user {}",
+                self.name(),
+                user
+            )))
+        }
+    }
+
+    /// Check that the caller is a server administrator.
+    pub fn require_admin(&self) -> Result<()> {
+        if self.is_admin {
+            Ok(())
+        } else {
+            Err(ServerError::auth(format!(
+                "{} is not a server administrator",
+                self.name()
+            )))
+        }
+    }
+}
+
+/// Permissions of a caller on one repository.
+#[derive(Debug, Clone)]
+pub struct Access {
+    repo: String,
+    caller: String,
+    user: Option<String>,
+    addr: Option<IpAddr>,
+    role: Option<Role>,
+    protected_channels: Vec<String>,
+    require_signatures: bool,
+    signed_channels: Vec<String>,
+}
+
+impl Access {
+    /// Compute the permissions of `caller` from a repository's access
+    /// list.
+    pub fn resolve(repo: &str, acl: &AccessList, caller: &Caller) -> Self {
+        let role = if caller.is_admin {
+            Some(Role::Admin)
+        } else {
+            let user_role = caller.user.as_ref().and_then(|u| acl.users.get(u)).copied();
+            let public_role = acl.public.then_some(Role::Read);
+            user_role.max(public_role)
+        };
+        Self {
+            repo: repo.to_string(),
+            caller: caller.name().to_string(),
+            user: caller.user.clone(),
+            addr: caller.addr,
+            role,
+            protected_channels: acl.protected_channels.clone(),
+            require_signatures: acl.require_signatures,
+            signed_channels: acl.signed_channels.clone(),
+        }
+    }
+
+    /// Name of the repository.
+    pub fn repo(&self) -> &str {
+        &self.repo
+    }
+
+    /// Name of the caller, as used in logs.
+    pub fn caller(&self) -> &str {
+        &self.caller
+    }
+
+    /// The authenticated user, if any.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Address the caller connected from, if known.
+    pub fn addr(&self) -> Option<IpAddr> {
+        self.addr
+    }
+
+    /// The caller's role, if they have any access.
+    pub fn role(&self) -> Option<Role> {
+        self.role
+    }
+
+    /// Check that the caller has at least `role`.
+    pub fn require(&self, role: Role) -> Result<()> {
+        if self.role >= Some(role) {
+            Ok(())
+        } else {
+            Err(ServerError::auth(format!(
+                "{} does not have {:?} access to {}",
+                self.caller, role, self.repo
+            )))
+        }
+    }
+
+    /// Check that the caller may push to `channel`.
+    pub fn require_push(&self, channel: &str) -> Result<()> {
+        self.require(Role::Write)?;
+        if self.protected_channels.iter().any(|c| c == channel) {
+            self.require(Role::Admin).map_err(|_| {
+                ServerError::auth(format!("Channel {} of {} is protected", channel, self.repo))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Whether changes pushed to `channel` must be signed.
+    pub fn requires_signatures(&self, channel: &str) -> bool {
+        self.require_signatures || self.signed_channels.iter().any(|c| c == channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caller(name: &str) -> Caller {
+        Caller {
+            user: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_roles() {
+        let acl: AccessList = serde_json::from_str(
+            r#"{ "users": { "alice": "admin", "bob": "write", "carol": "read" },
+                 "protected_channels": ["main"], "signed_channels": ["main"] }"#,
+        )
+        .unwrap();
+
+        let alice = Access::resolve("repo", &acl, &caller("alice"));
+        assert!(alice.require_push("main").is_ok());
+
+        let bob = Access::resolve("repo", &acl, &caller("bob"));
+        assert!(bob.require_push("dev").is_ok());
+        assert!(bob.require_push("main").is_err());
+        assert!(bob.require(Role::Admin).is_err());
+        assert!(bob.requires_signatures("main"));
+        assert!(!bob.requires_signatures("dev"));
+
+        let carol = Access::resolve("repo", &acl, &caller("carol"));
+        assert!(carol.require(Role::Read).is_ok());
+        assert!(carol.require_push("dev").is_err());
+
+        let anonymous = Access::resolve("repo", &acl, &Caller::anonymous());
+        assert_eq!(anonymous.role(), None);
+        let admin = Access::resolve("repo", &acl, &Caller::admin());
+        assert_eq!(admin.role(), Some(Role::Admin));
+    }
+
+    #[test]
+    fn test_public() {
+        let acl = AccessList {
+            public: true,
+            ..Default::default()
+        };
+        let anonymous = Access::resolve("repo", &acl, &Caller::anonymous());
+        assert!(anonymous.require(Role::Read).is_ok());
+        assert!(anonymous.require_push("main").is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! Identities linked to users.
+//!
+//! `pijul identity` links a signing key to an account on a remote by
+//! proving that it owns the key, with a `challenge`/`prove` exchange
+//! over SSH or HTTP. The server then records an [`Identity`] for the
+//! authenticated user in `users/<name>/identities.json`, and serves
+//! it to the clients of all repositories, along with the identities
+//! published in their `.pijul/identities` directories, so that the
+//! authors of changes signed with the key are known by their user
+//! names.
+//!
+//! Identities are serialized like those of `pijul-identity`, which
+//! clients store as they receive them.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use jiff::Timestamp;
+use libpijul::key::PublicKey;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::keys::validate_user_name;
+use crate::error::{Result, ServerError};
+
+/// File, in the directory of a user, listing their identities.
+pub const IDENTITIES_FILE: &str = "identities.json";
+
+/// Length of the random string clients sign to prove key ownership.
+const CHALLENGE_LEN: usize = 32;
+
+/// Serializes updates of identity files, which may touch several
+/// users when a key changes hands.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// A signing key linked to a user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    /// The user who proved they own the key
+    pub username: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub display_name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub email: String,
+    pub last_modified: Timestamp,
+    pub public_key: PublicKey,
+}
+
+impl Identity {
+    /// Whether `id` is the fingerprint of this identity's key, or the
+    /// key itself as found in the authors of changes.
+    pub fn matches(&self, id: &str) -> bool {
+        self.public_key.key == id || self.public_key.fingerprint() == id
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct IdentityList {
+    identities: Vec<Identity>,
+}
+
+/// Generate a random challenge, for a client to sign with the key it
+/// claims to own.
+pub fn challenge() -> String {
+    rand::rng()
+        .sample_iter(&rand::distr::Alphanumeric)
+        .take(CHALLENGE_LEN)
+        .map(char::from)
+        .collect()
+}
+
+fn path(users_dir: &Path, user: &str) -> PathBuf {
+    users_dir.join(user).join(IDENTITIES_FILE)
+}
+
+fn load(users_dir: &Path, user: &str) -> Result<Vec<Identity>> {
+    match std::fs::read(path(users_dir, user)) {
+        Ok(contents) => {
+            let list: IdentityList = serde_json::from_slice(&contents).map_err(|e| {
+                ServerError::internal(format!("Invalid identities of {}: {}", user, e))
+            })?;
+            Ok(list.identities)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write(users_dir: &Path, user: &str, identities: Vec<Identity>) -> Result<()> {
+    let path = path(users_dir, user);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let contents = serde_json::to_vec_pretty(&IdentityList { identities })
+        .map_err(|e| ServerError::internal(e.to_string()))?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// Names of the users with a directory.
+fn users(users_dir: &Path) -> Result<Vec<String>> {
+    let entries = match std::fs::read_dir(users_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut users = Vec::new();
+    for entry in entries.flatten() {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if entry.path().is_dir() && validate_user_name(&name).is_ok() {
+            users.push(name);
+        }
+    }
+    users.sort();
+    Ok(users)
+}
+
+/// List the identities of a user.
+pub fn list(users_dir: &Path, user: &str) -> Result<Vec<Identity>> {
+    validate_user_name(user)?;
+    load(users_dir, user)
+}
+
+/// List the identities of all users.
+pub fn all(users_dir: &Path) -> Result<Vec<Identity>> {
+    let mut identities = Vec::new();
+    for user in users(users_dir)? {
+        identities.extend(load(users_dir, &user)?);
+    }
+    Ok(identities)
+}
+
+/// Find the identity of a key, given as in [`Identity::matches`].
+pub fn find(users_dir: &Path, id: &str) -> Result<Option<Identity>> {
+    Ok(all(users_dir)?.into_iter().find(|i| i.matches(id)))
+}
+
+/// Link `key` to `user`, who just proved they own it. A key belongs
+/// to a single user: it is unlinked from the previous one, if any.
+/// Linking a key again refreshes it, e.g. after its expiry changed.
+pub fn link(users_dir: &Path, user: &str, key: PublicKey) -> Result<Identity> {
+    validate_user_name(user)?;
+    let _lock = LOCK.lock().unwrap();
+    for other in users(users_dir)? {
+        if other == user {
+            continue;
+        }
+        let mut identities = load(users_dir, &other)?;
+        let len = identities.len();
+        identities.retain(|i| i.public_key.key != key.key);
+        if identities.len() != len {
+            write(users_dir, &other, identities)?;
+        }
+    }
+    let mut identities = load(users_dir, user)?;
+    let identity = match identities.iter_mut().find(|i| i.public_key.key == key.key) {
+        Some(identity) => {
+            identity.public_key = key;
+            identity.last_modified = Timestamp::now();
+            identity.clone()
+        }
+        None => {
+            let identity = Identity {
+                username: user.to_string(),
+                display_name: String::new(),
+                email: String::new(),
+                last_modified: Timestamp::now(),
+                public_key: key,
+            };
+            identities.push(identity.clone());
+            identity
+        }
+    };
+    write(users_dir, user, identities)?;
+    Ok(identity)
+}
+
+/// Modify the identity matching `id` with `f`.
+pub fn update<F>(users_dir: &Path, id: &str, f: F) -> Result<Identity>
+where
+    F: FnOnce(&mut Identity) -> Result<()>,
+{
+    let _lock = LOCK.lock().unwrap();
+    for user in users(users_dir)? {
+        let mut identities = load(users_dir, &user)?;
+        if let Some(identity) = identities.iter_mut().find(|i| i.matches(id)) {
+            f(identity)?;
+            identity.last_modified = Timestamp::now();
+            let identity = identity.clone();
+            write(users_dir, &user, identities)?;
+            return Ok(identity);
+        }
+    }
+    Err(ServerError::not_found(format!(
+        "Identity not found: {}",
+        id
+    )))
+}
+
+/// Unlink the key matching `id` from its user, returning its former
+/// identity. Clients that already fetched it keep it.
+pub fn remove(users_dir: &Path, id: &str) -> Result<Identity> {
+    let _lock = LOCK.lock().unwrap();
+    for user in users(users_dir)? {
+        let mut identities = load(users_dir, &user)?;
+        if let Some(i) = identities.iter().position(|i| i.matches(id)) {
+            let identity = identities.remove(i);
+            write(users_dir, &user, identities)?;
+            return Ok(identity);
+        }
+    }
+    Err(ServerError::not_found(format!(
+        "Identity not found: {}",
+        id
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use libpijul::key::SKey;
+
+    use super::*;
+
+    #[test]
+    fn test_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let users = dir.path();
+        let key = SKey::generate(None).public_key();
+        assert!(all(users).unwrap().is_empty());
+
+        let linked = link(users, "alice", key.clone()).unwrap();
+        assert_eq!(linked.username, "alice");
+        assert_eq!(find(users, &key.key).unwrap(), Some(linked.clone()));
+        assert_eq!(find(users, &key.fingerprint()).unwrap(), Some(linked));
+        assert!(find(users, "unknown").unwrap().is_none());
+
+        let updated = update(users, &key.key, |i| {
+            i.display_name = "Alice".to_string();
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(updated.display_name, "Alice");
+        assert_eq!(list(users, "alice").unwrap(), vec![updated.clone()]);
+
+        // Linking again keeps the display name.
+        let relinked = link(users, "alice", key.clone()).unwrap();
+        assert_eq!(relinked.display_name, "Alice");
+        assert!(relinked.last_modified >= updated.last_modified);
+
+        // The key moves to the last user who proved they own it.
+        let moved = link(users, "bob", key.clone()).unwrap();
+        assert_eq!(moved.username, "bob");
+        assert!(list(users, "alice").unwrap().is_empty());
+        assert_eq!(all(users).unwrap(), vec![moved.clone()]);
+
+        assert_eq!(remove(users, &key.key).unwrap(), moved);
+        assert!(remove(users, &key.key).is_err());
+        assert!(update(users, &key.key, |_| Ok(())).is_err());
+        assert!(link(users, "../alice", key).is_err());
+    }
+}
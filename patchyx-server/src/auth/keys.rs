@@ -0,0 +1,232 @@
+//! Authorized SSH public keys.
+//!
+//! Each user has a directory under `users_dir` holding an
+//! `authorized_keys` file in the OpenSSH format (`TYPE BASE64
+//! [COMMENT]`, one key per line). Keys are identified by their
+//! OpenSSH-style SHA256 fingerprint.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::Serialize;
+use thrussh_keys::key::PublicKey;
+use thrussh_keys::PublicKeyBase64;
+use tracing::warn;
+
+use crate::error::{Result, ServerError};
+
+/// Name of the per-user file listing authorized keys.
+pub const AUTHORIZED_KEYS: &str = "authorized_keys";
+
+/// A public key allowed to authenticate as a user.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuthorizedKey {
+    /// Key algorithm, e.g. `ssh-ed25519`
+    pub algorithm: String,
+    /// Base64 key blob
+    pub key: String,
+    /// Free-form comment, usually `user@host`
+    pub comment: String,
+    /// SHA256 fingerprint, as printed by `ssh-keygen -l`
+    pub fingerprint: String,
+}
+
+impl AuthorizedKey {
+    /// Parse a line of an `authorized_keys` file.
+    pub fn parse(line: &str) -> Result<Self> {
+        let mut parts = line.split_whitespace();
+        let (Some(algorithm), Some(key)) = (parts.next(), parts.next()) else {
+            return Err(ServerError::bad_request("Expected `TYPE BASE64 [COMMENT]`"));
+        };
+        let public_key = thrussh_keys::parse_public_key_base64(key)
+            .map_err(|e| ServerError::bad_request(format!("Invalid public key: {}", e)))?;
+        if public_key.name() != algorithm {
+            return Err(ServerError::bad_request(format!(
+                "Key type mismatch: {} is a {} key",
+                algorithm,
+                public_key.name()
+            )));
+        }
+        Ok(AuthorizedKey {
+            algorithm: algorithm.to_string(),
+            key: public_key.public_key_base64(),
+            comment: parts.collect::<Vec<_>>().join(" "),
+            fingerprint: fingerprint(&public_key),
+        })
+    }
+
+    fn to_line(&self) -> String {
+        if self.comment.is_empty() {
+            format!("{} {}", self.algorithm, self.key)
+        } else {
+            format!("{} {} {}", self.algorithm, self.key, self.comment)
+        }
+    }
+}
+
+/// OpenSSH-style fingerprint of a public key.
+pub fn fingerprint(key: &PublicKey) -> String {
+    format!("SHA256:{}", key.fingerprint())
+}
+
+/// Check that a user name is safe to use as a directory name.
+pub fn validate_user_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if valid {
+        Ok(())
+    } else {
+        Err(ServerError::bad_request(format!(
+            "Invalid user name: {:?}",
+            name
+        )))
+    }
+}
+
+/// Registry of the public keys of each user.
+pub struct KeyStore {
+    users_dir: PathBuf,
+    /// Serializes updates of `authorized_keys` files.
+    write_lock: Mutex<()>,
+}
+
+impl KeyStore {
+    /// Create a store over the configured users directory.
+    pub fn new(users_dir: impl AsRef<Path>) -> Self {
+        Self {
+            users_dir: users_dir.as_ref().to_path_buf(),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    fn keys_path(&self, user: &str) -> PathBuf {
+        self.users_dir.join(user).join(AUTHORIZED_KEYS)
+    }
+
+    /// List the keys of a user.
+    pub fn list(&self, user: &str) -> Result<Vec<AuthorizedKey>> {
+        validate_user_name(user)?;
+        let contents = match std::fs::read_to_string(self.keys_path(user)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut keys = Vec::new();
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match AuthorizedKey::parse(line) {
+                Ok(key) => keys.push(key),
+                Err(e) => warn!(user = user, line = n + 1, error = %e, "Skipping invalid key"),
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Authorize a new key for a user, given as an `authorized_keys`
+    /// line.
+    pub fn add(&self, user: &str, line: &str) -> Result<AuthorizedKey> {
+        let key = AuthorizedKey::parse(line)?;
+        let _lock = self.write_lock.lock().unwrap();
+        let mut keys = self.list(user)?;
+        if keys.iter().any(|k| k.fingerprint == key.fingerprint) {
+            return Err(ServerError::bad_request(format!(
+                "Key {} is already authorized",
+                key.fingerprint
+            )));
+        }
+        keys.push(key.clone());
+        self.write(user, &keys)?;
+        Ok(key)
+    }
+
+    /// Revoke the key of a user with the given fingerprint.
+    pub fn revoke(&self, user: &str, fingerprint: &str) -> Result<()> {
+        let _lock = self.write_lock.lock().unwrap();
+        let mut keys = self.list(user)?;
+        let len = keys.len();
+        keys.retain(|k| k.fingerprint != fingerprint);
+        if keys.len() == len {
+            return Err(ServerError::not_found(format!(
+                "Key {} not found for user {}",
+                fingerprint, user
+            )));
+        }
+        self.write(user, &keys)
+    }
+
+    /// Check whether `key` may authenticate as `user`.
+    pub fn is_authorized(&self, user: &str, key: &PublicKey) -> bool {
+        let fingerprint = fingerprint(key);
+        match self.list(user) {
+            Ok(keys) => keys.iter().any(|k| k.fingerprint == fingerprint),
+            Err(e) => {
+                warn!(user = user, error = %e, "Cannot read authorized keys");
+                false
+            }
+        }
+    }
+
+    /// Replace the keys of a user.
+    fn write(&self, user: &str, keys: &[AuthorizedKey]) -> Result<()> {
+        let path = self.keys_path(user);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let mut contents = String::new();
+        for key in keys {
+            contents.push_str(&key.to_line());
+            contents.push('\n');
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use thrussh_keys::key::KeyPair;
+
+    use super::*;
+
+    fn key_line(key: &PublicKey, comment: &str) -> String {
+        format!("{} {} {}", key.name(), key.public_key_base64(), comment)
+    }
+
+    #[test]
+    fn test_add_list_revoke() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = KeyStore::new(dir.path());
+        let alice = KeyPair::generate_ed25519().unwrap().clone_public_key();
+        let bob = KeyPair::generate_ed25519().unwrap().clone_public_key();
+
+        assert!(!store.is_authorized("alice", &alice));
+        let added = store
+            .add("alice", &key_line(&alice, "alice@laptop"))
+            .unwrap();
+        assert_eq!(added.comment, "alice@laptop");
+        assert!(store.is_authorized("alice", &alice));
+        assert!(!store.is_authorized("alice", &bob));
+        assert!(!store.is_authorized("bob", &alice));
+        assert!(store.add("alice", &key_line(&alice, "")).is_err());
+
+        assert_eq!(store.list("alice").unwrap(), vec![added.clone()]);
+        store.revoke("alice", &added.fingerprint).unwrap();
+        assert!(!store.is_authorized("alice", &alice));
+        assert!(store.revoke("alice", &added.fingerprint).is_err());
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = KeyStore::new(dir.path());
+        assert!(store.add("alice", "ssh-ed25519").is_err());
+        assert!(store.add("alice", "ssh-ed25519 notbase64").is_err());
+        assert!(store.list("../alice").is_err());
+    }
+}
@@ -0,0 +1,11 @@
+//! User authentication and authorization.
+
+pub mod access;
+pub mod identities;
+pub mod keys;
+pub mod tokens;
+
+pub use access::{Access, AccessList, Caller, Role};
+pub use identities::Identity;
+pub use keys::{AuthorizedKey, KeyStore};
+pub use tokens::TokenStore;
@@ -0,0 +1,125 @@
+//! HTTP API tokens.
+//!
+//! Tokens look like `pxt_USER_SECRET`. Only a BLAKE3 hash of each
+//! token is stored, in the `tokens` file of the user's directory
+//! under `users_dir`, one hash per line.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rand::Rng;
+
+use super::keys::validate_user_name;
+use crate::error::Result;
+
+/// Name of the per-user file listing token hashes.
+pub const TOKENS_FILE: &str = "tokens";
+
+const TOKEN_PREFIX: &str = "pxt_";
+const SECRET_LEN: usize = 40;
+
+/// Registry of the API tokens of each user.
+pub struct TokenStore {
+    users_dir: PathBuf,
+    /// Serializes updates of `tokens` files.
+    write_lock: Mutex<()>,
+}
+
+impl TokenStore {
+    /// Create a store over the configured users directory.
+    pub fn new(users_dir: impl AsRef<Path>) -> Self {
+        Self {
+            users_dir: users_dir.as_ref().to_path_buf(),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    fn tokens_path(&self, user: &str) -> PathBuf {
+        self.users_dir.join(user).join(TOKENS_FILE)
+    }
+
+    /// Create a new token for `user`. The token itself is not stored
+    /// and can only be shown once.
+    pub fn create(&self, user: &str) -> Result<String> {
+        validate_user_name(user)?;
+        let secret: String = rand::rng()
+            .sample_iter(&rand::distr::Alphanumeric)
+            .take(SECRET_LEN)
+            .map(char::from)
+            .collect();
+        let token = format!("{}{}_{}", TOKEN_PREFIX, user, secret);
+        let _lock = self.write_lock.lock().unwrap();
+        let path = self.tokens_path(user);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let mut contents = std::fs::read_to_string(&path).unwrap_or_default();
+        contents.push_str(&blake3::hash(token.as_bytes()).to_hex());
+        contents.push('\n');
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(token)
+    }
+
+    /// Revoke all the tokens of `user`.
+    pub fn revoke_all(&self, user: &str) -> Result<()> {
+        validate_user_name(user)?;
+        let _lock = self.write_lock.lock().unwrap();
+        match std::fs::remove_file(self.tokens_path(user)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Find the user a token belongs to.
+    pub fn lookup(&self, token: &str) -> Option<String> {
+        let (user, _) = token.strip_prefix(TOKEN_PREFIX)?.rsplit_once('_')?;
+        validate_user_name(user).ok()?;
+        let hash = blake3::hash(token.as_bytes());
+        let contents = std::fs::read_to_string(self.tokens_path(user)).ok()?;
+        // Hashes compare in constant time.
+        contents
+            .lines()
+            .filter_map(|line| blake3::Hash::from_hex(line.trim()).ok())
+            .any(|h| h == hash)
+            .then(|| user.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_lookup_revoke() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::new(dir.path());
+        let first = store.create("alice").unwrap();
+        let second = store.create("alice").unwrap();
+        let bob = store.create("bob").unwrap();
+        assert!(first.starts_with("pxt_alice_"));
+        assert_ne!(first, second);
+        assert_eq!(store.lookup(&first).as_deref(), Some("alice"));
+        assert_eq!(store.lookup(&second).as_deref(), Some("alice"));
+        assert_eq!(store.lookup(&bob).as_deref(), Some("bob"));
+
+        // Only hashes are stored.
+        let stored = std::fs::read_to_string(dir.path().join("alice").join(TOKENS_FILE)).unwrap();
+        assert!(!stored.contains(&first[TOKEN_PREFIX.len()..]));
+
+        // Forged tokens are unknown.
+        assert_eq!(store.lookup(&first.replace("alice", "bob")), None);
+        assert_eq!(store.lookup(&format!("{}x", first)), None);
+        assert_eq!(store.lookup("pxt_alice"), None);
+        assert_eq!(store.lookup("nope"), None);
+        assert!(store.create("../alice").is_err());
+
+        // Revoking tokens leaves those of other users alone.
+        store.revoke_all("alice").unwrap();
+        assert_eq!(store.lookup(&first), None);
+        assert_eq!(store.lookup(&second), None);
+        assert_eq!(store.lookup(&bob).as_deref(), Some("bob"));
+        store.revoke_all("alice").unwrap();
+        let third = store.create("alice").unwrap();
+        assert_eq!(store.lookup(&third).as_deref(), Some("alice"));
+    }
+}
@@ -1,17 +1,317 @@
 //! Server configuration management.
 //!
-//! Configuration is loaded from environment variables with sensible defaults.
-//! This allows for easy deployment in containerized environments.
+//! Configuration is read from a TOML file (`patchyx.toml`, or the
+//! file named by `PATCHYX_CONFIG`), then overridden by environment
+//! variables, which makes deployments in containers easy. Sending
+//! `SIGHUP` to the server reloads it: see [`SharedConfig::reload`].
 
+use std::collections::BTreeMap;
 use std::env;
 use std::net::{IpAddr, Ipv4Addr};
-use std::path::PathBuf;
-use tracing::info;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn};
 
 use crate::error::{Result, ServerError};
 
-/// Server configuration loaded from environment variables.
-#[derive(Debug, Clone)]
+/// Configuration file read when `PATCHYX_CONFIG` isn't set, if it
+/// exists.
+pub const DEFAULT_CONFIG_FILE: &str = "patchyx.toml";
+
+/// A configuration value that must not end up in logs.
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl Secret {
+    /// Get the secret value.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+/// A way of authenticating users.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthBackend {
+    /// SSH keys listed in the users' `authorized_keys` files
+    Keys,
+    /// HTTP API tokens
+    Tokens,
+}
+
+/// Authentication settings (`[auth]`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Users with admin rights on every repository
+    pub admins: Vec<String>,
+    /// Bearer token granting server admin rights over HTTP
+    pub admin_token: Option<Secret>,
+    /// Enabled ways of authenticating users. The admin token works
+    /// regardless.
+    pub backends: Vec<AuthBackend>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            admins: Vec::new(),
+            admin_token: None,
+            backends: vec![AuthBackend::Keys, AuthBackend::Tokens],
+        }
+    }
+}
+
+impl AuthConfig {
+    /// Whether `backend` is enabled.
+    pub fn enabled(&self, backend: AuthBackend) -> bool {
+        self.backends.contains(&backend)
+    }
+}
+
+/// A rate limit, in operations per minute (`[limits.*]`). A missing
+/// or 0 rate means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    /// Operations from one address
+    pub per_addr: u32,
+    /// Operations by one user
+    pub per_user: u32,
+}
+
+/// Resource limits (`[limits]`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Maximum size of a pushed change, in bytes
+    pub max_upload_size: u64,
+    /// Maximum total size of the changes of a push, in bytes
+    pub max_push_size: u64,
+    /// Maximum number of concurrent pijul sessions on one SSH
+    /// connection
+    pub max_channels: usize,
+    /// SSH connections, counted per user once authenticated
+    pub ssh_connections: RateLimit,
+    /// Failed SSH and HTTP authentication attempts, counted per user
+    /// when the user is known before authenticating (SSH). All the
+    /// attempts are refused once the limit is reached.
+    pub auth_failures: RateLimit,
+    /// HTTP requests, counted per user for authenticated requests
+    pub http_requests: RateLimit,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_upload_size: 1 << 30,
+            max_push_size: 1 << 32,
+            max_channels: 8,
+            ssh_connections: RateLimit {
+                per_addr: 60,
+                per_user: 60,
+            },
+            auth_failures: RateLimit {
+                per_addr: 30,
+                per_user: 30,
+            },
+            http_requests: RateLimit {
+                per_addr: 1200,
+                per_user: 1200,
+            },
+        }
+    }
+}
+
+/// Push hooks settings (`[hooks]`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    /// Whether the repositories' `hooks.toml` files are used
+    pub enabled: bool,
+    /// Hooks running longer than this are killed
+    pub timeout_secs: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout_secs: 300,
+        }
+    }
+}
+
+/// Webhook delivery settings (`[webhooks]`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    /// Whether events are queued for webhooks
+    pub enabled: bool,
+    /// Deliveries are abandoned after that many failed attempts
+    pub max_attempts: usize,
+    /// Delay before the first retry, doubled after each failure
+    pub first_retry_secs: u64,
+    /// Timeout of each delivery request
+    pub timeout_secs: u64,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_attempts: 8,
+            first_retry_secs: 30,
+            timeout_secs: 10,
+        }
+    }
+}
+
+/// Mirror settings (`[mirrors]`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MirrorsConfig {
+    /// Whether pull mirrors are synced and push mirrors pushed to in
+    /// the background. Pull mirrors can still be synced through the
+    /// HTTP API.
+    pub enabled: bool,
+    /// Delay between two syncs of a pull mirror
+    pub interval_secs: u64,
+    /// Syncs and pushes of a channel taking longer than this are
+    /// abandoned
+    pub timeout_secs: u64,
+    /// Pushes to a push mirror are abandoned after that many failed
+    /// attempts
+    pub max_attempts: usize,
+    /// Delay before the first retry of a push, doubled after each
+    /// failure
+    pub first_retry_secs: u64,
+}
+
+impl Default for MirrorsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 600,
+            timeout_secs: 600,
+            max_attempts: 8,
+            first_retry_secs: 30,
+        }
+    }
+}
+
+/// Integrity check settings (`[fsck]`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FsckConfig {
+    /// Whether repositories are checked in the background. They can
+    /// still be checked through the HTTP API.
+    pub enabled: bool,
+    /// Delay between two checks of a repository
+    pub interval_secs: u64,
+}
+
+impl Default for FsckConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 24 * 3600,
+        }
+    }
+}
+
+/// Audit log settings (`[audit]`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Whether mutating operations are recorded
+    pub enabled: bool,
+    /// The audit log, a JSON record per line
+    pub path: PathBuf,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: PathBuf::from("./audit.log"),
+        }
+    }
+}
+
+/// An SSH host key algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostKeyAlgorithm {
+    Ed25519,
+    Rsa,
+}
+
+/// SSH host key settings (`[host_keys]`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostKeysConfig {
+    /// Algorithms of the host keys offered to clients
+    pub algorithms: Vec<HostKeyAlgorithm>,
+    /// How long a rotated key stays in use after its replacement is
+    /// published
+    pub grace_secs: u64,
+}
+
+impl Default for HostKeysConfig {
+    fn default() -> Self {
+        Self {
+            algorithms: vec![HostKeyAlgorithm::Ed25519, HostKeyAlgorithm::Rsa],
+            grace_secs: 7 * 24 * 3600,
+        }
+    }
+}
+
+/// Per-repository overrides of the global settings (`[repos.NAME]`).
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoConfig {
+    pub max_upload_size: Option<u64>,
+    pub max_push_size: Option<u64>,
+    /// Whether the repository's hooks run
+    pub hooks: Option<bool>,
+    /// Whether the repository's webhooks get events
+    pub webhooks: Option<bool>,
+}
+
+/// The settings applying to one repository.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepoSettings {
+    pub max_upload_size: u64,
+    pub max_push_size: u64,
+    pub hooks: bool,
+    pub hook_timeout: Duration,
+    pub webhooks: bool,
+    pub audit: AuditConfig,
+    /// Where the identities linked to users are stored
+    pub users_dir: PathBuf,
+}
+
+impl Default for RepoSettings {
+    fn default() -> Self {
+        ServerConfig::default().repo_settings("")
+    }
+}
+
+/// Server configuration.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ServerConfig {
     /// SSH server bind address
     pub ssh_host: IpAddr,
@@ -21,14 +321,30 @@ pub struct ServerConfig {
     pub http_host: IpAddr,
     /// HTTP server port (default: 3000)
     pub http_port: u16,
-    /// Path to SSH host key file
+    /// Path to the SSH Ed25519 host key. Keys of other algorithms
+    /// are stored next to it.
     pub host_key_path: PathBuf,
     /// Directory containing repositories
     pub repos_dir: PathBuf,
+    /// Directory containing per-user data (authorized keys, tokens)
+    pub users_dir: PathBuf,
     /// Log level (trace, debug, info, warn, error)
     pub log_level: String,
     /// Whether to generate host key if missing
     pub generate_host_key: bool,
+    pub host_keys: HostKeysConfig,
+    pub auth: AuthConfig,
+    pub limits: Limits,
+    pub hooks: HooksConfig,
+    pub webhooks: WebhooksConfig,
+    pub mirrors: MirrorsConfig,
+    pub fsck: FsckConfig,
+    pub audit: AuditConfig,
+    /// Per-repository settings, by repository name
+    pub repos: BTreeMap<String, RepoConfig>,
+    /// The configuration file, if any
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
 }
 
 impl Default for ServerConfig {
@@ -40,73 +356,249 @@ impl Default for ServerConfig {
             http_port: 3000,
             host_key_path: PathBuf::from("./host_key"),
             repos_dir: PathBuf::from("./repos"),
+            users_dir: PathBuf::from("./users"),
             log_level: String::from("info"),
             generate_host_key: true,
+            host_keys: HostKeysConfig::default(),
+            auth: AuthConfig::default(),
+            limits: Limits::default(),
+            hooks: HooksConfig::default(),
+            webhooks: WebhooksConfig::default(),
+            mirrors: MirrorsConfig::default(),
+            fsck: FsckConfig::default(),
+            audit: AuditConfig::default(),
+            repos: BTreeMap::new(),
+            path: None,
+        }
+    }
+}
+
+/// Where configuration values come from, to point at them in errors.
+struct Sources<'a> {
+    path: Option<&'a Path>,
+    text: &'a str,
+    doc: Option<toml_edit::ImDocument<&'a str>>,
+    /// Keys set by environment variables, and these variables
+    env: Vec<(&'static str, &'static str)>,
+}
+
+impl Sources<'_> {
+    /// Describe where the value at `key` comes from: a line of the
+    /// configuration file, an environment variable, or nothing when
+    /// the value is a default.
+    fn locate(&self, key: &[&str]) -> Option<String> {
+        let dotted = key.join(".");
+        if let Some((_, var)) = self.env.iter().find(|(k, _)| *k == dotted) {
+            return Some(var.to_string());
+        }
+        let mut item = self.doc.as_ref()?.as_item();
+        for k in key {
+            item = item.get(k)?;
+        }
+        let start = item.span()?.start;
+        let line = self.text[..start].matches('\n').count() + 1;
+        Some(format!("{}:{}", self.path?.display(), line))
+    }
+
+    fn error(&self, key: &[&str], msg: &str) -> String {
+        match self.locate(key) {
+            Some(location) => format!("{}: {}: {}", location, key.join("."), msg),
+            None => format!("{}: {}", key.join("."), msg),
         }
     }
 }
 
 impl ServerConfig {
-    /// Load configuration from environment variables.
+    /// Load the configuration file, if any, and apply the
+    /// environment variables overriding it.
     ///
     /// # Environment Variables
+    /// - `PATCHYX_CONFIG`: Configuration file (default: `patchyx.toml`)
     /// - `PATCHYX_SSH_HOST`: SSH bind address (default: 0.0.0.0)
     /// - `PATCHYX_SSH_PORT`: SSH port (default: 2222)
     /// - `PATCHYX_HTTP_HOST`: HTTP bind address (default: 127.0.0.1)
     /// - `PATCHYX_HTTP_PORT`: HTTP port (default: 3000)
     /// - `PATCHYX_HOST_KEY_PATH`: Path to host key file
     /// - `PATCHYX_REPOS_DIR`: Repository storage directory
+    /// - `PATCHYX_USERS_DIR`: User data directory
+    /// - `PATCHYX_ADMINS`: Comma-separated list of server administrators
+    /// - `PATCHYX_ADMIN_TOKEN`: HTTP bearer token with admin rights
     /// - `PATCHYX_LOG_LEVEL`: Logging level
+    /// - `PATCHYX_AUDIT_LOG`: Audit log file (default: ./audit.log)
     /// - `PATCHYX_GENERATE_HOST_KEY`: Generate key if missing (default: true)
-    pub fn from_env() -> Result<Self> {
-        let mut config = Self::default();
+    pub fn load() -> Result<Self> {
+        let path = match env::var("PATCHYX_CONFIG") {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|p| p.exists()),
+        };
+        let text = match path {
+            Some(ref path) => std::fs::read_to_string(path).map_err(|e| {
+                ServerError::config(format!("Cannot read {}: {}", path.display(), e))
+            })?,
+            None => String::new(),
+        };
+        Self::parse(path.as_deref(), &text, |var| env::var(var).ok())
+    }
 
-        if let Ok(val) = env::var("PATCHYX_SSH_HOST") {
-            config.ssh_host = val
-                .parse()
-                .map_err(|_| ServerError::config(format!("Invalid SSH host: {}", val)))?;
-        }
+    /// Parse a configuration file, apply the environment variables
+    /// given by `env` and validate the result.
+    fn parse(
+        path: Option<&Path>,
+        text: &str,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self> {
+        let name = path.map(|p| p.display().to_string()).unwrap_or_default();
+        let mut config: Self = toml::from_str(text).map_err(|e| {
+            let line = e
+                .span()
+                .map(|s| format!(":{}", text[..s.start].matches('\n').count() + 1))
+                .unwrap_or_default();
+            ServerError::config(format!("{}{}: {}", name, line, e.message()))
+        })?;
+        config.path = path.map(Path::to_path_buf);
+        let mut sources = Sources {
+            path,
+            text,
+            doc: toml_edit::ImDocument::parse(text).ok(),
+            env: Vec::new(),
+        };
+        config.apply_env(&mut sources, env)?;
+        config.validate(&sources)?;
+        Ok(config)
+    }
 
-        if let Ok(val) = env::var("PATCHYX_SSH_PORT") {
-            config.ssh_port = val
-                .parse()
-                .map_err(|_| ServerError::config(format!("Invalid SSH port: {}", val)))?;
+    fn apply_env(
+        &mut self,
+        sources: &mut Sources,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<()> {
+        fn parse<T: std::str::FromStr>(var: &str, val: &str) -> Result<T> {
+            val.parse()
+                .map_err(|_| ServerError::config(format!("{}: invalid value {:?}", var, val)))
         }
+        let mut var = |key: &'static str, var: &'static str| {
+            let val = env(var)?;
+            sources.env.push((key, var));
+            Some(val)
+        };
 
-        if let Ok(val) = env::var("PATCHYX_HTTP_HOST") {
-            config.http_host = val
-                .parse()
-                .map_err(|_| ServerError::config(format!("Invalid HTTP host: {}", val)))?;
+        if let Some(val) = var("ssh_host", "PATCHYX_SSH_HOST") {
+            self.ssh_host = parse("PATCHYX_SSH_HOST", &val)?;
         }
-
-        if let Ok(val) = env::var("PATCHYX_HTTP_PORT") {
-            config.http_port = val
-                .parse()
-                .map_err(|_| ServerError::config(format!("Invalid HTTP port: {}", val)))?;
+        if let Some(val) = var("ssh_port", "PATCHYX_SSH_PORT") {
+            self.ssh_port = parse("PATCHYX_SSH_PORT", &val)?;
         }
-
-        if let Ok(val) = env::var("PATCHYX_HOST_KEY_PATH") {
-            config.host_key_path = PathBuf::from(val);
+        if let Some(val) = var("http_host", "PATCHYX_HTTP_HOST") {
+            self.http_host = parse("PATCHYX_HTTP_HOST", &val)?;
         }
-
-        if let Ok(val) = env::var("PATCHYX_REPOS_DIR") {
-            config.repos_dir = PathBuf::from(val);
+        if let Some(val) = var("http_port", "PATCHYX_HTTP_PORT") {
+            self.http_port = parse("PATCHYX_HTTP_PORT", &val)?;
         }
-
-        if let Ok(val) = env::var("PATCHYX_LOG_LEVEL") {
-            config.log_level = val;
+        if let Some(val) = var("host_key_path", "PATCHYX_HOST_KEY_PATH") {
+            self.host_key_path = PathBuf::from(val);
         }
-
-        if let Ok(val) = env::var("PATCHYX_GENERATE_HOST_KEY") {
-            config.generate_host_key = val.to_lowercase() == "true" || val == "1";
+        if let Some(val) = var("repos_dir", "PATCHYX_REPOS_DIR") {
+            self.repos_dir = PathBuf::from(val);
         }
-
-        config.validate()?;
-        Ok(config)
+        if let Some(val) = var("users_dir", "PATCHYX_USERS_DIR") {
+            self.users_dir = PathBuf::from(val);
+        }
+        if let Some(val) = var("auth.admins", "PATCHYX_ADMINS") {
+            self.auth.admins = val
+                .split(',')
+                .map(|a| a.trim().to_string())
+                .filter(|a| !a.is_empty())
+                .collect();
+        }
+        if let Some(val) = var("auth.admin_token", "PATCHYX_ADMIN_TOKEN") {
+            self.auth.admin_token = Some(val).filter(|t| !t.is_empty()).map(Secret);
+        }
+        if let Some(val) = var("log_level", "PATCHYX_LOG_LEVEL") {
+            self.log_level = val;
+        }
+        if let Some(val) = var("audit.path", "PATCHYX_AUDIT_LOG") {
+            self.audit.path = PathBuf::from(val);
+        }
+        if let Some(val) = var("generate_host_key", "PATCHYX_GENERATE_HOST_KEY") {
+            self.generate_host_key = val.to_lowercase() == "true" || val == "1";
+        }
+        Ok(())
     }
 
-    /// Validate the configuration.
-    fn validate(&self) -> Result<()> {
+    /// Validate the configuration, reporting all the errors at once.
+    fn validate(&self, sources: &Sources) -> Result<()> {
+        let mut errors = Vec::new();
+        if self.ssh_port == 0 {
+            errors.push(sources.error(&["ssh_port"], "must not be 0"));
+        }
+        if self.http_port == 0 {
+            errors.push(sources.error(&["http_port"], "must not be 0"));
+        }
+        if self.ssh_addr() == self.http_addr().to_string() {
+            errors.push(sources.error(&["http_port"], "same address as the SSH server"));
+        }
+        if self.log_level.parse::<LevelFilter>().is_err() {
+            errors.push(sources.error(
+                &["log_level"],
+                "expected one of off, error, warn, info, debug, trace",
+            ));
+        }
+        if self.host_keys.algorithms.is_empty() {
+            errors.push(sources.error(&["host_keys", "algorithms"], "must not be empty"));
+        }
+        for admin in self.auth.admins.iter() {
+            if crate::auth::keys::validate_user_name(admin).is_err() {
+                let msg = format!("invalid user name {:?}", admin);
+                errors.push(sources.error(&["auth", "admins"], &msg));
+            }
+        }
+        if self.limits.max_upload_size == 0 {
+            errors.push(sources.error(&["limits", "max_upload_size"], "must not be 0"));
+        }
+        if self.limits.max_push_size == 0 {
+            errors.push(sources.error(&["limits", "max_push_size"], "must not be 0"));
+        }
+        if self.limits.max_channels == 0 {
+            errors.push(sources.error(&["limits", "max_channels"], "must not be 0"));
+        }
+        if self.hooks.timeout_secs == 0 {
+            errors.push(sources.error(&["hooks", "timeout_secs"], "must not be 0"));
+        }
+        if self.webhooks.max_attempts == 0 {
+            errors.push(sources.error(&["webhooks", "max_attempts"], "must not be 0"));
+        }
+        if self.webhooks.timeout_secs == 0 {
+            errors.push(sources.error(&["webhooks", "timeout_secs"], "must not be 0"));
+        }
+        if self.mirrors.interval_secs == 0 {
+            errors.push(sources.error(&["mirrors", "interval_secs"], "must not be 0"));
+        }
+        if self.mirrors.timeout_secs == 0 {
+            errors.push(sources.error(&["mirrors", "timeout_secs"], "must not be 0"));
+        }
+        if self.mirrors.max_attempts == 0 {
+            errors.push(sources.error(&["mirrors", "max_attempts"], "must not be 0"));
+        }
+        if self.fsck.interval_secs == 0 {
+            errors.push(sources.error(&["fsck", "interval_secs"], "must not be 0"));
+        }
+        for (name, repo) in self.repos.iter() {
+            if crate::repo::RepoStore::normalize_name(name).ok() != Some(name) {
+                errors.push(sources.error(&["repos", name], "invalid repository name"));
+            }
+            if repo.max_upload_size == Some(0) {
+                let key = ["repos", name, "max_upload_size"];
+                errors.push(sources.error(&key, "must not be 0"));
+            }
+            if repo.max_push_size == Some(0) {
+                let key = ["repos", name, "max_push_size"];
+                errors.push(sources.error(&key, "must not be 0"));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(ServerError::config(errors.join("\n")));
+        }
+
         // Ensure repos directory exists or can be created
         if !self.repos_dir.exists() {
             std::fs::create_dir_all(&self.repos_dir).map_err(|e| {
@@ -118,9 +610,38 @@ impl ServerConfig {
             info!("Created repositories directory: {:?}", self.repos_dir);
         }
 
+        if !self.users_dir.exists() {
+            std::fs::create_dir_all(&self.users_dir).map_err(|e| {
+                ServerError::config(format!(
+                    "Cannot create users directory {:?}: {}",
+                    self.users_dir, e
+                ))
+            })?;
+            info!("Created users directory: {:?}", self.users_dir);
+        }
+
         Ok(())
     }
 
+    /// The settings of repository `name`.
+    pub fn repo_settings(&self, name: &str) -> RepoSettings {
+        let repo = self.repos.get(name).cloned().unwrap_or_default();
+        RepoSettings {
+            max_upload_size: repo.max_upload_size.unwrap_or(self.limits.max_upload_size),
+            max_push_size: repo.max_push_size.unwrap_or(self.limits.max_push_size),
+            hooks: self.hooks.enabled && repo.hooks.unwrap_or(true),
+            hook_timeout: Duration::from_secs(self.hooks.timeout_secs),
+            webhooks: self.webhooks.enabled && repo.webhooks.unwrap_or(true),
+            audit: self.audit.clone(),
+            users_dir: self.users_dir.clone(),
+        }
+    }
+
+    /// The maximum log level.
+    pub fn log_filter(&self) -> LevelFilter {
+        self.log_level.parse().unwrap_or(LevelFilter::INFO)
+    }
+
     /// Get the SSH socket address as a string.
     pub fn ssh_addr(&self) -> String {
         format!("{}:{}", self.ssh_host, self.ssh_port)
@@ -132,14 +653,136 @@ impl ServerConfig {
     }
 }
 
+/// The configuration shared by the running servers.
+pub struct SharedConfig {
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl From<ServerConfig> for SharedConfig {
+    fn from(config: ServerConfig) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+}
+
+impl SharedConfig {
+    /// The current configuration.
+    pub fn get(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Load and validate the configuration again, and apply it if it
+    /// is valid. Settings of the listeners and storage directories
+    /// can't change while running: they keep their current value,
+    /// with a warning. Sessions in progress aren't interrupted.
+    pub fn reload(&self) -> Result<Arc<ServerConfig>> {
+        let mut new = ServerConfig::load()?;
+        let old = self.get();
+        macro_rules! keep {
+            ($($($field:ident).+),*) => {
+                $(if new.$($field).+ != old.$($field).+ {
+                    let setting = stringify!($($field).+);
+                    warn!(setting, "Setting needs a restart, ignoring");
+                    new.$($field).+ = old.$($field).+.clone();
+                })*
+            };
+        }
+        keep!(
+            ssh_host,
+            ssh_port,
+            http_host,
+            http_port,
+            host_key_path,
+            generate_host_key,
+            host_keys.algorithms,
+            repos_dir,
+            users_dir
+        );
+        let new = Arc::new(new);
+        *self.current.write().unwrap() = new.clone();
+        Ok(new)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn parse(text: &str, env: &[(&str, &str)]) -> Result<ServerConfig> {
+        let dir = tempfile::tempdir().unwrap();
+        let text = format!(
+            "repos_dir = {:?}\nusers_dir = {:?}\n{}",
+            dir.path().join("repos"),
+            dir.path().join("users"),
+            text
+        );
+        ServerConfig::parse(Some(Path::new("patchyx.toml")), &text, |var| {
+            env.iter()
+                .find(|(k, _)| *k == var)
+                .map(|(_, v)| v.to_string())
+        })
+    }
+
     #[test]
     fn test_default_config() {
         let config = ServerConfig::default();
         assert_eq!(config.ssh_port, 2222);
         assert_eq!(config.http_port, 3000);
     }
+
+    #[test]
+    fn test_file_and_env() {
+        let text = r#"
+ssh_port = 2022
+
+[auth]
+admins = ["alice"]
+backends = ["keys"]
+
+[limits]
+max_upload_size = 1000
+
+[limits.http_requests]
+per_addr = 10
+
+[repos.big]
+max_upload_size = 5000
+hooks = false
+"#;
+        let config = parse(text, &[("PATCHYX_ADMINS", "bob, carol")]).unwrap();
+        assert_eq!(config.ssh_port, 2022);
+        assert_eq!(config.auth.admins, vec!["bob", "carol"]);
+        assert!(!config.auth.enabled(AuthBackend::Tokens));
+        assert_eq!(config.repo_settings("small").max_upload_size, 1000);
+        assert_eq!(config.limits.http_requests.per_addr, 10);
+        assert_eq!(config.limits.http_requests.per_user, 0);
+        assert_eq!(config.limits.auth_failures, Limits::default().auth_failures);
+        let big = config.repo_settings("big");
+        assert_eq!(big.max_upload_size, 5000);
+        assert!(!big.hooks);
+        assert!(big.webhooks);
+    }
+
+    #[test]
+    fn test_errors() {
+        // Syntax and type errors point at their line.
+        let err = parse("[limits]\nmax_upload_size = \"big\"\n", &[]).unwrap_err();
+        assert!(err.to_string().contains("patchyx.toml:4: "), "{}", err);
+        let err = parse("[hooks]\nenabeld = true\n", &[]).unwrap_err();
+        assert!(err.to_string().contains("patchyx.toml:4: "), "{}", err);
+
+        // So do validation errors, all reported at once.
+        let text = "log_level = \"loud\"\n\n[repos.\"a/b\"]\nmax_upload_size = 0\n";
+        let err = parse(text, &[("PATCHYX_SSH_PORT", "0")]).unwrap_err();
+        let err = err.to_string();
+        for expected in [
+            "PATCHYX_SSH_PORT: ssh_port: must not be 0",
+            "patchyx.toml:3: log_level: expected",
+            "patchyx.toml:5: repos.a/b: invalid",
+            "patchyx.toml:6: repos.a/b.max_upload_size:",
+        ] {
+            assert!(err.contains(expected), "{}", err);
+        }
+    }
 }
@@ -3,6 +3,8 @@
 //! This module provides a unified error type for all server operations,
 //! with proper context and conversion from underlying library errors.
 
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
 use std::io;
 use thiserror::Error;
 
@@ -37,6 +39,18 @@ pub enum ServerError {
     #[error("Not found: {0}")]
     NotFound(String),
 
+    /// Conflicting state (resource already exists)
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    /// Invalid client input (malformed names, keys, bodies)
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+
+    /// Rate limit exceeded, with the number of seconds to wait
+    #[error("Rate limited: {0}")]
+    TooManyRequests(String, u64),
+
     /// Internal server error
     #[error("Internal error: {0}")]
     Internal(String),
@@ -73,6 +87,22 @@ impl ServerError {
         Self::NotFound(msg.into())
     }
 
+    /// Create a conflict error with a message.
+    pub fn conflict(msg: impl Into<String>) -> Self {
+        Self::Conflict(msg.into())
+    }
+
+    /// Create a bad request error with a message.
+    pub fn bad_request(msg: impl Into<String>) -> Self {
+        Self::BadRequest(msg.into())
+    }
+
+    /// Create a rate limit error with a message and the number of
+    /// seconds until the client may retry.
+    pub fn too_many_requests(msg: impl Into<String>, retry_secs: u64) -> Self {
+        Self::TooManyRequests(msg.into(), retry_secs)
+    }
+
     /// Create an internal error with a message.
     pub fn internal(msg: impl Into<String>) -> Self {
         Self::Internal(msg.into())
@@ -102,3 +132,25 @@ impl From<thrussh_keys::Error> for ServerError {
         ServerError::Ssh(format!("Key error: {}", err))
     }
 }
+
+// HTTP handlers return `Result<_, ServerError>` directly.
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            ServerError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServerError::Conflict(_) => StatusCode::CONFLICT,
+            ServerError::BadRequest(_) | ServerError::Protocol(_) => StatusCode::BAD_REQUEST,
+            ServerError::Auth(_) => StatusCode::FORBIDDEN,
+            ServerError::TooManyRequests(_, _) => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = Json(serde_json::json!({ "error": self.to_string() }));
+        let mut response = (status, body).into_response();
+        if let ServerError::TooManyRequests(_, retry_secs) = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_secs.into());
+        }
+        response
+    }
+}
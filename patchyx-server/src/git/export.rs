@@ -0,0 +1,563 @@
+//! Exporting channels as git histories.
+//!
+//! Each change of a channel becomes a git commit, whose tree is the
+//! channel's state after that change: the changes are applied in the
+//! order of the channel's log to a scratch channel, and after each of
+//! them only the files and directories it touched are output again,
+//! replacing those of the previous commit's tree. The commits keep
+//! the message, description, author and time of their changes, with
+//! the change's hash in a `Pijul-Change` trailer. Authors known only
+//! by their signing key get the name and email of the identity linked
+//! to it when the change is exported, if any.
+//!
+//! Exports are incremental: the last exported state of each channel
+//! is remembered in an [`ExportState`], and exporting again only adds
+//! commits for the changes applied since. Channels whose exported
+//! state was unrecorded are exported again from scratch.
+//!
+//! Nothing is committed to the pristine: the scratch channels live in
+//! a transaction that is dropped at the end of each export.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use git2::{FileMode, Oid};
+use libpijul::change::{Author, ChangeHeader};
+use libpijul::changestore::ChangeStore;
+use libpijul::pristine::sanakirja::MutTxn0;
+use libpijul::pristine::Position;
+use libpijul::working_copy::memory::Memory;
+use libpijul::{
+    ArcTxn, Base32, ChannelRef, ChannelTxnT, Hash, Merkle, MutTxnT, MutTxnTExt, TxnT, TxnTExt,
+};
+use pijul_repository::Repository;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::auth::identities;
+use crate::repo::browse;
+
+/// What has been exported so far.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportState {
+    /// Exported channels, by name
+    pub channels: BTreeMap<String, ExportedChannel>,
+}
+
+/// An exported channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedChannel {
+    /// State of the channel when it was exported
+    pub state: String,
+    /// Id of the commit of that state
+    pub commit: String,
+}
+
+/// What an export did.
+#[derive(Debug, Default, PartialEq)]
+pub struct ExportReport {
+    /// Number of commits added, by channel
+    pub commits: BTreeMap<String, usize>,
+    /// Channels exported again from scratch, since changes exported
+    /// before were unrecorded from them
+    pub rewritten: Vec<String>,
+    /// Channels that couldn't be exported, and why
+    pub errors: Vec<(String, String)>,
+}
+
+/// Open the bare repository at `path`, creating it if needed.
+pub fn open(path: &Path) -> anyhow::Result<git2::Repository> {
+    match git2::Repository::open_bare(path) {
+        Ok(git) => Ok(git),
+        Err(_) => Ok(git2::Repository::init_bare(path)?),
+    }
+}
+
+/// Push `branches` of `git` to the git repository at `url`, replacing
+/// the branches of the same name there.
+pub fn push<'a, I>(git: &git2::Repository, url: &str, branches: I) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let refspecs: Vec<_> = branches
+        .into_iter()
+        .map(|b| format!("+refs/heads/{0}:refs/heads/{0}", b))
+        .collect();
+    if refspecs.is_empty() {
+        return Ok(());
+    }
+    let mut callbacks = super::remote_callbacks();
+    callbacks.push_update_reference(|name, status| match status {
+        Some(e) => Err(git2::Error::from_str(&format!("{} rejected: {}", name, e))),
+        None => Ok(()),
+    });
+    let mut options = git2::PushOptions::new();
+    options.remote_callbacks(callbacks);
+    git.remote_anonymous(url)?
+        .push(&refspecs, Some(&mut options))
+        .with_context(|| format!("Cannot push to {}", url))?;
+    Ok(())
+}
+
+/// Export `channels` of `repo` (all of them if empty) to the branches
+/// of the same name in `git`, updating `state`. Authors are looked up
+/// in the identities of the users in `users_dir`.
+pub fn export(
+    repo: &Repository,
+    users_dir: &Path,
+    git: &git2::Repository,
+    channels: &[String],
+    state: &mut ExportState,
+) -> anyhow::Result<ExportReport> {
+    let channels = if channels.is_empty() {
+        browse::channels(repo)?
+            .into_iter()
+            .map(|c| c.name)
+            .collect()
+    } else {
+        channels.to_vec()
+    };
+    let identities: HashMap<_, _> = identities::all(users_dir)?
+        .into_iter()
+        .map(|i| (i.public_key.key.clone(), i))
+        .collect();
+    let mut report = ExportReport::default();
+    for name in channels {
+        let previous = state.channels.get(&name);
+        match export_channel(repo, git, &identities, &name, previous) {
+            Ok(Some((exported, commits, rewritten))) => {
+                info!(channel = %name, commits, rewritten, "Exported channel to git");
+                if rewritten {
+                    report.rewritten.push(name.clone());
+                }
+                report.commits.insert(name.clone(), commits);
+                state.channels.insert(name, exported);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!(channel = %name, error = %e, "Cannot export channel to git");
+                report.errors.push((name, format!("{:#}", e)));
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Export channel `name`, from the state in `previous` if it is still
+/// a state of the channel. Returns the exported channel, the number
+/// of commits added and whether the channel was exported from
+/// scratch although it had been exported before, or `None` if it is
+/// up to date or empty.
+fn export_channel(
+    repo: &Repository,
+    git: &git2::Repository,
+    identities: &HashMap<String, identities::Identity>,
+    name: &str,
+    previous: Option<&ExportedChannel>,
+) -> anyhow::Result<Option<(ExportedChannel, usize, bool)>> {
+    let branch = format!("refs/heads/{}", name);
+    if !git2::Reference::is_valid_name(&branch) {
+        bail!("{} is not a valid git branch name", name)
+    }
+    let txn = repo.pristine.arc_txn_begin()?;
+    let Some(channel) = txn.read().load_channel(name)? else {
+        bail!("Channel {} not found", name)
+    };
+    let current = txn.read().current_state(&channel.read())?;
+
+    // Where the last export stopped, if that state and its commit
+    // are still there.
+    let mut start = None;
+    if let Some(previous) = previous {
+        let commit = Oid::from_str(&previous.commit)?;
+        let Some(merkle) = Merkle::from_base32(previous.state.as_bytes()) else {
+            bail!("Invalid state: {}", previous.state)
+        };
+        let n = {
+            let txn = txn.read();
+            let ch = channel.read();
+            txn.channel_has_state(txn.states(&ch), &merkle.into())?
+        };
+        if let (Some(n), Ok(commit)) = (n, git.find_commit(commit)) {
+            if merkle == current {
+                git.reference(&branch, commit.id(), true, "patchyx export")?;
+                return Ok(None);
+            }
+            start = Some((u64::from(n), merkle, commit));
+        }
+    }
+    let rewritten = previous.is_some() && start.is_none();
+
+    let mut log = Vec::new();
+    for entry in txn.read().reverse_log(&channel.read(), None)? {
+        let (n, (hash, _)) = entry?;
+        if start.as_ref().is_some_and(|(s, _, _)| n <= *s) {
+            break;
+        }
+        log.push(Hash::from(hash));
+    }
+    log.reverse();
+    if log.is_empty() {
+        return Ok(None);
+    }
+
+    let scratch_name = format!("git-export-{}", name);
+    let scratch = match start {
+        Some((_, ref merkle, _)) => {
+            let scratch = txn.write().fork(&channel, &scratch_name)?;
+            browse::unrecord_until(repo, &txn, &scratch, merkle)?;
+            scratch
+        }
+        None => txn.write().open_or_create_channel(&scratch_name)?,
+    };
+    let mut parent = start.map(|(_, _, commit)| commit);
+    let mut tree = match parent {
+        Some(ref commit) => commit.tree()?,
+        None => empty_tree(git)?,
+    };
+    for hash in log.iter() {
+        let touched = touched_files(&txn, hash)?;
+        let before = paths(repo, &txn, &scratch, &touched)?;
+        txn.write()
+            .apply_change_rec(&repo.changes, &mut scratch.write(), hash)?;
+        let after = paths(repo, &txn, &scratch, &touched)?;
+        tree = update_tree(
+            git,
+            &tree,
+            repo,
+            &txn,
+            &scratch,
+            before.into_iter().zip(after),
+        )?;
+        let header = repo.changes.get_header(hash)?;
+        let oid = commit(git, identities, &tree, parent.as_ref(), hash, &header)?;
+        debug!(change = %hash.to_base32(), commit = %oid, "Exported change");
+        parent = Some(git.find_commit(oid)?);
+    }
+    let commit = parent.unwrap();
+    git.reference(&branch, commit.id(), true, "patchyx export")?;
+    let exported = ExportedChannel {
+        state: current.to_base32(),
+        commit: commit.id().to_string(),
+    };
+    Ok(Some((exported, log.len(), rewritten)))
+}
+
+fn empty_tree(git: &git2::Repository) -> anyhow::Result<git2::Tree<'_>> {
+    Ok(git.find_tree(git.treebuilder(None)?.write()?)?)
+}
+
+/// The files and directories touched by change `hash`.
+fn touched_files(txn: &ArcTxn<MutTxn0>, hash: &Hash) -> anyhow::Result<Vec<Position<Hash>>> {
+    let txn = txn.read();
+    let Some(touched) = txn.touched_files(hash)? else {
+        return Ok(Vec::new());
+    };
+    Ok(touched.collect::<Result<_, _>>()?)
+}
+
+/// The paths of `positions` in `channel`, or `None` for those that
+/// aren't alive there.
+fn paths(
+    repo: &Repository,
+    txn: &ArcTxn<MutTxn0>,
+    channel: &ChannelRef<MutTxn0>,
+    positions: &[Position<Hash>],
+) -> anyhow::Result<Vec<Option<String>>> {
+    let txn = txn.read();
+    let mut paths = Vec::new();
+    for position in positions {
+        paths.push(
+            match txn.find_youngest_path(&repo.changes, channel, *position)? {
+                Some((path, true)) => Some(path),
+                _ => None,
+            },
+        );
+    }
+    Ok(paths)
+}
+
+/// The paths of `paths` that aren't inside another one.
+fn outermost(mut paths: Vec<String>) -> Vec<String> {
+    paths.sort();
+    paths.dedup();
+    let inside = |path: &str, dir: &str| {
+        path != dir
+            && (dir.is_empty()
+                || path
+                    .strip_prefix(dir)
+                    .is_some_and(|rest| rest.starts_with('/')))
+    };
+    let all = paths.clone();
+    paths.retain(|p| !all.iter().any(|dir| inside(p, dir)));
+    paths
+}
+
+/// Update `tree` after a change, given the paths of the files and
+/// directories it touched before and after it: what was at these
+/// paths is removed, and the files now under them are written.
+/// Directories that stay at the same path are skipped, since the
+/// files added to or removed from them are touched too. Empty
+/// directories are left out, git having no way to store them.
+fn update_tree<'a, I: Iterator<Item = (Option<String>, Option<String>)>>(
+    git: &'a git2::Repository,
+    tree: &git2::Tree,
+    repo: &Repository,
+    txn: &ArcTxn<MutTxn0>,
+    channel: &ChannelRef<MutTxn0>,
+    touched: I,
+) -> anyhow::Result<git2::Tree<'a>> {
+    let is_dir = |path: &str| {
+        path.is_empty()
+            || tree
+                .get_path(Path::new(path))
+                .is_ok_and(|e| e.kind() == Some(git2::ObjectType::Tree))
+    };
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    for (before, after) in touched {
+        match (before, after) {
+            (Some(before), Some(after)) if before == after && is_dir(&before) => {}
+            (before, after) => {
+                removed.extend(before);
+                removed.extend(after.clone());
+                added.extend(after);
+            }
+        }
+    }
+
+    let removed = outermost(removed);
+    let base = if removed.iter().any(|p| p.is_empty()) {
+        empty_tree(git)?
+    } else {
+        let mut builder = git2::build::TreeUpdateBuilder::new();
+        for path in removed {
+            if tree.get_path(Path::new(&path)).is_ok() {
+                builder.remove(path);
+            }
+        }
+        git.find_tree(builder.create_updated(git, tree)?)?
+    };
+    let mut blobs = Blobs {
+        git,
+        files: Vec::new(),
+    };
+    for path in outermost(added) {
+        let mut prefix = path.split('/').filter(|x| !x.is_empty());
+        txn.archive_prefix::<_, _, _, Memory>(&repo.changes, channel, &mut prefix, &mut blobs)?;
+    }
+    let mut builder = git2::build::TreeUpdateBuilder::new();
+    for (path, blob, mode) in blobs.files {
+        builder.upsert(path, blob, mode);
+    }
+    Ok(git.find_tree(builder.create_updated(git, &base)?)?)
+}
+
+/// Writes the files of an archive as blobs of a git repository.
+struct Blobs<'a> {
+    git: &'a git2::Repository,
+    /// The path, blob and mode of each file
+    files: Vec<(String, Oid, FileMode)>,
+}
+
+/// A file of a [`Blobs`] archive, before it is written.
+struct BlobFile {
+    path: String,
+    permissions: u16,
+    contents: Vec<u8>,
+}
+
+impl std::io::Write for BlobFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.contents.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl libpijul::Archive for Blobs<'_> {
+    type File = BlobFile;
+    type Error = git2::Error;
+
+    fn create_file(&mut self, path: &str, _mtime: u64, permissions: u16) -> BlobFile {
+        BlobFile {
+            path: path.to_string(),
+            permissions,
+            contents: Vec::new(),
+        }
+    }
+
+    fn create_dir(
+        &mut self,
+        _path: &str,
+        _mtime: u64,
+        _permissions: u16,
+    ) -> Result<(), git2::Error> {
+        Ok(())
+    }
+
+    fn close_file(&mut self, file: BlobFile) -> Result<(), git2::Error> {
+        let mode = if file.permissions & 0o100 != 0 {
+            FileMode::BlobExecutable
+        } else {
+            FileMode::Blob
+        };
+        let blob = self.git.blob(&file.contents)?;
+        self.files.push((file.path, blob, mode));
+        Ok(())
+    }
+}
+
+/// Commit `tree` for change `hash`, whose header is `header`.
+fn commit(
+    git: &git2::Repository,
+    identities: &HashMap<String, identities::Identity>,
+    tree: &git2::Tree,
+    parent: Option<&git2::Commit>,
+    hash: &Hash,
+    header: &ChangeHeader,
+) -> anyhow::Result<Oid> {
+    let time = git2::Time::new(header.timestamp.as_second(), 0);
+    let signature = match header.authors.first() {
+        Some(author) => {
+            let (name, email) = author_name(identities, author);
+            git2::Signature::new(&name, &email, &time)?
+        }
+        None => git2::Signature::new("Unknown", "unknown", &time)?,
+    };
+    let mut message = header.message.clone();
+    if let Some(ref description) = header.description {
+        message.push_str("\n\n");
+        message.push_str(description.trim());
+    }
+    message.push_str("\n\nPijul-Change: ");
+    message.push_str(&hash.to_base32());
+    message.push('\n');
+    let parents: Vec<_> = parent.into_iter().collect();
+    Ok(git.commit(None, &signature, &signature, &message, tree, &parents)?)
+}
+
+/// The name and email of `author`, from those of the identity linked
+/// to their key if the change doesn't have them.
+fn author_name(
+    identities: &HashMap<String, identities::Identity>,
+    author: &Author,
+) -> (String, String) {
+    let identity = author.0.get("key").and_then(|key| identities.get(key));
+    let name = author
+        .0
+        .get("name")
+        .or_else(|| author.0.get("display_name"))
+        .cloned()
+        .or_else(|| {
+            identity.map(|i| match i.display_name.as_str() {
+                "" => i.username.clone(),
+                name => name.to_string(),
+            })
+        })
+        .or_else(|| author.0.get("key").cloned())
+        .unwrap_or_else(|| "Unknown".to_string());
+    let email = author
+        .0
+        .get("email")
+        .cloned()
+        .or_else(|| identity.map(|i| i.email.clone()))
+        .unwrap_or_default();
+    // Git signatures can't hold angle brackets or newlines, nor be
+    // empty.
+    let clean = |s: String, default: &str| match s.replace(['<', '>', '\n'], "").trim() {
+        "" => default.to_string(),
+        s => s.to_string(),
+    };
+    (clean(name, "Unknown"), clean(email, "unknown"))
+}
+
+#[cfg(test)]
+mod tests {
+    use libpijul::key::SKey;
+
+    use super::*;
+    use crate::repo::testing::{init, record, record_with};
+
+    fn file(git: &git2::Repository, commit: &git2::Commit, path: &str) -> String {
+        let entry = commit.tree().unwrap().get_path(Path::new(path)).unwrap();
+        let blob = git.find_blob(entry.id()).unwrap();
+        String::from_utf8(blob.content().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init(&dir.path().join("repo"));
+        let repo = repo.lock().unwrap();
+        let users = dir.path().join("users");
+        let git = open(&dir.path().join("export.git")).unwrap();
+        let a = record(&repo, "a", b"a\n");
+        let b = record(&repo, "src/b", b"b\n");
+
+        let mut state = ExportState::default();
+        let report = export(&repo, &users, &git, &[], &mut state).unwrap();
+        assert_eq!(report.commits, [("main".to_string(), 2)].into());
+        let tip = git.find_branch("main", git2::BranchType::Local).unwrap();
+        let tip = tip.get().peel_to_commit().unwrap();
+        assert_eq!(tip.id().to_string(), state.channels["main"].commit);
+        assert_eq!(file(&git, &tip, "a"), "a\n");
+        assert_eq!(file(&git, &tip, "src/b"), "b\n");
+        let message = tip.message().unwrap();
+        assert!(message.contains(&format!("Pijul-Change: {}", b.to_base32())));
+        let first = tip.parent(0).unwrap();
+        assert_eq!(first.parent_count(), 0);
+        assert!(first.message().unwrap().contains(&a.to_base32()));
+        assert!(first.tree().unwrap().get_path(Path::new("src")).is_err());
+
+        // Exporting again only exports new changes, whose authors are
+        // named after the identities of their keys.
+        assert_eq!(
+            export(&repo, &users, &git, &[], &mut state).unwrap(),
+            ExportReport::default()
+        );
+        let key = SKey::generate(None).public_key();
+        identities::link(&users, "alice", key.clone()).unwrap();
+        let author = Author([("key".to_string(), key.key)].into());
+        record_with(&repo, "c", b"c\n", vec![author], None);
+        let report = export(&repo, &users, &git, &["main".to_string()], &mut state).unwrap();
+        assert_eq!(report.commits, [("main".to_string(), 1)].into());
+        let new_tip = git.find_commit(Oid::from_str(&state.channels["main"].commit).unwrap());
+        let new_tip = new_tip.unwrap();
+        assert_eq!(new_tip.parent_id(0).unwrap(), tip.id());
+        assert_eq!(new_tip.author().name(), Some("alice"));
+        assert_eq!(file(&git, &new_tip, "c"), "c\n");
+
+        // Unrecording exported changes exports the channel again, with
+        // the same commits for the same changes.
+        let c = Hash::from_base32(
+            new_tip
+                .message()
+                .unwrap()
+                .rsplit("Pijul-Change: ")
+                .next()
+                .unwrap()
+                .trim()
+                .as_bytes(),
+        )
+        .unwrap();
+        let txn = repo.pristine.arc_txn_begin().unwrap();
+        let channel = txn.read().load_channel("main").unwrap().unwrap();
+        txn.write()
+            .unrecord(&repo.changes, &channel, &c, 0, &Memory::new())
+            .unwrap();
+        std::mem::drop(channel);
+        txn.commit().unwrap();
+        let report = export(&repo, &users, &git, &[], &mut state).unwrap();
+        assert_eq!(report.rewritten, vec!["main".to_string()]);
+        assert_eq!(report.commits, [("main".to_string(), 2)].into());
+        assert_eq!(state.channels["main"].commit, tip.id().to_string());
+
+        let report = export(&repo, &users, &git, &["missing".to_string()], &mut state).unwrap();
+        assert_eq!(report.errors.len(), 1);
+    }
+}
@@ -0,0 +1,987 @@
+//! Importing git repositories.
+//!
+//! Each git commit becomes a Pijul change: the commit's tree is
+//! written into a [`Memory`] working copy, and recorded with
+//! [`Builder::record`] against a channel holding the state of
+//! the commit's parents. The change header keeps the commit's author,
+//! timestamp and message. Branches are imported into the channels of
+//! the same name, and tags into Pijul tags on the channels where
+//! their commits were imported.
+//!
+//! Imports are incremental: an [`ImportState`] remembers the change
+//! recorded for each commit and the imported tip of each branch, so
+//! that importing again only records new commits. A commit is only
+//! recorded once, its change being applied to the other channels
+//! whose branches contain it. Branches are imported in batches of
+//! commits, each in its own transaction, releasing the repository in
+//! between so that long imports don't block its other users.
+//!
+//! In full mode, all the ancestors of a branch are imported, and
+//! merges are recorded after applying the changes of all their
+//! parents, so that the change only holds the conflict resolution. In
+//! first-parent mode, only first parents are followed, and a merge is
+//! recorded as one change bringing in the whole merged branch.
+//!
+//! Symbolic links and submodules are skipped, as are files whose
+//! names aren't UTF-8.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use git2::{ObjectType, Oid, Sort};
+use jiff::Timestamp;
+use libpijul::change::{Author, Change, ChangeHeader};
+use libpijul::changestore::filesystem::push_tag_filename;
+use libpijul::changestore::ChangeStore;
+use libpijul::pristine::sanakirja::MutTxn0;
+use libpijul::pristine::ArcTxn;
+use libpijul::record::{Algorithm, Builder};
+use libpijul::working_copy::memory::Memory;
+use libpijul::working_copy::WorkingCopy;
+use libpijul::{
+    Base32, ChannelMutTxnT, ChannelRef, ChannelTxnT, Hash, Merkle, MutTxnT, MutTxnTExt, TxnT,
+    TxnTExt,
+};
+use pijul_repository::Repository;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::repo::{browse, wire, SharedRepo};
+
+/// Refspecs mirroring the branches and tags of the imported
+/// repository.
+const REFSPECS: &[&str] = &["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"];
+
+/// Git file mode of symbolic links.
+const MODE_LINK: i32 = 0o120000;
+/// Git file mode of executable files.
+const MODE_EXECUTABLE: i32 = 0o100755;
+
+/// Number of commits recorded in a transaction, after which the
+/// transaction is committed and the repository released.
+const BATCH_SIZE: usize = 100;
+
+/// How to import a git repository.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImportOptions {
+    /// Only follow the first parent of merges
+    pub first_parent: bool,
+    /// Branches to import, all of them if empty
+    pub branches: Vec<String>,
+}
+
+/// What has been imported so far.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImportState {
+    /// Imported commits, by id
+    pub commits: BTreeMap<String, ImportedCommit>,
+    /// Imported branches, by name
+    pub branches: BTreeMap<String, ImportedBranch>,
+    /// Imported tags, with the id of their commit
+    pub tags: BTreeMap<String, String>,
+}
+
+/// An imported commit.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImportedCommit {
+    /// The change recorded for the commit, unless it changed nothing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change: Option<String>,
+    /// A channel that was in the state of the commit, and that state,
+    /// where tags of the commit go
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+}
+
+/// An imported branch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportedBranch {
+    /// Id of the last imported commit
+    pub tip: String,
+    /// State of the channel after the import
+    pub state: String,
+}
+
+/// What an import did.
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    /// Changes recorded or applied, by channel
+    pub changes: BTreeMap<String, Vec<Hash>>,
+    /// Number of commits recorded
+    pub recorded: usize,
+    /// Tags created
+    pub tags: Vec<String>,
+    /// Branches and tags that couldn't be imported, and why
+    pub errors: Vec<(String, String)>,
+}
+
+/// Fetch the branches and tags of the git repository at `url` into
+/// the bare repository at `path`, creating it if needed. Branches and
+/// tags deleted from `url` are deleted from `path`.
+pub fn fetch(path: &Path, url: &str) -> anyhow::Result<git2::Repository> {
+    let git = match git2::Repository::open_bare(path) {
+        Ok(git) => git,
+        Err(_) => git2::Repository::init_bare(path)?,
+    };
+    {
+        let mut remote = git.remote_anonymous(url)?;
+        let mut options = git2::FetchOptions::new();
+        options
+            .remote_callbacks(super::remote_callbacks())
+            .prune(git2::FetchPrune::On)
+            .download_tags(git2::AutotagOption::None);
+        remote
+            .fetch(REFSPECS, Some(&mut options), None)
+            .with_context(|| format!("Cannot fetch {}", url))?;
+    }
+    Ok(git)
+}
+
+/// Import the branches and tags of `git` into `repo`, updating
+/// `state`. `checkpoint` is called with the new state after each
+/// batch of commits, whose changes are committed to the pristine by
+/// then.
+///
+/// Branches that cannot be imported (rewritten since the last import,
+/// or whose channel was modified) are reported and skipped, as are
+/// their tags.
+pub fn import<F>(
+    repo: &SharedRepo,
+    git: &git2::Repository,
+    state: &mut ImportState,
+    options: &ImportOptions,
+    checkpoint: F,
+) -> anyhow::Result<ImportReport>
+where
+    F: FnMut(&ImportState) -> anyhow::Result<()>,
+{
+    import_(repo, git, state, options, BATCH_SIZE, checkpoint)
+}
+
+fn import_<F>(
+    repo: &SharedRepo,
+    git: &git2::Repository,
+    state: &mut ImportState,
+    options: &ImportOptions,
+    batch_size: usize,
+    mut checkpoint: F,
+) -> anyhow::Result<ImportReport>
+where
+    F: FnMut(&ImportState) -> anyhow::Result<()>,
+{
+    let mut report = ImportReport::default();
+    for (name, tip) in branches(git, &options.branches)? {
+        loop {
+            let result = {
+                let repo = repo.lock().unwrap();
+                let first_parent = options.first_parent;
+                import_branch(&repo, git, state, first_parent, &name, tip, batch_size)
+            };
+            match result {
+                Ok(Some((new_state, changes, recorded))) => {
+                    *state = new_state;
+                    checkpoint(state)?;
+                    info!(branch = %name, recorded, applied = changes.len(), "Imported git commits");
+                    report.recorded += recorded;
+                    report
+                        .changes
+                        .entry(name.clone())
+                        .or_default()
+                        .extend(changes);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!(branch = %name, error = %e, "Cannot import git branch");
+                    report.errors.push((name, format!("{:#}", e)));
+                    break;
+                }
+            }
+        }
+    }
+    let repo = repo.lock().unwrap();
+    for (name, reference) in tags(git)? {
+        match import_tag(&repo, state, &name, &reference) {
+            Ok(true) => report.tags.push(name),
+            Ok(false) => {}
+            Err(e) => {
+                warn!(tag = %name, error = %e, "Cannot import git tag");
+                report.errors.push((name, format!("{:#}", e)));
+            }
+        }
+    }
+    checkpoint(state)?;
+    Ok(report)
+}
+
+/// The branches of `git` to import, with their tips.
+fn branches(git: &git2::Repository, only: &[String]) -> anyhow::Result<BTreeMap<String, Oid>> {
+    let mut branches = BTreeMap::new();
+    for reference in git.references_glob("refs/heads/*")? {
+        let reference = reference?;
+        let Some(name) = reference.name().and_then(|n| n.strip_prefix("refs/heads/")) else {
+            continue;
+        };
+        if only.is_empty() || only.iter().any(|b| b == name) {
+            branches.insert(name.to_string(), reference.peel_to_commit()?.id());
+        }
+    }
+    Ok(branches)
+}
+
+/// The tags of `git` pointing to commits.
+fn tags(git: &git2::Repository) -> anyhow::Result<BTreeMap<String, git2::Reference<'_>>> {
+    let mut tags = BTreeMap::new();
+    for reference in git.references_glob("refs/tags/*")? {
+        let reference = reference?;
+        let Some(name) = reference.name().and_then(|n| n.strip_prefix("refs/tags/")) else {
+            continue;
+        };
+        if reference.peel_to_commit().is_ok() {
+            tags.insert(name.to_string(), reference);
+        }
+    }
+    Ok(tags)
+}
+
+/// Import the commits of branch `name` up to `tip`, in a single
+/// transaction, stopping at the first commit of the branch after
+/// which `batch_size` commits were recorded. Returns the new state,
+/// the changes recorded or applied to the channel and the number of
+/// commits recorded, or `None` if the branch is up to date. If the
+/// import fails, the change files it wrote are removed.
+///
+/// The commits of the first-parent chain of the branch are recorded
+/// on the channel, so that each of them is a state of the channel
+/// that tags can name. In full mode, the commits merged by each of
+/// them are applied to the channel first, after being recorded on a
+/// scratch channel if needed.
+fn import_branch(
+    repo: &Repository,
+    git: &git2::Repository,
+    state: &ImportState,
+    first_parent: bool,
+    name: &str,
+    tip: Oid,
+    batch_size: usize,
+) -> anyhow::Result<Option<(ImportState, Vec<Hash>, usize)>> {
+    let previous = state.branches.get(name);
+    if previous.is_some_and(|b| b.tip == tip.to_string()) {
+        return Ok(None);
+    }
+    let mut written = Vec::new();
+    let result = import_batch(
+        repo,
+        git,
+        state,
+        first_parent,
+        name,
+        tip,
+        batch_size,
+        &mut written,
+    );
+    if result.is_err() {
+        wire::remove_unused_changes(repo, &written);
+    }
+    result.map(Some)
+}
+
+/// The transaction of [`import_branch`], adding the changes it
+/// writes to the change store to `written`.
+#[allow(clippy::too_many_arguments)]
+fn import_batch(
+    repo: &Repository,
+    git: &git2::Repository,
+    state: &ImportState,
+    first_parent: bool,
+    name: &str,
+    tip: Oid,
+    batch_size: usize,
+    written: &mut Vec<Hash>,
+) -> anyhow::Result<(ImportState, Vec<Hash>, usize)> {
+    let previous = state.branches.get(name);
+    let txn = repo.pristine.arc_txn_begin()?;
+    let channel = txn.write().open_or_create_channel(name)?;
+    let current = txn.read().current_state(&channel.read())?;
+
+    let mut chain = git.revwalk()?;
+    chain.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    chain.push(tip)?;
+    chain.simplify_first_parent()?;
+    let mut prev = None;
+    match previous {
+        Some(branch) => {
+            if current.to_base32() != branch.state {
+                bail!("Channel {} was modified since the last import", name)
+            }
+            let old = Oid::from_str(&branch.tip)?;
+            if !git.graph_descendant_of(tip, old)? {
+                bail!("Branch {} was rewritten since the last import", name)
+            }
+            chain.hide(old)?;
+            prev = Some(old);
+        }
+        None if current != Merkle::zero() => {
+            bail!("Channel {} exists and wasn't imported from git", name)
+        }
+        None => {}
+    }
+    let chain = chain.collect::<Result<Vec<_>, _>>()?;
+
+    let mut importer = Importer {
+        repo,
+        git,
+        txn: txn.clone(),
+        state: state.clone(),
+        synced: None,
+        scratch: None,
+        changes: Vec::new(),
+        recorded: 0,
+        written,
+    };
+    for oid in chain {
+        let commit = git.find_commit(oid)?;
+        if !first_parent {
+            let merged = importer.ancestry(commit.parent_ids(), prev)?;
+            for oid in merged.iter() {
+                importer.record_merged(&git.find_commit(*oid)?)?;
+            }
+            for oid in merged.iter() {
+                importer.apply(&channel, name, oid)?;
+            }
+        }
+        if importer.state.commits.contains_key(&oid.to_string()) {
+            importer.apply(&channel, name, &oid)?;
+        } else {
+            let hash = importer.record(&channel, name, &commit)?;
+            importer.changes.extend(hash);
+        }
+        let current = txn.read().current_state(&channel.read())?;
+        let imported = importer.state.commits.get_mut(&oid.to_string()).unwrap();
+        if imported.channel.is_none() {
+            imported.channel = Some(name.to_string());
+            imported.state = Some(current.to_base32());
+        }
+        prev = Some(oid);
+        if importer.recorded >= batch_size {
+            break;
+        }
+    }
+    importer.drop_scratch()?;
+    let Importer {
+        mut state,
+        changes,
+        recorded,
+        txn: importer_txn,
+        ..
+    } = importer;
+    std::mem::drop(importer_txn);
+    let current = txn.read().current_state(&channel.read())?;
+    let branch = ImportedBranch {
+        tip: prev.unwrap_or(tip).to_string(),
+        state: current.to_base32(),
+    };
+    state.branches.insert(name.to_string(), branch);
+    std::mem::drop(channel);
+    txn.commit()?;
+    Ok((state, changes, recorded))
+}
+
+fn parse_hash(hash: &str) -> anyhow::Result<Hash> {
+    match Hash::from_base32(hash.as_bytes()) {
+        Some(hash) => Ok(hash),
+        None => bail!("Invalid hash: {}", hash),
+    }
+}
+
+/// Records commits within the transaction of a branch import.
+struct Importer<'a> {
+    repo: &'a Repository,
+    git: &'a git2::Repository,
+    txn: ArcTxn<MutTxn0>,
+    state: ImportState,
+    /// Channel the tracked files are in line with, if any
+    synced: Option<String>,
+    /// Channel holding exactly the changes of the ancestors of the
+    /// last merged commit, on which its children can be recorded
+    scratch: Option<Scratch>,
+    /// Changes recorded on or applied to the imported channel
+    changes: Vec<Hash>,
+    recorded: usize,
+    /// Changes written to the change store that weren't there
+    written: &'a mut Vec<Hash>,
+}
+
+struct Scratch {
+    name: String,
+    channel: ChannelRef<MutTxn0>,
+    /// The commits with no descendants on the channel
+    heads: BTreeSet<Oid>,
+}
+
+impl Importer<'_> {
+    /// The ancestors of `parents`, and `parents`, that aren't
+    /// ancestors of `hide`, parents first.
+    fn ancestry(
+        &self,
+        parents: impl Iterator<Item = Oid>,
+        hide: Option<Oid>,
+    ) -> anyhow::Result<Vec<Oid>> {
+        let mut walk = self.git.revwalk()?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+        let mut empty = true;
+        for parent in parents {
+            walk.push(parent)?;
+            empty = false;
+        }
+        if empty {
+            return Ok(Vec::new());
+        }
+        if let Some(hide) = hide {
+            walk.hide(hide)?;
+        }
+        Ok(walk.collect::<Result<_, _>>()?)
+    }
+
+    /// Apply the change of imported commit `oid` to `channel`, named
+    /// `name`, unless it's already there.
+    fn apply(
+        &mut self,
+        channel: &ChannelRef<MutTxn0>,
+        name: &str,
+        oid: &Oid,
+    ) -> anyhow::Result<()> {
+        let Some(imported) = self.state.commits.get(&oid.to_string()) else {
+            bail!("Commit {} wasn't imported", oid)
+        };
+        let Some(ref hash) = imported.change else {
+            return Ok(());
+        };
+        let hash = parse_hash(hash)?;
+        if self.txn.read().get_revchanges(channel, &hash)?.is_some() {
+            return Ok(());
+        }
+        self.txn
+            .write()
+            .apply_change_rec(&self.repo.changes, &mut channel.write(), &hash)?;
+        self.changes.push(hash);
+        if self.synced.as_deref() == Some(name) {
+            self.synced = None;
+        }
+        Ok(())
+    }
+
+    /// Record `commit`, merged into the imported branch, on the
+    /// scratch channel, unless it was imported already.
+    fn record_merged(&mut self, commit: &git2::Commit) -> anyhow::Result<()> {
+        if self.state.commits.contains_key(&commit.id().to_string()) {
+            return Ok(());
+        }
+        let parents: BTreeSet<Oid> = commit.parent_ids().collect();
+        if self.scratch.as_ref().is_some_and(|s| s.heads != parents) {
+            self.drop_scratch()?;
+        }
+        if self.scratch.is_none() {
+            let name = format!("git-import-{}", commit.id());
+            let channel = self.txn.write().open_or_create_channel(&name)?;
+            let scratch = Scratch {
+                name,
+                channel,
+                heads: parents.clone(),
+            };
+            for oid in self.ancestry(parents.into_iter(), None)? {
+                let Some(imported) = self.state.commits.get(&oid.to_string()) else {
+                    bail!("Commit {} wasn't imported", oid)
+                };
+                if let Some(ref hash) = imported.change {
+                    self.txn.write().apply_change_rec(
+                        &self.repo.changes,
+                        &mut scratch.channel.write(),
+                        &parse_hash(hash)?,
+                    )?;
+                }
+            }
+            self.scratch = Some(scratch);
+        }
+        let scratch = self.scratch.take().unwrap();
+        let result = self.record(&scratch.channel, &scratch.name, commit);
+        self.scratch = Some(Scratch {
+            heads: [commit.id()].into(),
+            ..scratch
+        });
+        result.map(|_| ())
+    }
+
+    fn drop_scratch(&mut self) -> anyhow::Result<()> {
+        if let Some(Scratch { name, channel, .. }) = self.scratch.take() {
+            std::mem::drop(channel);
+            self.txn.write().drop_channel(&name)?;
+            if self.synced.as_deref() == Some(&name) {
+                self.synced = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the tree of `commit` against `channel`, named `name`,
+    /// and apply the change there, returning its hash unless nothing
+    /// changed.
+    fn record(
+        &mut self,
+        channel: &ChannelRef<MutTxn0>,
+        name: &str,
+        commit: &git2::Commit,
+    ) -> anyhow::Result<Option<Hash>> {
+        let (repo, txn) = (self.repo, &self.txn);
+        // Bring the tracked files in line with the channel: files
+        // missing from the commit's tree are then recorded as deleted.
+        if self.synced.as_deref() != Some(name) {
+            libpijul::output::output_repository_no_pending(
+                &Memory::new(),
+                &repo.changes,
+                txn,
+                channel,
+                "",
+                true,
+                None,
+                1,
+                0,
+            )?;
+            self.synced = Some(name.to_string());
+        }
+        let wc = Memory::new();
+        let mut files = Vec::new();
+        write_tree(self.git, &commit.tree()?, "", &wc, &mut files)?;
+        {
+            let mut txn = txn.write();
+            for file in files.iter() {
+                if !txn.is_tracked(file)? {
+                    txn.add_file(file, 0)?;
+                }
+            }
+        }
+
+        let mut builder = Builder::new();
+        builder.record(
+            txn.clone(),
+            Algorithm::default(),
+            false,
+            &libpijul::DEFAULT_SEPARATOR,
+            channel.clone(),
+            &wc,
+            &repo.changes,
+            "",
+            1,
+        )?;
+        let rec = builder.finish();
+        let hash = if rec.actions.is_empty() {
+            None
+        } else {
+            let actions = rec
+                .actions
+                .into_iter()
+                .map(|a| a.globalize(&*txn.read()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let contents = std::mem::take(&mut *rec.contents.lock());
+            let mut change = Change::make_change(
+                &*txn.read(),
+                channel,
+                actions,
+                contents,
+                header(commit),
+                Vec::new(),
+            )?;
+            let mut new = false;
+            let hash = repo.changes.save_change(&mut change, |_, hash| {
+                new = !repo.changes.has_change(hash);
+                Ok::<_, anyhow::Error>(())
+            })?;
+            if new {
+                self.written.push(hash);
+            }
+            libpijul::apply::apply_local_change(
+                &mut *txn.write(),
+                channel,
+                &change,
+                &hash,
+                &rec.updatables,
+            )?;
+            Some(hash)
+        };
+        debug!(commit = %commit.id(), change = ?hash.map(|h| h.to_base32()), "Recorded commit");
+        let imported = ImportedCommit {
+            change: hash.map(|h| h.to_base32()),
+            ..Default::default()
+        };
+        self.state.commits.insert(commit.id().to_string(), imported);
+        self.recorded += 1;
+        Ok(hash)
+    }
+}
+
+/// Write the files of `tree` into `wc`, under `prefix`, adding their
+/// paths to `files`.
+fn write_tree(
+    git: &git2::Repository,
+    tree: &git2::Tree,
+    prefix: &str,
+    wc: &Memory,
+    files: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    for entry in tree.iter() {
+        let Some(name) = entry.name() else {
+            warn!(prefix, "Skipping a file whose name isn't UTF-8");
+            continue;
+        };
+        let path = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        match entry.kind() {
+            Some(ObjectType::Tree) => {
+                write_tree(git, &git.find_tree(entry.id())?, &path, wc, files)?;
+            }
+            Some(ObjectType::Blob) if entry.filemode() != MODE_LINK => {
+                let blob = git.find_blob(entry.id())?;
+                wc.add_file(&path, blob.content().to_vec());
+                if entry.filemode() == MODE_EXECUTABLE {
+                    wc.set_permissions(&path, 0o755)?;
+                }
+                files.push(path);
+            }
+            _ => debug!(path, "Skipping a symbolic link or submodule"),
+        }
+    }
+    Ok(())
+}
+
+/// The header of the change of `commit`: its author and time, and
+/// its message, the first line of which is the change's message.
+fn header(commit: &git2::Commit) -> ChangeHeader {
+    let message = String::from_utf8_lossy(commit.message_bytes());
+    let (message, description) = match message.trim().split_once('\n') {
+        Some((message, description)) => (message, Some(description.trim().to_string())),
+        None => (message.trim(), None),
+    };
+    let message = if message.is_empty() {
+        format!("git commit {}", commit.id())
+    } else {
+        message.to_string()
+    };
+    ChangeHeader {
+        message,
+        description: description.filter(|d| !d.is_empty()),
+        timestamp: timestamp(commit.author().when()),
+        authors: vec![author(&commit.author())],
+    }
+}
+
+fn author(signature: &git2::Signature) -> Author {
+    let mut author = BTreeMap::new();
+    if let Some(name) = signature.name() {
+        author.insert("name".to_string(), name.to_string());
+    }
+    if let Some(email) = signature.email() {
+        author.insert("email".to_string(), email.to_string());
+    }
+    Author(author)
+}
+
+fn timestamp(time: git2::Time) -> Timestamp {
+    Timestamp::from_second(time.seconds()).unwrap_or(Timestamp::UNIX_EPOCH)
+}
+
+/// Tag the state of the commit of git tag `name`, on the channel
+/// where that commit was imported. Returns `false` if the tag was
+/// already imported, or its commit wasn't.
+fn import_tag(
+    repo: &Repository,
+    state: &mut ImportState,
+    name: &str,
+    reference: &git2::Reference,
+) -> anyhow::Result<bool> {
+    let commit = reference.peel_to_commit()?;
+    let id = commit.id().to_string();
+    if state.tags.get(name) == Some(&id) {
+        return Ok(false);
+    }
+    let Some(imported) = state.commits.get(&id) else {
+        // Not on the imported branches.
+        return Ok(false);
+    };
+    let (Some(channel_name), Some(merkle)) = (&imported.channel, &imported.state) else {
+        bail!("Commit {} was never the state of a channel", id)
+    };
+    let Some(merkle) = Merkle::from_base32(merkle.as_bytes()) else {
+        bail!("Invalid state: {}", merkle)
+    };
+    let txn = repo.pristine.arc_txn_begin()?;
+    let Some(channel) = txn.read().load_channel(channel_name)? else {
+        bail!("Channel {} not found", channel_name)
+    };
+    let n = {
+        let txn = txn.read();
+        let ch = channel.read();
+        txn.channel_has_state(txn.states(&ch), &merkle.into())?
+    };
+    let Some(n) = n else {
+        bail!(
+            "Channel {} is no longer in the state of commit {}",
+            channel_name,
+            id
+        )
+    };
+    let n: u64 = n.into();
+
+    let mut path = repo.changes_dir.clone();
+    push_tag_filename(&mut path, &merkle);
+    if !path.exists() {
+        // Tag files are made from channels in the tagged state.
+        let scratch_name = format!("git-import-tag-{}", id);
+        let scratch = txn.write().fork(&channel, &scratch_name)?;
+        browse::unrecord_until(repo, &txn, &scratch, &merkle)?;
+        let mut tag = Vec::new();
+        libpijul::tag::from_channel(
+            &*txn.read(),
+            &scratch_name,
+            &tag_header(reference, &commit),
+            &mut tag,
+        )?;
+        wire::write_atomic(&path, &tag)?;
+        std::mem::drop(scratch);
+        txn.write().drop_channel(&scratch_name)?;
+    }
+    let tagged = {
+        let txn = txn.read();
+        let ch = channel.read();
+        txn.is_tagged(txn.tags(&ch), n)?
+    };
+    if !tagged {
+        let mut txn_ = txn.write();
+        let mut ch = channel.write();
+        let tags = txn_.tags_mut(&mut ch);
+        txn_.put_tags(tags, n, &merkle)?;
+    }
+    std::mem::drop(channel);
+    txn.commit()?;
+    state.tags.insert(name.to_string(), id);
+    Ok(!tagged)
+}
+
+/// The header of a Pijul tag made from a git tag: its name and, for
+/// annotated tags, their message, tagger and time.
+fn tag_header(reference: &git2::Reference, commit: &git2::Commit) -> ChangeHeader {
+    let name = reference.shorthand().unwrap_or_default().to_string();
+    match reference.peel_to_tag() {
+        Ok(tag) => ChangeHeader {
+            message: name,
+            description: tag
+                .message()
+                .map(|m| m.trim().to_string())
+                .filter(|m| !m.is_empty()),
+            timestamp: tag
+                .tagger()
+                .map_or_else(|| timestamp(commit.time()), |t| timestamp(t.when())),
+            authors: tag.tagger().map(|t| vec![author(&t)]).unwrap_or_default(),
+        },
+        Err(_) => ChangeHeader {
+            message: name,
+            description: None,
+            timestamp: timestamp(commit.time()),
+            authors: vec![author(&commit.committer())],
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::browse::{self, Node};
+    use crate::repo::testing::init;
+
+    /// Commit `files` with `parents`, and point `branch` to it.
+    fn commit(
+        git: &git2::Repository,
+        branch: &str,
+        parents: &[Oid],
+        files: &[(&str, &str)],
+    ) -> Oid {
+        let mut index = git2::Index::new().unwrap();
+        for (path, contents) in files {
+            let entry = git2::IndexEntry {
+                ctime: git2::IndexTime::new(0, 0),
+                mtime: git2::IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode: 0o100644,
+                uid: 0,
+                gid: 0,
+                file_size: contents.len() as u32,
+                id: git.blob(contents.as_bytes()).unwrap(),
+                flags: 0,
+                flags_extended: 0,
+                path: path.as_bytes().to_vec(),
+            };
+            index.add(&entry).unwrap();
+        }
+        let tree = git.find_tree(index.write_tree_to(git).unwrap()).unwrap();
+        let signature = git2::Signature::new(
+            "Alice",
+            "alice@example.com",
+            &git2::Time::new(1700000000, 0),
+        )
+        .unwrap();
+        let parents: Vec<_> = parents
+            .iter()
+            .map(|p| git.find_commit(*p).unwrap())
+            .collect();
+        let parents: Vec<_> = parents.iter().collect();
+        let message = format!("Commit on {}\n\nWith {} files.", branch, files.len());
+        let oid = git
+            .commit(None, &signature, &signature, &message, &tree, &parents)
+            .unwrap();
+        git.reference(&format!("refs/heads/{}", branch), oid, true, "test")
+            .unwrap();
+        oid
+    }
+
+    fn file(repo: &SharedRepo, channel: &str, path: &str) -> Option<String> {
+        match browse::lookup(&repo.lock().unwrap(), channel, None, path).unwrap() {
+            Some(Node::File(contents)) => Some(String::from_utf8(contents).unwrap()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = git2::Repository::init(dir.path().join("source")).unwrap();
+        let c1 = commit(&source, "main", &[], &[("a", "a\n")]);
+        let c2 = commit(&source, "feature", &[c1], &[("a", "a\n"), ("src/b", "b\n")]);
+        let c3 = commit(&source, "main", &[c1], &[("a", "a\nmore\n")]);
+        let merge = commit(
+            &source,
+            "main",
+            &[c3, c2],
+            &[("a", "a\nmore\n"), ("src/b", "b\n")],
+        );
+        let target = source.find_object(c3, None).unwrap();
+        let signature = git2::Signature::now("Alice", "alice@example.com").unwrap();
+        source
+            .tag("v1", &target, &signature, "First release", false)
+            .unwrap();
+
+        let url = dir.path().join("source").to_string_lossy().into_owned();
+        let git = fetch(&dir.path().join("import.git"), &url).unwrap();
+        let repo = init(&dir.path().join("repo"));
+        let mut state = ImportState::default();
+        let mut checkpoints = 0;
+        let options = ImportOptions::default();
+        let report = import(&repo, &git, &mut state, &options, |_| {
+            checkpoints += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.recorded, 4);
+        assert_eq!(report.changes["feature"].len(), 2);
+        assert_eq!(report.changes["main"].len(), 3);
+        assert_eq!(report.tags, vec!["v1".to_string()]);
+        assert_eq!(checkpoints, 3);
+        assert_eq!(state.branches["main"].tip, merge.to_string());
+        assert_eq!(state.tags["v1"], c3.to_string());
+
+        assert_eq!(file(&repo, "main", "a").as_deref(), Some("a\nmore\n"));
+        assert_eq!(file(&repo, "main", "src/b").as_deref(), Some("b\n"));
+        assert_eq!(file(&repo, "feature", "a").as_deref(), Some("a\n"));
+        assert!(browse::has_state(
+            &repo.lock().unwrap(),
+            "main",
+            Some(
+                &Merkle::from_base32(
+                    state.commits[&c3.to_string()]
+                        .state
+                        .as_ref()
+                        .unwrap()
+                        .as_bytes()
+                )
+                .unwrap()
+            ),
+        )
+        .unwrap());
+
+        // The change keeps the commit's author, time and message.
+        let main = state.commits[&c3.to_string()].change.clone().unwrap();
+        let change = repo
+            .lock()
+            .unwrap()
+            .changes
+            .get_change(&parse_hash(&main).unwrap())
+            .unwrap();
+        let header = &change.hashed.header;
+        assert_eq!(header.message, "Commit on main");
+        assert_eq!(header.description.as_deref(), Some("With 1 files."));
+        assert_eq!(header.timestamp.as_second(), 1700000000);
+        assert_eq!(header.authors[0].0["email"], "alice@example.com");
+
+        // Importing again only imports new commits.
+        let c4 = commit(&source, "main", &[merge], &[("a", "a\n"), ("src/b", "b\n")]);
+        let git = fetch(&dir.path().join("import.git"), &url).unwrap();
+        let report = import(&repo, &git, &mut state, &options, |_| Ok(())).unwrap();
+        assert_eq!(report.recorded, 1);
+        assert_eq!(report.changes.keys().collect::<Vec<_>>(), vec!["main"]);
+        assert!(report.tags.is_empty());
+        assert_eq!(state.branches["main"].tip, c4.to_string());
+        assert_eq!(file(&repo, "main", "a").as_deref(), Some("a\n"));
+        let report = import(&repo, &git, &mut state, &options, |_| Ok(())).unwrap();
+        assert_eq!(report, ImportReport::default());
+
+        // Rewritten branches are reported and left alone.
+        commit(&source, "main", &[c1], &[("a", "rewritten\n")]);
+        let git = fetch(&dir.path().join("import.git"), &url).unwrap();
+        let report = import(&repo, &git, &mut state, &options, |_| Ok(())).unwrap();
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, "main");
+        assert_eq!(state.branches["main"].tip, c4.to_string());
+    }
+
+    #[test]
+    fn test_import_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = git2::Repository::init(dir.path().join("source")).unwrap();
+        let c1 = commit(&source, "main", &[], &[("a", "a\n")]);
+        let c2 = commit(&source, "feature", &[c1], &[("a", "a\n"), ("b", "b\n")]);
+        let c3 = commit(&source, "main", &[c1], &[("a", "c\n")]);
+        let merge = commit(&source, "main", &[c3, c2], &[("a", "c\n"), ("b", "b\n")]);
+
+        let url = dir.path().join("source").to_string_lossy().into_owned();
+        let git = fetch(&dir.path().join("import.git"), &url).unwrap();
+        let repo = init(&dir.path().join("repo"));
+        let mut state = ImportState::default();
+        let mut tips = Vec::new();
+        let options = ImportOptions {
+            branches: vec!["main".to_string()],
+            ..Default::default()
+        };
+        let report = import_(&repo, &git, &mut state, &options, 1, |state| {
+            tips.push(state.branches.get("main").map(|b| b.tip.clone()));
+            Ok(())
+        })
+        .unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.recorded, 4);
+        // The merge changes nothing once both parents are applied.
+        assert_eq!(report.changes["main"].len(), 3);
+        // One transaction per commit of the first-parent chain, the
+        // merged commit being recorded with the merge.
+        let tip = |oid: Oid| Some(oid.to_string());
+        assert_eq!(tips, vec![tip(c1), tip(c3), tip(merge), tip(merge)]);
+        assert_eq!(file(&repo, "main", "a").as_deref(), Some("c\n"));
+        assert_eq!(file(&repo, "main", "b").as_deref(), Some("b\n"));
+    }
+}
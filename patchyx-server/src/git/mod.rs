@@ -0,0 +1,361 @@
+//! Git interoperability.
+//!
+//! Server admins can import a git repository into a hosted one: its
+//! branches become channels and its tags Pijul tags (see [`import`]).
+//! The git repository is fetched into `.pijul/git_import.git`, and
+//! the import, its progress and the outcome of its last run are
+//! stored in the `git_import.json` file of the repository's `.pijul`
+//! directory, so that running it again only imports new commits.
+//!
+//! They can also export channels as git branches (see [`export`]),
+//! for tools that only read git: the branches are written to the bare
+//! repository `.pijul/git_export.git`, and optionally pushed to a git
+//! remote. The export and its progress are stored in `git_export.json`,
+//! so that running it again only exports new changes.
+//!
+//! Imports and exports run in the background, on a blocking thread
+//! that holds the repository's lock while reading or writing it.
+
+pub mod export;
+pub mod import;
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use jiff::Timestamp;
+use libpijul::Base32;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::audit::{self, Action, Record};
+use crate::auth::Caller;
+use crate::config::ServerConfig;
+use crate::error::{Result, ServerError};
+use crate::mirror::{self, SyncStatus};
+use crate::repo::RepoStore;
+use export::ExportState;
+use import::{ImportOptions, ImportState};
+
+/// Name of the import file, in the repository's `.pijul` directory.
+pub const GIT_IMPORT_FILE: &str = "git_import.json";
+
+/// Bare repository the imported repository is fetched into, in the
+/// repository's `.pijul` directory.
+pub const GIT_IMPORT_DIR: &str = "git_import.git";
+
+/// Name of the export file, in the repository's `.pijul` directory.
+pub const GIT_EXPORT_FILE: &str = "git_export.json";
+
+/// Bare repository channels are exported to, in the repository's
+/// `.pijul` directory.
+pub const GIT_EXPORT_DIR: &str = "git_export.git";
+
+/// Actor of the audit records of imports.
+const ACTOR: &str = "<git-import>";
+
+/// Serializes the updates of import and export files.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Imports and exports running, by repository.
+static RUNNING: Mutex<BTreeSet<(PathBuf, Job)>> = Mutex::new(BTreeSet::new());
+
+/// A background job on a repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Job {
+    Import,
+    Export,
+}
+
+/// A git repository imported into a hosted repository.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GitImport {
+    /// URL or absolute path of the git repository
+    pub url: String,
+    #[serde(flatten)]
+    pub options: ImportOptions,
+    pub created: Timestamp,
+    /// Who configured the import
+    pub by: String,
+    #[serde(flatten)]
+    pub status: SyncStatus,
+    /// Imported commits, branches and tags
+    #[serde(default)]
+    pub state: ImportState,
+}
+
+/// Channels of a hosted repository exported as git branches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GitExport {
+    /// Channels to export, all of them if empty
+    pub channels: Vec<String>,
+    /// URL or absolute path of a git repository the branches are
+    /// pushed to, if any
+    pub url: Option<String>,
+    pub created: Timestamp,
+    /// Who configured the export
+    pub by: String,
+    #[serde(flatten)]
+    pub status: SyncStatus,
+    /// Exported states and their commits
+    #[serde(default)]
+    pub state: ExportState,
+}
+
+fn load<T: DeserializeOwned>(repo_path: &Path, file: &str) -> Result<Option<T>> {
+    match std::fs::read(repo_path.join(libpijul::DOT_DIR).join(file)) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(|e| ServerError::repository(format!("Invalid {}: {}", file, e))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write<T: Serialize>(repo_path: &Path, file: &str, value: &T) -> Result<()> {
+    let path = repo_path.join(libpijul::DOT_DIR).join(file);
+    let tmp = path.with_extension("tmp");
+    let contents =
+        serde_json::to_vec_pretty(value).map_err(|e| ServerError::internal(e.to_string()))?;
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+impl GitImport {
+    /// Load the import into the repository at `repo_path`, if any.
+    pub fn load(repo_path: &Path) -> Result<Option<Self>> {
+        load(repo_path, GIT_IMPORT_FILE)
+    }
+
+    /// Configure the import into the repository at `repo_path`,
+    /// replacing its previous configuration if any.
+    pub fn save(&self, repo_path: &Path) -> Result<()> {
+        let _lock = LOCK.lock().unwrap();
+        write(repo_path, GIT_IMPORT_FILE, self)
+    }
+
+    /// Update the import of `url` with `f`, unless it was pointed
+    /// elsewhere in the meantime.
+    fn update<F: FnOnce(&mut Self)>(repo_path: &Path, url: &str, f: F) -> Result<()> {
+        let _lock = LOCK.lock().unwrap();
+        match Self::load(repo_path)? {
+            Some(mut import) if import.url == url => {
+                f(&mut import);
+                write(repo_path, GIT_IMPORT_FILE, &import)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl GitExport {
+    /// Load the export of the repository at `repo_path`, if any.
+    pub fn load(repo_path: &Path) -> Result<Option<Self>> {
+        load(repo_path, GIT_EXPORT_FILE)
+    }
+
+    /// Configure the export of the repository at `repo_path`,
+    /// replacing its previous configuration if any.
+    pub fn save(&self, repo_path: &Path) -> Result<()> {
+        let _lock = LOCK.lock().unwrap();
+        write(repo_path, GIT_EXPORT_FILE, self)
+    }
+
+    /// Update the export with `f`, unless it was removed.
+    fn update<F: FnOnce(&mut Self)>(repo_path: &Path, f: F) -> Result<()> {
+        let _lock = LOCK.lock().unwrap();
+        match Self::load(repo_path)? {
+            Some(mut export) => {
+                f(&mut export);
+                write(repo_path, GIT_EXPORT_FILE, &export)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Whether `job` is running on the repository at `repo_path`.
+pub fn is_running(repo_path: &Path, job: Job) -> bool {
+    RUNNING
+        .lock()
+        .unwrap()
+        .contains(&(repo_path.to_path_buf(), job))
+}
+
+/// Whether `url` looks like a git repository the server can reach.
+pub fn validate_url(url: &str) -> Result<()> {
+    let valid = match reqwest::Url::parse(url) {
+        Ok(url) => matches!(url.scheme(), "http" | "https" | "ssh" | "git" | "file"),
+        Err(_) => Path::new(url).is_absolute(),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(ServerError::bad_request(format!(
+            "Invalid git URL: {}",
+            url
+        )))
+    }
+}
+
+/// Callbacks authenticating to SSH remotes with the server user's
+/// SSH agent.
+pub(crate) fn remote_callbacks<'a>() -> git2::RemoteCallbacks<'a> {
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(|_, user, allowed| {
+        if allowed.contains(git2::CredentialType::SSH_KEY) {
+            git2::Cred::ssh_key_from_agent(user.unwrap_or("git"))
+        } else {
+            git2::Cred::default()
+        }
+    });
+    callbacks
+}
+
+/// Run `job` on repository `name` in the background, failing if it is
+/// already running.
+pub fn start(
+    repos: Arc<RepoStore>,
+    config: Arc<ServerConfig>,
+    name: String,
+    job: Job,
+) -> Result<()> {
+    let key = (repos.path(&name), job);
+    if !RUNNING.lock().unwrap().insert(key.clone()) {
+        return Err(ServerError::conflict(format!(
+            "A git {} of {} is already running",
+            match job {
+                Job::Import => "import",
+                Job::Export => "export",
+            },
+            name
+        )));
+    }
+    tokio::spawn(async move {
+        let result = match job {
+            Job::Import => run_import(&repos, &config, &name).await,
+            Job::Export => run_export(&repos, &config, &name).await,
+        };
+        if let Err(e) = result {
+            warn!(repo = %name, job = ?job, error = %e, "Git job failed");
+        }
+        RUNNING.lock().unwrap().remove(&key);
+    });
+    Ok(())
+}
+
+/// Fetch and import the git repository of repository `name`, and
+/// record the outcome in its import file.
+async fn run_import(repos: &RepoStore, config: &ServerConfig, name: &str) -> Result<()> {
+    let repo_path = repos.path(name);
+    let Some(git_import) = GitImport::load(&repo_path)? else {
+        return Err(ServerError::not_found(format!(
+            "No git import into {}",
+            name
+        )));
+    };
+    let repo = repos.open(name)?;
+    let started = Timestamp::now();
+    let url = git_import.url.clone();
+    let path = repo_path.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let git_dir = path.join(libpijul::DOT_DIR).join(GIT_IMPORT_DIR);
+        let git = import::fetch(&git_dir, &git_import.url)?;
+        let mut state = git_import.state;
+        import::import(&repo, &git, &mut state, &git_import.options, |state| {
+            GitImport::update(&path, &git_import.url, |i| i.state = state.clone())?;
+            Ok(())
+        })
+    })
+    .await
+    .map_err(|e| ServerError::internal(e.to_string()))?;
+
+    let error = match result {
+        Ok(report) => {
+            info!(
+                repo = %name,
+                url = %url,
+                recorded = report.recorded,
+                tags = report.tags.len(),
+                errors = report.errors.len(),
+                "Imported git repository"
+            );
+            for (channel, changes) in report.changes {
+                if changes.is_empty() {
+                    continue;
+                }
+                let record = Record {
+                    actor: ACTOR.to_string(),
+                    repo: Some(name.to_string()),
+                    channel: Some(channel.clone()),
+                    changes: changes.iter().map(|h| h.to_base32()).collect(),
+                    target: Some(url.clone()),
+                    ..Record::new(Action::GitImport, &Caller::admin())
+                };
+                audit::append(&config.audit, record);
+                mirror::push::enqueue(&repo_path, name, &channel);
+            }
+            let errors: Vec<_> = report
+                .errors
+                .into_iter()
+                .map(|(name, e)| format!("{}: {}", name, e))
+                .collect();
+            Some(errors.join("; ")).filter(|e| !e.is_empty())
+        }
+        Err(e) => Some(format!("{:#}", e)),
+    };
+    GitImport::update(&repo_path, &url, |i| i.status.record(started, error))
+}
+
+/// Export the channels of repository `name`, push them if the export
+/// has a remote, and record the outcome in its export file.
+async fn run_export(repos: &RepoStore, config: &ServerConfig, name: &str) -> Result<()> {
+    let repo_path = repos.path(name);
+    let Some(git_export) = GitExport::load(&repo_path)? else {
+        return Err(ServerError::not_found(format!("No git export of {}", name)));
+    };
+    let repo = repos.open(name)?;
+    let users_dir = config.users_dir.clone();
+    let started = Timestamp::now();
+    let path = repo_path.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let git = export::open(&path.join(libpijul::DOT_DIR).join(GIT_EXPORT_DIR))?;
+        let mut state = git_export.state;
+        let report = export::export(
+            &repo.lock().unwrap(),
+            &users_dir,
+            &git,
+            &git_export.channels,
+            &mut state,
+        )?;
+        GitExport::update(&path, |e| e.state = state.clone())?;
+        if let Some(ref url) = git_export.url {
+            export::push(&git, url, state.channels.keys())?;
+        }
+        Ok::<_, anyhow::Error>(report)
+    })
+    .await
+    .map_err(|e| ServerError::internal(e.to_string()))?;
+
+    let error = match result {
+        Ok(report) => {
+            info!(
+                repo = %name,
+                commits = report.commits.values().sum::<usize>(),
+                rewritten = report.rewritten.len(),
+                errors = report.errors.len(),
+                "Exported channels to git"
+            );
+            let errors: Vec<_> = report
+                .errors
+                .into_iter()
+                .map(|(channel, e)| format!("{}: {}", channel, e))
+                .collect();
+            Some(errors.join("; ")).filter(|e| !e.is_empty())
+        }
+        Err(e) => Some(format!("{:#}", e)),
+    };
+    GitExport::update(&repo_path, |e| e.status.record(started, error))
+}
@@ -0,0 +1,420 @@
+//! Archive downloads.
+//!
+//! `/api/v1/repos/{repo}/archive/{channel}[@STATE].tar.gz` (or
+//! `.zip`) downloads the files of a channel, at its current state or
+//! at an earlier one, optionally restricted to `?path=DIR`. Files are
+//! placed in a `{repo}/` directory.
+//!
+//! Archives are streamed while they are generated, at the pace of the
+//! client. Zip files need to seek back into each entry to write its
+//! header, so only the entry being written is kept in memory. Archives
+//! of the current state are read from a read-only transaction, and
+//! don't hold the repository while the client downloads them. Earlier
+//! states need the repository locked, so their archives are written
+//! to a temporary file first.
+
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::sync::MutexGuard;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use libpijul::changestore::filesystem::FileSystem;
+use libpijul::output::{Tarball, Zip};
+use libpijul::pristine::sanakirja::Txn;
+use libpijul::{Hash, Merkle};
+use pijul_repository::Repository;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use super::remote::parse_merkle;
+use super::routes::AppState;
+use crate::auth::{Caller, Role};
+use crate::error::{Result, ServerError};
+use crate::repo::{browse, wire, SharedRepo};
+
+/// Size of the chunks of streamed archives.
+const CHUNK_SIZE: usize = 1 << 16;
+
+/// Number of chunks queued for a client before the archive waits for
+/// it.
+const QUEUE_SIZE: usize = 16;
+
+/// Archive formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::TarGz => ".tar.gz",
+            Format::Zip => ".zip",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::TarGz => "application/gzip",
+            Format::Zip => "application/zip",
+        }
+    }
+}
+
+/// Parse `CHANNEL[@STATE].EXT`.
+fn parse_name(name: &str) -> Result<(&str, Option<Merkle>, Format)> {
+    let (base, format) = [Format::TarGz, Format::Zip]
+        .into_iter()
+        .find_map(|f| name.strip_suffix(f.extension()).map(|base| (base, f)))
+        .ok_or_else(|| ServerError::bad_request(format!("Unknown archive format: {}", name)))?;
+    let (channel, state) = match base.rsplit_once('@') {
+        Some((channel, state)) => (channel, Some(parse_merkle(state)?)),
+        None => (base, None),
+    };
+    if channel.is_empty() {
+        return Err(ServerError::bad_request("Missing channel name"));
+    }
+    Ok((channel, state, format))
+}
+
+/// Query parameters of the archive endpoint.
+#[derive(Deserialize)]
+pub struct ArchiveQuery {
+    /// Only include this path
+    pub path: Option<String>,
+}
+
+/// Sends what it is given to an HTTP response body.
+struct ChunkWriter(mpsc::Sender<std::io::Result<Bytes>>);
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Client disconnected")
+            })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Lets the zip writer seek back into the entry it is writing, and
+/// sends everything before the current position when it is flushed,
+/// which the zip writer does after finishing each entry.
+struct Spool<W: Write> {
+    out: W,
+    /// Bytes written since the last flush
+    buf: Vec<u8>,
+    /// Offset of the start of `buf` in the archive
+    start: u64,
+    /// Current offset in the archive
+    pos: u64,
+}
+
+impl<W: Write> Spool<W> {
+    fn new(out: W) -> Self {
+        Spool {
+            out,
+            buf: Vec::new(),
+            start: 0,
+            pos: 0,
+        }
+    }
+
+    /// Send the rest of the archive, and return the underlying writer.
+    fn into_inner(mut self) -> std::io::Result<W> {
+        self.out.write_all(&self.buf)?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for Spool<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let off = (self.pos - self.start) as usize;
+        let end = off + data.len();
+        if end > self.buf.len() {
+            self.buf.resize(end, 0);
+        }
+        self.buf[off..end].copy_from_slice(data);
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let off = (self.pos - self.start) as usize;
+        self.out.write_all(&self.buf[..off])?;
+        self.buf.drain(..off);
+        self.start = self.pos;
+        Ok(())
+    }
+}
+
+impl<W: Write> Read for Spool<W> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let off = (self.pos - self.start) as usize;
+        let n = self.buf.len().saturating_sub(off).min(out.len());
+        out[..n].copy_from_slice(&self.buf[off..off + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<W: Write> Seek for Spool<W> {
+    fn seek(&mut self, to: SeekFrom) -> std::io::Result<u64> {
+        let end = self.start + self.buf.len() as u64;
+        let pos = match to {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => end.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match pos {
+            Some(pos) if pos >= self.start => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seek into the part of the archive already sent",
+            )),
+        }
+    }
+}
+
+/// Where an archive is read from.
+enum Source<'a> {
+    /// A read-only transaction on the current state, and the change
+    /// store
+    Snapshot(Txn, FileSystem),
+    /// The locked repository, to reach an earlier state
+    Locked(MutexGuard<'a, Repository>, Option<(Merkle, Vec<Hash>)>),
+}
+
+impl Source<'_> {
+    fn write<A: libpijul::Archive>(
+        self,
+        channel: &str,
+        path: Option<&str>,
+        arch: &mut A,
+    ) -> anyhow::Result<()>
+    where
+        A::Error: Send + Sync + 'static,
+    {
+        match self {
+            Source::Snapshot(txn, changes) => {
+                wire::write_snapshot_archive(txn, &changes, channel, path, arch)?
+            }
+            Source::Locked(repo, state) => wire::write_archive(&repo, channel, state, path, arch)?,
+        };
+        Ok(())
+    }
+}
+
+fn write_archive(
+    repo: &SharedRepo,
+    channel: &str,
+    state: Option<Merkle>,
+    path: Option<&str>,
+    format: Format,
+    root: String,
+    tx: &mpsc::Sender<std::io::Result<Bytes>>,
+) -> anyhow::Result<()> {
+    let state = state.map(|s| (s, Vec::new()));
+    let locked = repo.lock().unwrap();
+    let mut out = BufWriter::with_capacity(CHUNK_SIZE, ChunkWriter(tx.clone()));
+    if let Some(txn) = wire::snapshot(&locked, channel, state.as_ref())? {
+        let changes = locked.changes.clone();
+        std::mem::drop(locked);
+        let source = Source::Snapshot(txn, changes);
+        return encode(source, channel, path, format, root, out);
+    }
+    // Don't hold the repository at the pace of the client.
+    let mut file = tempfile::tempfile()?;
+    let source = Source::Locked(locked, state);
+    encode(source, channel, path, format, root, &mut file)?;
+    file.rewind()?;
+    std::io::copy(&mut file, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Write an archive of `source` to `out`. The source, and the lock it
+/// may hold, are released before the archive is finished.
+fn encode<W: Write>(
+    source: Source,
+    channel: &str,
+    path: Option<&str>,
+    format: Format,
+    root: String,
+    out: W,
+) -> anyhow::Result<()> {
+    match format {
+        Format::TarGz => {
+            let mut tarball = Tarball::new(out, Some(root), 0);
+            source.write(channel, path, &mut tarball)?;
+            tarball.archive.into_inner()?.finish()?.flush()?;
+        }
+        Format::Zip => {
+            let mut zip = Zip::new(Spool::new(out), Some(root), 0);
+            zip.archive.set_flush_on_finish_file(true);
+            source.write(channel, path, &mut zip)?;
+            zip.finish()?.into_inner()?.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Download an archive of a channel.
+pub async fn archive(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo_name, name)): Path<(String, String)>,
+    Query(q): Query<ArchiveQuery>,
+) -> Result<Response> {
+    state
+        .repos
+        .access(&repo_name, &caller)?
+        .require(Role::Read)?;
+    let (channel, at, format) = parse_name(&name)?;
+    let repo = state.repos.open(&repo_name)?;
+    let found = tokio::task::block_in_place(|| {
+        browse::has_state(&repo.lock().unwrap(), channel, at.as_ref())
+    })?;
+    if !found {
+        return Err(ServerError::not_found(match at {
+            Some(_) => format!("State not found in channel {}", channel),
+            None => format!("Channel not found: {}", channel),
+        }));
+    }
+
+    let (tx, mut rx) = mpsc::channel(QUEUE_SIZE);
+    let channel = channel.to_string();
+    let root = format!("{}/", repo_name);
+    tokio::task::spawn_blocking(move || {
+        let path = q.path.as_deref();
+        if let Err(e) = write_archive(&repo, &channel, at, path, format, root, &tx) {
+            tracing::warn!(channel = %channel, error = %e, "Archive failed");
+            let _ = tx.blocking_send(Err(std::io::Error::other(e.to_string())));
+        }
+    });
+    let body = Body::from_stream(futures::stream::poll_fn(move |cx| rx.poll_recv(cx)));
+
+    let filename = format!("{}-{}", repo_name, name.replace('/', "-"));
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use libpijul::{Archive, Base32, TxnT, TxnTExt};
+
+    use super::*;
+    use crate::repo::testing::{init, record};
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(
+            parse_name("main.tar.gz").unwrap(),
+            ("main", None, Format::TarGz)
+        );
+        let name = format!("dev@{}.zip", Merkle::zero().to_base32());
+        assert_eq!(
+            parse_name(&name).unwrap(),
+            ("dev", Some(Merkle::zero()), Format::Zip)
+        );
+        assert!(parse_name("main.tar").is_err());
+        assert!(parse_name("main@nope.zip").is_err());
+        assert!(parse_name(".zip").is_err());
+    }
+
+    fn fill<W: Write + Seek>(zip: &mut Zip<W>) {
+        zip.create_dir("a", 0, 0o755).unwrap();
+        for i in 0..10 {
+            let mut file = zip.create_file(&format!("a/{}", i), 0, 0o644);
+            file.write_all(&vec![i as u8; 100_000]).unwrap();
+            zip.close_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_spool() {
+        let mut zip = Zip::new(Cursor::new(Vec::new()), Some("r/".to_string()), 0);
+        fill(&mut zip);
+        let expected = zip.finish().unwrap().into_inner();
+
+        let (tx, mut rx) = mpsc::channel(QUEUE_SIZE);
+        let sent = std::thread::spawn(move || {
+            let mut zip = Zip::new(Spool::new(ChunkWriter(tx)), Some("r/".to_string()), 0);
+            zip.archive.set_flush_on_finish_file(true);
+            fill(&mut zip);
+            zip.finish().unwrap().into_inner().unwrap();
+        });
+        let mut chunks = Vec::new();
+        while let Some(chunk) = rx.blocking_recv() {
+            chunks.push(chunk.unwrap());
+        }
+        sent.join().unwrap();
+        // Each entry is sent once finished.
+        assert!(chunks.len() > 10);
+        assert_eq!(chunks.concat(), expected);
+
+        let mut spool = Spool::new(Vec::new());
+        spool.write_all(b"abc").unwrap();
+        spool.flush().unwrap();
+        assert!(spool.seek(SeekFrom::Start(1)).is_err());
+    }
+
+    #[test]
+    fn test_earlier_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init(dir.path());
+        let state = {
+            let locked = repo.lock().unwrap();
+            let contents: Vec<u8> = (0..1 << 20).map(|_| rand::random()).collect();
+            record(&locked, "a", &contents);
+            let txn = locked.pristine.txn_begin().unwrap();
+            let channel = txn.load_channel("main").unwrap().unwrap();
+            let state = txn.current_state(&channel.read()).unwrap();
+            record(&locked, "b", b"b\n");
+            state
+        };
+
+        let (tx, mut rx) = mpsc::channel(1);
+        let writer = {
+            let repo = repo.clone();
+            std::thread::spawn(move || {
+                let root = "r/".to_string();
+                write_archive(&repo, "main", Some(state), None, Format::TarGz, root, &tx)
+            })
+        };
+        // The repository is available while the client downloads.
+        let mut chunks = vec![rx.blocking_recv().unwrap().unwrap()];
+        assert!(repo.try_lock().is_ok());
+        while let Some(chunk) = rx.blocking_recv() {
+            chunks.push(chunk.unwrap());
+        }
+        writer.join().unwrap().unwrap();
+        let archive = chunks.concat();
+        assert!(archive.len() > QUEUE_SIZE * CHUNK_SIZE);
+        assert_eq!(archive[..2], [0x1f, 0x8b]);
+    }
+}
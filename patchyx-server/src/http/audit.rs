@@ -0,0 +1,59 @@
+//! Audit log endpoint.
+
+use axum::{
+    extract::{Query, State},
+    response::Json,
+};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+
+use super::routes::AppState;
+use crate::audit::{self, Action, Record};
+use crate::auth::Caller;
+use crate::error::Result;
+
+/// Default number of records listed.
+const DEFAULT_RECORDS: usize = 100;
+/// Largest number of records listed.
+const MAX_RECORDS: usize = 1000;
+
+/// Audit log query parameters. See [`audit::Query`].
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<Action>,
+    pub repo: Option<String>,
+    pub channel: Option<String>,
+    pub change: Option<String>,
+    pub since: Option<Timestamp>,
+    pub until: Option<Timestamp>,
+    pub limit: Option<usize>,
+}
+
+/// Audit log response.
+#[derive(Serialize)]
+pub struct AuditResponse {
+    pub records: Vec<Record>,
+}
+
+/// Search the audit log, newest records first.
+pub async fn audit_log(
+    State(state): State<AppState>,
+    caller: Caller,
+    Query(q): Query<AuditQuery>,
+) -> Result<Json<AuditResponse>> {
+    caller.require_admin()?;
+    let limit = q.limit.unwrap_or(DEFAULT_RECORDS).clamp(1, MAX_RECORDS);
+    let query = audit::Query {
+        actor: q.actor,
+        action: q.action,
+        repo: q.repo,
+        channel: q.channel,
+        change: q.change,
+        since: q.since,
+        until: q.until,
+    };
+    let config = state.config.get();
+    let records = tokio::task::block_in_place(|| audit::search(&config.audit, &query, limit))?;
+    Ok(Json(AuditResponse { records }))
+}
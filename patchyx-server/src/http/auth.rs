@@ -0,0 +1,92 @@
+//! Authentication of HTTP requests.
+//!
+//! Requests may carry an `Authorization: Bearer TOKEN` header, where
+//! `TOKEN` is either the server's admin token or a user's API token.
+//! Requests without one are anonymous. The address of the client is
+//! attached to the caller, for the audit log. Failed authentication
+//! attempts are rate limited per address, and authenticated requests
+//! per user.
+
+use std::net::SocketAddr;
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts},
+};
+
+use super::routes::AppState;
+use crate::auth::Caller;
+use crate::config::AuthBackend;
+use crate::error::ServerError;
+use crate::metrics;
+use crate::ratelimit::Key;
+
+#[async_trait]
+impl FromRequestParts<AppState> for Caller {
+    type Rejection = ServerError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let addr = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|c| c.0.ip());
+        let Some(value) = parts.headers.get(header::AUTHORIZATION) else {
+            return Ok(Caller::anonymous().with_addr(addr));
+        };
+        let token = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or_else(|| ServerError::auth("Expected a bearer token"))?;
+        let config = state.config.get();
+        let limits = &config.limits;
+        let failures = &state.limiters.auth_failures;
+        if let Some(addr) = addr {
+            if let Err(e) = failures.peek(&limits.auth_failures, &Key::Addr(addr)) {
+                tracing::warn!(addr = %addr, "HTTP authentication rate limited");
+                return Err(e.error(&format!("failed authentications from {}", addr)));
+            }
+        }
+        let failed = || {
+            if let Some(addr) = addr {
+                let _ = failures.check(&limits.auth_failures, Key::Addr(addr));
+            }
+        };
+        // BLAKE3 hashes compare in constant time, unlike strings.
+        let is_admin_token =
+            config.auth.admin_token.as_ref().is_some_and(|t| {
+                blake3::hash(t.expose().as_bytes()) == blake3::hash(token.as_bytes())
+            });
+        if is_admin_token {
+            metrics::AUTH_ATTEMPTS.inc(&["admin_token", "success"]);
+            return Ok(Caller::admin().with_addr(addr));
+        }
+        if !config.auth.enabled(AuthBackend::Tokens) {
+            metrics::AUTH_ATTEMPTS.inc(&["token", "failure"]);
+            failed();
+            return Err(ServerError::auth("API tokens are disabled"));
+        }
+        match state.tokens.lookup(token) {
+            Some(user) => {
+                metrics::AUTH_ATTEMPTS.inc(&["token", "success"]);
+                let limiter = &state.limiters.http_requests;
+                let key = Key::User(user.clone());
+                if let Err(e) = limiter.check(&limits.http_requests, key) {
+                    tracing::warn!(user = %user, "HTTP requests rate limited");
+                    return Err(e.error(&format!("requests by {}", user)));
+                }
+                Ok(Caller::user(&user, &config).with_addr(addr))
+            }
+            None => {
+                metrics::AUTH_ATTEMPTS.inc(&["token", "failure"]);
+                failed();
+                Err(ServerError::auth("Invalid token"))
+            }
+        }
+    }
+}
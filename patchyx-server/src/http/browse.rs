@@ -0,0 +1,109 @@
+//! Channel browsing endpoints.
+//!
+//! `tree` lists directories and shows text files as JSON, `raw`
+//! downloads files as-is. Both work on the current state of a
+//! channel, or on an earlier one given with `?state=MERKLE`.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Json, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use super::remote::parse_merkle;
+use super::routes::AppState;
+use crate::auth::{Caller, Role};
+use crate::error::{Result, ServerError};
+use crate::repo::browse::{self, Node, TreeEntry};
+
+/// Path parameters of browsing endpoints.
+#[derive(Deserialize)]
+pub struct BrowsePath {
+    pub repo: String,
+    pub channel: String,
+    /// Path in the channel, the root if absent
+    #[serde(default)]
+    pub path: String,
+}
+
+/// Query parameters of browsing endpoints.
+#[derive(Deserialize)]
+pub struct BrowseQuery {
+    /// Base32 Merkle state, defaults to the current one
+    pub state: Option<String>,
+}
+
+/// Tree response: a directory listing or a file.
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum TreeResponse {
+    Dir {
+        path: String,
+        entries: Vec<TreeEntry>,
+    },
+    File {
+        path: String,
+        size: usize,
+        /// Contents of the file, if it is valid UTF-8
+        content: Option<String>,
+    },
+}
+
+fn lookup(state: &AppState, caller: &Caller, p: &BrowsePath, q: &BrowseQuery) -> Result<Node> {
+    state.repos.access(&p.repo, caller)?.require(Role::Read)?;
+    let at = q.state.as_deref().map(parse_merkle).transpose()?;
+    let repo = state.repos.open(&p.repo)?;
+    let node = tokio::task::block_in_place(|| {
+        browse::lookup(&repo.lock().unwrap(), &p.channel, at.as_ref(), &p.path)
+    })?;
+    node.ok_or_else(|| {
+        ServerError::not_found(format!(
+            "{} not found in channel {}{}",
+            if p.path.is_empty() { "/" } else { &p.path },
+            p.channel,
+            q.state
+                .as_deref()
+                .map(|s| format!(" at state {}", s))
+                .unwrap_or_default()
+        ))
+    })
+}
+
+/// List a directory or show a file.
+pub async fn tree(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(p): Path<BrowsePath>,
+    Query(q): Query<BrowseQuery>,
+) -> Result<Json<TreeResponse>> {
+    let path = p.path.trim_matches('/').to_string();
+    Ok(Json(match lookup(&state, &caller, &p, &q)? {
+        Node::Dir(entries) => TreeResponse::Dir { path, entries },
+        Node::File(contents) => TreeResponse::File {
+            path,
+            size: contents.len(),
+            content: String::from_utf8(contents).ok(),
+        },
+    }))
+}
+
+/// Download a file.
+pub async fn raw(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(p): Path<BrowsePath>,
+    Query(q): Query<BrowseQuery>,
+) -> Result<Response> {
+    match lookup(&state, &caller, &p, &q)? {
+        Node::File(contents) => Ok((
+            [(header::CONTENT_TYPE, "application/octet-stream")],
+            contents,
+        )
+            .into_response()),
+        Node::Dir(_) => Err(ServerError::bad_request(format!(
+            "{} is a directory",
+            p.path
+        ))),
+    }
+}
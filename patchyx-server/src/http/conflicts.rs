@@ -0,0 +1,63 @@
+//! Conflict preview endpoint.
+
+use axum::{
+    extract::{Path, State},
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+
+use super::proposals::with_repos;
+use super::remote::parse_hash;
+use super::routes::AppState;
+use crate::auth::{Caller, Role};
+use crate::error::{Result, ServerError};
+use crate::repo::conflicts::{self, ConflictInfo};
+use crate::repo::RepoStore;
+
+/// Conflict preview request.
+#[derive(Deserialize)]
+pub struct PreviewRequest {
+    /// Hashes of the changes to apply
+    pub changes: Vec<String>,
+    /// Repository whose change store has the changes, if not this one
+    pub source_repo: Option<String>,
+}
+
+/// Conflict preview response.
+#[derive(Serialize)]
+pub struct PreviewResponse {
+    pub conflicts: Vec<ConflictInfo>,
+}
+
+/// Show the conflicts a channel would have after applying changes,
+/// without applying them. The changes must have been uploaded to this
+/// repository, or to the source repository.
+pub async fn preview(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo, channel)): Path<(String, String)>,
+    Json(req): Json<PreviewRequest>,
+) -> Result<Json<PreviewResponse>> {
+    state.repos.access(&repo, &caller)?.require(Role::Read)?;
+    let target = RepoStore::normalize_name(&repo)?;
+    let source = match req.source_repo {
+        Some(ref source) => RepoStore::normalize_name(source)?,
+        None => target,
+    };
+    state.repos.access(source, &caller)?.require(Role::Read)?;
+    if req.changes.is_empty() {
+        return Err(ServerError::bad_request("No changes to preview"));
+    }
+    let hashes = req
+        .changes
+        .iter()
+        .map(String::as_str)
+        .map(parse_hash)
+        .collect::<Result<Vec<_>>>()?;
+    let conflicts = tokio::task::block_in_place(|| {
+        with_repos(&state, source, target, |source, target| {
+            conflicts::preview_from(source, target, &channel, &hashes)
+        })
+    })?;
+    Ok(Json(PreviewResponse { conflicts }))
+}
@@ -0,0 +1,73 @@
+//! Repository integrity check endpoints.
+
+use axum::{
+    extract::{Path, State},
+    response::Json,
+};
+use jiff::Timestamp;
+use serde::Serialize;
+
+use super::routes::AppState;
+use crate::auth::{Caller, Role};
+use crate::error::{Result, ServerError};
+use crate::repo::fsck::{self, FsckReport};
+use crate::repo::RepoStore;
+
+/// The last check of a repository, without its findings.
+#[derive(Serialize)]
+pub struct FsckSummary {
+    pub repo: String,
+    /// When the last check started, if the repository was ever checked
+    pub started: Option<Timestamp>,
+    /// Number of findings of the last check
+    pub findings: usize,
+}
+
+/// List the outcome of the last check of each repository (server
+/// admins).
+pub async fn list_checks(
+    State(state): State<AppState>,
+    caller: Caller,
+) -> Result<Json<Vec<FsckSummary>>> {
+    caller.require_admin()?;
+    let mut summaries = Vec::new();
+    for repo in state.repos.list()? {
+        let report = FsckReport::load(&state.repos.path(&repo))?;
+        summaries.push(FsckSummary {
+            started: report.as_ref().map(|r| r.started),
+            findings: report.map_or(0, |r| r.findings.len() + r.omitted),
+            repo,
+        });
+    }
+    Ok(Json(summaries))
+}
+
+/// Show the report of the last check of a repository.
+pub async fn get_check(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<Json<FsckReport>> {
+    state.repos.access(&repo, &caller)?.require(Role::Admin)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    match FsckReport::load(&state.repos.path(repo))? {
+        Some(report) => Ok(Json(report)),
+        None => Err(ServerError::not_found(format!(
+            "{} was never checked",
+            repo
+        ))),
+    }
+}
+
+/// Check a repository now (server admins), returning the report.
+pub async fn run_check(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<Json<FsckReport>> {
+    caller.require_admin()?;
+    state.repos.access(&repo, &caller)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let report = fsck::run(&state.repos, repo).await?;
+    Ok(Json(report))
+}
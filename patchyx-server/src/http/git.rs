@@ -0,0 +1,280 @@
+//! Git import and export endpoints.
+
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+
+use super::routes::AppState;
+use crate::audit::{self, Action, Record};
+use crate::auth::{Caller, Role};
+use crate::error::{Result, ServerError};
+use crate::git::{self, import::ImportOptions, GitExport, GitImport, Job};
+use crate::mirror::SyncStatus;
+use crate::repo::RepoStore;
+
+/// Git import request.
+#[derive(Deserialize)]
+pub struct GitImportRequest {
+    /// URL or absolute path of the git repository
+    pub url: String,
+    /// Only follow the first parent of merges
+    #[serde(default)]
+    pub first_parent: bool,
+    /// Branches to import, all of them if empty
+    #[serde(default)]
+    pub branches: Vec<String>,
+}
+
+/// A git import, without the imported commits.
+#[derive(Serialize)]
+pub struct GitImportInfo {
+    pub url: String,
+    pub first_parent: bool,
+    pub branches: Vec<String>,
+    pub created: Timestamp,
+    pub by: String,
+    #[serde(flatten)]
+    pub status: SyncStatus,
+    pub running: bool,
+    /// Number of imported commits
+    pub commits: usize,
+    /// Last imported commit of each branch
+    pub tips: BTreeMap<String, String>,
+    /// Imported tags, with the id of their commit
+    pub tags: BTreeMap<String, String>,
+}
+
+impl GitImportInfo {
+    fn new(import: GitImport, running: bool) -> Self {
+        Self {
+            url: import.url,
+            first_parent: import.options.first_parent,
+            branches: import.options.branches,
+            created: import.created,
+            by: import.by,
+            status: import.status,
+            running,
+            commits: import.state.commits.len(),
+            tips: import
+                .state
+                .branches
+                .into_iter()
+                .map(|(name, branch)| (name, branch.tip))
+                .collect(),
+            tags: import.state.tags,
+        }
+    }
+}
+
+/// Show the git import into a repository and the outcome of its last
+/// run.
+pub async fn get_git_import(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<Json<GitImportInfo>> {
+    state.repos.access(&repo, &caller)?.require(Role::Admin)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let path = state.repos.path(repo);
+    match GitImport::load(&path)? {
+        Some(import) => Ok(Json(GitImportInfo::new(
+            import,
+            git::is_running(&path, Job::Import),
+        ))),
+        None => Err(ServerError::not_found(format!(
+            "No git import into {}",
+            repo
+        ))),
+    }
+}
+
+/// Import a git repository (server admins), or import the new commits
+/// of the one imported before. The import runs in the background.
+pub async fn start_git_import(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+    Json(req): Json<GitImportRequest>,
+) -> Result<(StatusCode, Json<GitImportInfo>)> {
+    caller.require_admin()?;
+    let access = state.repos.access(&repo, &caller)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    git::validate_url(&req.url)?;
+    let mut branches = req.branches;
+    branches.sort();
+    branches.dedup();
+    let options = ImportOptions {
+        first_parent: req.first_parent,
+        branches,
+    };
+    let path = state.repos.path(repo);
+    let import = match GitImport::load(&path)? {
+        // Commits can't be recorded again in another mode.
+        Some(old) if old.options.first_parent != options.first_parent => {
+            return Err(ServerError::bad_request(
+                "The mode of an existing import cannot be changed",
+            ));
+        }
+        Some(old) if old.url == req.url => GitImport { options, ..old },
+        Some(old) => GitImport {
+            url: req.url,
+            options,
+            status: SyncStatus::default(),
+            ..old
+        },
+        None => GitImport {
+            url: req.url,
+            options,
+            created: Timestamp::now(),
+            by: caller.name().to_string(),
+            status: SyncStatus::default(),
+            state: Default::default(),
+        },
+    };
+    if git::is_running(&path, Job::Import) {
+        return Err(ServerError::conflict(format!(
+            "An import into {} is already running",
+            repo
+        )));
+    }
+    import.save(&path)?;
+    git::start(
+        state.repos.clone(),
+        state.config.get(),
+        repo.to_string(),
+        Job::Import,
+    )?;
+    tracing::info!(repo = %repo, url = %import.url, by = caller.name(), "Git import started");
+    let record = Record {
+        target: Some(import.url.clone()),
+        ..Record::on_repo(Action::GitImport, &access)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok((StatusCode::ACCEPTED, Json(GitImportInfo::new(import, true))))
+}
+
+/// Git export request.
+#[derive(Deserialize)]
+pub struct GitExportRequest {
+    /// Channels to export, all of them if empty
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Git repository to push the branches to, if any
+    pub url: Option<String>,
+}
+
+/// A git export, without the exported states.
+#[derive(Serialize)]
+pub struct GitExportInfo {
+    pub channels: Vec<String>,
+    pub url: Option<String>,
+    pub created: Timestamp,
+    pub by: String,
+    #[serde(flatten)]
+    pub status: SyncStatus,
+    pub running: bool,
+    /// Last exported commit of each branch
+    pub tips: BTreeMap<String, String>,
+}
+
+impl GitExportInfo {
+    fn new(export: GitExport, running: bool) -> Self {
+        Self {
+            channels: export.channels,
+            url: export.url,
+            created: export.created,
+            by: export.by,
+            status: export.status,
+            running,
+            tips: export
+                .state
+                .channels
+                .into_iter()
+                .map(|(name, channel)| (name, channel.commit))
+                .collect(),
+        }
+    }
+}
+
+/// Show the git export of a repository and the outcome of its last
+/// run.
+pub async fn get_git_export(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<Json<GitExportInfo>> {
+    state.repos.access(&repo, &caller)?.require(Role::Admin)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let path = state.repos.path(repo);
+    match GitExport::load(&path)? {
+        Some(export) => Ok(Json(GitExportInfo::new(
+            export,
+            git::is_running(&path, Job::Export),
+        ))),
+        None => Err(ServerError::not_found(format!("No git export of {}", repo))),
+    }
+}
+
+/// Export channels as git branches (server admins), or export the
+/// changes applied since the last export. The export runs in the
+/// background.
+pub async fn start_git_export(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+    Json(req): Json<GitExportRequest>,
+) -> Result<(StatusCode, Json<GitExportInfo>)> {
+    caller.require_admin()?;
+    let access = state.repos.access(&repo, &caller)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    if let Some(ref url) = req.url {
+        git::validate_url(url)?;
+    }
+    let mut channels = req.channels;
+    channels.sort();
+    channels.dedup();
+    let path = state.repos.path(repo);
+    // The exported states are kept: they are those of the branches of
+    // `.pijul/git_export.git`.
+    let export = match GitExport::load(&path)? {
+        Some(old) => GitExport {
+            channels,
+            url: req.url,
+            ..old
+        },
+        None => GitExport {
+            channels,
+            url: req.url,
+            created: Timestamp::now(),
+            by: caller.name().to_string(),
+            status: SyncStatus::default(),
+            state: Default::default(),
+        },
+    };
+    if git::is_running(&path, Job::Export) {
+        return Err(ServerError::conflict(format!(
+            "A git export of {} is already running",
+            repo
+        )));
+    }
+    export.save(&path)?;
+    git::start(
+        state.repos.clone(),
+        state.config.get(),
+        repo.to_string(),
+        Job::Export,
+    )?;
+    tracing::info!(repo = %repo, by = caller.name(), "Git export started");
+    let record = Record {
+        target: export.url.clone(),
+        ..Record::on_repo(Action::GitExport, &access)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok((StatusCode::ACCEPTED, Json(GitExportInfo::new(export, true))))
+}
@@ -0,0 +1,56 @@
+//! Change history endpoints.
+
+use axum::{
+    extract::{Path, Query, State},
+    response::Json,
+};
+use serde::Deserialize;
+
+use super::routes::AppState;
+use crate::auth::{Caller, Role};
+use crate::error::{Result, ServerError};
+use crate::repo::history::{self, ChangeDetail, LogPage, MAX_LOG_PAGE};
+
+/// Default number of entries in a page of the log.
+const DEFAULT_LOG_PAGE: usize = 50;
+
+/// Query parameters of the log endpoint.
+#[derive(Deserialize)]
+pub struct LogQuery {
+    /// Position to start from, as returned in `next`
+    pub from: Option<u64>,
+    /// Maximum number of entries
+    pub limit: Option<usize>,
+}
+
+/// List the changes of a channel, newest first.
+pub async fn log(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo, channel)): Path<(String, String)>,
+    Query(q): Query<LogQuery>,
+) -> Result<Json<LogPage>> {
+    state.repos.access(&repo, &caller)?.require(Role::Read)?;
+    let limit = q.limit.unwrap_or(DEFAULT_LOG_PAGE).clamp(1, MAX_LOG_PAGE);
+    let shared = state.repos.open(&repo)?;
+    let page = tokio::task::block_in_place(|| {
+        history::log(&shared.lock().unwrap(), &channel, q.from, limit)
+    })?;
+    page.map(Json)
+        .ok_or_else(|| ServerError::not_found(format!("Channel not found: {}", channel)))
+}
+
+/// Show a change, given its hash or a prefix of it.
+pub async fn change(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo, hash)): Path<(String, String)>,
+) -> Result<Json<ChangeDetail>> {
+    state.repos.access(&repo, &caller)?.require(Role::Read)?;
+    let shared = state.repos.open(&repo)?;
+    tokio::task::block_in_place(|| {
+        let repo = shared.lock().unwrap();
+        let hash = history::resolve_hash(&repo, &hash)?;
+        Ok(Json(history::change(&repo, &hash)?))
+    })
+}
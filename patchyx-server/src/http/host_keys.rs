@@ -0,0 +1,67 @@
+//! SSH host key endpoints.
+
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode, response::Json};
+use serde::{Deserialize, Serialize};
+
+use super::routes::AppState;
+use crate::audit::{self, Action, Record};
+use crate::auth::Caller;
+use crate::config::HostKeyAlgorithm;
+use crate::error::{Result, ServerError};
+use crate::ssh::host_keys::HostKeyInfo;
+
+/// Host key listing response.
+#[derive(Serialize)]
+pub struct HostKeysResponse {
+    pub keys: Vec<HostKeyInfo>,
+}
+
+/// Host key rotation request.
+#[derive(Deserialize)]
+pub struct RotateRequest {
+    pub algorithm: HostKeyAlgorithm,
+    /// How long the current key stays in use, `host_keys.grace_secs`
+    /// if missing
+    pub grace_secs: Option<u64>,
+}
+
+/// List the host keys, including rotated keys not in use yet.
+pub async fn list_host_keys(State(state): State<AppState>) -> Result<Json<HostKeysResponse>> {
+    let keys = tokio::task::block_in_place(|| state.host_keys.list())?;
+    Ok(Json(HostKeysResponse { keys }))
+}
+
+/// Generate a new host key, replacing the current one of the same
+/// algorithm after a grace period.
+pub async fn rotate_host_key(
+    State(state): State<AppState>,
+    caller: Caller,
+    Json(req): Json<RotateRequest>,
+) -> Result<(StatusCode, Json<HostKeyInfo>)> {
+    caller.require_admin()?;
+    if !state.host_keys.algorithms().contains(&req.algorithm) {
+        return Err(ServerError::bad_request(format!(
+            "No {} host key is configured",
+            req.algorithm.key_type()
+        )));
+    }
+    let grace_secs = req
+        .grace_secs
+        .unwrap_or(state.config.get().host_keys.grace_secs);
+    let grace = Duration::from_secs(grace_secs);
+    let key = tokio::task::block_in_place(|| state.host_keys.rotate(req.algorithm, grace))?;
+    tracing::info!(
+        fingerprint = %key.fingerprint,
+        activates = ?key.activates,
+        by = caller.name(),
+        "Host key rotated"
+    );
+    let record = Record {
+        target: Some(key.fingerprint.clone()),
+        ..Record::new(Action::HostKeyRotate, &caller)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok((StatusCode::CREATED, Json(key)))
+}
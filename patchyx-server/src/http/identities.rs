@@ -0,0 +1,116 @@
+//! Identity directory endpoints.
+//!
+//! Identities are created when a user proves they own a signing key
+//! with `pijul identity` (see [`crate::auth::identities`]); their
+//! owners can then set the name and email shown for their changes.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+
+use super::routes::AppState;
+use crate::audit::{self, Action, Record};
+use crate::auth::{identities, Caller, Identity};
+use crate::error::{Result, ServerError};
+
+/// Identity listing response.
+#[derive(Serialize)]
+pub struct IdentitiesResponse {
+    pub identities: Vec<Identity>,
+}
+
+/// Identity update request. Missing fields are left unchanged.
+#[derive(Deserialize)]
+pub struct UpdateIdentityRequest {
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+}
+
+/// Show the identity of a key, given by its fingerprint or by the key
+/// itself, as in the authors of changes.
+pub async fn get_identity(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<Identity>> {
+    match identities::find(&state.config.get().users_dir, &id)? {
+        Some(identity) => Ok(Json(identity)),
+        None => Err(ServerError::not_found(format!(
+            "Identity not found: {}",
+            id
+        ))),
+    }
+}
+
+/// List the identities of a user.
+pub async fn list_identities(
+    State(state): State<AppState>,
+    Path(user): Path<String>,
+) -> Result<Json<IdentitiesResponse>> {
+    let identities = identities::list(&state.config.get().users_dir, &user)?;
+    Ok(Json(IdentitiesResponse { identities }))
+}
+
+/// Set the display name or email of an identity (its owner or server
+/// admins).
+pub async fn update_identity(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<String>,
+    Json(req): Json<UpdateIdentityRequest>,
+) -> Result<Json<Identity>> {
+    for value in [&req.display_name, &req.email].into_iter().flatten() {
+        if value.chars().any(char::is_control) {
+            return Err(ServerError::bad_request(format!(
+                "Invalid value: {:?}",
+                value
+            )));
+        }
+    }
+    let config = state.config.get();
+    let identity = identities::update(&config.users_dir, &id, |identity| {
+        caller.require_user(&identity.username)?;
+        if let Some(display_name) = req.display_name {
+            identity.display_name = display_name.trim().to_string();
+        }
+        if let Some(email) = req.email {
+            identity.email = email.trim().to_string();
+        }
+        Ok(())
+    })?;
+    let fingerprint = identity.public_key.fingerprint();
+    tracing::info!(user = %identity.username, fingerprint = %fingerprint, "Identity updated");
+    let record = Record {
+        target: Some(format!("{} {}", identity.username, fingerprint)),
+        ..Record::new(Action::IdentityUpdate, &caller)
+    };
+    audit::append(&config.audit, record);
+    Ok(Json(identity))
+}
+
+/// Unlink a key from its user (its owner or server admins).
+pub async fn delete_identity(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<String>,
+) -> Result<StatusCode> {
+    let config = state.config.get();
+    let Some(identity) = identities::find(&config.users_dir, &id)? else {
+        return Err(ServerError::not_found(format!(
+            "Identity not found: {}",
+            id
+        )));
+    };
+    caller.require_user(&identity.username)?;
+    let identity = identities::remove(&config.users_dir, &identity.public_key.key)?;
+    let fingerprint = identity.public_key.fingerprint();
+    tracing::info!(user = %identity.username, fingerprint = %fingerprint, "Identity removed");
+    let record = Record {
+        target: Some(format!("{} {}", identity.username, fingerprint)),
+        ..Record::new(Action::IdentityRemove, &caller)
+    };
+    audit::append(&config.audit, record);
+    Ok(StatusCode::NO_CONTENT)
+}
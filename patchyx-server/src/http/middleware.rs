@@ -1,11 +1,21 @@
 //! HTTP middleware configuration.
 
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
 use axum::http::{header, Method};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
 use tower_http::{
     cors::{Any, CorsLayer},
     trace::TraceLayer,
 };
 
+use super::routes::AppState;
+use crate::metrics;
+use crate::ratelimit::Key;
+
 /// Create the middleware stack for the HTTP server.
 pub fn create_cors_layer() -> CorsLayer {
     CorsLayer::new()
@@ -18,3 +28,35 @@ pub fn create_cors_layer() -> CorsLayer {
 pub fn create_trace_layer() -> TraceLayer<tower_http::classify::SharedClassifier<tower_http::classify::ServerErrorsAsFailures>> {
     TraceLayer::new_for_http()
 }
+
+/// Record the duration of requests, by route rather than by path to
+/// keep the number of series bounded.
+pub async fn track_metrics(path: MatchedPath, request: Request, next: Next) -> Response {
+    let method = request.method().clone();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let status = response.status();
+    metrics::HTTP_REQUESTS.observe(
+        &[method.as_str(), path.as_str(), status.as_str()],
+        start.elapsed(),
+    );
+    response
+}
+
+/// Limit the rate of requests from each address. Requests by users
+/// are also limited per user, once authenticated.
+pub async fn rate_limit(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let addr = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0.ip());
+    if let Some(addr) = addr {
+        let limit = state.config.get().limits.http_requests;
+        if let Err(e) = state.limiters.http_requests.check(&limit, Key::Addr(addr)) {
+            tracing::warn!(addr = %addr, "HTTP requests rate limited");
+            let what = format!("requests from {}", addr);
+            return e.error(&what).into_response();
+        }
+    }
+    next.run(request).await
+}
@@ -0,0 +1,347 @@
+//! Pull and push mirror endpoints.
+
+use std::path::PathBuf;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use jiff::Timestamp;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::routes::AppState;
+use crate::audit::{self, Action, Record};
+use crate::auth::{Caller, Role};
+use crate::error::{Result, ServerError};
+use crate::mirror::pull::{self, PullMirror};
+use crate::mirror::push::{PendingPush, PushMirror, PushMirrorList};
+use crate::mirror::{Credentials, SyncStatus};
+use crate::repo::{browse, RepoStore};
+
+/// Mirror configuration request.
+#[derive(Deserialize)]
+pub struct SetMirrorRequest {
+    /// Remote to mirror: `ssh://`, `http(s)://` URL or local path
+    pub url: String,
+    /// Channels to mirror, the default channel if empty
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Delay between syncs, `[mirrors] interval_secs` if missing
+    pub interval_secs: Option<u64>,
+    #[serde(default)]
+    pub credentials: Credentials,
+}
+
+/// A mirror, without the values of its HTTP headers.
+#[derive(Serialize)]
+pub struct PullMirrorInfo {
+    pub url: String,
+    pub channels: Vec<String>,
+    pub interval_secs: Option<u64>,
+    pub ssh_user: Option<String>,
+    pub ssh_key: Option<PathBuf>,
+    /// Names of the headers sent to HTTP remotes
+    pub headers: Vec<String>,
+    pub created: Timestamp,
+    pub by: String,
+    #[serde(flatten)]
+    pub status: SyncStatus,
+}
+
+impl From<&PullMirror> for PullMirrorInfo {
+    fn from(mirror: &PullMirror) -> Self {
+        Self {
+            url: mirror.url.clone(),
+            channels: mirror.channels.clone(),
+            interval_secs: mirror.interval_secs,
+            ssh_user: mirror.credentials.ssh_user.clone(),
+            ssh_key: mirror.credentials.ssh_key.clone(),
+            headers: mirror.credentials.headers.keys().cloned().collect(),
+            created: mirror.created,
+            by: mirror.by.clone(),
+            status: mirror.status.clone(),
+        }
+    }
+}
+
+/// Refuse SSH keys the server can't read.
+fn validate_credentials(credentials: &Credentials) -> Result<()> {
+    if let Some(ref key) = credentials.ssh_key {
+        if !key.is_absolute() || !key.is_file() {
+            return Err(ServerError::bad_request(format!(
+                "SSH key not found: {}",
+                key.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Show the mirror configuration of a repository and the outcome of
+/// its last sync.
+pub async fn get_mirror(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<Json<PullMirrorInfo>> {
+    state.repos.access(&repo, &caller)?.require(Role::Read)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    match PullMirror::load(&state.repos.path(repo))? {
+        Some(mirror) => Ok(Json(PullMirrorInfo::from(&mirror))),
+        None => Err(ServerError::not_found(format!(
+            "Repository is not a mirror: {}",
+            repo
+        ))),
+    }
+}
+
+/// Make a repository a mirror of a remote (server admins). Its
+/// channels are synced at the next round of the background task.
+pub async fn set_mirror(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+    Json(req): Json<SetMirrorRequest>,
+) -> Result<Json<PullMirrorInfo>> {
+    caller.require_admin()?;
+    let access = state.repos.access(&repo, &caller)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    pull::validate_url(&req.url)?;
+    validate_credentials(&req.credentials)?;
+    if req.interval_secs == Some(0) {
+        return Err(ServerError::bad_request("The interval must not be 0"));
+    }
+    let mut channels = req.channels;
+    if channels.is_empty() {
+        channels.push(libpijul::DEFAULT_CHANNEL.to_string());
+    }
+    channels.sort();
+    channels.dedup();
+    let path = state.repos.path(repo);
+    let old = PullMirror::load(&path)?;
+    let created = old.as_ref().map_or_else(Timestamp::now, |m| m.created);
+    // Keep the status when only the channels or interval change.
+    let status = match old {
+        Some(old) if old.url == req.url => old.status,
+        _ => SyncStatus::default(),
+    };
+    let mirror = PullMirror {
+        url: req.url,
+        channels,
+        interval_secs: req.interval_secs,
+        credentials: req.credentials,
+        created,
+        by: caller.name().to_string(),
+        status,
+    };
+    mirror.save(&path)?;
+    tracing::info!(repo = %repo, url = %mirror.url, by = caller.name(), "Mirror configured");
+    let record = Record {
+        target: Some(mirror.url.clone()),
+        ..Record::on_repo(Action::MirrorSet, &access)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok(Json(PullMirrorInfo::from(&mirror)))
+}
+
+/// Stop mirroring a remote (server admins).
+pub async fn delete_mirror(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<StatusCode> {
+    caller.require_admin()?;
+    let access = state.repos.access(&repo, &caller)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    PullMirror::remove(&state.repos.path(repo))?;
+    tracing::info!(repo = %repo, by = caller.name(), "Mirror removed");
+    audit::append(
+        &state.config.get().audit,
+        Record::on_repo(Action::MirrorRemove, &access),
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Sync a mirror now (server admins), returning the outcome.
+pub async fn sync_mirror(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<Json<PullMirrorInfo>> {
+    caller.require_admin()?;
+    state.repos.access(&repo, &caller)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let config = state.config.get();
+    let mirror = pull::sync_repo(&state.repos, &config, repo).await?;
+    Ok(Json(PullMirrorInfo::from(&mirror)))
+}
+
+/// Push mirror creation request.
+#[derive(Deserialize)]
+pub struct CreatePushMirrorRequest {
+    /// Remote to push to: `ssh://`, `http(s)://` URL or local path
+    pub url: String,
+    /// Channels to forward, all of them if empty
+    #[serde(default)]
+    pub channels: Vec<String>,
+    #[serde(default)]
+    pub credentials: Credentials,
+}
+
+/// A push mirror, without the values of its HTTP headers.
+#[derive(Serialize)]
+pub struct PushMirrorInfo {
+    pub id: String,
+    pub url: String,
+    pub channels: Vec<String>,
+    pub ssh_user: Option<String>,
+    pub ssh_key: Option<PathBuf>,
+    /// Names of the headers sent to HTTP remotes
+    pub headers: Vec<String>,
+    pub created: Timestamp,
+    pub by: String,
+    #[serde(flatten)]
+    pub status: SyncStatus,
+    pub pending: Vec<PendingPush>,
+}
+
+impl From<&PushMirror> for PushMirrorInfo {
+    fn from(mirror: &PushMirror) -> Self {
+        Self {
+            id: mirror.id.clone(),
+            url: mirror.url.clone(),
+            channels: mirror.channels.clone(),
+            ssh_user: mirror.credentials.ssh_user.clone(),
+            ssh_key: mirror.credentials.ssh_key.clone(),
+            headers: mirror.credentials.headers.keys().cloned().collect(),
+            created: mirror.created,
+            by: mirror.by.clone(),
+            status: mirror.status.clone(),
+            pending: mirror.pending.clone(),
+        }
+    }
+}
+
+/// Push mirror listing response.
+#[derive(Serialize)]
+pub struct PushMirrorsResponse {
+    pub mirrors: Vec<PushMirrorInfo>,
+}
+
+/// List the push mirrors of a repository, with their queues and the
+/// outcome of their last pushes.
+pub async fn list_push_mirrors(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<Json<PushMirrorsResponse>> {
+    state.repos.access(&repo, &caller)?.require(Role::Admin)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let list = PushMirrorList::load(&state.repos.path(repo))?;
+    Ok(Json(PushMirrorsResponse {
+        mirrors: list.mirrors.iter().map(PushMirrorInfo::from).collect(),
+    }))
+}
+
+/// Show a push mirror, its queue and the outcome of its last pushes.
+pub async fn get_push_mirror(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo, id)): Path<(String, String)>,
+) -> Result<Json<PushMirrorInfo>> {
+    state.repos.access(&repo, &caller)?.require(Role::Admin)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let list = PushMirrorList::load(&state.repos.path(repo))?;
+    match list.get(&id) {
+        Some(mirror) => Ok(Json(PushMirrorInfo::from(mirror))),
+        None => Err(ServerError::not_found(format!(
+            "Push mirror not found: {}",
+            id
+        ))),
+    }
+}
+
+/// Add a push mirror (server admins). The channels it forwards are
+/// queued right away, so that it catches up with the repository.
+pub async fn create_push_mirror(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+    Json(req): Json<CreatePushMirrorRequest>,
+) -> Result<(StatusCode, Json<PushMirrorInfo>)> {
+    caller.require_admin()?;
+    let access = state.repos.access(&repo, &caller)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    pull::validate_url(&req.url)?;
+    validate_credentials(&req.credentials)?;
+    let mut channels = req.channels;
+    channels.sort();
+    channels.dedup();
+    let now = Timestamp::now();
+    let mut mirror = PushMirror {
+        id: format!("{:016x}", rand::rng().random::<u64>()),
+        url: req.url,
+        channels,
+        credentials: req.credentials,
+        created: now,
+        by: caller.name().to_string(),
+        status: SyncStatus::default(),
+        pending: Vec::new(),
+    };
+    let shared = state.repos.open(repo)?;
+    let existing = tokio::task::block_in_place(|| browse::channels(&shared.lock().unwrap()))?;
+    for channel in existing {
+        if mirror.forwards(&channel.name) {
+            mirror.queue(&channel.name, now);
+        }
+    }
+    PushMirrorList::update(&state.repos.path(repo), |list| {
+        list.mirrors.push(mirror.clone());
+        Ok(())
+    })?;
+    tracing::info!(
+        repo = %repo,
+        id = %mirror.id,
+        url = %mirror.url,
+        by = caller.name(),
+        "Push mirror created"
+    );
+    let record = Record {
+        target: Some(mirror.id.clone()),
+        ..Record::on_repo(Action::PushMirrorCreate, &access)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok((StatusCode::CREATED, Json(PushMirrorInfo::from(&mirror))))
+}
+
+/// Delete a push mirror (server admins). Its queued pushes are
+/// dropped.
+pub async fn delete_push_mirror(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo, id)): Path<(String, String)>,
+) -> Result<StatusCode> {
+    caller.require_admin()?;
+    let access = state.repos.access(&repo, &caller)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    PushMirrorList::update(&state.repos.path(repo), |list| {
+        let len = list.mirrors.len();
+        list.mirrors.retain(|m| m.id != id);
+        if list.mirrors.len() == len {
+            return Err(ServerError::not_found(format!(
+                "Push mirror not found: {}",
+                id
+            )));
+        }
+        Ok(())
+    })?;
+    tracing::info!(repo = %repo, id = %id, by = caller.name(), "Push mirror deleted");
+    let record = Record {
+        target: Some(id),
+        ..Record::on_repo(Action::PushMirrorDelete, &access)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -3,8 +3,24 @@
 //! Provides the REST API for repository management, health checks,
 //! and web UI serving.
 
+pub mod archive;
+pub mod audit;
+mod auth;
+pub mod browse;
+pub mod conflicts;
+pub mod fsck;
+pub mod git;
+pub mod history;
+pub mod host_keys;
+pub mod identities;
 mod middleware;
+pub mod mirror;
+pub mod proposals;
+pub mod remote;
+pub mod repos;
 pub mod routes;
+pub mod users;
+pub mod webhooks;
 
 pub use middleware::{create_cors_layer, create_trace_layer};
 pub use routes::create_router;
@@ -0,0 +1,428 @@
+//! Change proposal endpoints.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use jiff::Timestamp;
+use libpijul::{Base32, Hash};
+use pijul_repository::Repository;
+use serde::{Deserialize, Serialize};
+
+use super::routes::AppState;
+use crate::audit::{self, Action, Record};
+use crate::auth::{Access, Caller, Role};
+use crate::error::{Result, ServerError};
+use crate::repo::conflicts::{self, ConflictInfo};
+use crate::repo::fork::{share_changes, unshare_changes};
+use crate::repo::proposals::{self, Comment, Proposal, Status};
+use crate::repo::push::{is_rejection, Push};
+use crate::repo::{wire, RepoStore};
+use crate::webhooks::{self, Event};
+
+/// Proposal creation request.
+#[derive(Deserialize)]
+pub struct CreateProposalRequest {
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    /// Repository the changes come from, this one if missing
+    pub source_repo: Option<String>,
+    pub source_channel: String,
+    pub target_channel: String,
+}
+
+/// Comment request.
+#[derive(Deserialize)]
+pub struct CommentRequest {
+    pub body: String,
+}
+
+/// Merge request.
+#[derive(Deserialize)]
+pub struct MergeRequest {
+    /// Merge even if the changes conflict with the target channel
+    #[serde(default)]
+    pub allow_conflicts: bool,
+}
+
+/// Query parameters of the proposal listing.
+#[derive(Deserialize)]
+pub struct ProposalsQuery {
+    pub status: Option<Status>,
+}
+
+/// Proposal listing response, newest first.
+#[derive(Serialize)]
+pub struct ProposalsResponse {
+    pub proposals: Vec<Proposal>,
+}
+
+/// A proposal, with the changes it would apply.
+#[derive(Serialize)]
+pub struct ProposalInfo {
+    #[serde(flatten)]
+    pub proposal: Proposal,
+    /// Changes of the source channel missing from the target channel,
+    /// for open proposals
+    pub changes: Vec<String>,
+}
+
+/// What merging a proposal would do.
+#[derive(Serialize)]
+pub struct CheckResponse {
+    pub changes: Vec<String>,
+    /// Conflicts the merge would produce
+    pub conflicts: Vec<ConflictInfo>,
+}
+
+/// Run `f` on the source and target repositories, locked in name
+/// order so that operations between the same two repositories can't
+/// deadlock.
+pub(super) fn with_repos<T, F>(state: &AppState, source: &str, target: &str, f: F) -> Result<T>
+where
+    F: FnOnce(&Repository, &Repository) -> Result<T>,
+{
+    let source_repo = state.repos.open(source)?;
+    if source == target {
+        let repo = source_repo.lock().unwrap();
+        return f(&repo, &repo);
+    }
+    let target_repo = state.repos.open(target)?;
+    let (source_repo, target_repo) = if source < target {
+        let source_repo = source_repo.lock().unwrap();
+        (source_repo, target_repo.lock().unwrap())
+    } else {
+        let target_repo = target_repo.lock().unwrap();
+        (source_repo.lock().unwrap(), target_repo)
+    };
+    f(&source_repo, &target_repo)
+}
+
+/// Changes of the source channel of `proposal` missing from its
+/// target channel.
+fn missing_changes(
+    source: &Repository,
+    target: &Repository,
+    proposal: &Proposal,
+) -> Result<Vec<Hash>> {
+    if wire::channel_id(source, &proposal.source_channel)?.is_none() {
+        return Err(ServerError::not_found(format!(
+            "Channel not found: {}",
+            proposal.source_channel
+        )));
+    }
+    Ok(proposals::missing_changes(
+        source,
+        &proposal.source_channel,
+        target,
+        &proposal.target_channel,
+    )?)
+}
+
+/// `proposal`, for callers who can read its source repository.
+fn info(
+    state: &AppState,
+    caller: &Caller,
+    target: &str,
+    proposal: Proposal,
+) -> Result<ProposalInfo> {
+    let source = state.repos.access(&proposal.source_repo, caller)?;
+    source.require(Role::Read)?;
+    let changes = if proposal.status == Status::Open {
+        tokio::task::block_in_place(|| {
+            with_repos(state, &proposal.source_repo, target, |source, target| {
+                missing_changes(source, target, &proposal)
+            })
+        })?
+    } else {
+        Vec::new()
+    };
+    Ok(ProposalInfo {
+        proposal,
+        changes: changes.iter().map(|h| h.to_base32()).collect(),
+    })
+}
+
+fn require_open(proposal: &Proposal) -> Result<()> {
+    if proposal.status == Status::Open {
+        Ok(())
+    } else {
+        Err(ServerError::conflict(format!(
+            "Proposal {} is {:?}",
+            proposal.id, proposal.status
+        )))
+    }
+}
+
+/// The webhook event of an operation on `proposal`.
+fn proposal_event(proposal: &Proposal, action: &str) -> Event {
+    Event::Proposal {
+        id: proposal.id,
+        action: action.to_string(),
+        title: proposal.title.clone(),
+    }
+}
+
+/// Audit an operation on a proposal and notify the webhooks of the
+/// target repository.
+fn record(state: &AppState, access: &Access, proposal: &Proposal, action: Action, event: Event) {
+    let config = state.config.get();
+    let record = Record {
+        channel: Some(proposal.target_channel.clone()),
+        target: Some(proposal.id.to_string()),
+        ..Record::on_repo(action, access)
+    };
+    audit::append(&config.audit, record);
+    let settings = config.repo_settings(access.repo());
+    let path = state.repos.path(access.repo());
+    webhooks::notify(&settings, &path, access.repo(), access.caller(), event);
+}
+
+/// List the proposals of a repository, newest first.
+pub async fn list_proposals(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+    Query(q): Query<ProposalsQuery>,
+) -> Result<Json<ProposalsResponse>> {
+    state.repos.access(&repo, &caller)?.require(Role::Read)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let mut proposals = Proposal::list(&state.repos.path(repo))?;
+    if let Some(status) = q.status {
+        proposals.retain(|p| p.status == status);
+    }
+    Ok(Json(ProposalsResponse { proposals }))
+}
+
+/// Propose to apply the changes of a channel, of this repository or
+/// of another one the caller can read, to a channel of this
+/// repository.
+pub async fn create_proposal(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+    Json(req): Json<CreateProposalRequest>,
+) -> Result<(StatusCode, Json<ProposalInfo>)> {
+    let access = state.repos.access(&repo, &caller)?;
+    access.require(Role::Read)?;
+    if caller.user.is_none() && !caller.is_admin {
+        return Err(ServerError::auth("Opening proposals requires a token"));
+    }
+    let target = RepoStore::normalize_name(&repo)?;
+    let source = match req.source_repo {
+        Some(ref source) => RepoStore::normalize_name(source)?,
+        None => target,
+    };
+    state.repos.access(source, &caller)?.require(Role::Read)?;
+    if req.title.trim().is_empty() {
+        return Err(ServerError::bad_request("Proposals need a title"));
+    }
+    let now = Timestamp::now();
+    let proposal = Proposal {
+        id: 0,
+        title: req.title,
+        description: req.description,
+        author: caller.name().to_string(),
+        source_repo: source.to_string(),
+        source_channel: req.source_channel,
+        target_channel: req.target_channel,
+        status: Status::Open,
+        created: now,
+        updated: now,
+        merged_changes: Vec::new(),
+        merged_state: None,
+        comments: Vec::new(),
+    };
+    let changes = tokio::task::block_in_place(|| {
+        with_repos(&state, source, target, |source, target| {
+            missing_changes(source, target, &proposal)
+        })
+    })?;
+    if changes.is_empty() {
+        return Err(ServerError::bad_request(format!(
+            "Nothing to propose: {} has no changes missing from {}",
+            proposal.source_channel, proposal.target_channel
+        )));
+    }
+    let proposal = proposal.create(&state.repos.path(target))?;
+    tracing::info!(
+        repo = %target,
+        id = proposal.id,
+        source = %source,
+        by = caller.name(),
+        "Proposal opened"
+    );
+    let event = proposal_event(&proposal, "opened");
+    record(&state, &access, &proposal, Action::ProposalCreate, event);
+    let changes = changes.iter().map(|h| h.to_base32()).collect();
+    Ok((
+        StatusCode::CREATED,
+        Json(ProposalInfo { proposal, changes }),
+    ))
+}
+
+/// Show a proposal.
+pub async fn get_proposal(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo, id)): Path<(String, u64)>,
+) -> Result<Json<ProposalInfo>> {
+    state.repos.access(&repo, &caller)?.require(Role::Read)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let proposal = Proposal::load(&state.repos.path(repo), id)?;
+    Ok(Json(info(&state, &caller, repo, proposal)?))
+}
+
+/// Comment on a proposal.
+pub async fn comment(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo, id)): Path<(String, u64)>,
+    Json(req): Json<CommentRequest>,
+) -> Result<(StatusCode, Json<Comment>)> {
+    let access = state.repos.access(&repo, &caller)?;
+    access.require(Role::Read)?;
+    if caller.user.is_none() && !caller.is_admin {
+        return Err(ServerError::auth("Commenting requires a token"));
+    }
+    if req.body.trim().is_empty() {
+        return Err(ServerError::bad_request("Empty comment"));
+    }
+    let repo = RepoStore::normalize_name(&repo)?;
+    let comment = Comment {
+        author: caller.name().to_string(),
+        body: req.body,
+        created: Timestamp::now(),
+    };
+    let proposal = Proposal::update(&state.repos.path(repo), id, |p| {
+        p.comments.push(comment.clone());
+        Ok(())
+    })?;
+    let event = Event::Discussion {
+        proposal: proposal.id,
+        title: proposal.title.clone(),
+        author: comment.author.clone(),
+        body: comment.body.clone(),
+    };
+    record(&state, &access, &proposal, Action::ProposalComment, event);
+    Ok((StatusCode::CREATED, Json(comment)))
+}
+
+/// Tell what merging a proposal would do.
+pub async fn check(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo, id)): Path<(String, u64)>,
+) -> Result<Json<CheckResponse>> {
+    state.repos.access(&repo, &caller)?.require(Role::Read)?;
+    let target = RepoStore::normalize_name(&repo)?;
+    let proposal = Proposal::load(&state.repos.path(target), id)?;
+    require_open(&proposal)?;
+    let source = state.repos.access(&proposal.source_repo, &caller)?;
+    source.require(Role::Read)?;
+    let (changes, conflicts) = tokio::task::block_in_place(|| {
+        with_repos(&state, &proposal.source_repo, target, |source, target| {
+            let changes = missing_changes(source, target, &proposal)?;
+            let channel = &proposal.target_channel;
+            let conflicts = conflicts::preview_from(source, target, channel, &changes)?;
+            Ok((changes, conflicts))
+        })
+    })?;
+    Ok(Json(CheckResponse {
+        changes: changes.iter().map(|h| h.to_base32()).collect(),
+        conflicts,
+    }))
+}
+
+/// Merge a proposal, applying its changes to the target channel as a
+/// push. Merges producing conflicts are refused unless
+/// `allow_conflicts` is set.
+pub async fn merge(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo, id)): Path<(String, u64)>,
+    req: Option<Json<MergeRequest>>,
+) -> Result<Json<ProposalInfo>> {
+    let access = state.repos.access(&repo, &caller)?;
+    let target = RepoStore::normalize_name(&repo)?;
+    let path = state.repos.path(target);
+    let proposal = Proposal::load(&path, id)?;
+    access.require_push(&proposal.target_channel)?;
+    require_open(&proposal)?;
+    let source = state.repos.access(&proposal.source_repo, &caller)?;
+    source.require(Role::Read)?;
+    let allow_conflicts = req.is_some_and(|r| r.allow_conflicts);
+    let settings = state.config.get().repo_settings(target);
+    let (changes, merged_state) = tokio::task::block_in_place(|| {
+        with_repos(&state, &proposal.source_repo, target, |source, target| {
+            let changes = missing_changes(source, target, &proposal)?;
+            if changes.is_empty() {
+                return Err(ServerError::conflict("Nothing to merge"));
+            }
+            if !allow_conflicts {
+                let channel = &proposal.target_channel;
+                let conflicts = conflicts::preview_from(source, target, channel, &changes)?;
+                if !conflicts.is_empty() {
+                    return Err(ServerError::conflict(format!(
+                        "Merging would produce {} conflicts",
+                        conflicts.len()
+                    )));
+                }
+            }
+            // The files of a failed merge aren't kept.
+            let shared = share_changes(source, target, &changes)?;
+            let push = Push::with_changes(&proposal.target_channel, settings, changes.clone());
+            let new_state = push.apply(target, &access).map_err(|e| {
+                unshare_changes(target, &shared);
+                if is_rejection(&e) {
+                    ServerError::auth(e.to_string())
+                } else {
+                    e.into()
+                }
+            })?;
+            Ok((changes, new_state))
+        })
+    })?;
+    let proposal = Proposal::update(&path, id, |p| {
+        p.status = Status::Merged;
+        p.merged_changes = changes.iter().map(|h| h.to_base32()).collect();
+        p.merged_state = Some(merged_state.to_base32());
+        Ok(())
+    })?;
+    tracing::info!(repo = %target, id, by = caller.name(), "Proposal merged");
+    let event = proposal_event(&proposal, "merged");
+    record(&state, &access, &proposal, Action::ProposalMerge, event);
+    Ok(Json(ProposalInfo {
+        proposal,
+        changes: Vec::new(),
+    }))
+}
+
+/// Close a proposal without merging it. Its author and the writers of
+/// the repository can close it.
+pub async fn close(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo, id)): Path<(String, u64)>,
+) -> Result<Json<ProposalInfo>> {
+    let access = state.repos.access(&repo, &caller)?;
+    access.require(Role::Read)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let proposal = Proposal::update(&state.repos.path(repo), id, |p| {
+        if caller.user.as_deref() != Some(p.author.as_str()) {
+            access.require(Role::Write)?;
+        }
+        require_open(p)?;
+        p.status = Status::Closed;
+        Ok(())
+    })?;
+    tracing::info!(repo = %repo, id, by = caller.name(), "Proposal closed");
+    let event = proposal_event(&proposal, "closed");
+    record(&state, &access, &proposal, Action::ProposalClose, event);
+    Ok(Json(ProposalInfo {
+        proposal,
+        changes: Vec::new(),
+    }))
+}
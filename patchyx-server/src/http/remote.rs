@@ -0,0 +1,382 @@
+//! The Pijul HTTP remote protocol.
+//!
+//! Stock HTTP remotes (`pijul-remote/src/http.rs`) send all their
+//! requests to `{url}/.pijul`, with the operation in the query
+//! string:
+//!
+//! - `?change=HASH`, `?tag=STATE`: download a change or a short tag
+//! - `?changelist=FROM&channel=CH&path=P...`: list changes
+//! - `?state=[N]&channel=CH`, `?id&channel=CH`: channel state and id
+//! - `?channel=CH[&archive=STATE&change=HASH...&outputPrefix=P]`:
+//!   download a tarball
+//! - `?identities=REV`: published identities
+//! - `?challenge=KEY`, then `?prove=SIGNATURE`: prove key ownership,
+//!   linking the key to the authenticated user (both need a login)
+//! - `POST ?apply=HASH[&to_channel=CH]`, `POST ?tagup=STATE[&to_channel=CH]`:
+//!   upload a change or a tag
+//!
+//! The answers are computed by [`crate::repo::wire`], like over SSH.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::{Bytes, HttpBody},
+    extract::{Path, Query, State},
+    http::{header, Method, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use byteorder::{BigEndian, WriteBytesExt};
+use libpijul::key::PKey;
+use libpijul::{Base32, Hash, Merkle, DEFAULT_CHANNEL};
+
+use super::routes::AppState;
+use crate::audit::{self, Action, Record};
+use crate::auth::{identities, Access, Caller, Role};
+use crate::config::RepoSettings;
+use crate::error::{Result, ServerError};
+use crate::metrics;
+use crate::repo::push::{check_size, is_rejection, Push};
+use crate::repo::{wire, SharedRepo};
+use crate::webhooks::{self, Event};
+
+/// Maximum size of an uploaded change, whatever the configured
+/// limits say.
+pub const MAX_UPLOAD_SIZE: usize = 1 << 30;
+
+/// How long a challenge stays valid.
+const CHALLENGE_TTL: Duration = Duration::from_secs(300);
+/// Maximum number of pending challenges of a user.
+const MAX_CHALLENGES: usize = 16;
+
+/// Challenges sent to HTTP clients and not proven yet, by user.
+///
+/// The protocol is stateless: `prove` doesn't say which challenge it
+/// answers, so the signature is checked against all pending ones of
+/// the user. Only logged-in users get challenges, and each user only
+/// evicts their own.
+#[derive(Default)]
+pub struct Challenges {
+    pending: Mutex<HashMap<String, Vec<Challenge>>>,
+}
+
+/// When a challenge was sent, the challenge, and the key it was sent
+/// for.
+type Challenge = (Instant, String, PKey);
+
+impl Challenges {
+    fn create(&self, user: &str, key: PKey) -> String {
+        let challenge = identities::challenge();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, user_pending| {
+            user_pending.retain(|(t, _, _)| t.elapsed() < CHALLENGE_TTL);
+            !user_pending.is_empty()
+        });
+        let user_pending = pending.entry(user.to_string()).or_default();
+        if user_pending.len() >= MAX_CHALLENGES {
+            user_pending.remove(0);
+        }
+        user_pending.push((Instant::now(), challenge.clone(), key));
+        challenge
+    }
+
+    /// Check a proof, returning the key it proves ownership of.
+    fn prove(&self, user: &str, signature: &str) -> Result<PKey> {
+        let now = jiff::Timestamp::now();
+        let mut pending = self.pending.lock().unwrap();
+        let Some(user_pending) = pending.get_mut(user) else {
+            return Err(ServerError::auth("Invalid proof"));
+        };
+        user_pending.retain(|(t, _, _)| t.elapsed() < CHALLENGE_TTL);
+        let proven = user_pending
+            .iter()
+            .position(|(_, c, key)| key.verify(c.as_bytes(), signature, &now).is_ok())
+            .map(|i| user_pending.remove(i).2);
+        if user_pending.is_empty() {
+            pending.remove(user);
+        }
+        proven.ok_or_else(|| ServerError::auth("Invalid proof"))
+    }
+}
+
+/// Parse the public key sent with `?challenge=`: a base58 Ed25519 key.
+fn parse_key(key: &str) -> Result<PKey> {
+    let invalid = || ServerError::bad_request(format!("Invalid key: {}", key));
+    let mut bytes = [0; 32];
+    bs58::decode(key.as_bytes())
+        .into(&mut bytes)
+        .map_err(|_| invalid())?;
+    let key = ed25519_dalek::PublicKey::from_bytes(&bytes).map_err(|_| invalid())?;
+    Ok(PKey::Ed25519 {
+        expires: None,
+        signature: String::new(),
+        key,
+    })
+}
+
+/// Query parameters, in order. Some of them (`path`, `change`) can be
+/// repeated.
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn channel(&self, key: &str) -> &str {
+        self.get(key)
+            .filter(|c| !c.is_empty())
+            .unwrap_or(DEFAULT_CHANNEL)
+    }
+}
+
+pub(super) fn parse_hash(s: &str) -> Result<Hash> {
+    Hash::from_base32(s.as_bytes())
+        .ok_or_else(|| ServerError::bad_request(format!("Invalid hash: {}", s)))
+}
+
+pub(super) fn parse_merkle(s: &str) -> Result<Merkle> {
+    Merkle::from_base32(s.as_bytes())
+        .ok_or_else(|| ServerError::bad_request(format!("Invalid state: {}", s)))
+}
+
+fn bytes(body: Vec<u8>) -> Response {
+    ([(header::CONTENT_TYPE, "application/octet-stream")], body).into_response()
+}
+
+fn text(body: Vec<u8>) -> Response {
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body).into_response()
+}
+
+/// Handle a request to `/{repo}/.pijul`.
+pub async fn dot_pijul(
+    State(state): State<AppState>,
+    caller: Caller,
+    method: Method,
+    Path(repo_name): Path<String>,
+    Query(params): Query<Vec<(String, String)>>,
+    body: Bytes,
+) -> Result<Response> {
+    let params = Params(params);
+    let access = state.repos.access(&repo_name, &caller)?;
+    access.require(Role::Read)?;
+    let repo = state.repos.open(&repo_name)?;
+    let start = Instant::now();
+    let response = tokio::task::block_in_place(|| {
+        if method == Method::POST {
+            let settings = state.config.get().repo_settings(access.repo());
+            upload(&repo, &access, &settings, &params, &body)
+        } else {
+            download(&state, &repo, &access, &params)
+        }
+    });
+    let name = request_name(&method, &params);
+    metrics::PROTOCOL_REQUESTS.observe(&["http", name], start.elapsed());
+    metrics::TRANSFERRED_BYTES.add(&["http", "in"], body.len() as u64);
+    let response = response?;
+    let len = response.body().size_hint().exact().unwrap_or(0);
+    metrics::TRANSFERRED_BYTES.add(&["http", "out"], len);
+    Ok(response)
+}
+
+/// Name of a request in metrics, as in the SSH protocol.
+fn request_name(method: &Method, params: &Params) -> &'static str {
+    let requests: &[&'static str] = if method == Method::POST {
+        &["apply", "tagup"]
+    } else {
+        &[
+            "challenge",
+            "prove",
+            "archive",
+            "change",
+            "tag",
+            "changelist",
+            "state",
+            "id",
+            "identities",
+        ]
+    };
+    requests
+        .iter()
+        .find(|r| params.get(r).is_some())
+        .copied()
+        .unwrap_or("tarball")
+}
+
+fn upload(
+    repo: &SharedRepo,
+    access: &Access,
+    settings: &RepoSettings,
+    params: &Params,
+    body: &[u8],
+) -> Result<Response> {
+    let channel = params.channel("to_channel");
+    access.require_push(channel)?;
+    let repo = repo.lock().unwrap();
+    if let Some(hash) = params.get("apply") {
+        check_size(settings, body.len()).map_err(|e| ServerError::bad_request(e.to_string()))?;
+        let mut push = Push::new(channel, settings.clone());
+        push.receive(&repo, parse_hash(hash)?, body)?;
+        push.apply(&repo, access).map_err(|e| {
+            if is_rejection(&e) {
+                ServerError::auth(e.to_string())
+            } else {
+                e.into()
+            }
+        })?;
+    } else if let Some(tag) = params.get("tagup") {
+        wire::tagup(&repo, &parse_merkle(tag)?, channel, body)?;
+        let event = Event::TagCreate {
+            channel: channel.to_string(),
+            state: tag.to_string(),
+        };
+        webhooks::notify(settings, &repo.path, access.repo(), access.caller(), event);
+        let record = Record {
+            channel: Some(channel.to_string()),
+            new_state: Some(tag.to_string()),
+            ..Record::on_repo(Action::TagCreate, access)
+        };
+        audit::append(&settings.audit, record);
+    } else {
+        return Err(ServerError::protocol("Unknown upload request"));
+    }
+    Ok(StatusCode::OK.into_response())
+}
+
+fn download(
+    state: &AppState,
+    repo: &SharedRepo,
+    access: &Access,
+    params: &Params,
+) -> Result<Response> {
+    let channel = params.channel("channel");
+    let users_dir = state.config.get().users_dir.clone();
+    if let Some(challenge) = params.get("challenge") {
+        let Some(user) = access.user() else {
+            return Err(ServerError::auth("Log in to link a key to your account"));
+        };
+        let key = parse_key(challenge)?;
+        return Ok(text(state.challenges.create(user, key).into_bytes()));
+    } else if let Some(signature) = params.get("prove") {
+        let Some(user) = access.user() else {
+            return Err(ServerError::auth("Log in to link a key to your account"));
+        };
+        let key = state.challenges.prove(user, signature)?;
+        let identity = identities::link(&users_dir, user, key.save())?;
+        tracing::info!(user = user, key = %identity.public_key.key, "Identity linked");
+        let record = Record {
+            target: Some(identity.public_key.fingerprint()),
+            ..Record::on_repo(Action::IdentityLink, access)
+        };
+        audit::append(&state.config.get().audit, record);
+        return Ok(StatusCode::OK.into_response());
+    }
+
+    let repo = repo.lock().unwrap();
+    let mut out = Vec::new();
+    if let Some(archive) = params.get("archive") {
+        let extra = params
+            .get_all("change")
+            .map(parse_hash)
+            .collect::<Result<_>>()?;
+        let state = Some((parse_merkle(archive)?, extra));
+        let prefix = params.get("outputPrefix");
+        let (tarball, conflicts) = wire::tarball(&repo, channel, state, prefix)?;
+        out.write_u64::<BigEndian>(conflicts as u64)?;
+        out.extend_from_slice(&tarball);
+        Ok(bytes(out))
+    } else if let Some(hash) = params.get("change") {
+        let hash = parse_hash(hash)?;
+        let mut path = repo.changes_dir.clone();
+        libpijul::changestore::filesystem::push_filename(&mut path, &hash);
+        match std::fs::read(&path) {
+            Ok(contents) => Ok(bytes(contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(ServerError::not_found(
+                format!("Change not found: {}", hash.to_base32()),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    } else if let Some(tag) = params.get("tag") {
+        Ok(bytes(wire::tag_short(&repo, &parse_merkle(tag)?)?))
+    } else if let Some(from) = params.get("changelist") {
+        let from = from
+            .parse()
+            .map_err(|_| ServerError::bad_request(format!("Invalid position: {}", from)))?;
+        let paths: Vec<String> = params.get_all("path").map(|p| p.to_string()).collect();
+        wire::changelist(&repo, channel, from, &paths, &mut out)?;
+        Ok(text(out))
+    } else if let Some(at) = params.get("state") {
+        let at = if at.is_empty() {
+            None
+        } else {
+            Some(
+                at.parse()
+                    .map_err(|_| ServerError::bad_request(format!("Invalid position: {}", at)))?,
+            )
+        };
+        wire::state(&repo, channel, at, &mut out)?;
+        Ok(text(out))
+    } else if params.get("id").is_some() {
+        // The remote id is sent as raw bytes, empty if the channel
+        // doesn't exist.
+        let id = wire::channel_id(&repo, channel)?;
+        Ok(bytes(
+            id.map(|id| id.as_bytes().to_vec()).unwrap_or_default(),
+        ))
+    } else if let Some(rev) = params.get("identities") {
+        let rev: i64 = rev.parse().unwrap_or(0);
+        let identities = wire::read_identities(&repo, &users_dir, Some(rev))?;
+        let new_rev = identities.iter().map(|(m, _)| *m).max().unwrap_or(rev);
+        let ids: Vec<_> = identities.into_iter().map(|(_, id)| id).collect();
+        Ok(Json(serde_json::json!({ "id": ids, "rev": new_rev })).into_response())
+    } else if params.0.iter().all(|(k, _)| k == "channel") {
+        // A bare `?channel=` is a request for the current tarball.
+        let (tarball, conflicts) = wire::tarball(&repo, channel, None, None)?;
+        out.write_u64::<BigEndian>(conflicts as u64)?;
+        out.extend_from_slice(&tarball);
+        Ok(bytes(out))
+    } else {
+        Err(ServerError::protocol("Unknown request"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libpijul::key::SKey;
+
+    use super::*;
+
+    #[test]
+    fn test_challenges() {
+        let challenges = Challenges::default();
+        let key = SKey::generate(None);
+        let challenge = challenges.create("alice", key.pkey());
+        let signature = key.sign_raw(challenge.as_bytes()).unwrap();
+
+        // Other users' challenges don't evict alice's, and can't be
+        // proven by alice.
+        let mallory = SKey::generate(None);
+        for _ in 0..2 * MAX_CHALLENGES {
+            challenges.create("mallory", mallory.pkey());
+        }
+        let other = challenges.create("mallory", key.pkey());
+        let other = key.sign_raw(other.as_bytes()).unwrap();
+        assert!(challenges.prove("alice", &other).is_err());
+
+        assert!(challenges.prove("bob", &signature).is_err());
+        assert!(challenges.prove("alice", &signature).is_ok());
+        // Challenges are only proven once.
+        assert!(challenges.prove("alice", &signature).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! Repository management endpoints.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+
+use super::routes::AppState;
+use crate::audit::{self, Action, Record};
+use crate::auth::{AccessList, Caller, Role};
+use crate::error::{Result, ServerError};
+use crate::repo::browse::{self, ChannelInfo};
+use crate::repo::fork::ForkInfo;
+use crate::repo::RepoStore;
+use crate::webhooks::{self, Event};
+
+/// Repository info response.
+#[derive(Serialize)]
+pub struct RepoInfo {
+    pub name: String,
+    pub channels: Vec<ChannelInfo>,
+    /// The repository this one is a fork of, if any
+    pub parent: Option<String>,
+}
+
+/// List repositories response.
+#[derive(Serialize)]
+pub struct ReposResponse {
+    pub repositories: Vec<RepoInfo>,
+}
+
+/// Repository creation request.
+#[derive(Deserialize)]
+pub struct CreateRepoRequest {
+    pub name: String,
+    /// Whether anyone can read the repository
+    #[serde(default)]
+    pub public: bool,
+}
+
+/// Fork request.
+#[derive(Deserialize)]
+pub struct ForkRepoRequest {
+    /// Name of the fork
+    pub name: String,
+    /// Whether anyone can read the fork
+    #[serde(default)]
+    pub public: bool,
+}
+
+/// List forks response.
+#[derive(Serialize)]
+pub struct ForksResponse {
+    pub forks: Vec<RepoInfo>,
+}
+
+/// Repository update request.
+#[derive(Deserialize)]
+pub struct UpdateRepoRequest {
+    /// New name of the repository
+    pub name: Option<String>,
+}
+
+fn repo_info(state: &AppState, name: &str) -> Result<RepoInfo> {
+    let repo = state.repos.open(name)?;
+    let channels = tokio::task::block_in_place(|| browse::channels(&repo.lock().unwrap()))?;
+    let parent = ForkInfo::load(&state.repos.path(name))?.map(|f| f.parent);
+    Ok(RepoInfo {
+        name: name.to_string(),
+        channels,
+        parent,
+    })
+}
+
+/// List the repositories the caller can read.
+pub async fn list_repos(
+    State(state): State<AppState>,
+    caller: Caller,
+) -> Result<Json<ReposResponse>> {
+    let mut repositories = Vec::new();
+    for name in state.repos.list()? {
+        let readable = state
+            .repos
+            .access(&name, &caller)
+            .is_ok_and(|a| a.require(Role::Read).is_ok());
+        if readable {
+            repositories.push(repo_info(&state, &name)?);
+        }
+    }
+    Ok(Json(ReposResponse { repositories }))
+}
+
+/// Get a repository's channels.
+pub async fn get_repo(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<Json<RepoInfo>> {
+    state.repos.access(&repo, &caller)?.require(Role::Read)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    Ok(Json(repo_info(&state, repo)?))
+}
+
+/// Create a repository. The caller becomes its admin.
+pub async fn create_repo(
+    State(state): State<AppState>,
+    caller: Caller,
+    Json(req): Json<CreateRepoRequest>,
+) -> Result<(StatusCode, Json<RepoInfo>)> {
+    if caller.user.is_none() && !caller.is_admin {
+        return Err(ServerError::auth("Creating repositories requires a token"));
+    }
+    let name = RepoStore::normalize_name(&req.name)?;
+    tokio::task::block_in_place(|| state.repos.create(name))?;
+    let mut acl = AccessList {
+        public: req.public,
+        ..Default::default()
+    };
+    if let Some(ref user) = caller.user {
+        acl.users.insert(user.clone(), Role::Admin);
+    }
+    acl.save(&state.repos.path(name))?;
+    tracing::info!(repo = %name, by = caller.name(), "Repository created");
+    let record = Record {
+        repo: Some(name.to_string()),
+        ..Record::new(Action::RepoCreate, &caller)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok((StatusCode::CREATED, Json(repo_info(&state, name)?)))
+}
+
+/// Fork a repository the caller can read. The caller becomes the
+/// admin of the fork, which can only be public if its parent is.
+pub async fn fork_repo(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+    Json(req): Json<ForkRepoRequest>,
+) -> Result<(StatusCode, Json<RepoInfo>)> {
+    let access = state.repos.access(&repo, &caller)?;
+    access.require(Role::Read)?;
+    if caller.user.is_none() && !caller.is_admin {
+        return Err(ServerError::auth("Forking repositories requires a token"));
+    }
+    let parent = RepoStore::normalize_name(&repo)?;
+    let name = RepoStore::normalize_name(&req.name)?;
+    let mut acl = AccessList {
+        public: req.public,
+        ..Default::default()
+    };
+    if let Some(ref user) = caller.user {
+        acl.users.insert(user.clone(), Role::Admin);
+    }
+    tokio::task::block_in_place(|| state.repos.fork(parent, name, &acl, caller.name()))?;
+    tracing::info!(repo = %name, parent = %parent, by = caller.name(), "Repository forked");
+    let config = state.config.get();
+    let record = Record {
+        repo: Some(name.to_string()),
+        target: Some(parent.to_string()),
+        ..Record::new(Action::RepoFork, &caller)
+    };
+    audit::append(&config.audit, record);
+    let event = Event::Fork {
+        fork: name.to_string(),
+    };
+    let settings = config.repo_settings(parent);
+    let parent_path = state.repos.path(parent);
+    webhooks::notify(&settings, &parent_path, parent, caller.name(), event);
+    Ok((StatusCode::CREATED, Json(repo_info(&state, name)?)))
+}
+
+/// List the forks of a repository the caller can read.
+pub async fn list_forks(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<Json<ForksResponse>> {
+    state.repos.access(&repo, &caller)?.require(Role::Read)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let mut forks = Vec::new();
+    for name in state.repos.forks(repo)? {
+        let readable = state
+            .repos
+            .access(&name, &caller)
+            .is_ok_and(|a| a.require(Role::Read).is_ok());
+        if readable {
+            forks.push(repo_info(&state, &name)?);
+        }
+    }
+    Ok(Json(ForksResponse { forks }))
+}
+
+/// Rename a repository.
+pub async fn update_repo(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+    Json(req): Json<UpdateRepoRequest>,
+) -> Result<Json<RepoInfo>> {
+    let access = state.repos.access(&repo, &caller)?;
+    access.require(Role::Admin)?;
+    let mut name = RepoStore::normalize_name(&repo)?;
+    if let Some(ref new_name) = req.name {
+        state.repos.rename(name, new_name)?;
+        tracing::info!(repo = %name, to = %new_name, by = caller.name(), "Repository renamed");
+        let record = Record {
+            target: Some(new_name.clone()),
+            ..Record::on_repo(Action::RepoRename, &access)
+        };
+        audit::append(&state.config.get().audit, record);
+        name = RepoStore::normalize_name(new_name)?;
+    }
+    Ok(Json(repo_info(&state, name)?))
+}
+
+/// Delete a repository.
+pub async fn delete_repo(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<StatusCode> {
+    let access = state.repos.access(&repo, &caller)?;
+    access.require(Role::Admin)?;
+    tokio::task::block_in_place(|| state.repos.delete(&repo))?;
+    tracing::info!(repo = %repo, by = caller.name(), "Repository deleted");
+    let record = Record::on_repo(Action::RepoDelete, &access);
+    audit::append(&state.config.get().audit, record);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Get the access list of a repository.
+pub async fn get_access(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<Json<AccessList>> {
+    state.repos.access(&repo, &caller)?.require(Role::Admin)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    Ok(Json(AccessList::load(&state.repos.path(repo))?))
+}
+
+/// Replace the access list of a repository. Forks can only be public
+/// if their parent is.
+pub async fn set_access(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+    Json(acl): Json<AccessList>,
+) -> Result<Json<AccessList>> {
+    let access = state.repos.access(&repo, &caller)?;
+    access.require(Role::Admin)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    state.repos.set_access(repo, &acl)?;
+    tracing::info!(repo = %repo, by = caller.name(), "Access list updated");
+    let record = Record {
+        target: serde_json::to_string(&acl).ok(),
+        ..Record::on_repo(Action::AccessChange, &access)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok(Json(acl))
+}
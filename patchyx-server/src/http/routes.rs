@@ -1,20 +1,35 @@
 //! HTTP route definitions.
 
 use axum::{
-    extract::State,
+    extract::{DefaultBodyLimit, State},
+    http::header,
     response::{IntoResponse, Json},
-    routing::get,
+    routing::{delete, get, post},
     Router,
 };
 use serde::Serialize;
 use std::sync::Arc;
 
-use crate::config::ServerConfig;
+use super::{
+    archive, audit, browse, conflicts, fsck, git, history, host_keys, identities, middleware,
+    mirror, proposals, remote, repos, users, webhooks,
+};
+use crate::auth::{KeyStore, TokenStore};
+use crate::config::SharedConfig;
+use crate::ratelimit::Limiters;
+use crate::repo::RepoStore;
+use crate::ssh::HostKeys;
 
 /// Application state shared across handlers.
 #[derive(Clone)]
 pub struct AppState {
-    pub config: Arc<ServerConfig>,
+    pub config: Arc<SharedConfig>,
+    pub repos: Arc<RepoStore>,
+    pub keys: Arc<KeyStore>,
+    pub tokens: Arc<TokenStore>,
+    pub challenges: Arc<remote::Challenges>,
+    pub host_keys: Arc<HostKeys>,
+    pub limiters: Arc<Limiters>,
     pub start_time: std::time::Instant,
 }
 
@@ -26,25 +41,150 @@ pub struct HealthResponse {
     pub uptime_secs: u64,
 }
 
-/// Repository info response.
-#[derive(Serialize)]
-pub struct RepoInfo {
-    pub name: String,
-    pub channels: Vec<String>,
-}
-
-/// List repositories response.
-#[derive(Serialize)]
-pub struct ReposResponse {
-    pub repositories: Vec<RepoInfo>,
-}
-
 /// Create the main router with all routes.
 pub fn create_router(state: AppState) -> Router {
     Router::new()
         .route("/", get(root))
         .route("/health", get(health))
-        .route("/api/v1/repos", get(list_repos))
+        .route("/metrics", get(metrics))
+        .route(
+            "/api/v1/repos",
+            get(repos::list_repos).post(repos::create_repo),
+        )
+        .route(
+            "/api/v1/repos/:repo",
+            get(repos::get_repo)
+                .patch(repos::update_repo)
+                .delete(repos::delete_repo),
+        )
+        .route("/api/v1/repos/:repo/fork", post(repos::fork_repo))
+        .route("/api/v1/repos/:repo/forks", get(repos::list_forks))
+        .route(
+            "/api/v1/repos/:repo/access",
+            get(repos::get_access).put(repos::set_access),
+        )
+        .route(
+            "/api/v1/repos/:repo/mirror",
+            get(mirror::get_mirror)
+                .put(mirror::set_mirror)
+                .delete(mirror::delete_mirror),
+        )
+        .route("/api/v1/repos/:repo/mirror/sync", post(mirror::sync_mirror))
+        .route(
+            "/api/v1/repos/:repo/push-mirrors",
+            get(mirror::list_push_mirrors).post(mirror::create_push_mirror),
+        )
+        .route(
+            "/api/v1/repos/:repo/push-mirrors/:id",
+            get(mirror::get_push_mirror).delete(mirror::delete_push_mirror),
+        )
+        .route(
+            "/api/v1/repos/:repo/git-import",
+            get(git::get_git_import).post(git::start_git_import),
+        )
+        .route(
+            "/api/v1/repos/:repo/git-export",
+            get(git::get_git_export).post(git::start_git_export),
+        )
+        .route(
+            "/api/v1/repos/:repo/fsck",
+            get(fsck::get_check).post(fsck::run_check),
+        )
+        .route(
+            "/api/v1/repos/:repo/webhooks",
+            get(webhooks::list_webhooks).post(webhooks::create_webhook),
+        )
+        .route(
+            "/api/v1/repos/:repo/webhooks/:id",
+            delete(webhooks::delete_webhook),
+        )
+        .route(
+            "/api/v1/repos/:repo/webhooks/:id/deliveries",
+            get(webhooks::deliveries),
+        )
+        .route(
+            "/api/v1/repos/:repo/proposals",
+            get(proposals::list_proposals).post(proposals::create_proposal),
+        )
+        .route(
+            "/api/v1/repos/:repo/proposals/:id",
+            get(proposals::get_proposal),
+        )
+        .route(
+            "/api/v1/repos/:repo/proposals/:id/comments",
+            post(proposals::comment),
+        )
+        .route(
+            "/api/v1/repos/:repo/proposals/:id/check",
+            get(proposals::check),
+        )
+        .route(
+            "/api/v1/repos/:repo/proposals/:id/merge",
+            post(proposals::merge),
+        )
+        .route(
+            "/api/v1/repos/:repo/proposals/:id/close",
+            post(proposals::close),
+        )
+        .route(
+            "/api/v1/repos/:repo/channels/:channel/log",
+            get(history::log),
+        )
+        .route("/api/v1/repos/:repo/changes/:hash", get(history::change))
+        .route("/api/v1/repos/:repo/archive/*name", get(archive::archive))
+        .route(
+            "/api/v1/repos/:repo/channels/:channel/tree",
+            get(browse::tree),
+        )
+        .route(
+            "/api/v1/repos/:repo/channels/:channel/tree/*path",
+            get(browse::tree),
+        )
+        .route(
+            "/api/v1/repos/:repo/channels/:channel/raw/*path",
+            get(browse::raw),
+        )
+        .route(
+            "/api/v1/repos/:repo/channels/:channel/preview",
+            post(conflicts::preview),
+        )
+        .route("/api/v1/audit", get(audit::audit_log))
+        .route("/api/v1/fsck", get(fsck::list_checks))
+        .route("/api/v1/host-keys", get(host_keys::list_host_keys))
+        .route("/api/v1/host-keys/rotate", post(host_keys::rotate_host_key))
+        .route(
+            "/api/v1/users/:name/keys",
+            get(users::list_keys).post(users::add_key),
+        )
+        .route(
+            "/api/v1/users/:name/keys/:fingerprint",
+            delete(users::revoke_key),
+        )
+        .route(
+            "/api/v1/users/:name/identities",
+            get(identities::list_identities),
+        )
+        .route(
+            "/api/v1/identities/:id",
+            get(identities::get_identity)
+                .put(identities::update_identity)
+                .delete(identities::delete_identity),
+        )
+        .route(
+            "/api/v1/users/:name/tokens",
+            post(users::create_token).delete(users::revoke_tokens),
+        )
+        .route(
+            "/:repo/.pijul",
+            get(remote::dot_pijul)
+                .post(remote::dot_pijul)
+                .layer(DefaultBodyLimit::max(remote::MAX_UPLOAD_SIZE)),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::rate_limit,
+        ))
+        .route_layer(axum::middleware::from_fn(middleware::track_metrics))
         .with_state(state)
 }
 
@@ -67,27 +207,10 @@ async fn health(State(state): State<AppState>) -> impl IntoResponse {
     })
 }
 
-/// List all repositories.
-async fn list_repos(State(state): State<AppState>) -> impl IntoResponse {
-    // TODO: Actually list repos from state.config.repos_dir
-    // For now, return empty list
-    let repos_dir = &state.config.repos_dir;
-    let mut repositories = Vec::new();
-
-    if repos_dir.exists() {
-        if let Ok(entries) = std::fs::read_dir(repos_dir) {
-            for entry in entries.flatten() {
-                if entry.path().is_dir() {
-                    if let Some(name) = entry.file_name().to_str() {
-                        repositories.push(RepoInfo {
-                            name: name.to_string(),
-                            channels: vec!["main".to_string()], // TODO: Read actual channels
-                        });
-                    }
-                }
-            }
-        }
-    }
-
-    Json(ReposResponse { repositories })
+/// Metrics endpoint, in the Prometheus text format.
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        crate::metrics::encode(),
+    )
 }
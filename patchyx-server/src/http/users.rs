@@ -0,0 +1,114 @@
+//! User management endpoints.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+
+use super::routes::AppState;
+use crate::audit::{self, Action, Record};
+use crate::auth::{AuthorizedKey, Caller};
+use crate::error::Result;
+
+/// Key listing response.
+#[derive(Serialize)]
+pub struct KeysResponse {
+    pub keys: Vec<AuthorizedKey>,
+}
+
+/// Token creation response.
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub token: String,
+}
+
+/// Key creation request.
+#[derive(Deserialize)]
+pub struct AddKeyRequest {
+    /// The key, as a line of an `authorized_keys` file
+    pub key: String,
+}
+
+/// List the keys of a user, for the user themself or a server
+/// administrator.
+pub async fn list_keys(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(user): Path<String>,
+) -> Result<Json<KeysResponse>> {
+    caller.require_user(&user)?;
+    let keys = state.keys.list(&user)?;
+    Ok(Json(KeysResponse { keys }))
+}
+
+/// Authorize a new key for a user.
+pub async fn add_key(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(user): Path<String>,
+    Json(req): Json<AddKeyRequest>,
+) -> Result<(StatusCode, Json<AuthorizedKey>)> {
+    caller.require_user(&user)?;
+    let key = state.keys.add(&user, &req.key)?;
+    tracing::info!(user = %user, fingerprint = %key.fingerprint, "Key added");
+    let record = Record {
+        target: Some(format!("{} {}", user, key.fingerprint)),
+        ..Record::new(Action::KeyAdd, &caller)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok((StatusCode::CREATED, Json(key)))
+}
+
+/// Revoke a key, identified by its fingerprint (`SHA256:...`,
+/// percent-encoded).
+pub async fn revoke_key(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((user, fingerprint)): Path<(String, String)>,
+) -> Result<StatusCode> {
+    caller.require_user(&user)?;
+    state.keys.revoke(&user, &fingerprint)?;
+    tracing::info!(user = %user, fingerprint = %fingerprint, "Key revoked");
+    let record = Record {
+        target: Some(format!("{} {}", user, fingerprint)),
+        ..Record::new(Action::KeyRevoke, &caller)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Create an API token for a user. The token is only shown once.
+pub async fn create_token(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(user): Path<String>,
+) -> Result<(StatusCode, Json<TokenResponse>)> {
+    caller.require_user(&user)?;
+    let token = state.tokens.create(&user)?;
+    tracing::info!(user = %user, by = caller.name(), "Token created");
+    let record = Record {
+        target: Some(user),
+        ..Record::new(Action::TokenCreate, &caller)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok((StatusCode::CREATED, Json(TokenResponse { token })))
+}
+
+/// Revoke all the API tokens of a user.
+pub async fn revoke_tokens(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(user): Path<String>,
+) -> Result<StatusCode> {
+    caller.require_user(&user)?;
+    state.tokens.revoke_all(&user)?;
+    tracing::info!(user = %user, by = caller.name(), "Tokens revoked");
+    let record = Record {
+        target: Some(user),
+        ..Record::new(Action::TokensRevoke, &caller)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,195 @@
+//! Webhook management endpoints.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::routes::AppState;
+use crate::audit::{self, Action, Record};
+use crate::auth::{Caller, Role};
+use crate::error::{Result, ServerError};
+use crate::repo::RepoStore;
+use crate::webhooks::delivery::{self, MAX_LOG};
+use crate::webhooks::{Delivery, EventKind, Webhook, WebhookList};
+
+/// Default number of deliveries listed.
+const DEFAULT_DELIVERIES: usize = 50;
+
+/// Length of generated secrets.
+const SECRET_LEN: usize = 32;
+
+/// A webhook, without its secret.
+#[derive(Serialize)]
+pub struct WebhookInfo {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<EventKind>,
+}
+
+impl From<&Webhook> for WebhookInfo {
+    fn from(webhook: &Webhook) -> Self {
+        Self {
+            id: webhook.id.clone(),
+            url: webhook.url.clone(),
+            events: webhook.events.clone(),
+        }
+    }
+}
+
+/// Webhook listing response.
+#[derive(Serialize)]
+pub struct WebhooksResponse {
+    pub webhooks: Vec<WebhookInfo>,
+}
+
+/// Webhook creation request.
+#[derive(Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    /// Events to send, at least one
+    pub events: Vec<EventKind>,
+    /// HMAC key, generated if missing
+    pub secret: Option<String>,
+}
+
+/// Webhook creation response. The secret is only shown here.
+#[derive(Serialize)]
+pub struct CreateWebhookResponse {
+    #[serde(flatten)]
+    pub webhook: WebhookInfo,
+    pub secret: String,
+}
+
+/// Query parameters of the delivery log endpoint.
+#[derive(Deserialize)]
+pub struct DeliveriesQuery {
+    /// Maximum number of deliveries
+    pub limit: Option<usize>,
+}
+
+/// Delivery log response, newest first.
+#[derive(Serialize)]
+pub struct DeliveriesResponse {
+    pub deliveries: Vec<Delivery>,
+}
+
+/// List the webhooks of a repository.
+pub async fn list_webhooks(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+) -> Result<Json<WebhooksResponse>> {
+    state.repos.access(&repo, &caller)?.require(Role::Admin)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let list = WebhookList::load(&state.repos.path(repo))?;
+    Ok(Json(WebhooksResponse {
+        webhooks: list.webhooks.iter().map(WebhookInfo::from).collect(),
+    }))
+}
+
+/// Register a webhook.
+pub async fn create_webhook(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(repo): Path<String>,
+    Json(req): Json<CreateWebhookRequest>,
+) -> Result<(StatusCode, Json<CreateWebhookResponse>)> {
+    let access = state.repos.access(&repo, &caller)?;
+    access.require(Role::Admin)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let url_ok =
+        reqwest::Url::parse(&req.url).is_ok_and(|u| u.scheme() == "http" || u.scheme() == "https");
+    if !url_ok {
+        return Err(ServerError::bad_request(format!(
+            "Invalid webhook URL: {}",
+            req.url
+        )));
+    }
+    if req.events.is_empty() {
+        return Err(ServerError::bad_request("Webhooks need at least one event"));
+    }
+    let secret = req.secret.filter(|s| !s.is_empty()).unwrap_or_else(|| {
+        rand::rng()
+            .sample_iter(&rand::distr::Alphanumeric)
+            .take(SECRET_LEN)
+            .map(char::from)
+            .collect()
+    });
+    let webhook = Webhook {
+        id: format!("{:016x}", rand::rng().random::<u64>()),
+        url: req.url,
+        secret,
+        events: req.events,
+    };
+    let path = state.repos.path(repo);
+    let mut list = WebhookList::load(&path)?;
+    list.webhooks.push(webhook.clone());
+    list.save(&path)?;
+    tracing::info!(
+        repo = %repo,
+        id = %webhook.id,
+        url = %webhook.url,
+        by = caller.name(),
+        "Webhook created"
+    );
+    let record = Record {
+        target: Some(webhook.id.clone()),
+        ..Record::on_repo(Action::WebhookCreate, &access)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateWebhookResponse {
+            webhook: WebhookInfo::from(&webhook),
+            secret: webhook.secret,
+        }),
+    ))
+}
+
+/// Delete a webhook. Its pending deliveries are abandoned.
+pub async fn delete_webhook(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo, id)): Path<(String, String)>,
+) -> Result<StatusCode> {
+    let access = state.repos.access(&repo, &caller)?;
+    access.require(Role::Admin)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let path = state.repos.path(repo);
+    let mut list = WebhookList::load(&path)?;
+    let len = list.webhooks.len();
+    list.webhooks.retain(|w| w.id != id);
+    if list.webhooks.len() == len {
+        return Err(ServerError::not_found(format!("Webhook not found: {}", id)));
+    }
+    list.save(&path)?;
+    tracing::info!(repo = %repo, id = %id, by = caller.name(), "Webhook deleted");
+    let record = Record {
+        target: Some(id),
+        ..Record::on_repo(Action::WebhookDelete, &access)
+    };
+    audit::append(&state.config.get().audit, record);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List the deliveries of a webhook, newest first.
+pub async fn deliveries(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path((repo, id)): Path<(String, String)>,
+    Query(q): Query<DeliveriesQuery>,
+) -> Result<Json<DeliveriesResponse>> {
+    state.repos.access(&repo, &caller)?.require(Role::Admin)?;
+    let repo = RepoStore::normalize_name(&repo)?;
+    let path = state.repos.path(repo);
+    if WebhookList::load(&path)?.get(&id).is_none() {
+        return Err(ServerError::not_found(format!("Webhook not found: {}", id)));
+    }
+    let limit = q.limit.unwrap_or(DEFAULT_DELIVERIES).clamp(1, MAX_LOG);
+    let deliveries = tokio::task::block_in_place(|| delivery::log(&path, &id, limit))?;
+    Ok(Json(DeliveriesResponse { deliveries }))
+}
@@ -3,10 +3,18 @@
 //! A production-grade server for hosting Pijul repositories.
 //! Supports SSH for push/pull operations and HTTP for web UI and API.
 
+pub mod audit;
+pub mod auth;
 pub mod config;
 pub mod error;
+pub mod git;
 pub mod http;
+pub mod metrics;
+pub mod mirror;
+pub mod ratelimit;
+pub mod repo;
 pub mod ssh;
+pub mod webhooks;
 
 pub use config::ServerConfig;
 pub use error::{Result, ServerError};
@@ -5,90 +5,121 @@
 use std::sync::Arc;
 
 use tokio::signal;
-use tracing::{error, info, Level};
-use tracing_subscriber::FmtSubscriber;
+use tracing::{error, info, warn};
+use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
-use patchyx_server::config::ServerConfig;
+use patchyx_server::auth::{KeyStore, TokenStore};
+use patchyx_server::config::{ServerConfig, SharedConfig};
 use patchyx_server::http::routes::AppState;
-use patchyx_server::ssh::SshServerFactory;
+use patchyx_server::mirror::{Puller, Pusher};
+use patchyx_server::ratelimit::Limiters;
+use patchyx_server::repo::fsck::Checker;
+use patchyx_server::repo::RepoStore;
+use patchyx_server::ssh::{host_keys, HostKeys, SshServerFactory};
+use patchyx_server::webhooks::Dispatcher;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Load configuration
-    let config = match ServerConfig::from_env() {
+    let config = match ServerConfig::load() {
         Ok(c) => c,
         Err(e) => {
-            eprintln!("Configuration error: {}", e);
+            eprintln!("{}", e);
             std::process::exit(1);
         }
     };
 
-    // Initialize logging
-    let log_level = match config.log_level.to_lowercase().as_str() {
-        "trace" => Level::TRACE,
-        "debug" => Level::DEBUG,
-        "info" => Level::INFO,
-        "warn" => Level::WARN,
-        "error" => Level::ERROR,
-        _ => Level::INFO,
-    };
-
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(log_level)
+    // Initialize logging, keeping a handle to change the level on reload
+    let builder = FmtSubscriber::builder()
+        .with_env_filter(log_filter(&config))
         .with_target(true)
         .with_thread_ids(true)
         .with_file(true)
         .with_line_number(true)
-        .finish();
+        .with_filter_reloading();
+    let log_handle = builder.reload_handle();
+    let subscriber = builder.finish();
 
     tracing::subscriber::set_global_default(subscriber)?;
 
     info!("Starting Patchyx Pijul Server");
     info!("Configuration: {:?}", config);
 
+    let shared_config: Arc<SharedConfig> = Arc::new(config.clone().into());
     let config = Arc::new(config);
 
-    // --- Load or generate SSH host key ---
-    let host_key = if config.host_key_path.exists() {
-        info!("Loading host key from {:?}", config.host_key_path);
-        thrussh_keys::load_secret_key(&config.host_key_path, None)?
-    } else if config.generate_host_key {
-        info!("Generating new host key");
-        let key = thrussh_keys::key::KeyPair::generate_ed25519()
-            .ok_or_else(|| anyhow::anyhow!("Failed to generate key"))?;
-
-        // Save the key for persistence
-        // Note: thrussh_keys doesn't have a direct save function, 
-        // so in production you'd want to handle this properly
-        info!("Generated ephemeral host key (not persisted)");
-        key
-    } else {
-        return Err(anyhow::anyhow!(
-            "Host key not found at {:?} and generation disabled",
-            config.host_key_path
-        ));
-    };
-
     // --- SSH Server Setup ---
-    let mut ssh_config = thrussh::server::Config::default();
-    ssh_config.connection_timeout = Some(std::time::Duration::from_secs(600));
-    ssh_config.auth_rejection_time = std::time::Duration::from_secs(3);
-    ssh_config.keys.push(host_key);
-    let ssh_config = Arc::new(ssh_config);
-
-    let ssh_factory = SshServerFactory::new(config.clone());
+    // Load the host keys now, to fail early if they are missing.
+    let host_keys = Arc::new(HostKeys::new(&config));
+    host_keys.load()?;
+
+    let repos = Arc::new(RepoStore::new(shared_config.clone()));
+    let keys = Arc::new(KeyStore::new(&config.users_dir));
+    let limiters = Arc::new(Limiters::default());
+    let ssh_factory = SshServerFactory::new(
+        shared_config.clone(),
+        repos.clone(),
+        keys.clone(),
+        limiters.clone(),
+    );
     let ssh_addr = config.ssh_addr();
 
     info!("SSH server listening on {}", ssh_addr);
-    let ssh_handle = tokio::spawn(async move {
-        if let Err(e) = thrussh::server::run(ssh_config, &ssh_addr, ssh_factory).await {
-            error!("SSH server error: {}", e);
-        }
-    });
+    let ssh_handle = {
+        let host_keys = host_keys.clone();
+        tokio::spawn(async move {
+            if let Err(e) = host_keys::serve(host_keys, &ssh_addr, ssh_factory).await {
+                error!("SSH server error: {}", e);
+            }
+        })
+    };
+
+    // --- Webhook deliveries ---
+    let dispatcher = Dispatcher::new(shared_config.clone(), repos.clone());
+    let webhooks_handle = tokio::spawn(dispatcher.run());
+
+    // --- Pull mirrors ---
+    let puller = Puller::new(shared_config.clone(), repos.clone());
+    let mirrors_handle = tokio::spawn(puller.run());
+
+    // --- Push mirrors ---
+    let pusher = Pusher::new(shared_config.clone(), repos.clone());
+    let push_mirrors_handle = tokio::spawn(pusher.run());
+
+    // --- Integrity checks ---
+    let checker = Checker::new(shared_config.clone(), repos.clone());
+    let fsck_handle = tokio::spawn(checker.run());
+
+    // --- Configuration reload on SIGHUP ---
+    #[cfg(unix)]
+    {
+        let shared_config = shared_config.clone();
+        let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("SIGHUP received, reloading configuration");
+                match shared_config.reload() {
+                    Ok(config) => {
+                        if let Err(e) = log_handle.reload(log_filter(&config)) {
+                            warn!("Cannot change the log level: {}", e);
+                        }
+                        info!("Configuration reloaded: {:?}", config);
+                    }
+                    Err(e) => error!("Configuration not reloaded: {}", e),
+                }
+            }
+        });
+    }
 
     // --- HTTP Server Setup ---
     let app_state = AppState {
-        config: config.clone(),
+        config: shared_config,
+        repos,
+        keys,
+        tokens: Arc::new(TokenStore::new(&config.users_dir)),
+        challenges: Default::default(),
+        host_keys,
+        limiters,
         start_time: std::time::Instant::now(),
     };
 
@@ -126,7 +157,8 @@ async fn main() -> anyhow::Result<()> {
     };
 
     // Run HTTP server with graceful shutdown
-    axum::serve(listener, router)
+    let service = router.into_make_service_with_connect_info::<std::net::SocketAddr>();
+    axum::serve(listener, service)
         .with_graceful_shutdown(shutdown_signal)
         .await?;
 
@@ -136,6 +168,20 @@ async fn main() -> anyhow::Result<()> {
     ssh_handle.abort();
     info!("SSH server stopped");
 
+    // Pending webhook deliveries are kept on disk and resumed on restart.
+    webhooks_handle.abort();
+    // Interrupted syncs leave their transaction uncommitted.
+    mirrors_handle.abort();
+    // Queued pushes are kept on disk and resumed on restart.
+    push_mirrors_handle.abort();
+    // An interrupted check saves no report, and runs again on restart.
+    fsck_handle.abort();
+
     info!("Server shutdown complete");
     Ok(())
 }
+
+/// The log filter of a configuration.
+fn log_filter(config: &ServerConfig) -> EnvFilter {
+    EnvFilter::default().add_directive(config.log_filter().into())
+}
@@ -0,0 +1,282 @@
+//! Prometheus metrics.
+//!
+//! Metrics are process-wide statics, updated where the measured
+//! events happen, and rendered in the Prometheus text format by
+//! `GET /metrics`. The cumulative timers libpijul keeps in
+//! [`libpijul::TIMERS`] are exported along with them.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// SSH connections accepted.
+pub static SSH_CONNECTIONS: Counter = Counter::new(
+    "patchyx_ssh_connections_total",
+    "SSH connections accepted",
+    &[],
+);
+
+/// Authentication attempts, by method (`ssh_key`, `token`,
+/// `admin_token`) and result (`success`, `failure`, or `refused` for
+/// SSH connections over a rate limit).
+pub static AUTH_ATTEMPTS: Counter = Counter::new(
+    "patchyx_auth_attempts_total",
+    "Authentication attempts",
+    &["method", "result"],
+);
+
+/// Durations of SSH protocol sessions, by command (`clone`, `pull`,
+/// `push`).
+pub static SSH_COMMANDS: Histogram = Histogram::new(
+    "patchyx_ssh_command_duration_seconds",
+    "Duration of pijul protocol sessions over SSH",
+    &["command"],
+);
+
+/// Durations of remote protocol requests, by transport (`ssh`,
+/// `http`) and request (`changelist`, `change`, `apply`...).
+pub static PROTOCOL_REQUESTS: Histogram = Histogram::new(
+    "patchyx_protocol_request_duration_seconds",
+    "Duration of pijul remote protocol requests",
+    &["transport", "request"],
+);
+
+/// Bytes of the remote protocol, by transport and direction (`in`,
+/// `out`).
+pub static TRANSFERRED_BYTES: Counter = Counter::new(
+    "patchyx_transferred_bytes_total",
+    "Bytes transferred by the pijul remote protocol",
+    &["transport", "direction"],
+);
+
+/// Time to apply pushed changes, pre-apply hooks included.
+pub static CHANGE_APPLY: Histogram = Histogram::new(
+    "patchyx_change_apply_duration_seconds",
+    "Time to apply pushed changes to a channel",
+    &[],
+);
+
+/// Durations of HTTP requests, by method, route and status.
+pub static HTTP_REQUESTS: Histogram = Histogram::new(
+    "patchyx_http_request_duration_seconds",
+    "Duration of HTTP requests",
+    &["method", "route", "status"],
+);
+
+/// Repository integrity checks, by result (`clean`, `findings`,
+/// `error`).
+pub static FSCK_RUNS: Counter = Counter::new(
+    "patchyx_fsck_runs_total",
+    "Repository integrity checks",
+    &["result"],
+);
+
+/// A monotonic counter, by label values.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Add one to the counter with these label values.
+    pub fn inc(&self, labels: &[&str]) {
+        self.add(labels, 1)
+    }
+
+    /// Add `n` to the counter with these label values.
+    pub fn add(&self, labels: &[&str], n: u64) {
+        debug_assert_eq!(labels.len(), self.labels.len());
+        let labels = labels.iter().map(|l| l.to_string()).collect();
+        *self.values.lock().unwrap().entry(labels).or_default() += n;
+    }
+
+    fn encode(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        let values = self.values.lock().unwrap();
+        if values.is_empty() && self.labels.is_empty() {
+            writeln!(out, "{} 0", self.name).unwrap();
+        }
+        for (values, n) in values.iter() {
+            let labels = labels(self.labels, values, None);
+            writeln!(out, "{}{} {}", self.name, labels, n).unwrap();
+        }
+    }
+}
+
+/// Observations of a histogram with one set of label values.
+#[derive(Default)]
+struct Buckets {
+    /// Number of observations in each bucket, not cumulated
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// A histogram of durations, by label values.
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, Buckets>>,
+}
+
+impl Histogram {
+    const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record a duration with these label values.
+    pub fn observe(&self, labels: &[&str], duration: Duration) {
+        debug_assert_eq!(labels.len(), self.labels.len());
+        let secs = duration.as_secs_f64();
+        let labels = labels.iter().map(|l| l.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        let buckets = values.entry(labels).or_default();
+        if let Some(i) = BUCKETS.iter().position(|&b| secs <= b) {
+            buckets.counts[i] += 1;
+        }
+        buckets.count += 1;
+        buckets.sum += secs;
+    }
+
+    fn encode(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        for (values, buckets) in self.values.lock().unwrap().iter() {
+            let mut cumulated = 0;
+            for (bound, count) in BUCKETS.iter().zip(buckets.counts.iter()) {
+                cumulated += count;
+                let labels = labels(self.labels, values, Some(&bound.to_string()));
+                writeln!(out, "{}_bucket{} {}", self.name, labels, cumulated).unwrap();
+            }
+            let labels_inf = labels(self.labels, values, Some("+Inf"));
+            writeln!(out, "{}_bucket{} {}", self.name, labels_inf, buckets.count).unwrap();
+            let labels = labels(self.labels, values, None);
+            writeln!(out, "{}_sum{} {}", self.name, labels, buckets.sum).unwrap();
+            writeln!(out, "{}_count{} {}", self.name, labels, buckets.count).unwrap();
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Format a label set, with an optional `le` label for histogram
+/// buckets.
+fn labels(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values.iter())
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render all the metrics in the Prometheus text format.
+pub fn encode() -> String {
+    let mut out = String::new();
+    SSH_CONNECTIONS.encode(&mut out);
+    AUTH_ATTEMPTS.encode(&mut out);
+    TRANSFERRED_BYTES.encode(&mut out);
+    SSH_COMMANDS.encode(&mut out);
+    PROTOCOL_REQUESTS.encode(&mut out);
+    CHANGE_APPLY.encode(&mut out);
+    HTTP_REQUESTS.encode(&mut out);
+    FSCK_RUNS.encode(&mut out);
+
+    let timers = libpijul::get_timers();
+    let name = "patchyx_libpijul_seconds_total";
+    let help = "Time spent in libpijul operations";
+    header(&mut out, name, help, "counter");
+    for (timer, duration) in [
+        ("alive_output", timers.alive_output),
+        ("alive_graph", timers.alive_graph),
+        ("alive_retrieve", timers.alive_retrieve),
+        ("alive_contents", timers.alive_contents),
+        ("alive_write", timers.alive_write),
+        ("record", timers.record),
+        ("apply", timers.apply),
+        ("repair_context", timers.repair_context),
+        ("check_cyclic_paths", timers.check_cyclic_paths),
+        ("find_alive", timers.find_alive),
+    ] {
+        let secs = duration.as_secs_f64();
+        writeln!(out, "{}{{timer=\"{}\"}} {}", name, timer, secs).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let counter = Counter::new("c_total", "A counter", &["a"]);
+        counter.inc(&["x\"y"]);
+        counter.add(&["x\"y"], 2);
+        let histogram = Histogram::new("h_seconds", "A histogram", &["a"]);
+        histogram.observe(&["x"], Duration::from_millis(20));
+        histogram.observe(&["x"], Duration::from_secs(60));
+        let mut out = String::new();
+        counter.encode(&mut out);
+        histogram.encode(&mut out);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            &lines[..3],
+            &[
+                "# HELP c_total A counter",
+                "# TYPE c_total counter",
+                "c_total{a=\"x\\\"y\"} 3",
+            ]
+        );
+        assert!(lines.contains(&"h_seconds_bucket{a=\"x\",le=\"0.01\"} 0"));
+        assert!(lines.contains(&"h_seconds_bucket{a=\"x\",le=\"0.025\"} 1"));
+        assert!(lines.contains(&"h_seconds_bucket{a=\"x\",le=\"30\"} 1"));
+        assert!(lines.contains(&"h_seconds_bucket{a=\"x\",le=\"+Inf\"} 2"));
+        assert!(lines.contains(&"h_seconds_sum{a=\"x\"} 60.02"));
+        assert!(lines.contains(&"h_seconds_count{a=\"x\"} 2"));
+
+        // Counters without labels are always present.
+        let mut out = String::new();
+        Counter::new("n_total", "Nothing", &[]).encode(&mut out);
+        assert!(out.ends_with("n_total 0\n"));
+    }
+}
@@ -0,0 +1,127 @@
+//! Repository mirroring.
+//!
+//! A hosted repository can be a pull mirror of an external Pijul
+//! remote (SSH, HTTP or a local path): the [`Puller`] periodically
+//! brings its channels and tags in line with the remote's. It can
+//! also have push mirrors, downstream remotes to which the [`Pusher`]
+//! forwards the changes of each accepted push. Both use the client
+//! side of the remote protocol from `pijul-remote`.
+//!
+//! Server admins configure mirrors through the HTTP API. The
+//! configuration and the outcome of the last syncs are stored in the
+//! `mirror.json` and `push_mirrors.json` files of the repository's
+//! `.pijul` directory.
+
+pub mod pull;
+pub mod push;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::bail;
+use jiff::Timestamp;
+use libpijul::pristine::sanakirja::Pristine;
+use pijul_remote::RemoteRepo;
+use pijul_repository::Repository;
+use serde::{Deserialize, Serialize};
+
+pub use pull::{PullMirror, Puller};
+pub use push::{PushMirror, Pusher};
+
+/// Actor of the audit records of syncs and pushes.
+const ACTOR: &str = "<mirror>";
+
+/// Connections to remotes taking longer than this are abandoned.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the server authenticates to a remote.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Credentials {
+    /// SSH user, instead of the one in the URL
+    pub ssh_user: Option<String>,
+    /// Private key file authenticating to SSH remotes, instead of the
+    /// agent's keys and those of the server's `~/.ssh`
+    pub ssh_key: Option<PathBuf>,
+    /// Headers added to the requests to HTTP remotes, such as
+    /// `Authorization`
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Outcome of the last syncs of a mirror.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncStatus {
+    /// Start of the last sync
+    pub last_attempt: Option<Timestamp>,
+    /// End of the last successful sync
+    pub last_sync: Option<Timestamp>,
+    /// Error of the last sync, if it failed
+    pub last_error: Option<String>,
+}
+
+impl SyncStatus {
+    /// Record the outcome of a sync started at `started`.
+    pub fn record(&mut self, started: Timestamp, error: Option<String>) {
+        self.last_attempt = Some(started);
+        if error.is_none() {
+            self.last_sync = Some(Timestamp::now());
+        }
+        self.last_error = error;
+    }
+}
+
+/// Connect to the remote at `url`, to sync or push `channel`.
+pub async fn connect(
+    url: &str,
+    channel: &str,
+    credentials: &Credentials,
+) -> anyhow::Result<RemoteRepo> {
+    match tokio::time::timeout(CONNECT_TIMEOUT, connect_(url, channel, credentials)).await {
+        Ok(remote) => remote,
+        Err(_) => bail!("Timed out connecting to {}", url),
+    }
+}
+
+async fn connect_(
+    url: &str,
+    channel: &str,
+    credentials: &Credentials,
+) -> anyhow::Result<RemoteRepo> {
+    let user = credentials.ssh_user.as_deref();
+    if let Some(ref key) = credentials.ssh_key {
+        let Some(mut ssh) = pijul_remote::ssh::ssh_remote(user, url, true) else {
+            bail!("Not an SSH remote: {}", url)
+        };
+        ssh.set_identity_file(&key.to_string_lossy());
+        return match ssh.connect(url, channel).await? {
+            Some(ssh) => Ok(RemoteRepo::Ssh(ssh)),
+            None => bail!("Cannot connect to {}", url),
+        };
+    }
+    let mut remote = pijul_remote::unknown_remote(None, user, url, channel, false, true).await?;
+    if let RemoteRepo::Http(ref mut http) = remote {
+        http.headers = credentials
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+    }
+    Ok(remote)
+}
+
+/// A copy of `repo` sharing its change store, with an empty pristine
+/// in memory. The client side of the remote protocol needs a
+/// repository and a transaction to move changes, but only uses the
+/// pristine to apply them: transfers go through this copy, so that
+/// they don't hold the hosted repository.
+fn detached(repo: &Repository) -> anyhow::Result<Repository> {
+    Ok(Repository {
+        pristine: Pristine::new_anon()?,
+        changes: repo.changes.clone(),
+        working_copy: libpijul::working_copy::filesystem::FileSystem::from_root(&repo.path),
+        config: Default::default(),
+        path: repo.path.clone(),
+        changes_dir: repo.changes_dir.clone(),
+    })
+}
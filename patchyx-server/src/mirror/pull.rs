@@ -0,0 +1,515 @@
+//! Pull mirrors: hosted repositories following an external remote.
+//!
+//! Each mirrored channel is synced with [`RemoteRepo::update_changelist`],
+//! which fetches the remote's changelist, then [`RemoteRepo::pull`],
+//! which downloads the missing changes with their dependencies into
+//! the change store. Both go through an in-memory pristine, so that
+//! the repository is only locked once everything is downloaded:
+//! changes unrecorded on the remote are then unrecorded from the
+//! mirror, the remote's changes applied and its tags added, so that
+//! the channels end up in the remote's states.
+//!
+//! Syncs run on a blocking thread, and are bounded by
+//! `[mirrors] timeout_secs`.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::bail;
+use jiff::Timestamp;
+use libpijul::changestore::filesystem::{pop_filename, push_filename, push_tag_filename};
+use libpijul::working_copy::memory::Memory;
+use libpijul::{
+    Base32, ChannelMutTxnT, ChannelTxnT, Hash, Merkle, MutTxnT, MutTxnTExt, TxnT, TxnTExt,
+};
+use pijul_remote::{RemoteRepo, CS};
+use pijul_repository::Repository;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::{Credentials, SyncStatus, ACTOR};
+use crate::audit::{self, Action, Record};
+use crate::auth::Caller;
+use crate::config::{ServerConfig, SharedConfig};
+use crate::error::{Result, ServerError};
+use crate::repo::{RepoStore, SharedRepo};
+
+/// Name of the mirror file, in the repository's `.pijul` directory.
+pub const MIRROR_FILE: &str = "mirror.json";
+
+/// How often mirrors are checked for due syncs.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Serializes the updates of mirror files.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// The remote a repository mirrors.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PullMirror {
+    /// `ssh://`, `http(s)://` URL or local path of the remote
+    pub url: String,
+    /// Channels to mirror
+    pub channels: Vec<String>,
+    /// Delay between syncs, instead of `[mirrors] interval_secs`
+    pub interval_secs: Option<u64>,
+    #[serde(default)]
+    pub credentials: Credentials,
+    pub created: Timestamp,
+    /// Who configured the mirror
+    pub by: String,
+    #[serde(flatten)]
+    pub status: SyncStatus,
+}
+
+impl PullMirror {
+    fn path(repo_path: &Path) -> PathBuf {
+        repo_path.join(libpijul::DOT_DIR).join(MIRROR_FILE)
+    }
+
+    /// Load the mirror configuration of the repository at
+    /// `repo_path`, if it is a mirror.
+    pub fn load(repo_path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(Self::path(repo_path)) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .map_err(|e| ServerError::repository(format!("Invalid {}: {}", MIRROR_FILE, e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Make the repository at `repo_path` a mirror, replacing its
+    /// previous configuration if any.
+    pub fn save(&self, repo_path: &Path) -> Result<()> {
+        let _lock = LOCK.lock().unwrap();
+        self.write(repo_path)
+    }
+
+    fn write(&self, repo_path: &Path) -> Result<()> {
+        let path = Self::path(repo_path);
+        let tmp = path.with_extension("tmp");
+        let contents =
+            serde_json::to_vec_pretty(self).map_err(|e| ServerError::internal(e.to_string()))?;
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Stop mirroring. The repository keeps its changes.
+    pub fn remove(repo_path: &Path) -> Result<()> {
+        let _lock = LOCK.lock().unwrap();
+        match std::fs::remove_file(Self::path(repo_path)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(ServerError::not_found("Repository is not a mirror"))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Record the outcome of a sync, unless the mirror was removed or
+    /// pointed elsewhere in the meantime.
+    fn record(
+        repo_path: &Path,
+        url: &str,
+        started: Timestamp,
+        error: Option<String>,
+    ) -> Result<()> {
+        let _lock = LOCK.lock().unwrap();
+        match Self::load(repo_path)? {
+            Some(mut mirror) if mirror.url == url => {
+                mirror.status.record(started, error);
+                mirror.write(repo_path)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the mirror should be synced at `now`.
+    fn is_due(&self, config: &ServerConfig, now: Timestamp) -> bool {
+        let interval = self.interval_secs.unwrap_or(config.mirrors.interval_secs);
+        match self.status.last_attempt {
+            Some(at) => now >= at + Duration::from_secs(interval),
+            None => true,
+        }
+    }
+}
+
+/// Whether `url` looks like a remote `pijul-remote` can reach.
+pub fn validate_url(url: &str) -> Result<()> {
+    let valid = match reqwest::Url::parse(url) {
+        Ok(url) => matches!(url.scheme(), "http" | "https" | "ssh"),
+        // `host:path` SSH remotes and local paths.
+        Err(_) => !url.trim().is_empty(),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(ServerError::bad_request(format!(
+            "Invalid mirror URL: {}",
+            url
+        )))
+    }
+}
+
+/// What syncing a channel did.
+#[derive(Debug, Default, PartialEq)]
+pub struct ChannelSync {
+    /// Changes applied, in the remote's order
+    pub applied: Vec<Hash>,
+    /// Changes unrecorded from the remote, newest first
+    pub unrecorded: Vec<Hash>,
+    /// Tags added
+    pub tagged: Vec<Merkle>,
+}
+
+impl ChannelSync {
+    fn is_empty(&self) -> bool {
+        self.applied.is_empty() && self.unrecorded.is_empty() && self.tagged.is_empty()
+    }
+}
+
+/// Bring `channel` of `repo` in line with the same channel of the
+/// remote at `url`, creating it if needed. The missing changes are
+/// downloaded into the change store first, and the repository is
+/// only locked to apply them.
+pub async fn sync_channel(
+    repo: &SharedRepo,
+    url: &str,
+    credentials: &Credentials,
+    channel: &str,
+) -> anyhow::Result<ChannelSync> {
+    let mut remote = super::connect(url, channel, credentials).await?;
+    let result = sync_channel_(repo, &mut remote, url, channel).await;
+    if let Err(e) = remote.finish().await {
+        warn!(url, error = %e, "Cannot close the connection to the remote");
+    }
+    result
+}
+
+async fn sync_channel_(
+    repo: &SharedRepo,
+    remote: &mut RemoteRepo,
+    url: &str,
+    channel: &str,
+) -> anyhow::Result<ChannelSync> {
+    let mut detached = super::detached(&repo.lock().unwrap())?;
+    let mut txn = detached.pristine.mut_txn_begin()?;
+    let Some((inodes, remote_ref)) = remote.update_changelist(&mut txn, &[]).await? else {
+        bail!("Channel {} not found on {}", channel, url)
+    };
+
+    // The remote's changes and tags, and the files we don't have.
+    let mut theirs = Vec::new();
+    for entry in txn.iter_remote(&remote_ref.lock().remote, 0)? {
+        let (_, pair) = entry?;
+        theirs.push(Hash::from(pair.a));
+    }
+    let mut tags = Vec::new();
+    for entry in txn.iter_tags(&remote_ref.lock().tags, 0)? {
+        let (_, pair) = entry?;
+        tags.push(Merkle::from(pair.a));
+    }
+    let mut path = detached.changes_dir.clone();
+    let mut to_download = Vec::new();
+    for hash in theirs.iter() {
+        push_filename(&mut path, hash);
+        if !path.exists() {
+            to_download.push(CS::Change(*hash));
+        }
+        pop_filename(&mut path);
+    }
+    for state in tags.iter() {
+        push_tag_filename(&mut path, state);
+        if !path.exists() {
+            to_download.push(CS::State(*state));
+        }
+        pop_filename(&mut path);
+    }
+
+    // Nothing is applied to this pristine, which only holds the
+    // remote's changelist.
+    let mut scratch = txn.open_or_create_channel(channel)?;
+    remote
+        .pull(
+            &mut detached,
+            &mut txn,
+            &mut scratch,
+            &to_download,
+            &inodes,
+            false,
+        )
+        .await?;
+    apply(&repo.lock().unwrap(), url, channel, &theirs, &tags)
+}
+
+/// Make `channel` of `repo` hold exactly the changes of `theirs`,
+/// and add the `tags` it reaches.
+fn apply(
+    repo: &Repository,
+    url: &str,
+    channel: &str,
+    theirs: &[Hash],
+    tags: &[Merkle],
+) -> anyhow::Result<ChannelSync> {
+    let mut txn = repo.pristine.mut_txn_begin()?;
+    let local = txn.open_or_create_channel(channel)?;
+
+    // Changes unrecorded on the remote. Their dependents aren't on
+    // the remote either, and come first in the log.
+    let mut sync = ChannelSync::default();
+    let remote: HashSet<&Hash> = theirs.iter().collect();
+    for entry in txn.reverse_log(&local.read(), None)? {
+        let (_, (hash, _)) = entry?;
+        let hash = Hash::from(hash);
+        if !remote.contains(&hash) {
+            sync.unrecorded.push(hash);
+        }
+    }
+    for hash in sync.unrecorded.iter() {
+        txn.unrecord(&repo.changes, &local, hash, 0, &Memory::new())?;
+    }
+
+    for hash in theirs {
+        if txn.get_revchanges(&local, hash)?.is_none() {
+            txn.apply_change_rec(&repo.changes, &mut local.write(), hash)?;
+            sync.applied.push(*hash);
+        }
+    }
+    for state in tags {
+        let mut ch = local.write();
+        let Some(n) = txn.channel_has_state(txn.states(&ch), &state.into())? else {
+            warn!(url, channel, state = %state.to_base32(), "Tagged state not reached");
+            continue;
+        };
+        if txn.is_tagged(txn.tags(&ch), n.into())? {
+            continue;
+        }
+        let tags = txn.tags_mut(&mut ch);
+        txn.put_tags(tags, n.into(), state)?;
+        sync.tagged.push(*state);
+    }
+    txn.commit()?;
+    Ok(sync)
+}
+
+/// Sync the channels of mirror repository `name` now, and record the
+/// outcome in its mirror file.
+pub async fn sync_repo(repos: &RepoStore, config: &ServerConfig, name: &str) -> Result<PullMirror> {
+    let repo_path = repos.path(name);
+    let Some(mirror) = PullMirror::load(&repo_path)? else {
+        return Err(ServerError::not_found(format!(
+            "Repository is not a mirror: {}",
+            name
+        )));
+    };
+    let repo = repos.open(name)?;
+    let started = Timestamp::now();
+    let timeout = Duration::from_secs(config.mirrors.timeout_secs);
+    let handle = tokio::runtime::Handle::current();
+    let (url, channels) = (mirror.url.clone(), mirror.channels.clone());
+    let credentials = mirror.credentials.clone();
+    let results = tokio::task::spawn_blocking(move || {
+        handle.block_on(async {
+            let mut results = Vec::new();
+            for channel in channels {
+                let sync = sync_channel(&repo, &url, &credentials, &channel);
+                let result = tokio::time::timeout(timeout, sync)
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out")));
+                results.push((channel, result));
+            }
+            results
+        })
+    })
+    .await
+    .map_err(|e| ServerError::internal(e.to_string()))?;
+
+    let mut errors = Vec::new();
+    for (channel, result) in results {
+        match result {
+            Ok(sync) if sync.is_empty() => {}
+            Ok(sync) => {
+                info!(
+                    repo = %name,
+                    channel = %channel,
+                    applied = sync.applied.len(),
+                    unrecorded = sync.unrecorded.len(),
+                    tagged = sync.tagged.len(),
+                    "Synced mirror"
+                );
+                let record = Record {
+                    actor: ACTOR.to_string(),
+                    repo: Some(name.to_string()),
+                    channel: Some(channel),
+                    changes: sync.applied.iter().map(|h| h.to_base32()).collect(),
+                    target: Some(mirror.url.clone()),
+                    ..Record::new(Action::MirrorSync, &Caller::admin())
+                };
+                audit::append(&config.audit, record);
+            }
+            Err(e) => {
+                warn!(repo = %name, channel = %channel, error = %e, "Cannot sync mirror");
+                errors.push(format!("{}: {}", channel, e));
+            }
+        }
+    }
+    let error = Some(errors.join("; ")).filter(|e| !e.is_empty());
+    PullMirror::record(&repo_path, &mirror.url, started, error)?;
+    PullMirror::load(&repo_path)?.ok_or_else(|| ServerError::not_found("Mirror removed"))
+}
+
+/// Syncs the mirrors that are due.
+pub struct Puller {
+    config: Arc<SharedConfig>,
+    repos: Arc<RepoStore>,
+}
+
+impl Puller {
+    /// Create a puller for the mirrors among all repositories.
+    pub fn new(config: Arc<SharedConfig>, repos: Arc<RepoStore>) -> Self {
+        Self { config, repos }
+    }
+
+    /// Sync mirrors until the server stops.
+    pub async fn run(self) {
+        loop {
+            self.run_once().await;
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Sync the mirrors that are due, one at a time.
+    pub async fn run_once(&self) {
+        let config = self.config.get();
+        if !config.mirrors.enabled {
+            return;
+        }
+        let repos = match self.repos.list() {
+            Ok(repos) => repos,
+            Err(e) => {
+                warn!(error = %e, "Cannot list repositories");
+                return;
+            }
+        };
+        for repo in repos {
+            let due = match PullMirror::load(&self.repos.path(&repo)) {
+                Ok(mirror) => mirror.is_some_and(|m| m.is_due(&config, Timestamp::now())),
+                Err(e) => {
+                    warn!(repo = %repo, error = %e, "Cannot read mirror configuration");
+                    false
+                }
+            };
+            if due {
+                if let Err(e) = sync_repo(&self.repos, &config, &repo).await {
+                    warn!(repo = %repo, error = %e, "Cannot sync mirror");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libpijul::DOT_DIR;
+
+    use super::*;
+    use crate::repo::testing::{init, record};
+
+    fn log(repo: &Repository) -> Vec<Hash> {
+        let txn = repo.pristine.txn_begin().unwrap();
+        let channel = txn.load_channel("main").unwrap().unwrap();
+        let channel = channel.read();
+        txn.log(&channel, 0)
+            .unwrap()
+            .map(|e| e.unwrap().1 .0.into())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let a_dir = tempfile::tempdir().unwrap();
+        let b_dir = tempfile::tempdir().unwrap();
+        let url = a_dir.path().to_str().unwrap().to_string();
+        // The remote side opens the pristine itself, so only keep it
+        // open while recording.
+        let (first, second) = {
+            let a = init(a_dir.path());
+            let a = a.lock().unwrap();
+            (record(&a, "a", b"a\n"), record(&a, "b", b"b\n"))
+        };
+        let b = init(b_dir.path());
+        let credentials = Credentials::default();
+
+        let sync = sync_channel(&b, &url, &credentials, "main").await.unwrap();
+        assert_eq!(sync.applied, vec![first, second]);
+        assert!(sync.unrecorded.is_empty());
+        assert_eq!(log(&b.lock().unwrap()), vec![first, second]);
+
+        // Nothing new.
+        let sync = sync_channel(&b, &url, &credentials, "main").await.unwrap();
+        assert!(sync.is_empty(), "{:?}", sync);
+
+        // Unrecords on the remote are followed.
+        let third = {
+            let a = Repository::find_root_with_dot_dir(Some(a_dir.path()), DOT_DIR).unwrap();
+            let mut txn = a.pristine.mut_txn_begin().unwrap();
+            let main = txn.load_channel("main").unwrap().unwrap();
+            txn.unrecord(&a.changes, &main, &second, 0, &Memory::new())
+                .unwrap();
+            txn.commit().unwrap();
+            record(&a, "c", b"c\n")
+        };
+        let sync = sync_channel(&b, &url, &credentials, "main").await.unwrap();
+        assert_eq!(sync.unrecorded, vec![second]);
+        assert_eq!(sync.applied, vec![third]);
+        assert_eq!(log(&b.lock().unwrap()), vec![first, third]);
+
+        // Missing remote channels are errors.
+        let nope = sync_channel(&b, &url, &credentials, "nope").await;
+        assert!(nope.is_err());
+    }
+
+    #[test]
+    fn test_mirror_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(DOT_DIR)).unwrap();
+        assert_eq!(PullMirror::load(dir.path()).unwrap(), None);
+        let mirror = PullMirror {
+            url: "https://nest.pijul.com/pijul/pijul".to_string(),
+            channels: vec!["main".to_string()],
+            interval_secs: Some(60),
+            credentials: Credentials::default(),
+            created: Timestamp::now(),
+            by: "alice".to_string(),
+            status: SyncStatus::default(),
+        };
+        mirror.save(dir.path()).unwrap();
+        let config = ServerConfig::default();
+        let now = Timestamp::now();
+        assert!(mirror.is_due(&config, now));
+
+        PullMirror::record(dir.path(), &mirror.url, now, Some("down".to_string())).unwrap();
+        let failed = PullMirror::load(dir.path()).unwrap().unwrap();
+        assert_eq!(failed.status.last_error.as_deref(), Some("down"));
+        assert_eq!(failed.status.last_sync, None);
+        assert!(!failed.is_due(&config, now));
+        assert!(failed.is_due(&config, now + Duration::from_secs(60)));
+
+        PullMirror::record(dir.path(), &mirror.url, now, None).unwrap();
+        let synced = PullMirror::load(dir.path()).unwrap().unwrap();
+        assert_eq!(synced.status.last_error, None);
+        assert!(synced.status.last_sync.is_some());
+
+        // Outcomes of syncs of a previous URL are dropped.
+        PullMirror::record(dir.path(), "elsewhere", now, Some("down".to_string())).unwrap();
+        assert_eq!(PullMirror::load(dir.path()).unwrap().unwrap(), synced);
+
+        PullMirror::remove(dir.path()).unwrap();
+        assert!(PullMirror::remove(dir.path()).is_err());
+        assert!(validate_url("ssh://me@host/repo").is_ok());
+        assert!(validate_url("ftp://host/repo").is_err());
+    }
+}
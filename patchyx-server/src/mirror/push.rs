@@ -0,0 +1,569 @@
+//! Push mirrors: downstream remotes receiving the changes pushed to a
+//! hosted repository.
+//!
+//! Each accepted push queues its channel for the push mirrors
+//! forwarding it. The [`Pusher`] then compares the channel with the
+//! mirror's changelist, and uploads what the mirror lacks with
+//! [`RemoteRepo::upload_changes`]. Failed pushes stay queued and are
+//! retried with exponential backoff, until `[mirrors] max_attempts`.
+//!
+//! Mirrors are only ever added to: changes unrecorded here stay on
+//! the mirror.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::bail;
+use jiff::Timestamp;
+use libpijul::{Base32, ChannelTxnT, Hash, Merkle, TxnT, TxnTExt};
+use pijul_remote::{RemoteRepo, CS};
+use pijul_repository::Repository;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::{Credentials, SyncStatus, ACTOR};
+use crate::audit::{self, Action, Record};
+use crate::auth::Caller;
+use crate::config::{MirrorsConfig, ServerConfig, SharedConfig};
+use crate::error::{Result, ServerError};
+use crate::repo::{RepoStore, SharedRepo};
+
+/// Name of the push mirrors file, in the repository's `.pijul`
+/// directory.
+pub const PUSH_MIRRORS_FILE: &str = "push_mirrors.json";
+
+/// Longest delay between two attempts.
+const MAX_RETRY: Duration = Duration::from_secs(24 * 3600);
+/// How often the queues are checked for due pushes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Serializes the updates of push mirror files.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// A channel waiting to be pushed to a mirror.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingPush {
+    pub channel: String,
+    /// Last time a push to the channel was accepted
+    pub queued: Timestamp,
+    /// Failed attempts so far
+    pub attempts: usize,
+    pub next_attempt: Timestamp,
+    pub last_error: Option<String>,
+}
+
+/// A downstream remote.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PushMirror {
+    pub id: String,
+    /// `ssh://`, `http(s)://` URL or local path of the remote
+    pub url: String,
+    /// Channels forwarded, all of them if empty
+    #[serde(default)]
+    pub channels: Vec<String>,
+    #[serde(default)]
+    pub credentials: Credentials,
+    pub created: Timestamp,
+    /// Who configured the mirror
+    pub by: String,
+    #[serde(flatten)]
+    pub status: SyncStatus,
+    /// Channels waiting to be pushed
+    #[serde(default)]
+    pub pending: Vec<PendingPush>,
+}
+
+impl PushMirror {
+    /// Whether pushes to `channel` are forwarded to this mirror.
+    pub fn forwards(&self, channel: &str) -> bool {
+        self.channels.is_empty() || self.channels.iter().any(|c| c == channel)
+    }
+
+    /// Queue a push of `channel`. A channel already queued keeps its
+    /// next attempt, so that a failing mirror isn't retried sooner.
+    pub fn queue(&mut self, channel: &str, now: Timestamp) {
+        match self.pending.iter_mut().find(|p| p.channel == channel) {
+            Some(pending) => pending.queued = now,
+            None => self.pending.push(PendingPush {
+                channel: channel.to_string(),
+                queued: now,
+                attempts: 0,
+                next_attempt: now,
+                last_error: None,
+            }),
+        }
+    }
+
+    /// Channels whose push is due at `now`.
+    fn due(&self, now: Timestamp) -> Vec<String> {
+        self.pending
+            .iter()
+            .filter(|p| p.next_attempt <= now)
+            .map(|p| p.channel.clone())
+            .collect()
+    }
+
+    /// Record the outcome of a push of `channel` started at `started`.
+    fn record(
+        &mut self,
+        config: &MirrorsConfig,
+        channel: &str,
+        started: Timestamp,
+        error: Option<String>,
+    ) {
+        self.status.record(
+            started,
+            error.as_ref().map(|e| format!("{}: {}", channel, e)),
+        );
+        let Some(i) = self.pending.iter().position(|p| p.channel == channel) else {
+            return;
+        };
+        let pending = &mut self.pending[i];
+        match error {
+            // A push accepted in the meantime may not have been
+            // forwarded.
+            None if pending.queued > started => {
+                pending.attempts = 0;
+                pending.next_attempt = pending.queued;
+                pending.last_error = None;
+            }
+            None => {
+                self.pending.remove(i);
+            }
+            Some(error) => {
+                pending.attempts += 1;
+                pending.last_error = Some(error);
+                if pending.attempts >= config.max_attempts {
+                    warn!(url = %self.url, channel, "Push to mirror abandoned");
+                    self.pending.remove(i);
+                } else {
+                    let factor = 2u64.saturating_pow(pending.attempts as u32 - 1);
+                    let delay = config.first_retry_secs.saturating_mul(factor);
+                    let delay = Duration::from_secs(delay);
+                    pending.next_attempt = started + delay.min(MAX_RETRY);
+                }
+            }
+        }
+    }
+}
+
+/// Push mirrors of a repository.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PushMirrorList {
+    #[serde(default)]
+    pub mirrors: Vec<PushMirror>,
+}
+
+impl PushMirrorList {
+    fn path(repo_path: &Path) -> PathBuf {
+        repo_path.join(libpijul::DOT_DIR).join(PUSH_MIRRORS_FILE)
+    }
+
+    /// Load the push mirrors of the repository at `repo_path`.
+    pub fn load(repo_path: &Path) -> Result<Self> {
+        match std::fs::read(Self::path(repo_path)) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|e| {
+                ServerError::repository(format!("Invalid {}: {}", PUSH_MIRRORS_FILE, e))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, repo_path: &Path) -> Result<()> {
+        let path = Self::path(repo_path);
+        let tmp = path.with_extension("tmp");
+        let contents =
+            serde_json::to_vec_pretty(self).map_err(|e| ServerError::internal(e.to_string()))?;
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Change the push mirrors of the repository at `repo_path` with
+    /// `f`, saving them if it succeeds.
+    pub fn update<T>(repo_path: &Path, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let _lock = LOCK.lock().unwrap();
+        let mut list = Self::load(repo_path)?;
+        let result = f(&mut list)?;
+        list.write(repo_path)?;
+        Ok(result)
+    }
+
+    /// Find a push mirror by id.
+    pub fn get(&self, id: &str) -> Option<&PushMirror> {
+        self.mirrors.iter().find(|m| m.id == id)
+    }
+}
+
+/// Queue pushes of `channel` to the push mirrors of the repository at
+/// `repo_path` forwarding it. Errors are only logged: mirrors never
+/// fail the push that caused them.
+pub fn enqueue(repo_path: &Path, repo: &str, channel: &str) {
+    let result = PushMirrorList::load(repo_path).and_then(|list| {
+        if !list.mirrors.iter().any(|m| m.forwards(channel)) {
+            return Ok(());
+        }
+        let now = Timestamp::now();
+        PushMirrorList::update(repo_path, |list| {
+            for mirror in list.mirrors.iter_mut().filter(|m| m.forwards(channel)) {
+                mirror.queue(channel, now);
+            }
+            Ok(())
+        })
+    });
+    if let Err(e) = result {
+        warn!(repo = %repo, channel = %channel, error = %e, "Cannot queue mirror pushes");
+    }
+}
+
+/// Upload the changes and tags of `channel` that the remote at `url`
+/// lacks, returning them. The repository is only locked while its
+/// channel is compared with the remote's changelist, not during the
+/// upload.
+pub async fn push_channel(
+    repo: &SharedRepo,
+    url: &str,
+    credentials: &Credentials,
+    channel: &str,
+) -> anyhow::Result<Vec<CS>> {
+    let mut remote = super::connect(url, channel, credentials).await?;
+    let result = push_channel_(repo, &mut remote, url, channel).await;
+    if let Err(e) = remote.finish().await {
+        warn!(url, error = %e, "Cannot close the connection to the remote");
+    }
+    result
+}
+
+async fn push_channel_(
+    repo: &SharedRepo,
+    remote: &mut RemoteRepo,
+    url: &str,
+    channel: &str,
+) -> anyhow::Result<Vec<CS>> {
+    let (_, theirs) = remote.download_changelist_nocache(0, &[]).await?;
+    let (to_upload, unknown, detached) = {
+        let repo = repo.lock().unwrap();
+        let (to_upload, unknown) = to_upload(&repo, channel, &theirs)?;
+        (to_upload, unknown, super::detached(&repo)?)
+    };
+    if unknown > 0 {
+        warn!(
+            url,
+            channel,
+            changes = unknown,
+            "The mirror has changes the channel doesn't"
+        );
+    }
+    if !to_upload.is_empty() {
+        // Only used by remotes that are channels of the same
+        // repository, which mirrors never are.
+        let mut txn = detached.pristine.mut_txn_begin()?;
+        remote
+            .upload_changes(
+                &mut txn,
+                detached.changes_dir.clone(),
+                Some(channel),
+                &to_upload,
+            )
+            .await?;
+    }
+    Ok(to_upload)
+}
+
+/// The changes and tags of `channel` missing from `theirs`, the
+/// remote's changelist, in the channel's order, and the number of
+/// remote changes the channel doesn't have.
+fn to_upload(
+    repo: &Repository,
+    channel: &str,
+    theirs: &[(u64, Hash, Merkle, bool)],
+) -> anyhow::Result<(Vec<CS>, usize)> {
+    let txn = repo.pristine.txn_begin()?;
+    let Some(local) = txn.load_channel(channel)? else {
+        bail!("Channel not found: {}", channel)
+    };
+    let changes: HashSet<Hash> = theirs.iter().map(|(_, h, _, _)| *h).collect();
+    let states: HashSet<Merkle> = theirs.iter().map(|(_, _, m, _)| *m).collect();
+    let tagged: HashSet<Merkle> = theirs
+        .iter()
+        .filter(|(_, _, _, is_tag)| *is_tag)
+        .map(|(_, _, m, _)| *m)
+        .collect();
+    let channel_ = local.read();
+    let mut tags = HashSet::new();
+    for t in txn.iter_tags(txn.tags(&channel_), 0)? {
+        let (_, pair) = t?;
+        tags.insert(Merkle::from(&pair.a));
+    }
+    let mut to_upload = Vec::new();
+    for entry in txn.log(&channel_, 0)? {
+        let (_, (h, m)) = entry?;
+        let (h, m): (Hash, Merkle) = (h.into(), m.into());
+        let uploaded = !changes.contains(&h);
+        if uploaded {
+            to_upload.push(CS::Change(h));
+        }
+        // Tags can only be pushed once the mirror reaches their state.
+        if tags.contains(&m) && !tagged.contains(&m) && (uploaded || states.contains(&m)) {
+            to_upload.push(CS::State(m));
+        }
+    }
+    drop(channel_);
+    let mut unknown = 0;
+    for h in changes.iter() {
+        if txn.get_revchanges(&local, h)?.is_none() {
+            unknown += 1
+        }
+    }
+    Ok((to_upload, unknown))
+}
+
+/// Pushes queued channels to push mirrors.
+pub struct Pusher {
+    config: Arc<SharedConfig>,
+    repos: Arc<RepoStore>,
+}
+
+impl Pusher {
+    /// Create a pusher for the push mirrors of all repositories.
+    pub fn new(config: Arc<SharedConfig>, repos: Arc<RepoStore>) -> Self {
+        Self { config, repos }
+    }
+
+    /// Push queued channels until the server stops.
+    pub async fn run(self) {
+        loop {
+            self.run_once().await;
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Push the channels whose push is due, one mirror at a time.
+    pub async fn run_once(&self) {
+        let config = self.config.get();
+        if !config.mirrors.enabled {
+            return;
+        }
+        let repos = match self.repos.list() {
+            Ok(repos) => repos,
+            Err(e) => {
+                warn!(error = %e, "Cannot list repositories");
+                return;
+            }
+        };
+        let now = Timestamp::now();
+        for repo in repos {
+            let list = match PushMirrorList::load(&self.repos.path(&repo)) {
+                Ok(list) => list,
+                Err(e) => {
+                    warn!(repo = %repo, error = %e, "Cannot read push mirrors");
+                    continue;
+                }
+            };
+            for mirror in list.mirrors {
+                let channels = mirror.due(now);
+                if channels.is_empty() {
+                    continue;
+                }
+                if let Err(e) = self.push(&config, &repo, &mirror, channels).await {
+                    warn!(repo = %repo, id = %mirror.id, error = %e, "Cannot push to mirror");
+                }
+            }
+        }
+    }
+
+    async fn push(
+        &self,
+        config: &ServerConfig,
+        name: &str,
+        mirror: &PushMirror,
+        channels: Vec<String>,
+    ) -> Result<()> {
+        let repo = self.repos.open(name)?;
+        let started = Timestamp::now();
+        let timeout = Duration::from_secs(config.mirrors.timeout_secs);
+        let handle = tokio::runtime::Handle::current();
+        let (url, credentials) = (mirror.url.clone(), mirror.credentials.clone());
+        let results = tokio::task::spawn_blocking(move || {
+            handle.block_on(async {
+                let mut results = Vec::new();
+                for channel in channels {
+                    let push = push_channel(&repo, &url, &credentials, &channel);
+                    let result = tokio::time::timeout(timeout, push)
+                        .await
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out")));
+                    results.push((channel, result));
+                }
+                results
+            })
+        })
+        .await
+        .map_err(|e| ServerError::internal(e.to_string()))?;
+
+        for (channel, result) in results.iter() {
+            match result {
+                Ok(uploaded) if uploaded.is_empty() => {}
+                Ok(uploaded) => {
+                    info!(
+                        repo = %name,
+                        channel = %channel,
+                        url = %mirror.url,
+                        uploaded = uploaded.len(),
+                        "Pushed to mirror"
+                    );
+                    let changes = uploaded.iter().filter_map(|cs| match cs {
+                        CS::Change(hash) => Some(hash.to_base32()),
+                        CS::State(_) => None,
+                    });
+                    let record = Record {
+                        actor: ACTOR.to_string(),
+                        repo: Some(name.to_string()),
+                        channel: Some(channel.clone()),
+                        changes: changes.collect(),
+                        target: Some(mirror.url.clone()),
+                        ..Record::new(Action::MirrorPush, &Caller::admin())
+                    };
+                    audit::append(&config.audit, record);
+                }
+                Err(e) => {
+                    warn!(
+                        repo = %name,
+                        channel = %channel,
+                        url = %mirror.url,
+                        error = %e,
+                        "Cannot push to mirror"
+                    );
+                }
+            }
+        }
+        // Unless the mirror was deleted or pointed elsewhere meanwhile.
+        PushMirrorList::update(&self.repos.path(name), |list| {
+            let mirror = list
+                .mirrors
+                .iter_mut()
+                .find(|m| m.id == mirror.id && m.url == mirror.url);
+            if let Some(mirror) = mirror {
+                for (channel, result) in results {
+                    let error = result.err().map(|e| e.to_string());
+                    mirror.record(&config.mirrors, &channel, started, error);
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libpijul::{MutTxnT, DOT_DIR};
+
+    use super::*;
+    use crate::repo::testing::{init, record};
+
+    fn log(repo: &Repository) -> Vec<Hash> {
+        let txn = repo.pristine.txn_begin().unwrap();
+        let channel = txn.load_channel("main").unwrap().unwrap();
+        let channel = channel.read();
+        txn.log(&channel, 0)
+            .unwrap()
+            .map(|e| e.unwrap().1 .0.into())
+            .collect()
+    }
+
+    fn mirror(url: &str) -> PushMirror {
+        PushMirror {
+            id: "m".to_string(),
+            url: url.to_string(),
+            channels: Vec::new(),
+            credentials: Credentials::default(),
+            created: Timestamp::now(),
+            by: "alice".to_string(),
+            status: SyncStatus::default(),
+            pending: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        let a_dir = tempfile::tempdir().unwrap();
+        let b_dir = tempfile::tempdir().unwrap();
+        let url = b_dir.path().to_str().unwrap().to_string();
+        let a = init(a_dir.path());
+        {
+            // The remote side opens the pristine itself.
+            let b = init(b_dir.path());
+            let b = b.lock().unwrap();
+            let mut txn = b.pristine.mut_txn_begin().unwrap();
+            txn.open_or_create_channel("main").unwrap();
+            txn.commit().unwrap();
+        }
+        let first = record(&a.lock().unwrap(), "a", b"a\n");
+        let second = record(&a.lock().unwrap(), "b", b"b\n");
+        let credentials = Credentials::default();
+
+        let uploaded = push_channel(&a, &url, &credentials, "main").await.unwrap();
+        assert_eq!(uploaded, vec![CS::Change(first), CS::Change(second)]);
+        let b = Repository::find_root_with_dot_dir(Some(b_dir.path()), DOT_DIR).unwrap();
+        assert_eq!(log(&b), vec![first, second]);
+        drop(b);
+
+        // Only what's new is uploaded.
+        let third = record(&a.lock().unwrap(), "c", b"c\n");
+        let uploaded = push_channel(&a, &url, &credentials, "main").await.unwrap();
+        assert_eq!(uploaded, vec![CS::Change(third)]);
+        let uploaded = push_channel(&a, &url, &credentials, "main").await.unwrap();
+        assert!(uploaded.is_empty(), "{:?}", uploaded);
+
+        assert!(push_channel(&a, &url, &credentials, "nope").await.is_err());
+    }
+
+    #[test]
+    fn test_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(DOT_DIR)).unwrap();
+        let config = MirrorsConfig {
+            max_attempts: 2,
+            ..Default::default()
+        };
+        let mut only_dev = mirror("elsewhere");
+        only_dev.id = "dev".to_string();
+        only_dev.channels = vec!["dev".to_string()];
+        PushMirrorList {
+            mirrors: vec![mirror("somewhere"), only_dev],
+        }
+        .write(dir.path())
+        .unwrap();
+
+        enqueue(dir.path(), "a", "main");
+        let list = PushMirrorList::load(dir.path()).unwrap();
+        let mut m = list.get("m").unwrap().clone();
+        assert!(list.get("dev").unwrap().pending.is_empty());
+        let queued = m.pending[0].queued;
+        assert_eq!(m.due(queued), vec!["main".to_string()]);
+
+        // Failures are retried later, then abandoned.
+        let started = Timestamp::now();
+        m.record(&config, "main", started, Some("down".to_string()));
+        let retry = started + Duration::from_secs(config.first_retry_secs);
+        assert_eq!(m.pending[0].next_attempt, retry);
+        assert_eq!(m.pending[0].last_error.as_deref(), Some("down"));
+        assert_eq!(m.status.last_error.as_deref(), Some("main: down"));
+        assert!(m.due(started).is_empty());
+        m.queue("main", started);
+        assert!(m.due(started).is_empty());
+        m.record(&config, "main", started, Some("down".to_string()));
+        assert!(m.pending.is_empty());
+
+        // Pushes accepted during an attempt are forwarded next.
+        m.queue("main", started + Duration::from_secs(1));
+        m.record(&config, "main", started, None);
+        assert_eq!(m.pending.len(), 1);
+        assert_eq!(m.status.last_error, None);
+        m.record(&config, "main", started + Duration::from_secs(2), None);
+        assert!(m.pending.is_empty());
+        assert!(m.status.last_sync.is_some());
+    }
+}
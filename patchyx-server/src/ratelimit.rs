@@ -0,0 +1,188 @@
+//! Rate limits.
+//!
+//! Limits are token buckets, one per client address and one per user:
+//! a bucket holds up to a minute's worth of operations, and refills
+//! continuously at the configured rate, so that clients can burst
+//! after being idle but not sustain more than the rate. The rates are
+//! read from the current configuration at each operation, and apply
+//! on reload.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimit;
+use crate::error::ServerError;
+
+/// Buckets idle for that long are full, and can be forgotten.
+const REFILL_TIME: Duration = Duration::from_secs(60);
+
+/// A rate limit was exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exceeded {
+    /// How long until the next operation is allowed
+    pub retry_after: Duration,
+}
+
+impl Exceeded {
+    /// `retry_after`, rounded up to whole seconds.
+    pub fn retry_secs(&self) -> u64 {
+        self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0)
+    }
+
+    /// Tell the client what was limited (`"SSH connections from
+    /// ..."`), and when to retry.
+    pub fn message(&self, what: &str) -> String {
+        format!("too many {}, retry in {} seconds", what, self.retry_secs())
+    }
+
+    /// The HTTP error of an exceeded limit.
+    pub fn error(&self, what: &str) -> ServerError {
+        ServerError::too_many_requests(self.message(what), self.retry_secs())
+    }
+}
+
+/// Who a bucket is for.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Addr(IpAddr),
+    User(String),
+}
+
+impl Key {
+    fn rate(&self, limit: &RateLimit) -> u32 {
+        match self {
+            Key::Addr(_) => limit.per_addr,
+            Key::User(_) => limit.per_user,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Buckets of one kind of operation.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<Key, Bucket>>,
+    pruned: Mutex<Instant>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+            pruned: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Count an operation of `key`, refusing it if its bucket is
+    /// empty.
+    pub fn check(&self, limit: &RateLimit, key: Key) -> Result<(), Exceeded> {
+        let rate = key.rate(limit);
+        self.take(key, rate, Instant::now(), true)
+    }
+
+    /// Whether an operation of `key` would be allowed, without
+    /// counting it.
+    pub fn peek(&self, limit: &RateLimit, key: &Key) -> Result<(), Exceeded> {
+        self.take(key.clone(), key.rate(limit), Instant::now(), false)
+    }
+
+    /// Take a token from the bucket of `key`, refilled at `rate` per
+    /// minute, or only check that there is one if `!consume`. A rate
+    /// of 0 means no limit.
+    fn take(&self, key: Key, rate: u32, now: Instant, consume: bool) -> Result<(), Exceeded> {
+        if rate == 0 {
+            return Ok(());
+        }
+        self.prune(now);
+        let rate = f64::from(rate);
+        let per_sec = rate / REFILL_TIME.as_secs_f64();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(rate);
+        bucket.updated = now;
+        if bucket.tokens >= 1. {
+            if consume {
+                bucket.tokens -= 1.;
+            }
+            Ok(())
+        } else {
+            let retry_after = Duration::from_secs_f64((1. - bucket.tokens) / per_sec);
+            Err(Exceeded { retry_after })
+        }
+    }
+
+    /// Forget the buckets that are full again, at most once per
+    /// refill time.
+    fn prune(&self, now: Instant) {
+        let mut pruned = self.pruned.lock().unwrap();
+        if now.saturating_duration_since(*pruned) < REFILL_TIME {
+            return;
+        }
+        *pruned = now;
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, b| now.saturating_duration_since(b.updated) < REFILL_TIME);
+    }
+}
+
+/// The rate limiters of the server, shared by the SSH and HTTP
+/// servers.
+#[derive(Debug, Default)]
+pub struct Limiters {
+    pub ssh_connections: RateLimiter,
+    /// Failed authentication attempts
+    pub auth_failures: RateLimiter,
+    pub http_requests: RateLimiter,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let limiter = RateLimiter::default();
+        let addr = Key::Addr("192.0.2.1".parse().unwrap());
+        let start = Instant::now();
+
+        // A minute's worth of operations is allowed at once.
+        for _ in 0..60 {
+            limiter.take(addr.clone(), 60, start, false).unwrap();
+            limiter.take(addr.clone(), 60, start, true).unwrap();
+        }
+        let exceeded = limiter.take(addr.clone(), 60, start, false).unwrap_err();
+        assert_eq!(exceeded.retry_secs(), 1);
+
+        // Other keys have their own bucket, and no rate means no limit.
+        limiter
+            .take(Key::User("alice".into()), 60, start, true)
+            .unwrap();
+        limiter.take(addr.clone(), 0, start, true).unwrap();
+
+        // Buckets refill over time.
+        let later = start + Duration::from_millis(2500);
+        limiter.take(addr.clone(), 60, later, true).unwrap();
+        limiter.take(addr.clone(), 60, later, true).unwrap();
+        let exceeded = limiter.take(addr.clone(), 60, later, true).unwrap_err();
+        assert_eq!(exceeded.retry_after, Duration::from_millis(500));
+
+        // Full buckets are forgotten.
+        let idle = later + REFILL_TIME;
+        limiter.prune(idle);
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+}
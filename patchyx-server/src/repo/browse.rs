@@ -0,0 +1,262 @@
+//! Read-only queries on hosted repositories, used by the HTTP API.
+
+use libpijul::pristine::sanakirja::MutTxn0;
+use libpijul::pristine::{ArcTxn, Position};
+use libpijul::vertex_buffer::Writer;
+use libpijul::{Base32, ChannelRef, ChannelTxnT, Hash, Merkle, MutTxnT, MutTxnTExt, TxnT, TxnTExt};
+use pijul_repository::Repository;
+use serde::Serialize;
+
+/// A channel and its current state.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelInfo {
+    pub name: String,
+    /// Base32 Merkle state
+    pub state: String,
+}
+
+/// List the channels of a repository along with their state.
+pub fn channels(repo: &Repository) -> anyhow::Result<Vec<ChannelInfo>> {
+    let txn = repo.pristine.txn_begin()?;
+    let mut channels = Vec::new();
+    for channel in txn.channels("")? {
+        let channel = channel.read();
+        channels.push(ChannelInfo {
+            name: txn.name(&channel).to_string(),
+            state: txn.current_state(&channel)?.to_base32(),
+        });
+    }
+    Ok(channels)
+}
+
+/// Check that `channel` exists and, if `state` is given, that it went
+/// through that state.
+pub fn has_state(repo: &Repository, channel: &str, state: Option<&Merkle>) -> anyhow::Result<bool> {
+    let txn = repo.pristine.txn_begin()?;
+    let Some(channel) = txn.load_channel(channel)? else {
+        return Ok(false);
+    };
+    let channel = channel.read();
+    match state {
+        Some(state) => Ok(txn
+            .channel_has_state(txn.states(&channel), &state.into())?
+            .is_some()),
+        None => Ok(true),
+    }
+}
+
+/// Kind of a tree entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+}
+
+/// An entry of a directory listing.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TreeEntry {
+    pub name: String,
+    /// Path from the root of the repository
+    pub path: String,
+    pub kind: EntryKind,
+    /// Unix permission bits
+    pub permissions: u16,
+}
+
+/// What a path points to.
+#[derive(Debug)]
+pub enum Node {
+    /// A directory and its entries, sorted by name
+    Dir(Vec<TreeEntry>),
+    /// The contents of a file, with conflict markers if any
+    File(Vec<u8>),
+}
+
+/// Find `path` in `channel`, at its current state or at an earlier
+/// `state`. Returns `None` if the channel, the state or the path
+/// doesn't exist.
+///
+/// Like archives, earlier states are reached by unrecording changes
+/// in a temporary fork, inside a transaction that is never
+/// committed.
+pub fn lookup(
+    repo: &Repository,
+    channel: &str,
+    state: Option<&Merkle>,
+    path: &str,
+) -> anyhow::Result<Option<Node>> {
+    let txn = repo.pristine.arc_txn_begin()?;
+    let Some(mut channel_ref) = txn.read().load_channel(channel)? else {
+        return Ok(None);
+    };
+    if let Some(state) = state {
+        if txn.read().current_state(&channel_ref.read())? != *state {
+            let fork_name = format!("browse-{}", jiff::Timestamp::now().as_nanosecond());
+            let fork = txn.write().fork(&channel_ref, &fork_name)?;
+            if !unrecord_until(repo, &txn, &fork, state)? {
+                return Ok(None);
+            }
+            channel_ref = fork;
+        }
+    }
+
+    // Walk down from the root. If a name is in conflict, the first
+    // matching entry is used.
+    let mut key = Position::ROOT;
+    let mut is_dir = true;
+    let mut current = Vec::new();
+    for name in path.split('/').filter(|c| !c.is_empty()) {
+        if !is_dir {
+            return Ok(None);
+        }
+        let child = children(repo, &txn, &channel_ref, key)?
+            .into_iter()
+            .find(|(_, entry)| entry.0 == name);
+        let Some((pos, (_, meta))) = child else {
+            return Ok(None);
+        };
+        key = pos;
+        is_dir = meta.is_dir();
+        current.push(name);
+    }
+
+    if is_dir {
+        let mut entries: Vec<_> = children(repo, &txn, &channel_ref, key)?
+            .into_iter()
+            .map(|(_, (name, meta))| {
+                let mut path = current.join("/");
+                if !path.is_empty() {
+                    path.push('/');
+                }
+                path.push_str(&name);
+                TreeEntry {
+                    name,
+                    path,
+                    kind: if meta.is_dir() {
+                        EntryKind::Dir
+                    } else {
+                        EntryKind::File
+                    },
+                    permissions: meta.permissions(),
+                }
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Some(Node::Dir(entries)))
+    } else {
+        let mut out = Writer::new(Vec::new());
+        libpijul::output::output_file(&repo.changes, &txn, &channel_ref, key, &mut out)?;
+        Ok(Some(Node::File(out.into_inner())))
+    }
+}
+
+type Child = (
+    Position<libpijul::ChangeId>,
+    (String, libpijul::pristine::InodeMetadata),
+);
+
+/// List the children of the directory at `key`.
+fn children(
+    repo: &Repository,
+    txn: &ArcTxn<MutTxn0>,
+    channel: &ChannelRef<MutTxn0>,
+    key: Position<libpijul::ChangeId>,
+) -> anyhow::Result<Vec<Child>> {
+    let txn = txn.read();
+    let channel = channel.read();
+    let mut children = Vec::new();
+    for child in libpijul::fs::iter_graph_children(&*txn, &repo.changes, txn.graph(&*channel), key)?
+    {
+        let (pos, _, meta, name) = child?;
+        children.push((pos, (name, meta)));
+    }
+    Ok(children)
+}
+
+/// Unrecord the changes of `channel` applied after `state`. Returns
+/// `false` if `state` isn't a state of the channel.
+pub(crate) fn unrecord_until(
+    repo: &Repository,
+    txn: &ArcTxn<MutTxn0>,
+    channel: &ChannelRef<MutTxn0>,
+    state: &Merkle,
+) -> anyhow::Result<bool> {
+    let mut unrecord = Vec::new();
+    let mut found = state == &Merkle::zero();
+    for entry in txn.read().reverse_log(&channel.read(), None)? {
+        let (_, (hash, merkle)) = entry?;
+        if Merkle::from(merkle) == *state {
+            found = true;
+            break;
+        }
+        unrecord.push(Hash::from(hash));
+    }
+    if !found {
+        return Ok(false);
+    }
+    let mut txn = txn.write();
+    for hash in unrecord {
+        txn.unrecord(
+            &repo.changes,
+            channel,
+            &hash,
+            0,
+            &libpijul::working_copy::memory::Memory::new(),
+        )?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::testing::{init, record};
+
+    fn file(repo: &Repository, state: Option<&Merkle>, path: &str) -> Option<Vec<u8>> {
+        match lookup(repo, "main", state, path).unwrap() {
+            Some(Node::File(contents)) => Some(contents),
+            _ => None,
+        }
+    }
+
+    fn dir(repo: &Repository, state: Option<&Merkle>, path: &str) -> Option<Vec<String>> {
+        match lookup(repo, "main", state, path).unwrap() {
+            Some(Node::Dir(entries)) => Some(entries.into_iter().map(|e| e.path).collect()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = init(tmp.path());
+        let repo = repo.lock().unwrap();
+        record(&repo, "src/lib.rs", b"fn main() {}\n");
+
+        assert_eq!(dir(&repo, None, ""), Some(vec!["src".to_string()]));
+        assert_eq!(
+            dir(&repo, None, "/src/"),
+            Some(vec!["src/lib.rs".to_string()])
+        );
+        assert_eq!(
+            file(&repo, None, "src/lib.rs").as_deref(),
+            Some(&b"fn main() {}\n"[..])
+        );
+        assert!(lookup(&repo, "main", None, "src/main.rs")
+            .unwrap()
+            .is_none());
+        assert!(lookup(&repo, "main", None, "src/lib.rs/x")
+            .unwrap()
+            .is_none());
+        assert!(lookup(&repo, "dev", None, "").unwrap().is_none());
+
+        // Earlier states are read from a fork that is never committed.
+        let current = channels(&repo).unwrap()[0].state.clone();
+        let current = Merkle::from_base32(current.as_bytes()).unwrap();
+        assert!(file(&repo, Some(&current), "src/lib.rs").is_some());
+        assert_eq!(dir(&repo, Some(&Merkle::zero()), ""), Some(Vec::new()));
+        assert!(file(&repo, None, "src/lib.rs").is_some());
+        assert_eq!(channels(&repo).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,268 @@
+//! Conflict previews: the conflicts a channel would have after
+//! applying some changes, without applying them.
+//!
+//! The changes are applied with their dependencies to a scratch fork
+//! of the channel, which is output to memory to find the conflicts,
+//! then dropped. The transaction is never committed. Changes coming
+//! from another repository are read from its change store, so that
+//! previews never write to the repository.
+
+use jiff::Timestamp;
+use libpijul::change::{Change, ChangeError, ChangeHeader};
+use libpijul::changestore::filesystem::{self, FileSystem};
+use libpijul::changestore::ChangeStore;
+use libpijul::pristine::{ChangeId, Position, Vertex};
+use libpijul::working_copy::memory::Memory;
+use libpijul::{Base32, Conflict, Hash, Merkle, MutTxnT, MutTxnTExt, TxnT};
+use pijul_repository::Repository;
+use serde::Serialize;
+
+use crate::error::{Result, ServerError};
+
+/// A conflict, as shown by the HTTP API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConflictInfo {
+    /// `name`, `zombie_file`, `multiple_names`, `zombie`, `cyclic` or
+    /// `order`
+    pub kind: &'static str,
+    pub path: String,
+    /// Line of the conflict in the output file, for conflicts inside
+    /// files
+    pub line: Option<usize>,
+    /// Changes involved in the conflict
+    pub changes: Vec<String>,
+}
+
+impl From<&Conflict> for ConflictInfo {
+    fn from(conflict: &Conflict) -> Self {
+        let (kind, path, line) = match conflict {
+            Conflict::Name { path, .. } => ("name", path, None),
+            Conflict::ZombieFile { path, .. } => ("zombie_file", path, None),
+            Conflict::MultipleNames { path, .. } => ("multiple_names", path, None),
+            Conflict::Zombie { path, line, .. } => ("zombie", path, Some(*line)),
+            Conflict::Cyclic { path, line, .. } => ("cyclic", path, Some(*line)),
+            Conflict::Order { path, line, .. } => ("order", path, Some(*line)),
+        };
+        ConflictInfo {
+            kind,
+            path: path.clone(),
+            line,
+            changes: conflict.changes().iter().map(|h| h.to_base32()).collect(),
+        }
+    }
+}
+
+/// Conflicts that applying `hashes`, whose files must be in the change
+/// store, to `channel` would produce. A missing channel is treated as
+/// empty.
+pub fn preview(repo: &Repository, channel: &str, hashes: &[Hash]) -> Result<Vec<ConflictInfo>> {
+    preview_from(repo, repo, channel, hashes)
+}
+
+/// Conflicts that applying `hashes` to `channel` of `target` would
+/// produce, reading the changes `target` doesn't have from the change
+/// store of `source`.
+pub fn preview_from(
+    source: &Repository,
+    target: &Repository,
+    channel: &str,
+    hashes: &[Hash],
+) -> Result<Vec<ConflictInfo>> {
+    let changes = Overlay {
+        base: target.changes.clone(),
+        extra: source.changes.clone(),
+    };
+    for hash in hashes {
+        if !changes.base.has_change(hash) && !changes.extra.has_change(hash) {
+            return Err(ServerError::not_found(format!(
+                "Change not found: {}",
+                hash.to_base32()
+            )));
+        }
+    }
+    Ok(preview_(target, &changes, channel, hashes)?)
+}
+
+fn preview_<C: ChangeStore + Clone + Send + 'static>(
+    repo: &Repository,
+    changes: &C,
+    channel: &str,
+    hashes: &[Hash],
+) -> anyhow::Result<Vec<ConflictInfo>> {
+    let txn = repo.pristine.arc_txn_begin()?;
+    let scratch = format!("preview-{}", Timestamp::now().as_nanosecond());
+    let channel = txn.read().load_channel(channel)?;
+    let fork = match channel {
+        Some(channel) => txn.write().fork(&channel, &scratch)?,
+        None => txn.write().open_or_create_channel(&scratch)?,
+    };
+    for hash in hashes {
+        txn.write()
+            .apply_change_rec(changes, &mut fork.write(), hash)?;
+    }
+    let conflicts = libpijul::output::output_repository_no_pending(
+        &Memory::new(),
+        changes,
+        &txn,
+        &fork,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )?;
+    std::mem::drop(fork);
+    txn.write().drop_channel(&scratch)?;
+    Ok(conflicts.iter().map(ConflictInfo::from).collect())
+}
+
+/// A read-only change store reading the changes `base` doesn't have
+/// from `extra`.
+#[derive(Clone)]
+struct Overlay {
+    base: FileSystem,
+    extra: FileSystem,
+}
+
+impl Overlay {
+    fn pick(&self, hash: &Hash) -> &FileSystem {
+        if self.base.has_change(hash) {
+            &self.base
+        } else {
+            &self.extra
+        }
+    }
+
+    fn pick_id<F: Fn(ChangeId) -> Option<Hash>>(&self, hash: &F, id: ChangeId) -> &FileSystem {
+        match hash(id) {
+            Some(h) => self.pick(&h),
+            None => &self.base,
+        }
+    }
+}
+
+impl ChangeStore for Overlay {
+    type Error = filesystem::Error;
+
+    fn has_contents(&self, hash: Hash, change_id: Option<ChangeId>) -> bool {
+        self.pick(&hash).has_contents(hash, change_id)
+    }
+
+    fn get_contents<F: Fn(ChangeId) -> Option<Hash>>(
+        &self,
+        hash: F,
+        key: Vertex<ChangeId>,
+        buf: &mut [u8],
+    ) -> std::result::Result<usize, Self::Error> {
+        self.pick_id(&hash, key.change).get_contents(hash, key, buf)
+    }
+
+    fn get_header(&self, h: &Hash) -> std::result::Result<ChangeHeader, Self::Error> {
+        self.pick(h).get_header(h)
+    }
+
+    fn get_tag_header(&self, h: &Merkle) -> std::result::Result<ChangeHeader, Self::Error> {
+        if self.base.tag_filename(h).exists() {
+            self.base.get_tag_header(h)
+        } else {
+            self.extra.get_tag_header(h)
+        }
+    }
+
+    fn get_contents_ext(
+        &self,
+        key: Vertex<Option<Hash>>,
+        buf: &mut [u8],
+    ) -> std::result::Result<usize, Self::Error> {
+        match key.change {
+            Some(ref h) => self.pick(h).get_contents_ext(key, buf),
+            None => Ok(0),
+        }
+    }
+
+    fn change_deletes_position<F: Fn(ChangeId) -> Option<Hash>>(
+        &self,
+        hash: F,
+        change: ChangeId,
+        pos: Position<Option<Hash>>,
+    ) -> std::result::Result<Vec<Hash>, Self::Error> {
+        self.pick_id(&hash, change)
+            .change_deletes_position(hash, change, pos)
+    }
+
+    fn save_change<
+        E: From<Self::Error> + From<ChangeError>,
+        F: FnOnce(&mut Change, &Hash) -> std::result::Result<(), E>,
+    >(
+        &self,
+        _: &mut Change,
+        _: F,
+    ) -> std::result::Result<Hash, E> {
+        unreachable!("previews don't record changes")
+    }
+
+    fn del_change(&self, _: &Hash) -> std::result::Result<bool, Self::Error> {
+        unreachable!("previews don't delete changes")
+    }
+
+    fn get_change(&self, h: &Hash) -> std::result::Result<Change, Self::Error> {
+        self.pick(h).get_change(h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::fork;
+    use crate::repo::testing::{init, record};
+    use libpijul::ChannelTxnT;
+
+    #[test]
+    fn test_preview() {
+        let a_dir = tempfile::tempdir().unwrap();
+        let b_dir = tempfile::tempdir().unwrap();
+        let a = init(a_dir.path());
+        let b = init(b_dir.path());
+        let a = a.lock().unwrap();
+        let b = b.lock().unwrap();
+        record(&a, "a", b"a\n");
+        fork::fork(&a, &b).unwrap();
+        let theirs = record(&b, "c", b"c\n");
+        let ours = record(&a, "c", b"not c\n");
+
+        // Changes of another repository are read from its store.
+        let conflicts = preview_from(&b, &a, "main", &[theirs]).unwrap();
+        assert!(!a.changes.has_change(&theirs));
+        fork::share_changes(&b, &a, &[theirs]).unwrap();
+        assert_eq!(preview(&a, "main", &[theirs]).unwrap(), conflicts);
+        assert!(!conflicts.is_empty());
+        let conflict = &conflicts[0];
+        assert!(conflict.path.ends_with('c'), "{:?}", conflict);
+        assert!(
+            conflict.changes.contains(&theirs.to_base32()),
+            "{:?}",
+            conflict
+        );
+        assert!(
+            conflict.changes.contains(&ours.to_base32()),
+            "{:?}",
+            conflict
+        );
+
+        // Without conflicts, and without touching the channels.
+        assert!(preview(&a, "empty", &[theirs]).unwrap().is_empty());
+        let txn = a.pristine.txn_begin().unwrap();
+        let names: Vec<_> = txn
+            .channels("")
+            .unwrap()
+            .iter()
+            .map(|c| txn.name(&c.read()).to_string())
+            .collect();
+        assert_eq!(names, ["main"]);
+
+        // Changes must be in the change store.
+        let unknown = record(&b, "d", b"d\n");
+        let err = preview(&a, "main", &[unknown]).unwrap_err();
+        assert!(matches!(err, ServerError::NotFound(_)));
+    }
+}
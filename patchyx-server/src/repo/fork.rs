@@ -0,0 +1,277 @@
+//! Server-side forks.
+//!
+//! A fork is a new hosted repository with the channels of its parent.
+//! Change files are content-addressed and never modified once
+//! written, so the fork hard-links them instead of copying them. The
+//! channels are then rebuilt in the fork's fresh pristine: a channel
+//! whose changes include all the changes of a channel already rebuilt
+//! is forked from it with [`MutTxnT::fork`], and only gets the
+//! remaining changes applied.
+//!
+//! The parent of a fork is recorded in the `fork.json` file of its
+//! `.pijul` directory.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use jiff::Timestamp;
+use libpijul::changestore::filesystem::push_filename;
+use libpijul::{ChannelMutTxnT, ChannelTxnT, Hash, Merkle, MutTxnT, MutTxnTExt, TxnT, TxnTExt};
+use pijul_repository::Repository;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::wire;
+use crate::error::{Result, ServerError};
+
+/// Name of the fork file, in the repository's `.pijul` directory.
+pub const FORK_FILE: &str = "fork.json";
+
+/// Where a fork comes from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ForkInfo {
+    /// Name of the parent repository
+    pub parent: String,
+    pub created: Timestamp,
+    /// Who created the fork
+    pub by: String,
+}
+
+impl ForkInfo {
+    fn path(repo_path: &Path) -> PathBuf {
+        repo_path.join(libpijul::DOT_DIR).join(FORK_FILE)
+    }
+
+    /// Load the fork information of the repository at `repo_path`, if
+    /// it is a fork.
+    pub fn load(repo_path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(Self::path(repo_path)) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .map_err(|e| ServerError::repository(format!("Invalid {}: {}", FORK_FILE, e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the fork information of the repository at `repo_path`.
+    pub fn save(&self, repo_path: &Path) -> Result<()> {
+        let path = Self::path(repo_path);
+        let tmp = path.with_extension("tmp");
+        let contents =
+            serde_json::to_vec_pretty(self).map_err(|e| ServerError::internal(e.to_string()))?;
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+/// A channel of the parent repository.
+struct Channel {
+    name: String,
+    /// Changes, in the order of the channel's log
+    changes: Vec<Hash>,
+    /// States the channel tagged
+    tags: HashSet<Merkle>,
+}
+
+/// Copy the changes and channels of `parent` to `fork`, an empty
+/// repository.
+pub fn fork(parent: &Repository, fork: &Repository) -> anyhow::Result<()> {
+    link_dir(&parent.changes_dir, &fork.changes_dir)?;
+
+    let mut channels = Vec::new();
+    {
+        let txn = parent.pristine.txn_begin()?;
+        for channel in txn.channels("")? {
+            let channel = channel.read();
+            let mut changes = Vec::new();
+            let mut states = Vec::new();
+            for entry in txn.log(&channel, 0)? {
+                let (n, (h, m)) = entry?;
+                changes.push(Hash::from(h));
+                states.push((n, Merkle::from(m)));
+            }
+            let mut tags = HashSet::new();
+            for tag in txn.iter_tags(txn.tags(&channel), 0)? {
+                let n = u64::from_le(tag?.0 .0);
+                if let Some((_, m)) = states.iter().find(|(k, _)| *k == n) {
+                    tags.insert(*m);
+                }
+            }
+            channels.push(Channel {
+                name: txn.name(&channel).to_string(),
+                changes,
+                tags,
+            });
+        }
+    }
+    // Channels with fewer changes may be the bases of others.
+    channels.sort_by_key(|c| c.changes.len());
+
+    let txn = fork.pristine.arc_txn_begin()?;
+    let mut built: Vec<(&str, HashSet<Hash>)> = Vec::new();
+    let mut ws = libpijul::ApplyWorkspace::new();
+    for channel in channels.iter() {
+        let changes: HashSet<Hash> = channel.changes.iter().copied().collect();
+        let base = built
+            .iter()
+            .filter(|(_, b)| b.is_subset(&changes))
+            .max_by_key(|(_, b)| b.len());
+        let (channel_ref, applied) = match base {
+            Some((base, base_changes)) => {
+                let base_ref = txn.read().load_channel(base)?.unwrap();
+                debug!("forking {} from {}", channel.name, base);
+                (
+                    txn.write().fork(&base_ref, &channel.name)?,
+                    base_changes.clone(),
+                )
+            }
+            None => (
+                txn.write().open_or_create_channel(&channel.name)?,
+                HashSet::new(),
+            ),
+        };
+        for hash in channel.changes.iter().filter(|h| !applied.contains(h)) {
+            txn.write()
+                .apply_change_ws(&fork.changes, &mut channel_ref.write(), hash, &mut ws)?;
+        }
+        // Tag the same states as the parent, at their positions in
+        // this channel's log.
+        let mut tagged = Vec::new();
+        for entry in txn.read().log(&channel_ref.read(), 0)? {
+            let (n, (_, m)) = entry?;
+            let m = Merkle::from(m);
+            if channel.tags.contains(&m) {
+                tagged.push((n, m));
+            }
+        }
+        {
+            let mut txn_ = txn.write();
+            let mut channel_ = channel_ref.write();
+            for (n, m) in tagged {
+                let tags = txn_.tags_mut(&mut channel_);
+                txn_.put_tags(tags, n, &m)?;
+            }
+        }
+        built.push((&channel.name, changes));
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// Make the change files of `hashes` in `from` available in `to`,
+/// hard-linking those it doesn't have yet, and return the hashes of
+/// the links. Changes `from` doesn't have are skipped.
+pub fn share_changes(
+    from: &Repository,
+    to: &Repository,
+    hashes: &[Hash],
+) -> anyhow::Result<Vec<Hash>> {
+    let mut shared = Vec::new();
+    for hash in hashes {
+        let mut source = from.changes_dir.clone();
+        push_filename(&mut source, hash);
+        let mut dest = to.changes_dir.clone();
+        push_filename(&mut dest, hash);
+        if dest.exists() || !source.exists() {
+            continue;
+        }
+        if let Err(e) = link_file(&source, &dest) {
+            unshare_changes(to, &shared);
+            return Err(e);
+        }
+        shared.push(*hash);
+    }
+    Ok(shared)
+}
+
+/// Remove the change files `share_changes` linked into `to`, unless
+/// a channel of `to` uses them.
+pub fn unshare_changes(to: &Repository, shared: &[Hash]) {
+    wire::remove_unused_changes(to, shared)
+}
+
+fn link_file(source: &Path, dest: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dest.parent().unwrap())?;
+    if std::fs::hard_link(source, dest).is_err() {
+        std::fs::copy(source, dest)?;
+    }
+    Ok(())
+}
+
+/// Hard-link the files of `from` into `to`, recursively, copying them
+/// where links aren't possible (different file systems).
+fn link_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(to)?;
+    let entries = match std::fs::read_dir(from) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let (from, to) = (entry.path(), to.join(entry.file_name()));
+        if entry.file_type()?.is_dir() {
+            link_dir(&from, &to)?;
+        } else if std::fs::hard_link(&from, &to).is_err() {
+            std::fs::copy(&from, &to)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use libpijul::Base32;
+
+    use super::*;
+    use crate::repo::testing::{init, record};
+
+    #[test]
+    fn test_fork() {
+        let a_dir = tempfile::tempdir().unwrap();
+        let b_dir = tempfile::tempdir().unwrap();
+        let a = init(a_dir.path());
+        let b = init(b_dir.path());
+        let a = a.lock().unwrap();
+        let b = b.lock().unwrap();
+        let first = record(&a, "a", b"a\n");
+        let second = record(&a, "b", b"b\n");
+        {
+            // `stable` only has the first change.
+            let mut txn = a.pristine.mut_txn_begin().unwrap();
+            let main = txn.load_channel("main").unwrap().unwrap();
+            let stable = txn.fork(&main, "stable").unwrap();
+            let wc = libpijul::working_copy::memory::Memory::new();
+            txn.unrecord(&a.changes, &stable, &second, 0, &wc).unwrap();
+            txn.commit().unwrap();
+        }
+        let states = |repo: &Repository| {
+            let mut states = crate::repo::browse::channels(repo).unwrap();
+            states.sort_by(|x, y| x.name.cmp(&y.name));
+            states
+                .into_iter()
+                .map(|c| (c.name, c.state))
+                .collect::<Vec<_>>()
+        };
+
+        fork(&a, &b).unwrap();
+        assert_eq!(states(&a), states(&b));
+        assert_eq!(states(&b).len(), 2);
+        let mut out = Vec::new();
+        wire::changelist(&b, "main", 0, &[], &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&first.to_base32()));
+        assert!(out.contains(&second.to_base32()));
+
+        // Change files are shared.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let mut path = b.changes_dir.clone();
+            push_filename(&mut path, &first);
+            assert_eq!(std::fs::metadata(&path).unwrap().nlink(), 2);
+        }
+    }
+}
@@ -0,0 +1,505 @@
+//! Integrity checks of hosted repositories.
+//!
+//! [`check`] runs over a whole repository the checks libpijul has as
+//! debugging helpers, and gathers what they find in an [`FsckReport`]
+//! instead of panicking:
+//!
+//! - the pages of the pristine, with `Txn::check_database`;
+//! - the hash and contents hash of each change file, with
+//!   `Change::check_from_buffer`;
+//! - the changes of each channel being in the change store;
+//! - the edges of each channel's graph pointing to existing vertices
+//!   and coming from changes of the channel;
+//! - alive vertices being reachable from the root, with
+//!   `check_alive`;
+//! - the states of each channel, recomputed from its log.
+//!
+//! The last report of a repository is stored in the `fsck.json` file
+//! of its `.pijul` directory. The [`Checker`] checks each repository
+//! every `[fsck] interval_secs`. Checks work on a snapshot of the
+//! pristine, so pushes can go on in the meantime.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use jiff::Timestamp;
+use libpijul::change::Change;
+use libpijul::changestore::filesystem::push_filename;
+use libpijul::pristine::sanakirja::Txn;
+use libpijul::pristine::{ChangeId, GraphIter, Position};
+use libpijul::{Base32, ChannelTxnT, EdgeFlags, GraphTxnT, Hash, Merkle, TxnT, TxnTExt, Vertex};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::RepoStore;
+use crate::config::SharedConfig;
+use crate::error::{Result, ServerError};
+use crate::metrics;
+
+/// Name of the report file, in the repository's `.pijul` directory.
+pub const FSCK_FILE: &str = "fsck.json";
+
+/// How often repositories are checked for due checks.
+const POLL_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Findings kept per kind of check, the others being only counted.
+const MAX_FINDINGS: usize = 100;
+
+/// Repositories being checked.
+static RUNNING: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// What a finding is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Check {
+    /// The pages of the pristine
+    Database,
+    /// A change file that isn't the change its name says
+    ChangeFile,
+    /// A change of a channel missing from the change store
+    MissingChange,
+    /// An edge to a missing vertex, or from a change not on the channel
+    DanglingEdge,
+    /// An alive vertex unreachable from the root
+    Unreachable,
+    /// A channel state that isn't the one of its changes
+    State,
+}
+
+/// Something wrong in a repository.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Finding {
+    pub check: Check,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    /// Hash of the change concerned, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change: Option<String>,
+    pub message: String,
+}
+
+/// The outcome of a check of a repository.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FsckReport {
+    pub started: Timestamp,
+    pub duration_ms: u64,
+    /// Number of change files checked
+    pub change_files: usize,
+    /// Number of channels checked
+    pub channels: usize,
+    pub findings: Vec<Finding>,
+    /// Findings left out, beyond the first 100 of each kind
+    pub omitted: usize,
+}
+
+impl FsckReport {
+    fn path(repo_path: &Path) -> PathBuf {
+        repo_path.join(libpijul::DOT_DIR).join(FSCK_FILE)
+    }
+
+    /// Load the last report of the repository at `repo_path`, if it
+    /// was ever checked.
+    pub fn load(repo_path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(Self::path(repo_path)) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .map_err(|e| ServerError::repository(format!("Invalid {}: {}", FSCK_FILE, e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, repo_path: &Path) -> Result<()> {
+        let path = Self::path(repo_path);
+        let tmp = path.with_extension("tmp");
+        let contents =
+            serde_json::to_vec_pretty(self).map_err(|e| ServerError::internal(e.to_string()))?;
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Whether nothing was found.
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    fn add(
+        &mut self,
+        check: Check,
+        channel: Option<&str>,
+        change: Option<String>,
+        message: String,
+    ) {
+        if self.findings.iter().filter(|f| f.check == check).count() >= MAX_FINDINGS {
+            self.omitted += 1;
+            return;
+        }
+        self.findings.push(Finding {
+            check,
+            channel: channel.map(String::from),
+            change,
+            message,
+        });
+    }
+}
+
+/// Run `f`, turning its panics into errors: libpijul's debugging
+/// checks panic when they find something wrong.
+fn catch<R>(f: impl FnOnce() -> R) -> std::result::Result<R, String> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).map_err(|e| {
+        e.downcast_ref::<String>()
+            .cloned()
+            .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "Check failed".to_string())
+    })
+}
+
+/// Check the pristine read by `txn` and the change files in
+/// `changes_dir`.
+pub fn check(txn: &Txn, changes_dir: &Path) -> anyhow::Result<FsckReport> {
+    let started = Instant::now();
+    let mut report = FsckReport {
+        started: Timestamp::now(),
+        duration_ms: 0,
+        change_files: 0,
+        channels: 0,
+        findings: Vec::new(),
+        omitted: 0,
+    };
+    if let Err(e) = catch(|| txn.check_database(&mut BTreeMap::new())) {
+        report.add(Check::Database, None, None, e);
+    }
+    check_change_files(changes_dir, &mut report)?;
+    for channel in txn.channels("")? {
+        let channel = channel.read();
+        let name = txn.name(&channel).to_string();
+        report.channels += 1;
+        check_log(txn, &channel, &name, changes_dir, &mut report)?;
+        check_graph(txn, &channel, &name, &mut report)?;
+        match catch(|| libpijul::pristine::check_alive(txn, txn.graph(&channel))) {
+            Ok((unreachable, pseudo)) => {
+                for (vertex, _) in unreachable.iter() {
+                    let message = format!("Vertex {:?} is alive but unreachable", vertex);
+                    let change = external(txn, &vertex.change);
+                    report.add(Check::Unreachable, Some(&name), change, message);
+                }
+                for (vertex, _) in pseudo.iter() {
+                    let message = format!("Vertex {:?} is only reachable by pseudo-edges", vertex);
+                    let change = external(txn, &vertex.change);
+                    report.add(Check::Unreachable, Some(&name), change, message);
+                }
+            }
+            Err(e) => report.add(Check::Unreachable, Some(&name), None, e),
+        }
+    }
+    report.duration_ms = started.elapsed().as_millis() as u64;
+    Ok(report)
+}
+
+/// The hash of change `id`, if it is known.
+fn external(txn: &Txn, id: &ChangeId) -> Option<String> {
+    let hash = txn.get_external(id).ok()??;
+    Some(Hash::from(hash).to_base32())
+}
+
+/// Check the hashes of the change files in `changes_dir`.
+fn check_change_files(changes_dir: &Path, report: &mut FsckReport) -> anyhow::Result<()> {
+    let dirs = match std::fs::read_dir(changes_dir) {
+        Ok(dirs) => dirs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for dir in dirs {
+        let dir = dir?;
+        let Ok(prefix) = dir.file_name().into_string() else {
+            continue;
+        };
+        if !dir.file_type()?.is_dir() {
+            continue;
+        }
+        for file in std::fs::read_dir(dir.path())? {
+            let path = file?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("change") {
+                continue;
+            }
+            let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            report.change_files += 1;
+            let name = format!("{}{}", prefix, stem);
+            let Some(hash) = Hash::from_base32(name.as_bytes()) else {
+                let message = format!("{} is not named after a hash", path.display());
+                report.add(Check::ChangeFile, None, None, message);
+                continue;
+            };
+            let contents = std::fs::read(&path)?;
+            let result = match catch(|| Change::check_from_buffer(&contents, &hash)) {
+                Ok(result) => result.map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                report.add(Check::ChangeFile, None, Some(name), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that the changes of `channel` are in the change store, and
+/// that each state of its log follows from the previous one.
+fn check_log(
+    txn: &Txn,
+    channel: &<Txn as ChannelTxnT>::Channel,
+    name: &str,
+    changes_dir: &Path,
+    report: &mut FsckReport,
+) -> anyhow::Result<()> {
+    let mut state = Merkle::zero();
+    for entry in txn.log(channel, 0)? {
+        let (n, (hash, merkle)) = entry?;
+        let hash: Hash = hash.into();
+        let mut path = changes_dir.to_path_buf();
+        push_filename(&mut path, &hash);
+        if !path.exists() {
+            let message = "Change not in the change store".to_string();
+            report.add(
+                Check::MissingChange,
+                Some(name),
+                Some(hash.to_base32()),
+                message,
+            );
+        }
+        let stored: Merkle = merkle.into();
+        let expected = state.next(&hash);
+        if stored != expected {
+            let message = format!(
+                "State {} at position {} should be {}",
+                stored.to_base32(),
+                n,
+                expected.to_base32()
+            );
+            report.add(Check::State, Some(name), Some(hash.to_base32()), message);
+        }
+        // Go on from the stored state, to point at each bad one.
+        state = stored;
+    }
+    let current = txn.current_state(channel)?;
+    if current != state {
+        let message = format!(
+            "Current state {} should be {}",
+            current.to_base32(),
+            state.to_base32()
+        );
+        report.add(Check::State, Some(name), None, message);
+    }
+    Ok(())
+}
+
+/// Check that the edges of the graph of `channel` point to existing
+/// vertices, and that they and their sources come from changes of the
+/// channel.
+fn check_graph(
+    txn: &Txn,
+    channel: &<Txn as ChannelTxnT>::Channel,
+    name: &str,
+    report: &mut FsckReport,
+) -> anyhow::Result<()> {
+    let graph = txn.graph(channel);
+    let changes = txn.changes(channel);
+    let mut unknown = BTreeSet::new();
+    for entry in txn.iter_graph(graph, None)? {
+        let (vertex, edge) = entry?;
+        for id in [vertex.change, edge.introduced_by()] {
+            if id.is_root() || unknown.contains(&id) {
+                continue;
+            }
+            if txn.get_changeset(changes, &id)?.is_none() {
+                unknown.insert(id);
+                let message = format!("Vertex {:?} comes from a change not on the channel", vertex);
+                report.add(Check::DanglingEdge, Some(name), external(txn, &id), message);
+            }
+        }
+        if !points_to_vertex(txn, graph, edge.flag(), edge.dest()) {
+            let message = format!(
+                "Edge from {:?} points to {:?}, which is not a vertex",
+                vertex,
+                edge.dest()
+            );
+            let change = external(txn, &edge.introduced_by());
+            report.add(Check::DanglingEdge, Some(name), change, message);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `dest` is the start of a vertex, or its end for parent
+/// edges.
+fn points_to_vertex(
+    txn: &Txn,
+    graph: &<Txn as GraphTxnT>::Graph,
+    flag: EdgeFlags,
+    dest: Position<ChangeId>,
+) -> bool {
+    if dest.change.is_root() {
+        return true;
+    }
+    let vertex: std::result::Result<&Vertex<ChangeId>, _> = if flag.contains(EdgeFlags::PARENT) {
+        txn.find_block_end(graph, dest)
+    } else {
+        txn.find_block(graph, dest)
+    };
+    vertex.is_ok()
+}
+
+/// Check repository `name` now, and store the report.
+pub async fn run(repos: &RepoStore, name: &str) -> Result<FsckReport> {
+    let repo_path = repos.path(name);
+    if !RUNNING.lock().unwrap().insert(repo_path.clone()) {
+        return Err(ServerError::conflict(format!(
+            "A check of {} is already running",
+            name
+        )));
+    }
+    let result = run_(repos, name, &repo_path).await;
+    RUNNING.lock().unwrap().remove(&repo_path);
+    let label = match result {
+        Ok(ref report) if report.is_clean() => "clean",
+        Ok(_) => "findings",
+        Err(_) => "error",
+    };
+    metrics::FSCK_RUNS.inc(&[label]);
+    result
+}
+
+async fn run_(repos: &RepoStore, name: &str, repo_path: &Path) -> Result<FsckReport> {
+    let (txn, changes_dir) = {
+        let repo = repos.open(name)?;
+        let repo = repo.lock().unwrap();
+        let txn = repo
+            .pristine
+            .txn_begin()
+            .map_err(|e| ServerError::repository(e.to_string()))?;
+        (txn, repo.changes_dir.clone())
+    };
+    let report = tokio::task::spawn_blocking(move || check(&txn, &changes_dir))
+        .await
+        .map_err(|e| ServerError::internal(e.to_string()))?
+        .map_err(|e| ServerError::repository(format!("{:#}", e)))?;
+    report.save(repo_path)?;
+    if report.is_clean() {
+        info!(repo = %name, duration_ms = report.duration_ms, "Repository checked");
+    } else {
+        warn!(
+            repo = %name,
+            findings = report.findings.len() + report.omitted,
+            "Repository check found problems"
+        );
+    }
+    Ok(report)
+}
+
+/// Checks the repositories that are due.
+pub struct Checker {
+    config: Arc<SharedConfig>,
+    repos: Arc<RepoStore>,
+}
+
+impl Checker {
+    /// Create a checker for all repositories.
+    pub fn new(config: Arc<SharedConfig>, repos: Arc<RepoStore>) -> Self {
+        Self { config, repos }
+    }
+
+    /// Check repositories until the server stops.
+    pub async fn run(self) {
+        loop {
+            self.run_once().await;
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Check the repositories whose last check is older than
+    /// `[fsck] interval_secs`, one at a time.
+    pub async fn run_once(&self) {
+        let config = self.config.get();
+        if !config.fsck.enabled {
+            return;
+        }
+        let repos = match self.repos.list() {
+            Ok(repos) => repos,
+            Err(e) => {
+                warn!(error = %e, "Cannot list repositories");
+                return;
+            }
+        };
+        let interval = Duration::from_secs(config.fsck.interval_secs);
+        for repo in repos {
+            let due = match FsckReport::load(&self.repos.path(&repo)) {
+                Ok(report) => report.is_none_or(|r| Timestamp::now() >= r.started + interval),
+                Err(e) => {
+                    warn!(repo = %repo, error = %e, "Cannot read the last check");
+                    true
+                }
+            };
+            if due {
+                if let Err(e) = run(&self.repos, &repo).await {
+                    warn!(repo = %repo, error = %e, "Cannot check repository");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::testing::{init, record};
+
+    fn check_repo(repo: &pijul_repository::Repository) -> FsckReport {
+        check(&repo.pristine.txn_begin().unwrap(), &repo.changes_dir).unwrap()
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init(dir.path());
+        let repo = repo.lock().unwrap();
+        let a = record(&repo, "a", b"a\n");
+        let b = record(&repo, "b", b"b\n");
+
+        let report = check_repo(&repo);
+        assert!(report.is_clean(), "{:?}", report.findings);
+        assert_eq!(report.change_files, 2);
+        assert_eq!(report.channels, 1);
+
+        // A corrupted change file, and a missing one.
+        let mut path = repo.changes_dir.clone();
+        push_filename(&mut path, &a);
+        let mut contents = std::fs::read(&path).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        std::fs::write(&path, contents).unwrap();
+        let mut path = repo.changes_dir.clone();
+        push_filename(&mut path, &b);
+        std::fs::remove_file(&path).unwrap();
+
+        let report = check_repo(&repo);
+        assert_eq!(report.change_files, 1);
+        let found: Vec<_> = report
+            .findings
+            .iter()
+            .map(|f| (f.check, f.change.clone()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Check::ChangeFile, Some(a.to_base32())),
+                (Check::MissingChange, Some(b.to_base32())),
+            ]
+        );
+        assert_eq!(report.findings[1].channel.as_deref(), Some("main"));
+    }
+}
@@ -0,0 +1,405 @@
+//! Change history of hosted repositories, used by the HTTP API.
+
+use anyhow::anyhow;
+use libpijul::change::{parse_hunks, Author, PrintableAtom, PrintableHunk};
+use libpijul::changestore::ChangeStore;
+use libpijul::pristine::HashPrefixError;
+use libpijul::{Base32, Hash, Merkle, TxnT, TxnTExt};
+use pijul_repository::Repository;
+use serde::Serialize;
+
+use crate::error::{Result, ServerError};
+
+/// Maximum number of entries in a page of the log.
+pub const MAX_LOG_PAGE: usize = 500;
+
+/// A change applied to a channel.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    /// Position of the change in the channel
+    pub position: u64,
+    pub hash: String,
+    pub message: String,
+    pub description: Option<String>,
+    pub authors: Vec<Author>,
+    pub timestamp: jiff::Timestamp,
+    /// State of the channel after this change
+    pub state: String,
+}
+
+/// A page of a channel's log, newest first.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogPage {
+    pub entries: Vec<LogEntry>,
+    /// Position to start the next page from, if there are more
+    /// changes
+    pub next: Option<u64>,
+}
+
+/// A change, with its hunks.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeDetail {
+    pub hash: String,
+    pub message: String,
+    pub description: Option<String>,
+    pub authors: Vec<Author>,
+    pub timestamp: jiff::Timestamp,
+    pub dependencies: Vec<String>,
+    /// The change in Pijul's text format, as shown by `pijul change`
+    pub text: String,
+    pub hunks: Vec<HunkInfo>,
+}
+
+/// A summary of one hunk of a change.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HunkInfo {
+    /// Number of the hunk in the change, from 1
+    pub number: u64,
+    pub kind: &'static str,
+    pub path: Option<String>,
+    /// New name of moved files
+    pub name: Option<String>,
+    /// Line where an edit starts
+    pub line: Option<usize>,
+    /// Names involved in a name conflict
+    pub names: Vec<String>,
+    /// Whether the contents are binary, in which case they are
+    /// omitted
+    pub binary: bool,
+    /// Inserted text
+    pub added: Option<String>,
+    /// Deleted text
+    pub deleted: Option<String>,
+}
+
+impl HunkInfo {
+    fn new(number: u64, hunk: PrintableHunk) -> Self {
+        let info = HunkInfo {
+            number,
+            ..Default::default()
+        };
+        let with_contents = |info: HunkInfo, encoding: &Option<_>, added, deleted| {
+            let text = |c: Option<Vec<u8>>| {
+                c.filter(|_| encoding.is_some())
+                    .map(|c| String::from_utf8_lossy(&c).into_owned())
+            };
+            HunkInfo {
+                binary: encoding.is_none(),
+                added: text(added),
+                deleted: text(deleted),
+                ..info
+            }
+        };
+        match hunk {
+            PrintableHunk::FileMoveV { path, name, .. } => HunkInfo {
+                kind: "file_move",
+                path: Some(path),
+                name: Some(name),
+                ..info
+            },
+            PrintableHunk::FileMoveE { path, .. } => HunkInfo {
+                kind: "file_move",
+                path: Some(path),
+                ..info
+            },
+            PrintableHunk::FileAddition {
+                name,
+                parent,
+                encoding,
+                contents,
+                ..
+            } => {
+                let path = if parent.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", parent, name)
+                };
+                let info = HunkInfo {
+                    kind: "file_addition",
+                    path: Some(path),
+                    ..info
+                };
+                with_contents(info, &encoding, Some(contents), None)
+            }
+            PrintableHunk::FileDel {
+                path,
+                encoding,
+                contents,
+                ..
+            } => {
+                let info = HunkInfo {
+                    kind: "file_deletion",
+                    path: Some(path),
+                    ..info
+                };
+                with_contents(info, &encoding, None, Some(contents))
+            }
+            PrintableHunk::FileUndel {
+                path,
+                encoding,
+                contents,
+                ..
+            } => {
+                let info = HunkInfo {
+                    kind: "file_undeletion",
+                    path: Some(path),
+                    ..info
+                };
+                with_contents(info, &encoding, Some(contents), None)
+            }
+            PrintableHunk::Edit {
+                path,
+                line,
+                encoding,
+                change,
+                contents,
+                ..
+            } => {
+                let info = HunkInfo {
+                    kind: "edit",
+                    path: Some(path),
+                    line: Some(line),
+                    ..info
+                };
+                match change {
+                    PrintableAtom::NewVertex(_) => {
+                        with_contents(info, &encoding, Some(contents), None)
+                    }
+                    PrintableAtom::Edges(_) => with_contents(info, &encoding, None, Some(contents)),
+                }
+            }
+            PrintableHunk::Replace {
+                path,
+                line,
+                encoding,
+                change_contents,
+                replacement_contents,
+                ..
+            } => {
+                let info = HunkInfo {
+                    kind: "replacement",
+                    path: Some(path),
+                    line: Some(line),
+                    ..info
+                };
+                with_contents(
+                    info,
+                    &encoding,
+                    Some(replacement_contents),
+                    Some(change_contents),
+                )
+            }
+            PrintableHunk::SolveNameConflict { path, names, .. } => HunkInfo {
+                kind: "solve_name_conflict",
+                path: Some(path),
+                names,
+                ..info
+            },
+            PrintableHunk::UnsolveNameConflict { path, names, .. } => HunkInfo {
+                kind: "unsolve_name_conflict",
+                path: Some(path),
+                names,
+                ..info
+            },
+            PrintableHunk::SolveOrderConflict {
+                path,
+                line,
+                encoding,
+                contents,
+                ..
+            } => {
+                let info = HunkInfo {
+                    kind: "solve_order_conflict",
+                    path: Some(path),
+                    line: Some(line),
+                    ..info
+                };
+                with_contents(info, &encoding, Some(contents), None)
+            }
+            PrintableHunk::UnsolveOrderConflict {
+                path,
+                line,
+                encoding,
+                contents,
+                ..
+            } => {
+                let info = HunkInfo {
+                    kind: "unsolve_order_conflict",
+                    path: Some(path),
+                    line: Some(line),
+                    ..info
+                };
+                with_contents(info, &encoding, None, Some(contents))
+            }
+            PrintableHunk::ResurrectZombies {
+                path,
+                line,
+                encoding,
+                contents,
+                ..
+            } => {
+                let info = HunkInfo {
+                    kind: "resurrect_zombies",
+                    path: Some(path),
+                    line: Some(line),
+                    ..info
+                };
+                with_contents(info, &encoding, Some(contents), None)
+            }
+            PrintableHunk::AddRoot { .. } => HunkInfo {
+                kind: "add_root",
+                ..info
+            },
+            PrintableHunk::DelRoot { .. } => HunkInfo {
+                kind: "delete_root",
+                ..info
+            },
+        }
+    }
+}
+
+/// Find the change whose hash starts with `prefix`, among the changes
+/// applied to at least one channel.
+pub fn resolve_hash(repo: &Repository, prefix: &str) -> Result<Hash> {
+    let txn = repo
+        .pristine
+        .txn_begin()
+        .map_err(|e| ServerError::repository(e.to_string()))?;
+    match txn.hash_from_prefix(prefix) {
+        Ok((hash, _)) => Ok(hash),
+        Err(HashPrefixError::NotFound(_)) => Err(ServerError::not_found(format!(
+            "Change not found: {}",
+            prefix
+        ))),
+        Err(e @ (HashPrefixError::Parse(_) | HashPrefixError::Ambiguous(_))) => {
+            Err(ServerError::bad_request(e.to_string()))
+        }
+        Err(e) => Err(ServerError::repository(e.to_string())),
+    }
+}
+
+/// List at most `limit` changes of `channel`, newest first, starting
+/// at position `from` (the latest change by default). Returns `None`
+/// if the channel doesn't exist.
+pub fn log(
+    repo: &Repository,
+    channel: &str,
+    from: Option<u64>,
+    limit: usize,
+) -> anyhow::Result<Option<LogPage>> {
+    let txn = repo.pristine.txn_begin()?;
+    let Some(channel) = txn.load_channel(channel)? else {
+        return Ok(None);
+    };
+    let channel = channel.read();
+    let mut entries = Vec::new();
+    let mut next = None;
+    for entry in txn.reverse_log(&channel, from)? {
+        let (position, (hash, state)) = entry?;
+        if entries.len() >= limit {
+            next = Some(position);
+            break;
+        }
+        let hash: Hash = hash.into();
+        let state: Merkle = state.into();
+        let header = repo.changes.get_header(&hash)?;
+        entries.push(LogEntry {
+            position,
+            hash: hash.to_base32(),
+            message: header.message,
+            description: header.description,
+            authors: header.authors,
+            timestamp: header.timestamp,
+            state: state.to_base32(),
+        });
+    }
+    Ok(Some(LogPage { entries, next }))
+}
+
+/// Read a change from the change store.
+pub fn change(repo: &Repository, hash: &Hash) -> anyhow::Result<ChangeDetail> {
+    let change = repo.changes.get_change(hash)?;
+    let mut text = Vec::new();
+    change.write(&repo.changes, Some(*hash), true, &mut text)?;
+
+    // Parse the hunks back from the text format, which resolves their
+    // paths and contents.
+    let mut body = Vec::new();
+    change.write(&repo.changes, Some(*hash), false, &mut body)?;
+    let body = String::from_utf8(body)?;
+    let hunks_start = if body.starts_with("# Hunks") {
+        Some(0)
+    } else {
+        body.find("\n# Hunks").map(|i| i + 1)
+    };
+    let hunks = match hunks_start {
+        Some(start) => {
+            parse_hunks(&body[start..])
+                .map_err(|e| anyhow!("Cannot parse hunks: {}", e))?
+                .1
+        }
+        None => Vec::new(),
+    };
+
+    let header = change.hashed.header;
+    Ok(ChangeDetail {
+        hash: hash.to_base32(),
+        message: header.message,
+        description: header.description,
+        authors: header.authors,
+        timestamp: header.timestamp,
+        dependencies: change
+            .hashed
+            .dependencies
+            .iter()
+            .map(|h| h.to_base32())
+            .collect(),
+        text: String::from_utf8(text)?,
+        hunks: hunks
+            .into_iter()
+            .map(|(n, hunk)| HunkInfo::new(n, hunk))
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::testing::{init, record};
+
+    #[test]
+    fn test_log_and_change() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = init(tmp.path());
+        let repo = repo.lock().unwrap();
+        let first = record(&repo, "a", b"hello\n");
+        let second = record(&repo, "b", b"world\n");
+
+        let page = log(&repo, "main", None, 1).unwrap().unwrap();
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.entries[0].hash, second.to_base32());
+        let page = log(&repo, "main", page.next, 10).unwrap().unwrap();
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.entries[0].hash, first.to_base32());
+        assert_eq!(page.entries[0].message, "test");
+        assert_eq!(page.next, None);
+        assert!(log(&repo, "dev", None, 10).unwrap().is_none());
+
+        let prefix = &first.to_base32()[..10];
+        assert_eq!(resolve_hash(&repo, prefix).unwrap(), first);
+        assert!(matches!(
+            resolve_hash(&repo, "AAAAAAAAAA"),
+            Err(ServerError::NotFound(_))
+        ));
+
+        let detail = change(&repo, &first).unwrap();
+        assert!(detail.text.contains("hello"));
+        let hunk = detail
+            .hunks
+            .iter()
+            .find(|h| h.kind == "file_addition")
+            .unwrap();
+        assert_eq!(hunk.path.as_deref(), Some("a"));
+        assert_eq!(hunk.added.as_deref(), Some("hello\n"));
+    }
+}
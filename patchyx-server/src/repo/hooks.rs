@@ -0,0 +1,229 @@
+//! Server-side push hooks.
+//!
+//! Each repository may have a `hooks.toml` file in its `.pijul`
+//! directory, with the same entry format as Pijul's client-side
+//! hooks (a shell command, or a command and its arguments):
+//!
+//! ```toml
+//! pre_apply = ["./ci/check-push"]
+//! post_apply = [{ command = "notify-chat", args = ["#pijul"] }]
+//! ```
+//!
+//! Hooks run in the repository's directory. They get the repository,
+//! channel, pushing user and old/new states in the `PATCHYX_REPO`,
+//! `PATCHYX_CHANNEL`, `PATCHYX_USER`, `PATCHYX_OLD_STATE` and
+//! `PATCHYX_NEW_STATE` environment variables, and the hashes of the
+//! pushed changes on their standard input, one per line.
+//!
+//! Pre-apply hooks run before the changes are committed, while the
+//! repository is locked. If one of them fails, the push is rejected
+//! and its standard error is sent back to the client. Post-apply
+//! hooks run in the background once the push is committed; their
+//! failures are only logged.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use libpijul::{Base32, Hash, Merkle};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+/// Name of the hooks file, in the repository's `.pijul` directory.
+pub const HOOKS_FILE: &str = "hooks.toml";
+
+/// A hook command.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum HookEntry {
+    /// A command run by `$SHELL -c`
+    Shell(String),
+    /// A program and its arguments
+    Command {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+/// Hooks of a repository.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Hooks {
+    pub pre_apply: Vec<HookEntry>,
+    pub post_apply: Vec<HookEntry>,
+}
+
+/// A push, as seen by hooks.
+#[derive(Debug, Clone)]
+pub struct PushEvent {
+    pub repo: String,
+    pub channel: String,
+    pub user: String,
+    pub changes: Vec<Hash>,
+    pub old_state: Merkle,
+    pub new_state: Merkle,
+}
+
+/// A push rejected by a pre-apply hook.
+#[derive(Debug, thiserror::Error)]
+#[error("Push rejected by pre-apply hook `{command}`{}", stderr_suffix(.stderr))]
+pub struct HookRejected {
+    pub command: String,
+    pub stderr: String,
+}
+
+fn stderr_suffix(stderr: &str) -> String {
+    let stderr = stderr.trim_end();
+    if stderr.is_empty() {
+        String::new()
+    } else {
+        format!(":\n{}", stderr)
+    }
+}
+
+/// Output of a finished hook.
+struct HookOutput {
+    success: bool,
+    stderr: String,
+}
+
+impl HookEntry {
+    fn name(&self) -> &str {
+        match self {
+            HookEntry::Shell(s) => s,
+            HookEntry::Command { command, .. } => command,
+        }
+    }
+
+    fn command(&self) -> Command {
+        match self {
+            HookEntry::Shell(s) => {
+                let mut cmd = Command::new(std::env::var("SHELL").unwrap_or("sh".to_string()));
+                cmd.arg("-c").arg(s);
+                cmd
+            }
+            HookEntry::Command { command, args } => {
+                let mut cmd = Command::new(command);
+                cmd.args(args);
+                cmd
+            }
+        }
+    }
+
+    fn run(&self, dir: &Path, event: &PushEvent, timeout: Duration) -> anyhow::Result<HookOutput> {
+        let mut child = self
+            .command()
+            .current_dir(dir)
+            .env("PATCHYX_REPO", &event.repo)
+            .env("PATCHYX_CHANNEL", &event.channel)
+            .env("PATCHYX_USER", &event.user)
+            .env("PATCHYX_OLD_STATE", event.old_state.to_base32())
+            .env("PATCHYX_NEW_STATE", event.new_state.to_base32())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Feed stdin and drain stderr from other threads, so that
+        // hooks ignoring their input or writing a lot don't block.
+        let mut stdin = child.stdin.take().unwrap();
+        let changes: String = event.changes.iter().map(|h| h.to_base32() + "\n").collect();
+        std::thread::spawn(move || stdin.write_all(changes.as_bytes()));
+        let mut stderr = child.stderr.take().unwrap();
+        let stderr = std::thread::spawn(move || {
+            let mut s = Vec::new();
+            stderr.read_to_end(&mut s).map(|_| s)
+        });
+
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if start.elapsed() > timeout {
+                child.kill()?;
+                child.wait()?;
+                break None;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        };
+        let stderr = stderr.join().unwrap_or(Ok(Vec::new()))?;
+        let mut stderr = String::from_utf8_lossy(&stderr).into_owned();
+        if status.is_none() {
+            stderr.push_str(&format!("Timed out after {}s\n", timeout.as_secs()));
+        }
+        Ok(HookOutput {
+            success: status.is_some_and(|s| s.success()),
+            stderr,
+        })
+    }
+}
+
+impl Hooks {
+    fn path(repo_path: &Path) -> PathBuf {
+        repo_path.join(libpijul::DOT_DIR).join(HOOKS_FILE)
+    }
+
+    /// Load the hooks of the repository at `repo_path`.
+    pub fn load(repo_path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(Self::path(repo_path)) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| anyhow::anyhow!("Invalid {}: {}", HOOKS_FILE, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Run the pre-apply hooks, failing with [`HookRejected`] as soon
+    /// as one of them fails. Hooks are killed after `timeout`.
+    pub fn pre_apply(
+        &self,
+        repo_path: &Path,
+        event: &PushEvent,
+        timeout: Duration,
+    ) -> anyhow::Result<()> {
+        for hook in self.pre_apply.iter() {
+            let output = hook.run(repo_path, event, timeout)?;
+            if !output.success {
+                return Err(HookRejected {
+                    command: hook.name().to_string(),
+                    stderr: output.stderr,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the post-apply hooks in a background thread.
+    pub fn post_apply(&self, repo_path: &Path, event: PushEvent, timeout: Duration) {
+        if self.post_apply.is_empty() {
+            return;
+        }
+        let hooks = self.post_apply.clone();
+        let repo_path = repo_path.to_path_buf();
+        std::thread::spawn(move || {
+            for hook in hooks {
+                match hook.run(&repo_path, &event, timeout) {
+                    Ok(output) if output.success => {
+                        info!(repo = %event.repo, hook = hook.name(), "Post-apply hook done")
+                    }
+                    Ok(output) => warn!(
+                        repo = %event.repo,
+                        hook = hook.name(),
+                        stderr = %output.stderr.trim_end(),
+                        "Post-apply hook failed"
+                    ),
+                    Err(e) => warn!(
+                        repo = %event.repo,
+                        hook = hook.name(),
+                        error = %e,
+                        "Cannot run post-apply hook"
+                    ),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,20 @@
+//! Hosted repository access.
+//!
+//! Opening repositories, answering the requests of the Pijul remote
+//! protocol and the queries of the HTTP API.
+
+pub mod browse;
+pub mod conflicts;
+pub mod fork;
+pub mod fsck;
+pub mod history;
+pub mod hooks;
+pub mod proposals;
+pub mod push;
+pub mod signatures;
+pub mod store;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod wire;
+
+pub use store::{RepoStore, SharedRepo};
@@ -0,0 +1,297 @@
+//! Change proposals: requests to apply the changes of a channel to
+//! another channel, possibly of another repository (usually a fork).
+//!
+//! A proposal is stored in the `proposals` directory of the target
+//! repository's `.pijul` directory, as `ID.json`. Its changes aren't
+//! stored: they are the changes of the source channel missing from
+//! the target channel, computed again until the proposal is merged,
+//! so that pushes to the source channel update the proposal.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::bail;
+use jiff::Timestamp;
+use libpijul::{Hash, TxnT, TxnTExt};
+use pijul_repository::Repository;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, ServerError};
+
+/// Name of the proposals directory, in the repository's `.pijul`
+/// directory.
+pub const PROPOSALS_DIR: &str = "proposals";
+
+/// Serializes the allocation of ids and the updates of proposals.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// State of a proposal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Open,
+    Merged,
+    Closed,
+}
+
+/// A comment on a proposal.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Comment {
+    pub author: String,
+    pub body: String,
+    pub created: Timestamp,
+}
+
+/// A proposal to apply changes to a channel of the repository it is
+/// stored in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Proposal {
+    pub id: u64,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    pub author: String,
+    /// Repository the changes come from
+    pub source_repo: String,
+    pub source_channel: String,
+    pub target_channel: String,
+    pub status: Status,
+    pub created: Timestamp,
+    pub updated: Timestamp,
+    /// Changes applied by the merge, for merged proposals
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged_changes: Vec<String>,
+    /// State of the target channel after the merge
+    pub merged_state: Option<String>,
+    #[serde(default)]
+    pub comments: Vec<Comment>,
+}
+
+fn dir(repo_path: &Path) -> PathBuf {
+    repo_path.join(libpijul::DOT_DIR).join(PROPOSALS_DIR)
+}
+
+fn write(repo_path: &Path, proposal: &Proposal) -> Result<()> {
+    let dir = dir(repo_path);
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}.json", proposal.id));
+    let tmp = path.with_extension("tmp");
+    let contents =
+        serde_json::to_vec_pretty(proposal).map_err(|e| ServerError::internal(e.to_string()))?;
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+impl Proposal {
+    /// Load proposal `id` of the repository at `repo_path`.
+    pub fn load(repo_path: &Path, id: u64) -> Result<Self> {
+        let path = dir(repo_path).join(format!("{}.json", id));
+        match std::fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| ServerError::repository(format!("Invalid proposal {}: {}", id, e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(ServerError::not_found(
+                format!("Proposal not found: {}", id),
+            )),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// List the proposals of the repository at `repo_path`, newest
+    /// first.
+    pub fn list(repo_path: &Path) -> Result<Vec<Self>> {
+        let mut proposals = Vec::new();
+        for id in ids(repo_path)? {
+            proposals.push(Self::load(repo_path, id)?);
+        }
+        proposals.reverse();
+        Ok(proposals)
+    }
+
+    /// Store a new proposal in the repository at `repo_path`, giving
+    /// it the next id.
+    pub fn create(mut self, repo_path: &Path) -> Result<Self> {
+        let _lock = LOCK.lock().unwrap();
+        self.id = ids(repo_path)?.last().map_or(1, |id| id + 1);
+        write(repo_path, &self)?;
+        Ok(self)
+    }
+
+    /// Modify proposal `id` of the repository at `repo_path` with
+    /// `f`, which may refuse the update by failing.
+    pub fn update<F>(repo_path: &Path, id: u64, f: F) -> Result<Self>
+    where
+        F: FnOnce(&mut Self) -> Result<()>,
+    {
+        let _lock = LOCK.lock().unwrap();
+        let mut proposal = Self::load(repo_path, id)?;
+        f(&mut proposal)?;
+        proposal.updated = Timestamp::now();
+        write(repo_path, &proposal)?;
+        Ok(proposal)
+    }
+}
+
+/// Ids of the proposals of the repository at `repo_path`, in
+/// increasing order.
+fn ids(repo_path: &Path) -> Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    match std::fs::read_dir(dir(repo_path)) {
+        Ok(entries) => {
+            for entry in entries {
+                let name = entry?.file_name();
+                let id = name.to_str().and_then(|n| n.strip_suffix(".json"));
+                if let Some(id) = id.and_then(|id| id.parse().ok()) {
+                    ids.push(id);
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Point the proposals of the repository at `repo_path` coming from
+/// repository `from` at its new name `to`.
+pub fn rename_source(repo_path: &Path, from: &str, to: &str) -> Result<()> {
+    let _lock = LOCK.lock().unwrap();
+    for id in ids(repo_path)? {
+        let mut proposal = Proposal::load(repo_path, id)?;
+        if proposal.source_repo == from {
+            proposal.source_repo = to.to_string();
+            write(repo_path, &proposal)?;
+        }
+    }
+    Ok(())
+}
+
+/// Hashes of the changes of a channel, in the order of its log, or
+/// `None` if the channel doesn't exist.
+fn log(repo: &Repository, channel: &str) -> anyhow::Result<Option<Vec<Hash>>> {
+    let txn = repo.pristine.txn_begin()?;
+    let Some(channel) = txn.load_channel(channel)? else {
+        return Ok(None);
+    };
+    let channel = channel.read();
+    let mut hashes = Vec::new();
+    for entry in txn.log(&channel, 0)? {
+        let (_, (h, _)) = entry?;
+        hashes.push(h.into());
+    }
+    Ok(Some(hashes))
+}
+
+/// Changes of channel `source_channel` of `source` missing from
+/// channel `target_channel` of `target`, in the order of the source
+/// channel's log. A missing target channel has no changes.
+pub fn missing_changes(
+    source: &Repository,
+    source_channel: &str,
+    target: &Repository,
+    target_channel: &str,
+) -> anyhow::Result<Vec<Hash>> {
+    let Some(changes) = log(source, source_channel)? else {
+        bail!("Channel not found: {}", source_channel)
+    };
+    let present: HashSet<Hash> = log(target, target_channel)?
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    Ok(changes
+        .into_iter()
+        .filter(|h| !present.contains(h))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Access, AccessList, Caller};
+    use crate::config::{AuditConfig, RepoSettings};
+    use crate::repo::fork;
+    use crate::repo::push::Push;
+    use crate::repo::testing::{init, record};
+
+    fn proposal(title: &str) -> Proposal {
+        Proposal {
+            id: 0,
+            title: title.to_string(),
+            description: String::new(),
+            author: "alice".to_string(),
+            source_repo: "fork".to_string(),
+            source_channel: "main".to_string(),
+            target_channel: "main".to_string(),
+            status: Status::Open,
+            created: Timestamp::now(),
+            updated: Timestamp::now(),
+            merged_changes: Vec::new(),
+            merged_state: None,
+            comments: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(libpijul::DOT_DIR)).unwrap();
+        let first = proposal("first").create(dir.path()).unwrap();
+        let second = proposal("second").create(dir.path()).unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+
+        let closed = Proposal::update(dir.path(), 1, |p| {
+            p.status = Status::Closed;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(closed.status, Status::Closed);
+        let err = Proposal::update(dir.path(), 3, |_| Ok(())).unwrap_err();
+        assert!(matches!(err, ServerError::NotFound(_)));
+
+        rename_source(dir.path(), "fork", "spoon").unwrap();
+        let list = Proposal::list(dir.path()).unwrap();
+        let titles: Vec<_> = list.iter().map(|p| p.title.as_str()).collect();
+        assert_eq!(titles, ["second", "first"]);
+        assert_eq!(list[1].status, Status::Closed);
+        assert!(list.iter().all(|p| p.source_repo == "spoon"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let a_dir = tempfile::tempdir().unwrap();
+        let b_dir = tempfile::tempdir().unwrap();
+        let a = init(a_dir.path());
+        let b = init(b_dir.path());
+        let a = a.lock().unwrap();
+        let b = b.lock().unwrap();
+        record(&a, "a", b"a\n");
+        fork::fork(&a, &b).unwrap();
+        let new = record(&b, "b", b"b\n");
+        let conflicting = record(&b, "c", b"c\n");
+        record(&a, "c", b"not c\n");
+
+        let missing = missing_changes(&b, "main", &a, "main").unwrap();
+        assert_eq!(missing, vec![new, conflicting]);
+        assert!(missing_changes(&b, "nope", &a, "main").is_err());
+        assert_eq!(missing_changes(&b, "main", &a, "new").unwrap().len(), 3);
+
+        fork::share_changes(&b, &a, &missing).unwrap();
+
+        let access = Access::resolve("a", &AccessList::default(), &Caller::admin());
+        let settings = RepoSettings {
+            audit: AuditConfig {
+                enabled: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let push = Push::with_changes("main", settings, missing[..1].to_vec());
+        push.apply(&a, &access).unwrap();
+        assert_eq!(
+            missing_changes(&b, "main", &a, "main").unwrap(),
+            vec![conflicting]
+        );
+    }
+}
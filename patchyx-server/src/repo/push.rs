@@ -0,0 +1,370 @@
+//! Pushes: changes uploaded by a client and applied together.
+//!
+//! Over SSH, a push is a sequence of `apply` requests to the same
+//! channel; over HTTP, each upload is its own push. The changes wait
+//! in a staging directory of the push as they arrive, then are moved
+//! to the change store and applied in one transaction guarded by the
+//! channel's [signature policy](super::signatures) and the
+//! repository's [hooks](super::hooks). Other pushes never see staged
+//! changes, which are removed with the push unless it is applied,
+//! including when the client goes away before.
+
+use std::time::Instant;
+
+use libpijul::change::Change;
+use libpijul::changestore::filesystem::push_filename;
+use libpijul::{Base32, Hash, Merkle, DOT_DIR};
+use pijul_repository::Repository;
+use tempfile::TempDir;
+use tracing::info;
+
+use super::hooks::{HookRejected, Hooks, PushEvent};
+use super::signatures::{self, SignatureRejected};
+use super::wire;
+use crate::audit::{self, Action, Record};
+use crate::auth::Access;
+use crate::config::RepoSettings;
+use crate::metrics;
+use crate::mirror::push as push_mirror;
+use crate::mirror::PullMirror;
+use crate::webhooks::{self, Event};
+
+/// Changes received from a client but not applied yet.
+#[derive(Debug)]
+pub struct Push {
+    channel: String,
+    settings: RepoSettings,
+    changes: Vec<Hash>,
+    /// Changes that were not in the change store when received.
+    staged: Vec<Hash>,
+    /// Where `staged` are, named by their hashes.
+    staging: Option<TempDir>,
+    /// Total size of the received changes, in bytes.
+    size: u64,
+}
+
+impl Push {
+    /// Start a push to `channel`, with the settings of the
+    /// repository.
+    pub fn new(channel: &str, settings: RepoSettings) -> Self {
+        Self {
+            channel: channel.to_string(),
+            settings,
+            changes: Vec::new(),
+            staged: Vec::new(),
+            staging: None,
+            size: 0,
+        }
+    }
+
+    /// Start a push of changes already in the change store, as when
+    /// merging a proposal.
+    pub fn with_changes(channel: &str, settings: RepoSettings, changes: Vec<Hash>) -> Self {
+        let mut push = Self::new(channel, settings);
+        push.changes = changes;
+        push
+    }
+
+    /// The channel pushed to.
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    /// Store a change file received from the client.
+    pub fn receive(
+        &mut self,
+        repo: &Repository,
+        hash: Hash,
+        contents: &[u8],
+    ) -> anyhow::Result<()> {
+        check_size(&self.settings, contents.len())?;
+        self.size += contents.len() as u64;
+        if self.size > self.settings.max_push_size {
+            anyhow::bail!(
+                "Push too large: more than {} bytes",
+                self.settings.max_push_size
+            )
+        }
+        Change::check_from_buffer(contents, &hash)?;
+        if !repo.changes.has_change(&hash) && !self.staged.contains(&hash) {
+            let staging = match self.staging {
+                Some(ref staging) => staging,
+                None => self.staging.insert(
+                    tempfile::Builder::new()
+                        .prefix("push-")
+                        .tempdir_in(repo.path.join(DOT_DIR))?,
+                ),
+            };
+            wire::write_atomic(&staging.path().join(hash.to_base32()), contents)?;
+            self.staged.push(hash);
+        }
+        self.changes.push(hash);
+        Ok(())
+    }
+
+    /// Apply the received changes, running the pre-apply hooks before
+    /// committing and the post-apply hooks after. If the push fails,
+    /// the change files it added are removed. Returns the new state
+    /// of the channel.
+    pub fn apply(mut self, repo: &Repository, access: &Access) -> anyhow::Result<Merkle> {
+        let added = self.unstage(repo)?;
+        let result = self.apply_(repo, access);
+        if result.is_err() {
+            wire::remove_unused_changes(repo, &added);
+        }
+        result
+    }
+
+    /// Move the staged changes to the change store, returning those
+    /// that weren't there yet.
+    fn unstage(&mut self, repo: &Repository) -> anyhow::Result<Vec<Hash>> {
+        let Some(staging) = self.staging.take() else {
+            return Ok(Vec::new());
+        };
+        let mut added = Vec::new();
+        for hash in std::mem::take(&mut self.staged) {
+            let mut path = repo.changes_dir.clone();
+            push_filename(&mut path, &hash);
+            if path.exists() {
+                // Stored by another push in the meantime.
+                continue;
+            }
+            let moved = std::fs::create_dir_all(path.parent().unwrap())
+                .and_then(|()| std::fs::rename(staging.path().join(hash.to_base32()), &path));
+            if let Err(e) = moved {
+                wire::remove_unused_changes(repo, &added);
+                return Err(e.into());
+            }
+            added.push(hash);
+        }
+        Ok(added)
+    }
+
+    fn apply_(&self, repo: &Repository, access: &Access) -> anyhow::Result<Merkle> {
+        // Mirrors follow their remote, which would undo the push.
+        if let Some(mirror) = PullMirror::load(&repo.path)? {
+            anyhow::bail!(
+                "Repository is a mirror of {}, it can't be pushed to",
+                mirror.url
+            )
+        }
+        if access.requires_signatures(&self.channel) {
+            signatures::check(repo, &self.settings.users_dir, &self.changes)?;
+        }
+        let hooks = if self.settings.hooks {
+            Hooks::load(&repo.path)?
+        } else {
+            Hooks::default()
+        };
+        let timeout = self.settings.hook_timeout;
+        let mut event = PushEvent {
+            repo: access.repo().to_string(),
+            channel: self.channel.clone(),
+            user: access.caller().to_string(),
+            changes: self.changes.clone(),
+            old_state: Merkle::zero(),
+            new_state: Merkle::zero(),
+        };
+        let created = wire::channel_id(repo, &self.channel)?.is_none();
+        let start = Instant::now();
+        let state = wire::apply(repo, &self.channel, &self.changes, |old, new| {
+            event.old_state = old;
+            event.new_state = new;
+            hooks.pre_apply(&repo.path, &event, timeout)
+        })?;
+        metrics::CHANGE_APPLY.observe(&[], start.elapsed());
+        info!(
+            repo = %event.repo,
+            channel = %self.channel,
+            user = %event.user,
+            changes = self.changes.len(),
+            state = %state.to_base32(),
+            "Applied push"
+        );
+        let settings = &self.settings;
+        let record = Record {
+            channel: Some(self.channel.clone()),
+            old_state: Some(event.old_state.to_base32()),
+            new_state: Some(state.to_base32()),
+            changes: self.changes.iter().map(|h| h.to_base32()).collect(),
+            ..Record::on_repo(Action::Push, access)
+        };
+        audit::append(&settings.audit, record);
+        if created {
+            let channel = self.channel.clone();
+            let created = Event::ChannelCreate { channel };
+            webhooks::notify(settings, &repo.path, &event.repo, &event.user, created);
+        }
+        let pushed = Event::push(&event);
+        webhooks::notify(settings, &repo.path, &event.repo, &event.user, pushed);
+        push_mirror::enqueue(&repo.path, &event.repo, &self.channel);
+        hooks.post_apply(&repo.path, event, timeout);
+        Ok(state)
+    }
+}
+
+/// Whether a push failed because it was refused, by a hook or by the
+/// signature policy, rather than because of an error.
+pub fn is_rejection(e: &anyhow::Error) -> bool {
+    e.is::<HookRejected>() || e.is::<SignatureRejected>()
+}
+
+/// Check the size of an uploaded change against the repository's
+/// limit.
+pub fn check_size(settings: &RepoSettings, len: usize) -> anyhow::Result<()> {
+    if len as u64 > settings.max_upload_size {
+        anyhow::bail!(
+            "Change too large: {} bytes, the limit is {}",
+            len,
+            settings.max_upload_size
+        )
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use libpijul::changestore::filesystem::push_filename;
+
+    use super::*;
+    use crate::auth::{AccessList, Caller};
+    use crate::config::AuditConfig;
+    use crate::repo::testing::{init, record};
+
+    #[test]
+    fn test_hooks() {
+        let a_dir = tempfile::tempdir().unwrap();
+        let b_dir = tempfile::tempdir().unwrap();
+        let a = init(a_dir.path());
+        let b = init(b_dir.path());
+        let a = a.lock().unwrap();
+        let b = b.lock().unwrap();
+        let hash = record(&a, "file", b"a\n");
+        let mut path = a.changes_dir.clone();
+        push_filename(&mut path, &hash);
+        let contents = std::fs::read(&path).unwrap();
+        let access = Access::resolve("b", &AccessList::default(), &Caller::admin());
+        let hooks = b_dir.path().join(libpijul::DOT_DIR).join("hooks.toml");
+        let audit = AuditConfig {
+            enabled: true,
+            path: b_dir.path().join("audit.log"),
+        };
+        let settings = RepoSettings {
+            audit: audit.clone(),
+            ..Default::default()
+        };
+
+        // A failing pre-apply hook rejects the push.
+        std::fs::write(&hooks, r#"pre_apply = ["echo no way >&2; exit 1"]"#).unwrap();
+        let mut push = Push::new("main", settings.clone());
+        push.receive(&b, hash, &contents).unwrap();
+        let err = push.apply(&b, &access).unwrap_err();
+        let rejected = err.downcast_ref::<HookRejected>().unwrap();
+        assert_eq!(rejected.stderr, "no way\n");
+        let mut path = b.changes_dir.clone();
+        push_filename(&mut path, &hash);
+        assert!(!path.exists());
+        let mut out = Vec::new();
+        wire::state(&b, "main", None, &mut out).unwrap();
+        assert_eq!(out, b"-\n");
+
+        // Hooks get the push on their environment and stdin.
+        std::fs::write(
+            &hooks,
+            r#"pre_apply = [{ command = "sh", args = ["-c", "echo $PATCHYX_REPO $PATCHYX_CHANNEL $PATCHYX_USER $PATCHYX_NEW_STATE > pre; cat >> pre"] }]"#,
+        )
+        .unwrap();
+        let mut push = Push::new("main", settings);
+        push.receive(&b, hash, &contents).unwrap();
+        let state = push.apply(&b, &access).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(b_dir.path().join("pre")).unwrap(),
+            format!(
+                "b main <admin> {}\n{}\n",
+                state.to_base32(),
+                hash.to_base32()
+            )
+        );
+
+        // Only the applied push is audited.
+        let records = audit::search(&audit, &Default::default(), 10).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].action, Action::Push);
+        assert_eq!(records[0].old_state, Some(Merkle::zero().to_base32()));
+        assert_eq!(records[0].new_state, Some(state.to_base32()));
+        assert_eq!(records[0].changes, vec![hash.to_base32()]);
+    }
+
+    #[test]
+    fn test_push_size() {
+        let a_dir = tempfile::tempdir().unwrap();
+        let a = init(a_dir.path());
+        let a = a.lock().unwrap();
+        let first = record(&a, "a", b"a\n");
+        let second = record(&a, "b", b"b\n");
+        let read = |hash: &Hash| {
+            let mut path = a.changes_dir.clone();
+            push_filename(&mut path, hash);
+            std::fs::read(&path).unwrap()
+        };
+        let (first_contents, second_contents) = (read(&first), read(&second));
+
+        // Each change fits, but not both.
+        let settings = RepoSettings {
+            max_push_size: second_contents.len().max(first_contents.len()) as u64,
+            ..Default::default()
+        };
+        let mut push = Push::new("main", settings);
+        push.receive(&a, first, &first_contents).unwrap();
+        let err = push.receive(&a, second, &second_contents).unwrap_err();
+        assert!(err.to_string().starts_with("Push too large"), "{}", err);
+    }
+
+    #[test]
+    fn test_abandoned() {
+        let a_dir = tempfile::tempdir().unwrap();
+        let b_dir = tempfile::tempdir().unwrap();
+        let a = init(a_dir.path());
+        let b = init(b_dir.path());
+        let a = a.lock().unwrap();
+        let b = b.lock().unwrap();
+        let hash = record(&a, "a", b"a\n");
+        let mut path = a.changes_dir.clone();
+        push_filename(&mut path, &hash);
+        let contents = std::fs::read(&path).unwrap();
+
+        // Files of pushes never applied don't stay behind.
+        let mut push = Push::new("main", RepoSettings::default());
+        push.receive(&b, hash, &contents).unwrap();
+        let staging = push.staging.as_ref().unwrap().path().to_path_buf();
+        assert!(staging.join(hash.to_base32()).exists());
+        std::mem::drop(push);
+        assert!(!staging.exists());
+        assert!(!b.changes.has_change(&hash));
+
+        // Nor do they get in the way of other pushes of the same
+        // changes.
+        let settings = RepoSettings {
+            audit: AuditConfig {
+                enabled: true,
+                path: b_dir.path().join("audit.log"),
+            },
+            ..Default::default()
+        };
+        let mut abandoned = Push::new("main", settings.clone());
+        abandoned.receive(&b, hash, &contents).unwrap();
+        let mut push = Push::new("main", settings);
+        push.receive(&b, hash, &contents).unwrap();
+        let access = Access::resolve("b", &AccessList::default(), &Caller::admin());
+        push.apply(&b, &access).unwrap();
+        std::mem::drop(abandoned);
+        assert!(b.changes.has_change(&hash));
+
+        // Files that were already there stay.
+        let mut push = Push::new("main", RepoSettings::default());
+        push.receive(&a, hash, &contents).unwrap();
+        assert!(push.staging.is_none());
+        std::mem::drop(push);
+        assert!(a.changes.has_change(&hash));
+    }
+}
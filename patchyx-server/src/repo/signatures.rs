@@ -0,0 +1,187 @@
+//! Signed changes.
+//!
+//! `pijul record` signs the hash of a change with its author's key,
+//! and stores the [`Signature`] in the `signature` field of the
+//! change's unhashed section, which isn't covered by the hash. On
+//! channels requiring signatures, each pushed change must be signed
+//! by a key that is the `key` of one of its authors, and belongs to
+//! an identity published in the repository or linked to a user of
+//! the server.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use libpijul::change::Change;
+use libpijul::changestore::ChangeStore;
+use libpijul::key::Signature;
+use libpijul::{Base32, Hash};
+use pijul_repository::Repository;
+
+use super::wire;
+
+/// Why a change doesn't satisfy the signature policy.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SignatureError {
+    #[error("not signed")]
+    Missing,
+    #[error("invalid signature: {0}")]
+    Invalid(String),
+    #[error("signed with key {0}, which is not the key of one of its authors")]
+    NotAuthor(String),
+    #[error("signed with key {0}, which is not registered")]
+    Unregistered(String),
+}
+
+/// A push with changes that don't satisfy the signature policy.
+#[derive(Debug, thiserror::Error)]
+#[error("Push rejected, the channel requires signed changes:{}", list(.changes))]
+pub struct SignatureRejected {
+    pub changes: Vec<(Hash, SignatureError)>,
+}
+
+fn list(changes: &[(Hash, SignatureError)]) -> String {
+    changes
+        .iter()
+        .map(|(hash, e)| format!("\n{}: {}", hash.to_base32(), e))
+        .collect()
+}
+
+/// Keys of the identities published in the repository or linked to
+/// the users in `users_dir`.
+pub fn registered_keys(repo: &Repository, users_dir: &Path) -> anyhow::Result<HashSet<String>> {
+    let identities = wire::read_identities(repo, users_dir, None)?;
+    Ok(identities
+        .iter()
+        .filter_map(|(_, id)| id.get("public_key")?.get("key")?.as_str())
+        .map(String::from)
+        .collect())
+}
+
+/// Check the signature of `change`, whose hash is `hash`, against
+/// the keys in `registered`.
+pub fn check_change(
+    hash: &Hash,
+    change: &Change,
+    registered: &HashSet<String>,
+) -> Result<(), SignatureError> {
+    let signature = change
+        .unhashed
+        .as_ref()
+        .and_then(|unhashed| unhashed.get("signature"))
+        .ok_or(SignatureError::Missing)?;
+    let signature: Signature = serde_json::from_value(signature.clone())
+        .map_err(|e| SignatureError::Invalid(e.to_string()))?;
+    signature
+        .verify(&hash.to_bytes())
+        .map_err(|e| SignatureError::Invalid(e.to_string()))?;
+    let key = &signature.key.key;
+    let by_author = change
+        .hashed
+        .header
+        .authors
+        .iter()
+        .any(|author| author.0.get("key") == Some(key));
+    if !by_author {
+        return Err(SignatureError::NotAuthor(key.clone()));
+    }
+    if !registered.contains(key) {
+        return Err(SignatureError::Unregistered(key.clone()));
+    }
+    Ok(())
+}
+
+/// Check the signatures of `changes`, which must be in the change
+/// store, failing with [`SignatureRejected`] if any of them isn't
+/// properly signed.
+pub fn check(repo: &Repository, users_dir: &Path, changes: &[Hash]) -> anyhow::Result<()> {
+    let registered = registered_keys(repo, users_dir)?;
+    let mut rejected = Vec::new();
+    for hash in changes {
+        let change = repo.changes.get_change(hash)?;
+        if let Err(e) = check_change(hash, &change, &registered) {
+            rejected.push((*hash, e));
+        }
+    }
+    if rejected.is_empty() {
+        Ok(())
+    } else {
+        Err(SignatureRejected { changes: rejected }.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libpijul::change::Author;
+    use libpijul::key::SKey;
+
+    use super::*;
+    use crate::auth::identities::link;
+    use crate::repo::testing::{init, record, record_with};
+
+    fn author(key: &SKey) -> Author {
+        Author([("key".to_string(), key.public_key().key)].into())
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init(dir.path());
+        let repo = repo.lock().unwrap();
+        let users = tempfile::tempdir().unwrap();
+        let users = users.path();
+        let alice = SKey::generate(None);
+        let bob = SKey::generate(None);
+        let mallory = SKey::generate(None);
+
+        let unsigned = record(&repo, "a", b"a\n");
+        let signed = record_with(&repo, "b", b"b\n", vec![author(&alice)], Some(&alice));
+        let impostor = record_with(&repo, "c", b"c\n", vec![author(&alice)], Some(&mallory));
+        let by_bob = record_with(&repo, "d", b"d\n", vec![author(&bob)], Some(&bob));
+
+        // Alice's key isn't registered yet.
+        let err = check(&repo, users, &[signed]).unwrap_err();
+        let rejected = err.downcast_ref::<SignatureRejected>().unwrap();
+        let key = alice.public_key().key;
+        assert_eq!(
+            rejected.changes,
+            vec![(signed, SignatureError::Unregistered(key.clone()))]
+        );
+
+        let identities = dir
+            .path()
+            .join(libpijul::DOT_DIR)
+            .join(wire::IDENTITIES_DIR);
+        std::fs::create_dir_all(&identities).unwrap();
+        let identity = serde_json::json!({
+            "public_key": alice.public_key(),
+            "last_modified": jiff::Timestamp::now(),
+        });
+        std::fs::write(identities.join("alice"), identity.to_string()).unwrap();
+        check(&repo, users, &[signed]).unwrap();
+
+        // Keys linked to users are registered too.
+        assert!(check(&repo, users, &[by_bob]).is_err());
+        link(users, "bob", bob.public_key()).unwrap();
+        check(&repo, users, &[by_bob]).unwrap();
+
+        // Each bad change is listed with its reason.
+        let err = check(&repo, users, &[unsigned, signed, impostor]).unwrap_err();
+        let rejected = err.downcast_ref::<SignatureRejected>().unwrap();
+        let mallory_key = mallory.public_key().key;
+        assert_eq!(
+            rejected.changes,
+            vec![
+                (unsigned, SignatureError::Missing),
+                (impostor, SignatureError::NotAuthor(mallory_key)),
+            ]
+        );
+        assert!(err.to_string().contains(&unsigned.to_base32()));
+
+        // The signature must be of the change's hash.
+        let mut change = repo.changes.get_change(&signed).unwrap();
+        change.unhashed = repo.changes.get_change(&impostor).unwrap().unhashed;
+        let registered = registered_keys(&repo, users).unwrap();
+        let result = check_change(&signed, &change, &registered);
+        assert!(matches!(result, Err(SignatureError::Invalid(_))));
+    }
+}
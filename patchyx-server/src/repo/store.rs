@@ -0,0 +1,383 @@
+//! Registry of hosted repositories.
+//!
+//! Each hosted repository lives in its own directory under
+//! `repos_dir`, with the usual `.pijul` layout. Sanakirja takes an
+//! exclusive lock on the pristine, so every repository is opened at
+//! most once and shared between SSH sessions and HTTP handlers. The
+//! change store caches decoded changes in a `RefCell`, hence the
+//! mutex around each repository.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use libpijul::{MutTxnT, DEFAULT_CHANNEL, DOT_DIR};
+use pijul_repository::Repository;
+use tracing::warn;
+
+use super::fork::{self, ForkInfo};
+use super::proposals;
+use crate::auth::{Access, AccessList, Caller};
+use crate::config::SharedConfig;
+use crate::error::{Result, ServerError};
+
+/// A repository shared between connections.
+pub type SharedRepo = Arc<Mutex<Repository>>;
+
+/// Shared handle on the repositories under `repos_dir`.
+pub struct RepoStore {
+    config: Arc<SharedConfig>,
+    open: Mutex<HashMap<String, SharedRepo>>,
+}
+
+impl RepoStore {
+    /// Create a store over the configured repositories directory.
+    pub fn new(config: Arc<SharedConfig>) -> Self {
+        Self {
+            config,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Normalize a repository name as sent by clients.
+    ///
+    /// SSH clients send the path part of the remote URL, which may
+    /// start with `/` (`ssh://host/repo`) or not (`host:repo`).
+    pub fn normalize_name(name: &str) -> Result<&str> {
+        let name = name.trim_matches('/');
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        if valid {
+            Ok(name)
+        } else {
+            Err(ServerError::bad_request(format!(
+                "Invalid repository name: {:?}",
+                name
+            )))
+        }
+    }
+
+    /// Get the on-disk path of a repository.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.config.get().repos_dir.join(name)
+    }
+
+    /// Check whether a repository exists.
+    pub fn exists(&self, name: &str) -> bool {
+        self.path(name).join(DOT_DIR).is_dir()
+    }
+
+    /// List the names of all repositories.
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.config.get().repos_dir)? {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str() {
+                if Self::normalize_name(name).is_ok_and(|n| n == name) && self.exists(name) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Create a repository with an empty default channel.
+    pub fn create(&self, name: &str) -> Result<SharedRepo> {
+        let name = Self::normalize_name(name)?;
+        let mut open = self.open.lock().unwrap();
+        let path = self.path(name);
+        if path.exists() {
+            return Err(ServerError::conflict(format!(
+                "Repository already exists: {}",
+                name
+            )));
+        }
+        let repo = Repository::init(Some(&path), None, None)
+            .map_err(|e| ServerError::repository(e.to_string()))?;
+        let mut txn = repo
+            .pristine
+            .mut_txn_begin()
+            .map_err(|e| ServerError::repository(e.to_string()))?;
+        txn.open_or_create_channel(DEFAULT_CHANNEL)
+            .map_err(|e| ServerError::repository(e.to_string()))?;
+        txn.commit()
+            .map_err(|e| ServerError::repository(e.to_string()))?;
+        let repo = Arc::new(Mutex::new(repo));
+        open.insert(name.to_string(), repo.clone());
+        Ok(repo)
+    }
+
+    /// Create repository `to` as a fork of `from` by `by`, with the
+    /// same channels and sharing its change files, and with access
+    /// list `acl`. The fork is usable once this returns; sessions
+    /// trying to use it before wait for it.
+    pub fn fork(&self, from: &str, to: &str, acl: &AccessList, by: &str) -> Result<SharedRepo> {
+        let parent = self.open(from)?;
+        let from = Self::normalize_name(from)?;
+        let to = Self::normalize_name(to)?;
+        self.check_fork_access(from, acl)?;
+        let repo = {
+            let mut open = self.open.lock().unwrap();
+            let path = self.path(to);
+            if path.exists() {
+                return Err(ServerError::conflict(format!(
+                    "Repository already exists: {}",
+                    to
+                )));
+            }
+            let repo = Repository::init(Some(&path), None, None)
+                .map_err(|e| ServerError::repository(e.to_string()))?;
+            // Registered with its access list and parent, so that it
+            // is never seen without them.
+            let info = ForkInfo {
+                parent: from.to_string(),
+                created: jiff::Timestamp::now(),
+                by: by.to_string(),
+            };
+            if let Err(e) = acl.save(&path).and_then(|()| info.save(&path)) {
+                std::mem::drop(repo);
+                if let Err(e) = std::fs::remove_dir_all(&path) {
+                    warn!(repo = %to, error = %e, "Cannot remove failed fork");
+                }
+                return Err(e);
+            }
+            let repo = Arc::new(Mutex::new(repo));
+            open.insert(to.to_string(), repo.clone());
+            repo
+        };
+        // Copy without the lock of the store, so that other
+        // repositories stay available. Only the parent and the fork
+        // are locked.
+        let result = {
+            let forked = repo.lock().unwrap();
+            let parent = parent.lock().unwrap();
+            fork::fork(&parent, &forked)
+        };
+        if let Err(e) = result {
+            self.open.lock().unwrap().remove(to);
+            std::mem::drop(repo);
+            if let Err(e) = std::fs::remove_dir_all(self.path(to)) {
+                warn!(repo = %to, error = %e, "Cannot remove failed fork");
+            }
+            return Err(ServerError::repository(format!(
+                "Cannot fork {}: {}",
+                from, e
+            )));
+        }
+        Ok(repo)
+    }
+
+    /// The names of the forks of a repository.
+    pub fn forks(&self, name: &str) -> Result<Vec<String>> {
+        let mut forks = Vec::new();
+        for repo in self.list()? {
+            let info = ForkInfo::load(&self.path(&repo))?;
+            if info.is_some_and(|i| i.parent == name) {
+                forks.push(repo);
+            }
+        }
+        Ok(forks)
+    }
+
+    /// Delete a repository and all its changes.
+    pub fn delete(&self, name: &str) -> Result<()> {
+        let name = Self::normalize_name(name)?;
+        let mut open = self.open.lock().unwrap();
+        self.close(&mut open, name)?;
+        std::fs::remove_dir_all(self.path(name))?;
+        Ok(())
+    }
+
+    /// Rename a repository.
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let from = Self::normalize_name(from)?;
+        let to = Self::normalize_name(to)?;
+        let mut open = self.open.lock().unwrap();
+        if self.path(to).exists() {
+            return Err(ServerError::conflict(format!(
+                "Repository already exists: {}",
+                to
+            )));
+        }
+        self.close(&mut open, from)?;
+        std::fs::rename(self.path(from), self.path(to))?;
+        // Keep the forks pointing at their parent.
+        for fork in self.forks(from)? {
+            let path = self.path(&fork);
+            if let Some(mut info) = ForkInfo::load(&path)? {
+                info.parent = to.to_string();
+                info.save(&path)?;
+            }
+        }
+        // And the proposals coming from it.
+        for repo in self.list()? {
+            proposals::rename_source(&self.path(&repo), from, to)?;
+        }
+        Ok(())
+    }
+
+    /// Forget an opened repository before moving or deleting it,
+    /// making sure no session is using it.
+    fn close(&self, open: &mut HashMap<String, SharedRepo>, name: &str) -> Result<()> {
+        if !self.exists(name) {
+            return Err(ServerError::not_found(format!(
+                "Repository not found: {}",
+                name
+            )));
+        }
+        if let Some(repo) = open.get(name) {
+            if Arc::strong_count(repo) > 1 {
+                return Err(ServerError::conflict(format!(
+                    "Repository is in use: {}",
+                    name
+                )));
+            }
+        }
+        open.remove(name);
+        Ok(())
+    }
+
+    /// Replace the access list of a repository.
+    pub fn set_access(&self, name: &str, acl: &AccessList) -> Result<()> {
+        let name = Self::normalize_name(name)?;
+        let path = self.path(name);
+        if let Some(info) = ForkInfo::load(&path)? {
+            self.check_fork_access(&info.parent, acl)?;
+        }
+        acl.save(&path)
+    }
+
+    /// Check that a fork of `parent` may have access list `acl`:
+    /// forks of private repositories can't be public.
+    fn check_fork_access(&self, parent: &str, acl: &AccessList) -> Result<()> {
+        if acl.public && self.exists(parent) && !AccessList::load(&self.path(parent))?.public {
+            return Err(ServerError::bad_request(
+                "Forks of private repositories can't be public",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Compute the permissions of `caller` on a repository.
+    pub fn access(&self, name: &str, caller: &Caller) -> Result<Access> {
+        let name = Self::normalize_name(name)?;
+        if !self.exists(name) {
+            return Err(ServerError::not_found(format!(
+                "Repository not found: {}",
+                name
+            )));
+        }
+        let acl = AccessList::load(&self.path(name))?;
+        Ok(Access::resolve(name, &acl, caller))
+    }
+
+    /// Open a repository, reusing the already opened pristine if any.
+    pub fn open(&self, name: &str) -> Result<SharedRepo> {
+        let name = Self::normalize_name(name)?;
+        let mut open = self.open.lock().unwrap();
+        if let Some(repo) = open.get(name) {
+            return Ok(repo.clone());
+        }
+        // `find_root` walks up the directory tree, so make sure we
+        // don't end up in a repository containing `repos_dir`.
+        if !self.exists(name) {
+            return Err(ServerError::not_found(format!(
+                "Repository not found: {}",
+                name
+            )));
+        }
+        let repo = Repository::find_root_with_dot_dir(Some(&self.path(name)), DOT_DIR)
+            .map_err(|e| ServerError::repository(e.to_string()))?;
+        // The pristine version is only written by the first mutable
+        // transaction, and read-only transactions refuse to start
+        // before that.
+        repo.pristine
+            .mut_txn_begin()
+            .and_then(|txn| txn.commit())
+            .map_err(|e| ServerError::repository(e.to_string()))?;
+        let repo = Arc::new(Mutex::new(repo));
+        open.insert(name.to_string(), repo.clone());
+        Ok(repo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(RepoStore::normalize_name("/myrepo").unwrap(), "myrepo");
+        assert_eq!(RepoStore::normalize_name("myrepo/").unwrap(), "myrepo");
+        assert!(RepoStore::normalize_name("../etc").is_err());
+        assert!(RepoStore::normalize_name("a/b").is_err());
+        assert!(RepoStore::normalize_name(".pijul").is_err());
+        assert!(RepoStore::normalize_name("").is_err());
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig {
+            repos_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let store = RepoStore::new(Arc::new(config.into()));
+        let repo = store.create("a").unwrap();
+        assert!(store.create("a").is_err());
+        assert_eq!(store.list().unwrap(), vec!["a"]);
+
+        // The repository can't move while it is in use.
+        assert!(store.rename("a", "b").is_err());
+        std::mem::drop(repo);
+        store.rename("a", "b").unwrap();
+        assert_eq!(store.list().unwrap(), vec!["b"]);
+        assert!(store.open("a").is_err());
+        store.open("b").unwrap();
+
+        store.delete("b").unwrap();
+        assert!(store.list().unwrap().is_empty());
+        assert!(store.delete("b").is_err());
+    }
+
+    #[test]
+    fn test_fork() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig {
+            repos_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let store = RepoStore::new(Arc::new(config.into()));
+        store.create("a").unwrap();
+        let acl = AccessList {
+            public: true,
+            ..Default::default()
+        };
+        assert!(store.fork("a", "b", &acl, "alice").is_err());
+        store.set_access("a", &acl).unwrap();
+        store.fork("a", "b", &acl, "alice").unwrap();
+        assert!(store.fork("a", "b", &acl, "alice").is_err());
+        assert!(store.fork("c", "d", &acl, "alice").is_err());
+        assert!(!store.path("d").exists());
+        assert_eq!(AccessList::load(&store.path("b")).unwrap(), acl);
+        let info = ForkInfo::load(&store.path("b")).unwrap().unwrap();
+        assert_eq!((info.parent.as_str(), info.by.as_str()), ("a", "alice"));
+        assert_eq!(store.forks("a").unwrap(), vec!["b"]);
+
+        // Forks follow their parent's renames.
+        store.rename("a", "c").unwrap();
+        assert_eq!(store.forks("c").unwrap(), vec!["b"]);
+        let info = ForkInfo::load(&store.path("b")).unwrap().unwrap();
+        assert_eq!(info.parent, "c");
+
+        // Once the parent is private, so must be the fork.
+        store.set_access("c", &AccessList::default()).unwrap();
+        assert!(store.set_access("b", &acl).is_err());
+        store.set_access("b", &AccessList::default()).unwrap();
+    }
+}
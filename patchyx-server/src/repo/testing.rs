@@ -0,0 +1,110 @@
+//! Helpers for tests working on real repositories.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use libpijul::change::{Author, Change, ChangeHeader};
+use libpijul::changestore::ChangeStore;
+use libpijul::key::SKey;
+use libpijul::record::{Algorithm, Builder};
+use libpijul::working_copy::memory::Memory;
+use libpijul::{Hash, MutTxnT, MutTxnTExt};
+use pijul_repository::Repository;
+
+use super::SharedRepo;
+
+/// Create a repository in `path`.
+pub fn init(path: &Path) -> SharedRepo {
+    let repo = Repository::init(Some(path), None, None).unwrap();
+    repo.pristine.mut_txn_begin().unwrap().commit().unwrap();
+    Arc::new(Mutex::new(repo))
+}
+
+/// Record a change adding `file` to channel `main`.
+pub fn record(repo: &Repository, file: &str, contents: &[u8]) -> Hash {
+    record_with(repo, file, contents, Vec::new(), None)
+}
+
+/// Record a change adding `file` to channel `main`, by `authors` and
+/// signed with `key` as `pijul record` does.
+pub fn record_with(
+    repo: &Repository,
+    file: &str,
+    contents: &[u8],
+    authors: Vec<Author>,
+    key: Option<&SKey>,
+) -> Hash {
+    // The files of the channel, so that they aren't recorded as
+    // deleted.
+    let wc = Memory::new();
+    let txn = repo.pristine.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main").unwrap();
+    libpijul::output::output_repository_no_pending(
+        &wc,
+        &repo.changes,
+        &txn,
+        &channel,
+        "",
+        true,
+        None,
+        1,
+        0,
+    )
+    .unwrap();
+    wc.add_file(file, contents.to_vec());
+    txn.write().add_file(file, 0).unwrap();
+    let mut builder = Builder::new();
+    builder
+        .record(
+            txn.clone(),
+            Algorithm::default(),
+            false,
+            &libpijul::DEFAULT_SEPARATOR,
+            channel.clone(),
+            &wc,
+            &repo.changes,
+            "",
+            1,
+        )
+        .unwrap();
+    let rec = builder.finish();
+    let actions = rec
+        .actions
+        .into_iter()
+        .map(|a| a.globalize(&*txn.read()).unwrap())
+        .collect();
+    let mut change = Change::make_change(
+        &*txn.read(),
+        &channel,
+        actions,
+        std::mem::take(&mut *rec.contents.lock()),
+        ChangeHeader {
+            message: "test".to_string(),
+            authors,
+            description: None,
+            timestamp: jiff::Timestamp::now(),
+        },
+        Vec::new(),
+    )
+    .unwrap();
+    let hash = repo
+        .changes
+        .save_change(&mut change, |change, hash| {
+            if let Some(key) = key {
+                let signature = key.sign(&hash.to_bytes())?;
+                change.unhashed = Some(serde_json::json!({ "signature": signature }));
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .unwrap();
+    libpijul::apply::apply_local_change(
+        &mut *txn.write(),
+        &channel,
+        &change,
+        &hash,
+        &rec.updatables,
+    )
+    .unwrap();
+    txn.commit().unwrap();
+    hash
+}
@@ -0,0 +1,504 @@
+//! Repository operations of the Pijul remote protocol.
+//!
+//! These are the server-side halves of the requests sent by
+//! `pijul-remote` (`state`, `id`, `changelist`, `change`, `tag`,
+//! `apply`, `tagup`, `archive`, `identities`). They only deal with
+//! the repository (and the identities linked to users) and write
+//! their answers in the exact format the client expects, so that any
+//! transport can forward them as-is.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::bail;
+use byteorder::{BigEndian, WriteBytesExt};
+use libpijul::change::Change;
+use libpijul::changestore::filesystem::{push_filename, push_tag_filename, FileSystem};
+use libpijul::changestore::ChangeStore;
+use libpijul::pristine::sanakirja::Txn;
+use libpijul::pristine::{Position, RemoteId};
+use libpijul::{
+    ArcTxn, Base32, ChannelMutTxnT, ChannelRef, ChannelTxnT, DepsTxnT, GraphTxnT, Hash, Merkle,
+    MutTxnT, MutTxnTExt, TxnT, TxnTExt,
+};
+use pijul_repository::Repository;
+use tracing::{debug, warn};
+
+use crate::auth::identities as linked;
+
+/// Changes larger than this are only sent without their contents
+/// when the client asks for a `partial` change.
+pub const PARTIAL_CHANGE_SIZE: u64 = 1 << 20;
+
+/// Directory, relative to the `.pijul` directory, where identities
+/// are published.
+pub const IDENTITIES_DIR: &str = "identities";
+
+/// Load a channel from a read-only transaction.
+fn load_channel(txn: &Txn, name: &str) -> anyhow::Result<Option<ChannelRef<Txn>>> {
+    Ok(txn.load_channel(name)?)
+}
+
+/// Merkle state of the last tag at or before position `n`.
+fn last_tag<T: ChannelTxnT>(txn: &T, channel: &T::Channel, n: u64) -> anyhow::Result<Merkle>
+where
+    T::GraphError: Send + Sync + 'static,
+{
+    if let Some(x) = txn.rev_iter_tags(txn.tags(channel), Some(n))?.next() {
+        Ok((&x?.1.b).into())
+    } else {
+        Ok(Merkle::zero())
+    }
+}
+
+/// Answer a `state CHANNEL [N]` request: `N STATE TAG`, or `-` if the
+/// channel is empty or has no change at position `N`.
+pub fn state<W: Write>(
+    repo: &Repository,
+    channel: &str,
+    at: Option<u64>,
+    w: &mut W,
+) -> anyhow::Result<()> {
+    let txn = repo.pristine.txn_begin()?;
+    let Some(channel) = load_channel(&txn, channel)? else {
+        writeln!(w, "-")?;
+        return Ok(());
+    };
+    let channel = channel.read();
+    let found = if let Some(pos) = at {
+        match txn.log(&channel, pos)?.next() {
+            Some(x) => {
+                let (n, (_, m)) = x?;
+                (n == pos).then(|| (n, m.into()))
+            }
+            None => None,
+        }
+    } else if let Some(x) = txn.reverse_log(&channel, None)?.next() {
+        let (n, (_, m)) = x?;
+        Some((n, m.into()))
+    } else {
+        None
+    };
+    if let Some((n, m)) = found {
+        let m: Merkle = m;
+        let tag = last_tag(&txn, &channel, n)?;
+        writeln!(w, "{} {} {}", n, m.to_base32(), tag.to_base32())?;
+    } else {
+        writeln!(w, "-")?;
+    }
+    Ok(())
+}
+
+/// Remote id of a channel, if it exists.
+pub fn channel_id(repo: &Repository, channel: &str) -> anyhow::Result<Option<RemoteId>> {
+    let txn = repo.pristine.txn_begin()?;
+    Ok(load_channel(&txn, channel)?.and_then(|c| txn.id(&c.read()).cloned()))
+}
+
+/// Answer an `id CHANNEL` request with the channel's remote id.
+pub fn id<W: Write>(repo: &Repository, channel: &str, w: &mut W) -> anyhow::Result<()> {
+    if let Some(id) = channel_id(repo, channel)? {
+        writeln!(w, "{}", id)?;
+    } else {
+        writeln!(w, "-")?;
+    }
+    Ok(())
+}
+
+/// Answer a `changelist CHANNEL FROM [PATHS]` request.
+///
+/// The positions of the requested paths come first (`HASH.POS`),
+/// followed by one `N.HASH.STATE` line per change (with a trailing
+/// `.` for tagged states), and an empty line.
+pub fn changelist<W: Write>(
+    repo: &Repository,
+    channel: &str,
+    from: u64,
+    paths: &[String],
+    w: &mut W,
+) -> anyhow::Result<()> {
+    let txn = repo.pristine.txn_begin()?;
+    let Some(channel) = load_channel(&txn, channel)? else {
+        writeln!(w)?;
+        return Ok(());
+    };
+    let mut positions = Vec::new();
+    for path in paths {
+        let Ok((pos, ambiguous)) = txn.follow_oldest_path(&repo.changes, &channel, path) else {
+            writeln!(w, "error: Path not found: {}", path)?;
+            writeln!(w)?;
+            return Ok(());
+        };
+        if ambiguous {
+            writeln!(w, "error: Ambiguous path: {}", path)?;
+            writeln!(w)?;
+            return Ok(());
+        }
+        let h: Hash = txn.get_external(&pos.change)?.unwrap().into();
+        writeln!(w, "{}.{}", h.to_base32(), pos.pos.0)?;
+        positions.push(pos);
+    }
+    let channel = channel.read();
+    let tags: Vec<u64> = txn
+        .iter_tags(txn.tags(&channel), from)?
+        .map(|t| t.map(|(n, _)| u64::from_le(n.0)))
+        .collect::<Result<_, _>>()?;
+    let mut tags = tags.into_iter().peekable();
+    for x in txn.log(&channel, from)? {
+        let (n, (h, m)) = x?;
+        let h_int = txn.get_internal(h)?.unwrap();
+        let touches = positions.is_empty()
+            || positions.iter().any(|pos: &Position<_>| {
+                txn.get_touched_files(pos, Some(h_int))
+                    .ok()
+                    .flatten()
+                    .is_some()
+            });
+        while tags.peek().is_some_and(|t| *t < n) {
+            tags.next();
+        }
+        let tagged = tags.peek() == Some(&n);
+        if touches {
+            let h: Hash = h.into();
+            let m: Merkle = m.into();
+            write!(w, "{}.{}.{}", n, h.to_base32(), m.to_base32())?;
+            if tagged {
+                write!(w, ".")?;
+            }
+            writeln!(w)?;
+        }
+    }
+    writeln!(w)?;
+    Ok(())
+}
+
+/// Answer a `change HASH` or `partial HASH` request: the length of
+/// the change file as a big-endian `u64`, followed by its bytes.
+pub fn change<W: Write>(
+    repo: &Repository,
+    hash: &Hash,
+    partial: bool,
+    w: &mut W,
+) -> anyhow::Result<()> {
+    let mut path = repo.changes_dir.clone();
+    push_filename(&mut path, hash);
+    let mut f = std::fs::File::open(&path)?;
+    let size = f.metadata()?.len();
+    let size = if partial && size > PARTIAL_CHANGE_SIZE {
+        let size = Change::size_no_contents(&mut f)?;
+        f = std::fs::File::open(&path)?;
+        size
+    } else {
+        size
+    };
+    w.write_u64::<BigEndian>(size)?;
+    std::io::copy(&mut f.take(size), w)?;
+    Ok(())
+}
+
+/// Read the short version of a tag file.
+pub fn tag_short(repo: &Repository, state: &Merkle) -> anyhow::Result<Vec<u8>> {
+    let mut path = repo.changes_dir.clone();
+    push_tag_filename(&mut path, state);
+    let mut tag = libpijul::tag::OpenTagFile::open(&path, state)?;
+    let mut buf = Vec::new();
+    tag.short(&mut buf)?;
+    Ok(buf)
+}
+
+/// Answer a `tag STATE` request with the short version of a tag file.
+pub fn tag<W: Write>(repo: &Repository, state: &Merkle, w: &mut W) -> anyhow::Result<()> {
+    let buf = tag_short(repo, state)?;
+    w.write_u64::<BigEndian>(buf.len() as u64)?;
+    w.write_all(&buf)?;
+    Ok(())
+}
+
+/// Write `bytes` to `path` atomically, creating parent directories.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Remove the change files of `hashes`, written by an operation that
+/// failed. Changes the pristine knows are in use by a channel, and
+/// are kept. The repository must stay locked since the files were
+/// written.
+pub fn remove_unused_changes(repo: &Repository, hashes: &[Hash]) {
+    let txn = match repo.pristine.txn_begin() {
+        Ok(txn) => txn,
+        Err(e) => {
+            warn!(error = %e, "Cannot check which changes are in use");
+            return;
+        }
+    };
+    for hash in hashes {
+        match txn.get_internal(&hash.into()) {
+            Ok(None) => {
+                if let Err(e) = repo.changes.del_change(hash) {
+                    warn!(change = %hash.to_base32(), error = %e, "Cannot remove change");
+                }
+            }
+            Ok(Some(_)) => {}
+            Err(e) => warn!(change = %hash.to_base32(), error = %e, "Cannot check change"),
+        }
+    }
+}
+
+/// Apply changes from the change store to `channel`, which is
+/// created if needed, along with their dependencies missing from the
+/// channel, in a single transaction. Before committing,
+/// `check` is called with the old and new states of the channel, and
+/// can cancel everything by failing. Returns the new state.
+pub fn apply<F>(
+    repo: &Repository,
+    channel: &str,
+    hashes: &[Hash],
+    check: F,
+) -> anyhow::Result<Merkle>
+where
+    F: FnOnce(Merkle, Merkle) -> anyhow::Result<()>,
+{
+    let txn = repo.pristine.arc_txn_begin()?;
+    let channel = txn.write().open_or_create_channel(channel)?;
+    let old_state = txn.read().current_state(&channel.read())?;
+    let mut ws = libpijul::ApplyWorkspace::new();
+    for hash in hashes {
+        txn.write()
+            .apply_change_rec_ws(&repo.changes, &mut channel.write(), hash, &mut ws)?;
+        debug!(
+            "applied {} to {}",
+            hash.to_base32(),
+            txn.read().name(&channel.read())
+        );
+    }
+    let state = txn.read().current_state(&channel.read())?;
+    check(old_state, state)?;
+    std::mem::drop(channel);
+    txn.commit()?;
+    Ok(state)
+}
+
+/// Handle a `tagup STATE CHANNEL LEN` request, where `short` is the
+/// short tag file sent by the client.
+pub fn tagup(repo: &Repository, state: &Merkle, channel: &str, short: &[u8]) -> anyhow::Result<()> {
+    let txn = repo.pristine.arc_txn_begin()?;
+    let Some(channel_ref) = txn.read().load_channel(channel)? else {
+        bail!("Channel not found: {}", channel)
+    };
+    let current = txn.read().current_state(&channel_ref.read())?;
+    if &current != state {
+        bail!("Wrong state, cannot tag")
+    }
+    let mut path = repo.changes_dir.clone();
+    push_tag_filename(&mut path, state);
+    if path.exists() {
+        bail!("Tag for state {} already exists", state.to_base32());
+    }
+    let last_t = if let Some(n) = txn.read().reverse_log(&channel_ref.read(), None)?.next() {
+        n?.0
+    } else {
+        bail!("Channel {} is empty", channel);
+    };
+    if txn
+        .read()
+        .is_tagged(txn.read().tags(&channel_ref.read()), last_t)?
+    {
+        bail!("Current state is already tagged")
+    }
+    let header = libpijul::tag::read_short(std::io::Cursor::new(short), state)?;
+    let mut tag = Vec::new();
+    libpijul::tag::from_channel(&*txn.read(), channel, &header, &mut tag)?;
+    write_atomic(&path, &tag)?;
+    {
+        let mut txn_ = txn.write();
+        let mut channel_ = channel_ref.write();
+        let tags = txn_.tags_mut(&mut channel_);
+        txn_.put_tags(tags, last_t, state)?;
+    }
+    std::mem::drop(channel_ref);
+    txn.commit()?;
+    Ok(())
+}
+
+/// Output a channel as a gzipped tarball, returning the tarball and
+/// the number of conflicts.
+pub fn tarball(
+    repo: &Repository,
+    channel: &str,
+    state: Option<(Merkle, Vec<Hash>)>,
+    prefix: Option<&str>,
+) -> anyhow::Result<(Vec<u8>, usize)> {
+    let mut tarball_bytes = Vec::new();
+    let conflicts = {
+        let mut tarball = libpijul::output::Tarball::new(&mut tarball_bytes, None, 0);
+        write_archive(repo, channel, state, prefix, &mut tarball)?
+    };
+    Ok((tarball_bytes, conflicts.len()))
+}
+
+/// Answer an `archive CHANNEL [STATE [EXTRA...]] [:PREFIX]` request:
+/// the length of the gzipped tarball and the number of conflicts, as
+/// big-endian `u64`s, followed by the tarball.
+pub fn archive<W: Write>(
+    repo: &Repository,
+    channel: &str,
+    state: Option<(Merkle, Vec<Hash>)>,
+    prefix: Option<&str>,
+    w: &mut W,
+) -> anyhow::Result<()> {
+    let (tarball_bytes, conflicts) = tarball(repo, channel, state, prefix)?;
+    w.write_u64::<BigEndian>(tarball_bytes.len() as u64)?;
+    w.write_u64::<BigEndian>(conflicts as u64)?;
+    w.write_all(&tarball_bytes)?;
+    Ok(())
+}
+
+/// A read-only transaction on `repo`, if `state` is absent or the
+/// current state of `channel`: archives of the current state can be
+/// written from it without holding the repository.
+pub fn snapshot(
+    repo: &Repository,
+    channel: &str,
+    state: Option<&(Merkle, Vec<Hash>)>,
+) -> anyhow::Result<Option<Txn>> {
+    let txn = repo.pristine.txn_begin()?;
+    let Some(channel_ref) = load_channel(&txn, channel)? else {
+        bail!("Channel not found: {}", channel)
+    };
+    let current = match state {
+        None => true,
+        Some((state, extra)) => {
+            extra.is_empty() && txn.current_state(&channel_ref.read())? == *state
+        }
+    };
+    std::mem::drop(channel_ref);
+    Ok(current.then_some(txn))
+}
+
+/// Output the current state of a channel into `arch`, from a
+/// transaction returned by [`snapshot`].
+pub fn write_snapshot_archive<A: libpijul::Archive>(
+    txn: Txn,
+    changes: &FileSystem,
+    channel: &str,
+    prefix: Option<&str>,
+    arch: &mut A,
+) -> anyhow::Result<Vec<libpijul::Conflict>>
+where
+    A::Error: Send + Sync + 'static,
+{
+    type Memory = libpijul::working_copy::memory::Memory;
+    let Some(channel_ref) = load_channel(&txn, channel)? else {
+        bail!("Channel not found: {}", channel)
+    };
+    let txn = ArcTxn::new(txn);
+    let mut prefix = prefix.unwrap_or("").split('/').filter(|x| !x.is_empty());
+    Ok(txn.archive_prefix::<_, _, _, Memory>(changes, &channel_ref, &mut prefix, arch)?)
+}
+
+/// Output a channel, optionally at an earlier state, into `arch`.
+///
+/// Reaching an earlier state requires unrecording changes, which is
+/// done in a temporary fork inside a transaction that is never
+/// committed.
+pub fn write_archive<A: libpijul::Archive>(
+    repo: &Repository,
+    channel: &str,
+    state: Option<(Merkle, Vec<Hash>)>,
+    prefix: Option<&str>,
+    arch: &mut A,
+) -> anyhow::Result<Vec<libpijul::Conflict>>
+where
+    A::Error: Send + Sync + 'static,
+{
+    if let Some(txn) = snapshot(repo, channel, state.as_ref())? {
+        return write_snapshot_archive(txn, &repo.changes, channel, prefix, arch);
+    }
+    let (state, extra) = state.expect("archives of the current state use a snapshot");
+    let txn = repo.pristine.arc_txn_begin()?;
+    let Some(channel_ref) = txn.read().load_channel(channel)? else {
+        bail!("Channel not found: {}", channel)
+    };
+    let mut prefix = prefix.unwrap_or("").split('/').filter(|x| !x.is_empty());
+    let fork_name = format!("archive-{}", jiff::Timestamp::now().as_nanosecond());
+    let fork = txn.write().fork(&channel_ref, &fork_name)?;
+    Ok(txn.archive_prefix_with_state(
+        &repo.changes,
+        &fork,
+        &state,
+        &extra,
+        &mut prefix,
+        arch,
+        0,
+        &libpijul::working_copy::memory::Memory::new(),
+    )?)
+}
+
+/// Read the identities modified after `rev` (in seconds), or all of
+/// them: those published in the repository, and those linked to the
+/// users of the server in `users_dir`, which take precedence for the
+/// same key since their owners proved they own it.
+pub fn read_identities(
+    repo: &Repository,
+    users_dir: &Path,
+    rev: Option<i64>,
+) -> anyhow::Result<Vec<(i64, serde_json::Value)>> {
+    let dir = repo.path.join(libpijul::DOT_DIR).join(IDENTITIES_DIR);
+    let mut identities = BTreeMap::new();
+    if let Ok(entries) = std::fs::read_dir(&dir) {
+        for entry in entries.flatten() {
+            let Ok(contents) = std::fs::read(entry.path()) else {
+                continue;
+            };
+            let Ok(id) = serde_json::from_slice::<serde_json::Value>(&contents) else {
+                continue;
+            };
+            let modified = id
+                .get("last_modified")
+                .and_then(|x| x.as_str())
+                .and_then(|x| x.parse::<jiff::Timestamp>().ok())
+                .map(|x| x.as_second())
+                .unwrap_or(0);
+            let key = id
+                .get("public_key")
+                .and_then(|k| k.get("key"))
+                .and_then(|k| k.as_str())
+                .map(String::from)
+                .unwrap_or_else(|| entry.file_name().to_string_lossy().into_owned());
+            identities.insert(key, (modified, id));
+        }
+    }
+    for id in linked::all(users_dir)? {
+        let modified = id.last_modified.as_second();
+        identities.insert(
+            id.public_key.key.clone(),
+            (modified, serde_json::to_value(&id)?),
+        );
+    }
+    Ok(identities
+        .into_values()
+        .filter(|(modified, _)| rev.is_none_or(|rev| *modified > rev))
+        .collect())
+}
+
+/// Answer an `identities [REV]` request: one JSON identity per line,
+/// only those modified after `REV` (in seconds) if given, followed by
+/// an empty line.
+pub fn identities<W: Write>(
+    repo: &Repository,
+    users_dir: &Path,
+    rev: Option<i64>,
+    w: &mut W,
+) -> anyhow::Result<()> {
+    for (_, id) in read_identities(repo, users_dir, rev)? {
+        serde_json::to_writer(&mut *w, &id)?;
+        writeln!(w)?;
+    }
+    writeln!(w)?;
+    Ok(())
+}
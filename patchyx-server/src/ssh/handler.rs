@@ -2,68 +2,170 @@
 //!
 //! Implements the thrussh Server and Handler traits for handling
 //! SSH connections and Pijul protocol commands.
+//!
+//! Connections over a rate limit are refused: their authentication
+//! attempts are all rejected. SSH has no way of telling a client why
+//! a public key was rejected, so the reason is the instruction of a
+//! keyboard-interactive challenge without prompts, which clients
+//! trying that method next show to their user.
 
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
+use anyhow::bail;
 use thrussh::{server, ChannelId, CryptoVec};
 use thrussh_keys::key::PublicKey;
 use tokio::sync::Mutex;
 use tracing::{debug, error, info, warn};
 
-use super::protocol::PijulCommand;
-use crate::config::ServerConfig;
+use super::protocol::{PijulCommand, PROTOCOL_VERSION};
+use super::session::ProtocolSession;
+use crate::auth::{Caller, KeyStore, Role};
+use crate::config::{AuthBackend, SharedConfig};
+use crate::metrics;
+use crate::ratelimit::{Key, Limiters};
+use crate::repo::RepoStore;
+
+type BoxFuture<T> = Pin<Box<dyn futures::future::Future<Output = T> + Send>>;
 
 /// Per-channel session state.
-#[derive(Debug)]
 struct ChannelState {
     /// The authenticated username
     user: String,
-    /// The command being executed, if any
-    command: Option<PijulCommand>,
-    /// Buffer for incoming data
-    buffer: Vec<u8>,
+    /// The command being executed
+    command: PijulCommand,
+    /// The protocol session answering the client's requests
+    session: ProtocolSession,
 }
 
 /// SSH server state.
 #[derive(Clone)]
 pub struct SshServer {
     /// Server configuration
-    config: Arc<ServerConfig>,
+    config: Arc<SharedConfig>,
+    /// Hosted repositories
+    repos: Arc<RepoStore>,
+    /// Authorized public keys
+    keys: Arc<KeyStore>,
+    /// Rate limits
+    limiters: Arc<Limiters>,
     /// Active channel sessions
     channels: Arc<Mutex<HashMap<ChannelId, ChannelState>>>,
     /// Connection ID for logging
     conn_id: u64,
+    /// Address of the client
+    peer_addr: Option<SocketAddr>,
+    /// The authenticated username, once authentication succeeded
+    user: Option<String>,
+    /// Why the connection is refused, if it is
+    refused: Option<String>,
 }
 
 impl SshServer {
     /// Create a new SSH server instance.
-    pub fn new(config: Arc<ServerConfig>, conn_id: u64) -> Self {
-        Self {
+    pub fn new(
+        config: Arc<SharedConfig>,
+        repos: Arc<RepoStore>,
+        keys: Arc<KeyStore>,
+        limiters: Arc<Limiters>,
+        conn_id: u64,
+        peer_addr: Option<SocketAddr>,
+    ) -> Self {
+        let mut server = Self {
             config,
+            repos,
+            keys,
+            limiters,
             channels: Arc::new(Mutex::new(HashMap::new())),
             conn_id,
+            peer_addr,
+            user: None,
+            refused: None,
+        };
+        if let Some(addr) = peer_addr {
+            let limit = server.config.get().limits.ssh_connections;
+            let connections = &server.limiters.ssh_connections;
+            if let Err(e) = connections.check(&limit, Key::Addr(addr.ip())) {
+                server.refuse(e.message(&format!("SSH connections from {}", addr.ip())));
+            }
+        }
+        server
+    }
+
+    /// Refuse the connection, telling the client why if it tries
+    /// keyboard-interactive authentication.
+    fn refuse(&mut self, reason: String) {
+        warn!(conn = self.conn_id, peer = ?self.peer_addr, reason = %reason, "Connection refused");
+        self.refused.get_or_insert(reason);
+    }
+
+    /// Check the rate limits of authentication attempts as `user`,
+    /// returning whether the key should be checked. Refused
+    /// connections don't get that far.
+    fn check_auth_rate(&mut self, user: &str) -> bool {
+        if self.refused.is_some() {
+            return false;
+        }
+        let limit = self.config.get().limits.auth_failures;
+        let failures = &self.limiters.auth_failures;
+        let mut keys = vec![(
+            Key::User(user.to_string()),
+            format!("failed logins as {}", user),
+        )];
+        if let Some(addr) = self.peer_addr {
+            let what = format!("failed logins from {}", addr.ip());
+            keys.push((Key::Addr(addr.ip()), what));
         }
+        for (key, what) in keys {
+            if let Err(e) = failures.peek(&limit, &key) {
+                self.refuse(e.message(&what));
+                return false;
+            }
+        }
+        true
     }
 
-    /// Get the repository path for a given repo name.
-    fn repo_path(&self, name: &str) -> PathBuf {
-        self.config.repos_dir.join(name)
+    /// Count a failed authentication attempt as `user`.
+    fn auth_failed(&self, user: &str) {
+        let limit = self.config.get().limits.auth_failures;
+        let failures = &self.limiters.auth_failures;
+        let _ = failures.check(&limit, Key::User(user.to_string()));
+        if let Some(addr) = self.peer_addr {
+            let _ = failures.check(&limit, Key::Addr(addr.ip()));
+        }
     }
 
-    /// Check if the repository exists.
-    fn repo_exists(&self, name: &str) -> bool {
-        let path = self.repo_path(name);
-        path.exists() && path.is_dir()
+    /// The authenticated caller of this connection.
+    fn caller(&self) -> Caller {
+        let caller = match self.user {
+            Some(ref user) => Caller::user(user, &self.config.get()),
+            None => Caller::anonymous(),
+        };
+        caller.with_addr(self.peer_addr.map(|a| a.ip()))
+    }
+
+    /// Report an error to the client on standard error and terminate
+    /// the channel with a non-zero exit status.
+    fn fail(channel: ChannelId, msg: &str, session: &mut server::Session) {
+        session.extended_data(
+            channel,
+            1,
+            CryptoVec::from_slice(format!("{}\n", msg).as_bytes()),
+        );
+        session.exit_status_request(channel, 1);
+        session.eof(channel);
+        session.close(channel);
     }
 
     /// Handle a Pijul command execution.
     async fn handle_command(
         &self,
         channel: ChannelId,
-        cmd: &PijulCommand,
+        cmd: PijulCommand,
         session: &mut server::Session,
     ) -> anyhow::Result<()> {
         info!(
@@ -73,94 +175,70 @@ impl SshServer {
             "Executing Pijul command"
         );
 
+        let repo = RepoStore::normalize_name(cmd.repo())?;
+        let access = self.repos.access(repo, &self.caller())?;
+        access.require(Role::Read)?;
         match cmd {
-            PijulCommand::Ping { repo } => {
-                if self.repo_exists(repo) {
-                    session.data(channel, CryptoVec::from_slice(b"pong\n"));
-                    session.exit_status_request(channel, 0);
-                } else {
-                    session.data(
-                        channel,
-                        CryptoVec::from_slice(format!("Repository not found: {}\n", repo).as_bytes()),
-                    );
-                    session.exit_status_request(channel, 1);
-                }
-            }
-            PijulCommand::Clone { repo, channel: ch } => {
-                if !self.repo_exists(repo) {
-                    session.data(
-                        channel,
-                        CryptoVec::from_slice(format!("Repository not found: {}\n", repo).as_bytes()),
-                    );
-                    session.exit_status_request(channel, 1);
-                    return Ok(());
-                }
-
-                // TODO: Implement actual clone using libpijul
-                let msg = format!(
-                    "PIJUL_CLONE {} {}\n",
-                    repo,
-                    ch.as_deref().unwrap_or("main")
-                );
-                session.data(channel, CryptoVec::from_slice(msg.as_bytes()));
+            PijulCommand::Ping { .. } => {
+                session.data(channel, CryptoVec::from_slice(b"pong\n"));
                 session.exit_status_request(channel, 0);
+                session.close(channel);
             }
-            PijulCommand::Pull { repo, channel: ch } => {
-                if !self.repo_exists(repo) {
-                    session.data(
-                        channel,
-                        CryptoVec::from_slice(format!("Repository not found: {}\n", repo).as_bytes()),
+            PijulCommand::Protocol { version, .. } => {
+                if version != PROTOCOL_VERSION {
+                    bail!(
+                        "Unsupported protocol version {} (server speaks {})",
+                        version,
+                        PROTOCOL_VERSION
                     );
-                    session.exit_status_request(channel, 1);
-                    return Ok(());
                 }
-
-                // TODO: Implement actual pull using libpijul
-                let msg = format!(
-                    "PIJUL_PULL {} {}\n",
-                    repo,
-                    ch.as_deref().unwrap_or("main")
-                );
-                session.data(channel, CryptoVec::from_slice(msg.as_bytes()));
-                session.exit_status_request(channel, 0);
-            }
-            PijulCommand::Push { repo, channel: ch } => {
-                if !self.repo_exists(repo) {
-                    session.data(
-                        channel,
-                        CryptoVec::from_slice(format!("Repository not found: {}\n", repo).as_bytes()),
+                let config = self.config.get();
+                let max_channels = config.limits.max_channels;
+                if self.channels.lock().await.len() >= max_channels {
+                    bail!(
+                        "Too many concurrent sessions on this connection (limit {})",
+                        max_channels
                     );
-                    session.exit_status_request(channel, 1);
-                    return Ok(());
                 }
-
-                // TODO: Implement actual push using libpijul
-                let msg = format!(
-                    "PIJUL_PUSH {} {}\n",
-                    repo,
-                    ch.as_deref().unwrap_or("main")
+                let settings = config.repo_settings(repo);
+                let repo = self.repos.open(repo)?;
+                self.channels.lock().await.insert(
+                    channel,
+                    ChannelState {
+                        user: self.user.clone().unwrap_or_default(),
+                        command: cmd,
+                        session: ProtocolSession::new(repo, access, settings),
+                    },
                 );
-                session.data(channel, CryptoVec::from_slice(msg.as_bytes()));
-                session.exit_status_request(channel, 0);
+                session.channel_success(channel);
             }
         }
-
-        session.close(channel);
         Ok(())
     }
 }
 
 /// Factory for creating new SSH server handlers per connection.
 pub struct SshServerFactory {
-    config: Arc<ServerConfig>,
-    next_conn_id: Arc<Mutex<u64>>,
+    config: Arc<SharedConfig>,
+    repos: Arc<RepoStore>,
+    keys: Arc<KeyStore>,
+    limiters: Arc<Limiters>,
+    next_conn_id: Arc<AtomicU64>,
 }
 
 impl SshServerFactory {
-    pub fn new(config: Arc<ServerConfig>) -> Self {
+    pub fn new(
+        config: Arc<SharedConfig>,
+        repos: Arc<RepoStore>,
+        keys: Arc<KeyStore>,
+        limiters: Arc<Limiters>,
+    ) -> Self {
         Self {
             config,
-            next_conn_id: Arc::new(Mutex::new(0)),
+            repos,
+            keys,
+            limiters,
+            next_conn_id: Arc::new(AtomicU64::new(0)),
         }
     }
 }
@@ -168,24 +246,28 @@ impl SshServerFactory {
 impl server::Server for SshServerFactory {
     type Handler = SshServer;
 
-    fn new(&mut self, _peer_addr: Option<SocketAddr>) -> SshServer {
-        // Generate connection ID synchronously for simplicity
-        let conn_id = {
-            let mut guard = self.next_conn_id.blocking_lock();
-            let id = *guard;
-            *guard += 1;
-            id
-        };
-        info!(conn = conn_id, peer = ?_peer_addr, "New SSH connection");
-        SshServer::new(self.config.clone(), conn_id)
+    fn new(&mut self, peer_addr: Option<SocketAddr>) -> SshServer {
+        let conn_id = self.next_conn_id.fetch_add(1, Ordering::Relaxed);
+        info!(conn = conn_id, peer = ?peer_addr, "New SSH connection");
+        metrics::SSH_CONNECTIONS.inc(&[]);
+        SshServer::new(
+            self.config.clone(),
+            self.repos.clone(),
+            self.keys.clone(),
+            self.limiters.clone(),
+            conn_id,
+            peer_addr,
+        )
     }
 }
 
 impl server::Handler for SshServer {
     type Error = anyhow::Error;
-    type FutureAuth = futures::future::Ready<std::result::Result<(Self, server::Auth), anyhow::Error>>;
-    type FutureUnit = futures::future::Ready<std::result::Result<(Self, server::Session), anyhow::Error>>;
-    type FutureBool = futures::future::Ready<std::result::Result<(Self, server::Session, bool), anyhow::Error>>;
+    type FutureAuth =
+        futures::future::Ready<std::result::Result<(Self, server::Auth), anyhow::Error>>;
+    type FutureUnit = BoxFuture<std::result::Result<(Self, server::Session), anyhow::Error>>;
+    type FutureBool =
+        futures::future::Ready<std::result::Result<(Self, server::Session, bool), anyhow::Error>>;
 
     fn finished_auth(self, auth: server::Auth) -> Self::FutureAuth {
         futures::future::ready(Ok((self, auth)))
@@ -196,10 +278,10 @@ impl server::Handler for SshServer {
     }
 
     fn finished(self, session: server::Session) -> Self::FutureUnit {
-        futures::future::ready(Ok((self, session)))
+        Box::pin(futures::future::ready(Ok((self, session))))
     }
 
-    fn auth_publickey(self, user: &str, public_key: &PublicKey) -> Self::FutureAuth {
+    fn auth_publickey(mut self, user: &str, public_key: &PublicKey) -> Self::FutureAuth {
         info!(
             conn = self.conn_id,
             user = user,
@@ -207,14 +289,34 @@ impl server::Handler for SshServer {
             "Public key authentication attempt"
         );
 
-        // TODO: Validate public key against stored keys for user
-        warn!(
-            conn = self.conn_id,
-            user = user,
-            "Accepting all keys (development mode)"
-        );
-
-        self.finished_auth(server::Auth::Accept)
+        if !self.check_auth_rate(user) {
+            metrics::AUTH_ATTEMPTS.inc(&["ssh_key", "refused"]);
+            return self.finished_auth(server::Auth::Reject);
+        }
+        let config = self.config.get();
+        let enabled = config.auth.enabled(AuthBackend::Keys);
+        if enabled && self.keys.is_authorized(user, public_key) {
+            let limit = config.limits.ssh_connections;
+            let key = Key::User(user.to_string());
+            if let Err(e) = self.limiters.ssh_connections.check(&limit, key) {
+                metrics::AUTH_ATTEMPTS.inc(&["ssh_key", "refused"]);
+                self.refuse(e.message(&format!("SSH connections by {}", user)));
+                return self.finished_auth(server::Auth::Reject);
+            }
+            metrics::AUTH_ATTEMPTS.inc(&["ssh_key", "success"]);
+            self.user = Some(user.to_string());
+            self.finished_auth(server::Auth::Accept)
+        } else {
+            metrics::AUTH_ATTEMPTS.inc(&["ssh_key", "failure"]);
+            self.auth_failed(user);
+            warn!(
+                conn = self.conn_id,
+                user = user,
+                fingerprint = %crate::auth::keys::fingerprint(public_key),
+                "Unknown public key"
+            );
+            self.finished_auth(server::Auth::Reject)
+        }
     }
 
     fn auth_none(self, user: &str) -> Self::FutureAuth {
@@ -222,13 +324,38 @@ impl server::Handler for SshServer {
         self.finished_auth(server::Auth::Reject)
     }
 
+    fn auth_keyboard_interactive(
+        self,
+        user: &str,
+        _submethods: &str,
+        response: Option<server::Response>,
+    ) -> Self::FutureAuth {
+        debug!(
+            conn = self.conn_id,
+            user = user,
+            "Keyboard-interactive auth rejected"
+        );
+        match self.refused {
+            // Tell refused clients why, then reject their answer.
+            Some(ref reason) if response.is_none() => {
+                let auth = server::Auth::Partial {
+                    name: Cow::Borrowed(""),
+                    instructions: Cow::Owned(format!("Error: {}", reason)),
+                    prompts: Cow::Borrowed(&[]),
+                };
+                self.finished_auth(auth)
+            }
+            _ => self.finished_auth(server::Auth::Reject),
+        }
+    }
+
     fn channel_open_session(
         self,
         channel: ChannelId,
         session: server::Session,
     ) -> Self::FutureUnit {
         debug!(conn = self.conn_id, channel = ?channel, "Channel opened");
-        futures::future::ready(Ok((self, session)))
+        self.finished(session)
     }
 
     fn exec_request(
@@ -237,7 +364,7 @@ impl server::Handler for SshServer {
         data: &[u8],
         mut session: server::Session,
     ) -> Self::FutureUnit {
-        let command_str = String::from_utf8_lossy(data);
+        let command_str = String::from_utf8_lossy(data).into_owned();
         info!(
             conn = self.conn_id,
             channel = ?channel,
@@ -245,53 +372,38 @@ impl server::Handler for SshServer {
             "Exec request"
         );
 
-        match PijulCommand::parse(&command_str) {
-            Ok(cmd) => {
-                let result = tokio::task::block_in_place(|| {
-                    tokio::runtime::Handle::current().block_on(async {
-                        self.handle_command(channel, &cmd, &mut session).await
-                    })
-                });
-
-                if let Err(e) = result {
-                    error!(
+        Box::pin(async move {
+            match PijulCommand::parse(&command_str) {
+                Ok(cmd) => {
+                    if let Err(e) = self.handle_command(channel, cmd, &mut session).await {
+                        error!(
+                            conn = self.conn_id,
+                            channel = ?channel,
+                            error = %e,
+                            "Command execution failed"
+                        );
+                        Self::fail(channel, &format!("Error: {}", e), &mut session);
+                    }
+                }
+                Err(e) => {
+                    warn!(
                         conn = self.conn_id,
                         channel = ?channel,
                         error = %e,
-                        "Command execution failed"
+                        "Invalid command"
                     );
-                    session.data(
-                        channel,
-                        CryptoVec::from_slice(format!("Error: {}\n", e).as_bytes()),
-                    );
-                    session.exit_status_request(channel, 1);
-                    session.close(channel);
+                    Self::fail(channel, &format!("Invalid command: {}", e), &mut session);
                 }
             }
-            Err(e) => {
-                warn!(
-                    conn = self.conn_id,
-                    channel = ?channel,
-                    error = %e,
-                    "Invalid command"
-                );
-                session.data(
-                    channel,
-                    CryptoVec::from_slice(format!("Invalid command: {}\n", e).as_bytes()),
-                );
-                session.exit_status_request(channel, 1);
-                session.close(channel);
-            }
-        }
-
-        futures::future::ready(Ok((self, session)))
+            Ok((self, session))
+        })
     }
 
     fn data(
         self,
         channel: ChannelId,
         data: &[u8],
-        session: server::Session,
+        mut session: server::Session,
     ) -> Self::FutureUnit {
         debug!(
             conn = self.conn_id,
@@ -299,16 +411,74 @@ impl server::Handler for SshServer {
             len = data.len(),
             "Received data"
         );
-        futures::future::ready(Ok((self, session)))
+        metrics::TRANSFERRED_BYTES.add(&["ssh", "in"], data.len() as u64);
+        let data = data.to_vec();
+        Box::pin(async move {
+            {
+                let mut channels = self.channels.lock().await;
+                if let Some(state) = channels.get_mut(&channel) {
+                    let mut out = Vec::new();
+                    // Repository operations block on disk I/O.
+                    let result =
+                        tokio::task::block_in_place(|| state.session.feed(&data, &mut out));
+                    if !out.is_empty() {
+                        metrics::TRANSFERRED_BYTES.add(&["ssh", "out"], out.len() as u64);
+                        session.data(channel, CryptoVec::from_slice(&out));
+                    }
+                    if let Err(e) = result {
+                        error!(
+                            conn = self.conn_id,
+                            channel = ?channel,
+                            user = %state.user,
+                            cmd = ?state.command,
+                            error = %e,
+                            "Protocol request failed"
+                        );
+                        channels.remove(&channel);
+                        Self::fail(channel, &format!("Error: {}", e), &mut session);
+                    }
+                } else {
+                    debug!(conn = self.conn_id, channel = ?channel, "No session for data");
+                }
+            }
+            Ok((self, session))
+        })
     }
 
     fn channel_close(self, channel: ChannelId, session: server::Session) -> Self::FutureUnit {
         debug!(conn = self.conn_id, channel = ?channel, "Channel closed");
-        futures::future::ready(Ok((self, session)))
+        Box::pin(async move {
+            self.channels.lock().await.remove(&channel);
+            Ok((self, session))
+        })
     }
 
-    fn channel_eof(self, channel: ChannelId, session: server::Session) -> Self::FutureUnit {
+    fn channel_eof(self, channel: ChannelId, mut session: server::Session) -> Self::FutureUnit {
         debug!(conn = self.conn_id, channel = ?channel, "Channel EOF");
-        futures::future::ready(Ok((self, session)))
+        Box::pin(async move {
+            // The client is done sending requests: apply its push, if
+            // any, and end the session.
+            let state = self.channels.lock().await.remove(&channel);
+            if let Some(mut state) = state {
+                match tokio::task::block_in_place(|| state.session.finish()) {
+                    Ok(()) => {
+                        session.exit_status_request(channel, 0);
+                        session.eof(channel);
+                        session.close(channel);
+                    }
+                    Err(e) => {
+                        error!(
+                            conn = self.conn_id,
+                            channel = ?channel,
+                            user = %state.user,
+                            error = %e,
+                            "Push failed"
+                        );
+                        Self::fail(channel, &format!("Error: {}", e), &mut session);
+                    }
+                }
+            }
+            Ok((self, session))
+        })
     }
 }
@@ -0,0 +1,382 @@
+//! SSH host keys.
+//!
+//! The server has a host key per configured algorithm: the Ed25519
+//! key is stored at `host_key_path`, the RSA key next to it with an
+//! `_rsa` suffix. Missing keys are generated when `generate_host_key`
+//! is set, and written with mode 0600.
+//!
+//! Clients remember host keys, and refuse to connect when they
+//! change. A rotation therefore doesn't replace a key at once: the
+//! new key is written next to the current one as `<file>.next-<time>`
+//! and only replaces it at that time. Until then, clients still get
+//! the old key, and the new one is listed by `GET /api/v1/host-keys`
+//! so that users can add it to their `known_hosts` files.
+
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use jiff::Timestamp;
+use serde::Serialize;
+use thrussh::server::{self, Server as _};
+use thrussh_keys::key::{self, KeyPair, SignatureHash};
+use thrussh_keys::PublicKeyBase64;
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+use super::SshServerFactory;
+use crate::auth::keys::fingerprint;
+use crate::config::{HostKeyAlgorithm, ServerConfig};
+
+/// Size of generated RSA keys, in bits.
+const RSA_BITS: usize = 3072;
+
+/// Suffix of rotated key files, followed by their activation time in
+/// seconds since the epoch.
+const NEXT_SUFFIX: &str = ".next-";
+
+impl HostKeyAlgorithm {
+    /// Key type, as written in `known_hosts` files.
+    pub fn key_type(&self) -> &'static str {
+        match self {
+            HostKeyAlgorithm::Ed25519 => "ssh-ed25519",
+            HostKeyAlgorithm::Rsa => "ssh-rsa",
+        }
+    }
+
+    fn generate(&self) -> anyhow::Result<KeyPair> {
+        match self {
+            HostKeyAlgorithm::Ed25519 => KeyPair::generate_ed25519(),
+            HostKeyAlgorithm::Rsa => KeyPair::generate_rsa(RSA_BITS, SignatureHash::SHA2_256),
+        }
+        .context("Cannot generate host key")
+    }
+}
+
+/// A host key, as published to clients.
+#[derive(Debug, Clone, Serialize)]
+pub struct HostKeyInfo {
+    pub algorithm: HostKeyAlgorithm,
+    /// The public key, as written in `known_hosts` files
+    pub key: String,
+    /// SHA256 fingerprint, as printed by `ssh-keygen -l`
+    pub fingerprint: String,
+    /// When the key replaces the current one, for rotated keys
+    pub activates: Option<Timestamp>,
+}
+
+impl HostKeyInfo {
+    fn new(algorithm: HostKeyAlgorithm, key: &KeyPair, activates: Option<Timestamp>) -> Self {
+        let public = key.clone_public_key();
+        Self {
+            algorithm,
+            key: format!("{} {}", algorithm.key_type(), public.public_key_base64()),
+            fingerprint: fingerprint(&public),
+            activates,
+        }
+    }
+}
+
+/// The server's host keys.
+pub struct HostKeys {
+    /// The Ed25519 key file
+    path: PathBuf,
+    algorithms: Vec<HostKeyAlgorithm>,
+    generate: bool,
+    changed: Notify,
+}
+
+impl HostKeys {
+    /// The host keys of a configuration.
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            path: config.host_key_path.clone(),
+            algorithms: config.host_keys.algorithms.clone(),
+            generate: config.generate_host_key,
+            changed: Notify::new(),
+        }
+    }
+
+    /// Algorithms of the keys offered to clients.
+    pub fn algorithms(&self) -> &[HostKeyAlgorithm] {
+        &self.algorithms
+    }
+
+    /// The key file of `algorithm`.
+    fn file(&self, algorithm: HostKeyAlgorithm) -> PathBuf {
+        match algorithm {
+            HostKeyAlgorithm::Ed25519 => self.path.clone(),
+            HostKeyAlgorithm::Rsa => append(&self.path, "_rsa"),
+        }
+    }
+
+    /// The rotated key of `algorithm`, if any, and its activation
+    /// time.
+    fn next(&self, algorithm: HostKeyAlgorithm) -> anyhow::Result<Option<(Timestamp, PathBuf)>> {
+        let file = self.file(algorithm);
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = format!(
+            "{}{}",
+            file.file_name().unwrap_or_default().to_string_lossy(),
+            NEXT_SUFFIX
+        );
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut next = None;
+        for entry in entries {
+            let name = entry?.file_name();
+            let at = name
+                .to_str()
+                .and_then(|n| n.strip_prefix(&prefix))
+                .and_then(|at| Timestamp::from_second(at.parse().ok()?).ok());
+            if let Some(at) = at {
+                if next.as_ref().is_none_or(|(t, _)| at > *t) {
+                    next = Some((at, dir.join(name)));
+                }
+            }
+        }
+        Ok(next)
+    }
+
+    /// Load the keys, generating missing ones and replacing the keys
+    /// whose rotation is due.
+    pub fn load(&self) -> anyhow::Result<Vec<KeyPair>> {
+        let now = Timestamp::now();
+        let mut keys = Vec::new();
+        for &algorithm in self.algorithms.iter() {
+            let file = self.file(algorithm);
+            if let Some((at, next)) = self.next(algorithm)? {
+                if at <= now {
+                    std::fs::rename(&next, &file)?;
+                    info!(path = %file.display(), "Host key rotated");
+                }
+            }
+            if file.exists() {
+                let key = thrussh_keys::load_secret_key(&file, None)
+                    .with_context(|| format!("Cannot load host key {}", file.display()))?;
+                keys.push(key);
+            } else if self.generate {
+                let key = algorithm.generate()?;
+                write_key(&file, &key)?;
+                info!(path = %file.display(), "Generated host key");
+                keys.push(key);
+            } else {
+                warn!(path = %file.display(), "Host key not found and generation disabled");
+            }
+        }
+        if keys.is_empty() {
+            bail!("No host key found at {:?}", self.path);
+        }
+        Ok(keys)
+    }
+
+    /// When the next rotated key replaces the current one.
+    pub fn next_rotation(&self) -> anyhow::Result<Option<Timestamp>> {
+        let mut next: Option<Timestamp> = None;
+        for &algorithm in self.algorithms.iter() {
+            if let Some((at, _)) = self.next(algorithm)? {
+                next = Some(next.map_or(at, |n| n.min(at)));
+            }
+        }
+        Ok(next)
+    }
+
+    /// The current keys, followed by the rotated keys not in use yet.
+    pub fn list(&self) -> anyhow::Result<Vec<HostKeyInfo>> {
+        let mut current = Vec::new();
+        let mut next = Vec::new();
+        for &algorithm in self.algorithms.iter() {
+            let file = self.file(algorithm);
+            if file.exists() {
+                let key = thrussh_keys::load_secret_key(&file, None)?;
+                current.push(HostKeyInfo::new(algorithm, &key, None));
+            }
+            if let Some((at, file)) = self.next(algorithm)? {
+                let key = thrussh_keys::load_secret_key(&file, None)?;
+                next.push(HostKeyInfo::new(algorithm, &key, Some(at)));
+            }
+        }
+        current.extend(next);
+        Ok(current)
+    }
+
+    /// Generate a new key for `algorithm`, replacing the current one
+    /// after `grace`. A rotation of the same algorithm still waiting
+    /// for its grace period is cancelled.
+    pub fn rotate(
+        &self,
+        algorithm: HostKeyAlgorithm,
+        grace: Duration,
+    ) -> anyhow::Result<HostKeyInfo> {
+        if !self.algorithms.contains(&algorithm) {
+            bail!("No {} host key is configured", algorithm.key_type());
+        }
+        if let Some((_, next)) = self.next(algorithm)? {
+            std::fs::remove_file(next)?;
+        }
+        let at = Timestamp::from_second((Timestamp::now() + grace).as_second())?;
+        let key = algorithm.generate()?;
+        let suffix = format!("{}{}", NEXT_SUFFIX, at.as_second());
+        write_key(&append(&self.file(algorithm), &suffix), &key)?;
+        self.changed.notify_one();
+        Ok(HostKeyInfo::new(algorithm, &key, Some(at)))
+    }
+}
+
+fn append(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Write a private key, only readable by the server's user.
+fn write_key(path: &Path, key: &KeyPair) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = append(path, ".tmp");
+    let _ = std::fs::remove_file(&tmp);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(&tmp)
+        .with_context(|| format!("Cannot write host key {}", path.display()))?;
+    thrussh_keys::encode_pkcs8_pem(key, &mut file)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The thrussh configuration serving `keys`.
+pub fn server_config(keys: Vec<KeyPair>) -> server::Config {
+    let has = |name: key::Name| keys.iter().any(|k| k.name() == name.0);
+    // Clients may only pick algorithms of our keys.
+    let algorithms: &'static [key::Name] = match (has(key::ED25519), has(key::RSA_SHA2_256)) {
+        (true, true) => &[key::ED25519, key::RSA_SHA2_256],
+        (false, true) => &[key::RSA_SHA2_256],
+        _ => &[key::ED25519],
+    };
+    server::Config {
+        connection_timeout: Some(Duration::from_secs(600)),
+        auth_rejection_time: Duration::from_secs(3),
+        preferred: thrussh::Preferred {
+            key: algorithms,
+            ..thrussh::Preferred::DEFAULT_SERVER
+        },
+        keys,
+        ..Default::default()
+    }
+}
+
+/// Run the SSH server on `addr`. New connections get the new keys
+/// when a rotation happens; sessions in progress aren't interrupted.
+pub async fn serve(
+    host_keys: Arc<HostKeys>,
+    addr: &str,
+    mut factory: SshServerFactory,
+) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let mut config = Arc::new(server_config(host_keys.load()?));
+    loop {
+        let rotation = host_keys.next_rotation().unwrap_or_else(|e| {
+            error!(error = %e, "Cannot read rotated host keys");
+            None
+        });
+        let until_rotation = async {
+            match rotation {
+                Some(at) => {
+                    let delay = Duration::try_from(at - Timestamp::now()).unwrap_or_default();
+                    tokio::time::sleep(delay).await
+                }
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, peer) = accepted?;
+                let handler = factory.new(Some(peer));
+                tokio::spawn(server::run_stream(config.clone(), socket, handler));
+                continue;
+            }
+            _ = host_keys.changed.notified() => {}
+            _ = until_rotation => {}
+        }
+        match host_keys.load() {
+            Ok(keys) => config = Arc::new(server_config(keys)),
+            Err(e) => error!(error = %e, "Cannot load host keys, keeping the current ones"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig {
+            host_key_path: dir.path().join("host_key"),
+            ..Default::default()
+        };
+        let host_keys = HostKeys::new(&config);
+
+        // Keys are generated once, and private.
+        let keys = host_keys.load().unwrap();
+        assert_eq!(keys.len(), 2);
+        let listed = host_keys.list().unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed[0].key.starts_with("ssh-ed25519 "));
+        assert!(listed[1].key.starts_with("ssh-rsa "));
+        host_keys.load().unwrap();
+        assert_eq!(
+            host_keys.list().unwrap()[0].fingerprint,
+            listed[0].fingerprint
+        );
+        #[cfg(unix)]
+        for file in ["host_key", "host_key_rsa"] {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.path().join(file))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Rotated keys are published, but not used before the end of
+        // the grace period.
+        let grace = Duration::from_secs(3600);
+        let next = host_keys.rotate(HostKeyAlgorithm::Ed25519, grace).unwrap();
+        assert_eq!(host_keys.next_rotation().unwrap(), next.activates);
+        host_keys.load().unwrap();
+        let keys = host_keys.list().unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys[0].fingerprint, listed[0].fingerprint);
+        assert_eq!(keys[2].fingerprint, next.fingerprint);
+
+        // Rotating again replaces the pending key, which is used
+        // immediately without a grace period.
+        let next = host_keys
+            .rotate(HostKeyAlgorithm::Ed25519, Duration::ZERO)
+            .unwrap();
+        host_keys.load().unwrap();
+        let keys = host_keys.list().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].fingerprint, next.fingerprint);
+        assert_eq!(keys[1].fingerprint, listed[1].fingerprint);
+        assert_eq!(host_keys.next_rotation().unwrap(), None);
+    }
+}
@@ -1,10 +1,15 @@
 //! SSH server module.
 //!
 //! Implements the SSH protocol handler for Pijul operations.
-//! Supports `pijul clone`, `pijul pull`, and `pijul push` over SSH.
+//! Speaks the Pijul remote protocol used by `pijul clone`, `pijul
+//! pull` and `pijul push` over SSH.
 
 pub mod handler;
+pub mod host_keys;
 pub mod protocol;
+pub mod session;
 
 pub use handler::{SshServer, SshServerFactory};
-pub use protocol::PijulCommand;
+pub use host_keys::HostKeys;
+pub use protocol::{PijulCommand, PROTOCOL_VERSION};
+pub use session::ProtocolSession;
@@ -4,24 +4,15 @@
 
 use crate::error::{Result, ServerError};
 
+/// Version of the Pijul remote protocol spoken by this server.
+pub const PROTOCOL_VERSION: usize = 3;
+
 /// Pijul protocol commands that can be executed over SSH.
 #[derive(Debug, Clone, PartialEq)]
 pub enum PijulCommand {
-    /// Clone a repository channel
-    Clone {
-        repo: String,
-        channel: Option<String>,
-    },
-    /// Pull changes from a repository
-    Pull {
-        repo: String,
-        channel: Option<String>,
-    },
-    /// Push changes to a repository
-    Push {
-        repo: String,
-        channel: Option<String>,
-    },
+    /// Start a protocol session on a repository, as sent by
+    /// `pijul clone/pull/push`
+    Protocol { repo: String, version: usize },
     /// Check if a repository exists
     Ping { repo: String },
 }
@@ -30,9 +21,7 @@ impl PijulCommand {
     /// Parse an SSH exec command into a PijulCommand.
     ///
     /// Expected formats:
-    /// - `pijul clone REPO [CHANNEL]`
-    /// - `pijul pull REPO [CHANNEL]`
-    /// - `pijul push REPO [CHANNEL]`
+    /// - `pijul protocol --version N --repository REPO`
     /// - `pijul ping REPO`
     pub fn parse(command: &str) -> Result<Self> {
         let parts: Vec<&str> = command.split_whitespace().collect();
@@ -41,8 +30,9 @@ impl PijulCommand {
             return Err(ServerError::protocol("Empty command"));
         }
 
-        // Handle both "pijul <cmd>" and just "<cmd>" formats
-        let (cmd, args) = if parts[0] == "pijul" {
+        // Handle both "pijul <cmd>" and just "<cmd>" formats. Clients
+        // may also run a different binary through `REMOTE_PIJUL`.
+        let (cmd, args) = if parts[0] == "pijul" || parts[0].ends_with("/pijul") {
             if parts.len() < 2 {
                 return Err(ServerError::protocol("Missing pijul subcommand"));
             }
@@ -52,31 +42,33 @@ impl PijulCommand {
         };
 
         match cmd {
-            "clone" => {
-                if args.is_empty() {
-                    return Err(ServerError::protocol("Clone requires repository name"));
-                }
-                Ok(PijulCommand::Clone {
-                    repo: args[0].to_string(),
-                    channel: args.get(1).map(|s| s.to_string()),
-                })
-            }
-            "pull" => {
-                if args.is_empty() {
-                    return Err(ServerError::protocol("Pull requires repository name"));
-                }
-                Ok(PijulCommand::Pull {
-                    repo: args[0].to_string(),
-                    channel: args.get(1).map(|s| s.to_string()),
-                })
-            }
-            "push" => {
-                if args.is_empty() {
-                    return Err(ServerError::protocol("Push requires repository name"));
+            "protocol" => {
+                let mut repo = None;
+                let mut version = None;
+                let mut args = args.iter();
+                while let Some(arg) = args.next() {
+                    match *arg {
+                        "--version" => {
+                            version = args.next().and_then(|v| v.parse().ok());
+                            if version.is_none() {
+                                return Err(ServerError::protocol("Invalid protocol version"));
+                            }
+                        }
+                        "--repository" => repo = args.next().map(|r| r.to_string()),
+                        _ => {
+                            return Err(ServerError::protocol(format!(
+                                "Unknown protocol argument: {}",
+                                arg
+                            )))
+                        }
+                    }
                 }
-                Ok(PijulCommand::Push {
-                    repo: args[0].to_string(),
-                    channel: args.get(1).map(|s| s.to_string()),
+                let Some(repo) = repo else {
+                    return Err(ServerError::protocol("Protocol requires repository name"));
+                };
+                Ok(PijulCommand::Protocol {
+                    repo,
+                    version: version.unwrap_or(PROTOCOL_VERSION),
                 })
             }
             "ping" => {
@@ -94,22 +86,10 @@ impl PijulCommand {
     /// Get the repository name for this command.
     pub fn repo(&self) -> &str {
         match self {
-            PijulCommand::Clone { repo, .. } => repo,
-            PijulCommand::Pull { repo, .. } => repo,
-            PijulCommand::Push { repo, .. } => repo,
+            PijulCommand::Protocol { repo, .. } => repo,
             PijulCommand::Ping { repo } => repo,
         }
     }
-
-    /// Get the channel name, defaulting to "main".
-    pub fn channel(&self) -> &str {
-        match self {
-            PijulCommand::Clone { channel, .. } => channel.as_deref().unwrap_or("main"),
-            PijulCommand::Pull { channel, .. } => channel.as_deref().unwrap_or("main"),
-            PijulCommand::Push { channel, .. } => channel.as_deref().unwrap_or("main"),
-            PijulCommand::Ping { .. } => "main",
-        }
-    }
 }
 
 #[cfg(test)]
@@ -117,25 +97,24 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_parse_clone() {
-        let cmd = PijulCommand::parse("pijul clone myrepo").unwrap();
+    fn test_parse_protocol() {
+        let cmd = PijulCommand::parse("pijul protocol --version 3 --repository /myrepo").unwrap();
         assert_eq!(
             cmd,
-            PijulCommand::Clone {
-                repo: "myrepo".to_string(),
-                channel: None
+            PijulCommand::Protocol {
+                repo: "/myrepo".to_string(),
+                version: 3
             }
         );
     }
 
     #[test]
-    fn test_parse_clone_with_channel() {
-        let cmd = PijulCommand::parse("pijul clone myrepo feature").unwrap();
+    fn test_parse_ping() {
+        let cmd = PijulCommand::parse("pijul ping myrepo").unwrap();
         assert_eq!(
             cmd,
-            PijulCommand::Clone {
+            PijulCommand::Ping {
                 repo: "myrepo".to_string(),
-                channel: Some("feature".to_string())
             }
         );
     }
@@ -143,6 +122,8 @@ mod tests {
     #[test]
     fn test_parse_error() {
         assert!(PijulCommand::parse("pijul").is_err());
-        assert!(PijulCommand::parse("pijul clone").is_err());
+        assert!(PijulCommand::parse("pijul protocol --version 3").is_err());
+        assert!(PijulCommand::parse("pijul protocol --version x --repository r").is_err());
+        assert!(PijulCommand::parse("pijul clone myrepo").is_err());
     }
 }
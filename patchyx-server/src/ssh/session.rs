@@ -0,0 +1,499 @@
+//! Pijul protocol sessions.
+//!
+//! A session is started by `pijul protocol --version N --repository
+//! PATH` on an exec channel. The client then sends one request per
+//! line, some of them (`apply`, `tagup`) followed by a binary
+//! payload. Requests are parsed here and answered by
+//! [`crate::repo::wire`].
+
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use anyhow::{bail, Context};
+use libpijul::key::{PKey, PublicKey};
+use libpijul::{Base32, Hash, Merkle};
+use pijul_repository::Repository;
+use regex::Regex;
+use tracing::debug;
+
+use crate::audit::{self, Action, Record};
+use crate::auth::{identities, Access};
+use crate::config::RepoSettings;
+use crate::metrics;
+use crate::repo::push::{check_size, Push};
+use crate::repo::{wire, SharedRepo};
+use crate::webhooks::{self, Event};
+
+static STATE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^state\s+(\S+)(\s+([0-9]+))?\s*$"#).unwrap());
+static ID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"^id\s+(\S+)\s*$"#).unwrap());
+static IDENTITIES: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^identities(\s+([0-9]+))?\s*$"#).unwrap());
+static CHANGELIST: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^changelist\s+(\S+)\s+([0-9]+)(.*)$"#).unwrap());
+static CHANGELIST_PATHS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#""(((\\")|[^"])+)""#).unwrap());
+static CHANGE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^(change|partial)\s+(\S+)\s*$"#).unwrap());
+static TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"^tag\s+(\S+)\s*$"#).unwrap());
+static TAGUP: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^tagup\s+(\S+)\s+(\S+)\s+([0-9]+)\s*$"#).unwrap());
+static APPLY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^apply\s+(\S+)\s+(\S+)\s+([0-9]+)\s*$"#).unwrap());
+static ARCHIVE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"^archive\s+(\S+)((\s+[^:\s]+)*)(\s+:(.*))?$"#).unwrap());
+static CHALLENGE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"^challenge\s+(.+)$"#).unwrap());
+static PROVE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"^prove\s+(\S+)\s*$"#).unwrap());
+
+/// Longest request line accepted. Payloads are not lines and are
+/// bounded by the push size limit instead.
+const MAX_LINE_LEN: usize = 16 << 10;
+
+/// Requests of the protocol, as named in metrics.
+const REQUESTS: &[&str] = &[
+    "state",
+    "id",
+    "identities",
+    "changelist",
+    "change",
+    "partial",
+    "tag",
+    "tagup",
+    "apply",
+    "archive",
+    "challenge",
+    "prove",
+];
+
+/// State of a `pijul protocol` session on one channel.
+pub struct ProtocolSession {
+    repo: SharedRepo,
+    /// Permissions of the client on the repository.
+    access: Access,
+    /// Settings of the repository.
+    settings: RepoSettings,
+    /// Bytes received but not processed yet.
+    buffer: Vec<u8>,
+    /// Pending `challenge` waiting for a `prove`.
+    challenge: Option<(PKey, String)>,
+    /// Keys the client proved it owns during this session, and linked
+    /// to its user.
+    proven_keys: Vec<PublicKey>,
+    /// Changes received by consecutive `apply` requests, applied
+    /// together before the next request or at the end of the session.
+    push: Option<Push>,
+    started: Instant,
+    /// Whether the client uploaded changes or tags.
+    pushed: bool,
+    /// Whether the client downloaded a channel from the start.
+    cloned: bool,
+}
+
+impl ProtocolSession {
+    /// Start a session on an opened repository.
+    pub fn new(repo: SharedRepo, access: Access, settings: RepoSettings) -> Self {
+        Self {
+            repo,
+            access,
+            settings,
+            buffer: Vec::new(),
+            challenge: None,
+            proven_keys: Vec::new(),
+            push: None,
+            started: Instant::now(),
+            pushed: false,
+            cloned: false,
+        }
+    }
+
+    /// Keys proven through `challenge`/`prove` during this session.
+    pub fn proven_keys(&self) -> &[PublicKey] {
+        &self.proven_keys
+    }
+
+    /// Feed bytes received from the client, writing the answers to
+    /// all complete requests into `out`.
+    pub fn feed(&mut self, data: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        self.buffer.extend_from_slice(data);
+        loop {
+            let Some(eol) = self.buffer.iter().position(|&c| c == b'\n') else {
+                if self.buffer.len() > MAX_LINE_LEN {
+                    bail!("Request line too long")
+                }
+                break;
+            };
+            if eol > MAX_LINE_LEN {
+                bail!("Request line too long")
+            }
+            let line = std::str::from_utf8(&self.buffer[..eol])?.to_string();
+            let payload_len = payload_len(&line)?;
+            check_size(&self.settings, payload_len)?;
+            let end = eol + 1 + payload_len;
+            if self.buffer.len() < end {
+                // Wait for the rest of the payload.
+                break;
+            }
+            let payload = self.buffer[eol + 1..end].to_vec();
+            self.buffer.drain(..end);
+            debug!("protocol request {:?}", line);
+            let start = Instant::now();
+            let result = self.request(&line, &payload, out);
+            let name = line.split_whitespace().next().unwrap_or_default();
+            let name = REQUESTS.iter().find(|r| **r == name).unwrap_or(&"unknown");
+            metrics::PROTOCOL_REQUESTS.observe(&["ssh", name], start.elapsed());
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Apply the pending push, if any. Called when the client is done
+    /// sending requests.
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        let repo = self.repo.lock().unwrap();
+        flush(&mut self.push, &repo, &self.access)?;
+        metrics::SSH_COMMANDS.observe(&[self.command()], self.started.elapsed());
+        Ok(())
+    }
+
+    /// The command that started this session, guessed from its
+    /// requests: `push` if it uploaded anything, `clone` if it
+    /// downloaded a channel from the start, `pull` otherwise.
+    fn command(&self) -> &'static str {
+        if self.pushed {
+            "push"
+        } else if self.cloned {
+            "clone"
+        } else {
+            "pull"
+        }
+    }
+
+    fn request(&mut self, line: &str, payload: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        let repo = self.repo.lock().unwrap();
+        let repo = &*repo;
+        if let Some(cap) = APPLY.captures(line) {
+            self.access.require_push(&cap[1])?;
+            self.pushed = true;
+            if self.push.as_ref().is_some_and(|p| p.channel() != &cap[1]) {
+                flush(&mut self.push, repo, &self.access)?;
+            }
+            let push = self
+                .push
+                .get_or_insert_with(|| Push::new(&cap[1], self.settings.clone()));
+            let received = parse_hash(&cap[2]).and_then(|h| push.receive(repo, h, payload));
+            if received.is_err() {
+                // Never apply part of a push.
+                self.push = None;
+            }
+            return received;
+        }
+        // Later requests may depend on the pushed changes.
+        flush(&mut self.push, repo, &self.access)?;
+
+        if let Some(cap) = STATE.captures(line) {
+            let at = cap.get(3).map(|x| x.as_str().parse()).transpose()?;
+            wire::state(repo, &cap[1], at, out)
+        } else if let Some(cap) = ID.captures(line) {
+            wire::id(repo, &cap[1], out)
+        } else if let Some(cap) = CHANGELIST.captures(line) {
+            let paths: Vec<String> = CHANGELIST_PATHS
+                .captures_iter(&cap[3])
+                .map(|p| p[1].replace("\\\"", "\""))
+                .collect();
+            let from = cap[2].parse()?;
+            self.cloned |= from == 0;
+            wire::changelist(repo, &cap[1], from, &paths, out)
+        } else if let Some(cap) = CHANGE.captures(line) {
+            wire::change(repo, &parse_hash(&cap[2])?, &cap[1] == "partial", out)
+        } else if let Some(cap) = TAG.captures(line) {
+            wire::tag(repo, &parse_merkle(&cap[1])?, out)
+        } else if let Some(cap) = TAGUP.captures(line) {
+            self.access.require_push(&cap[2])?;
+            self.pushed = true;
+            wire::tagup(repo, &parse_merkle(&cap[1])?, &cap[2], payload)?;
+            let event = Event::TagCreate {
+                channel: cap[2].to_string(),
+                state: cap[1].to_string(),
+            };
+            let (name, caller) = (self.access.repo(), self.access.caller());
+            webhooks::notify(&self.settings, &repo.path, name, caller, event);
+            let record = Record {
+                channel: Some(cap[2].to_string()),
+                new_state: Some(cap[1].to_string()),
+                ..Record::on_repo(Action::TagCreate, &self.access)
+            };
+            audit::append(&self.settings.audit, record);
+            Ok(())
+        } else if let Some(cap) = ARCHIVE.captures(line) {
+            self.cloned = true;
+            let mut hashes = cap[2].split_whitespace();
+            let state = if let Some(state) = hashes.next() {
+                let extra = hashes.map(parse_hash).collect::<anyhow::Result<_>>()?;
+                Some((parse_merkle(state)?, extra))
+            } else {
+                None
+            };
+            wire::archive(repo, &cap[1], state, cap.get(5).map(|x| x.as_str()), out)
+        } else if let Some(cap) = IDENTITIES.captures(line) {
+            let rev = cap.get(2).map(|x| x.as_str().parse()).transpose()?;
+            wire::identities(repo, &self.settings.users_dir, rev, out)
+        } else if let Some(cap) = CHALLENGE.captures(line) {
+            let key: PublicKey = serde_json::from_str(&cap[1])?;
+            let pkey = key.load()?;
+            let challenge = identities::challenge();
+            out.extend_from_slice(challenge.as_bytes());
+            out.push(b'\n');
+            self.challenge = Some((pkey, challenge));
+            Ok(())
+        } else if let Some(cap) = PROVE.captures(line) {
+            let Some((pkey, challenge)) = self.challenge.take() else {
+                bail!("No pending challenge")
+            };
+            pkey.verify(challenge.as_bytes(), &cap[1], &jiff::Timestamp::now())?;
+            let Some(user) = self.access.user() else {
+                bail!("Log in to link a key to your account")
+            };
+            let identity = identities::link(&self.settings.users_dir, user, pkey.save())?;
+            tracing::info!(user = user, key = %identity.public_key.key, "Identity linked");
+            let record = Record {
+                target: Some(identity.public_key.fingerprint()),
+                ..Record::on_repo(Action::IdentityLink, &self.access)
+            };
+            audit::append(&self.settings.audit, record);
+            self.proven_keys.push(identity.public_key);
+            Ok(())
+        } else {
+            bail!("Protocol error: {:?}", line)
+        }
+    }
+}
+
+fn flush(push: &mut Option<Push>, repo: &Repository, access: &Access) -> anyhow::Result<()> {
+    match push.take() {
+        Some(push) => push.apply(repo, access).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+/// Number of payload bytes following a request line.
+fn payload_len(line: &str) -> anyhow::Result<usize> {
+    let cap = if let Some(cap) = APPLY.captures(line) {
+        cap
+    } else if let Some(cap) = TAGUP.captures(line) {
+        cap
+    } else {
+        return Ok(0);
+    };
+    cap[3]
+        .parse()
+        .with_context(|| format!("Invalid payload length: {:?}", &cap[3]))
+}
+
+fn parse_hash(s: &str) -> anyhow::Result<Hash> {
+    if let Some(h) = Hash::from_base32(s.as_bytes()) {
+        Ok(h)
+    } else {
+        bail!("Invalid hash: {:?}", s)
+    }
+}
+
+fn parse_merkle(s: &str) -> anyhow::Result<Merkle> {
+    if let Some(m) = Merkle::from_base32(s.as_bytes()) {
+        Ok(m)
+    } else {
+        bail!("Invalid state: {:?}", s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libpijul::changestore::filesystem::push_filename;
+    use libpijul::key::SKey;
+
+    use super::*;
+    use crate::auth::{AccessList, Caller, Role};
+    use crate::config::AuditConfig;
+    use crate::repo::testing::{init, record};
+
+    #[test]
+    fn test_push_and_pull() {
+        let a_dir = tempfile::tempdir().unwrap();
+        let b_dir = tempfile::tempdir().unwrap();
+        let a = init(a_dir.path());
+        let b = init(b_dir.path());
+        let a = a.lock().unwrap();
+        let hash = record(&a, "file", b"a\nb\n");
+        let mut path = a.changes_dir.clone();
+        push_filename(&mut path, &hash);
+        let contents = std::fs::read(&path).unwrap();
+
+        let acl = AccessList {
+            users: [("alice".to_string(), Role::Write)].into(),
+            protected_channels: vec!["stable".to_string()],
+            ..Default::default()
+        };
+        let caller = Caller {
+            user: Some("alice".to_string()),
+            ..Default::default()
+        };
+        let access = Access::resolve("b", &acl, &caller);
+        let settings = RepoSettings {
+            audit: AuditConfig {
+                enabled: true,
+                path: b_dir.path().join("audit.log"),
+            },
+            ..Default::default()
+        };
+        let mut session = ProtocolSession::new(b, access, settings);
+        let mut out = Vec::new();
+        session.feed(b"state main\n", &mut out).unwrap();
+        assert_eq!(out, b"-\n");
+
+        // Push the change, with the payload split across two packets.
+        let mut req = format!("apply main {} {}\n", hash.to_base32(), contents.len()).into_bytes();
+        req.extend_from_slice(&contents);
+        let (first, second) = req.split_at(req.len() / 2);
+        out.clear();
+        session.feed(first, &mut out).unwrap();
+        session.feed(second, &mut out).unwrap();
+        assert!(out.is_empty());
+
+        out.clear();
+        session.feed(b"state main\n", &mut out).unwrap();
+        assert!(out.starts_with(b"0 "));
+
+        out.clear();
+        session.feed(b"changelist main 0\n", &mut out).unwrap();
+        let list = String::from_utf8(out.clone()).unwrap();
+        assert!(list.starts_with(&format!("0.{}.", hash.to_base32())));
+        assert!(list.ends_with("\n\n"));
+
+        // Pull it back.
+        out.clear();
+        let req = format!("change {}\n", hash.to_base32());
+        session.feed(req.as_bytes(), &mut out).unwrap();
+        assert_eq!(out[..8], (contents.len() as u64).to_be_bytes());
+        assert_eq!(out[8..], contents[..]);
+
+        assert!(session.feed(b"frobnicate\n", &mut out).is_err());
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let repo = init(repo_dir.path());
+        let access = Access::resolve("repo", &AccessList::default(), &Caller::admin());
+        let mut out = Vec::new();
+
+        let mut session =
+            ProtocolSession::new(repo.clone(), access.clone(), RepoSettings::default());
+        let req = format!("apply main {} 99999999999999999999999\n", "A".repeat(53));
+        let err = session.feed(req.as_bytes(), &mut out).unwrap_err();
+        assert!(err.to_string().contains("Invalid payload length"));
+
+        let mut session = ProtocolSession::new(repo, access, RepoSettings::default());
+        let line = vec![b'a'; MAX_LINE_LEN];
+        session.feed(&line, &mut out).unwrap();
+        let err = session.feed(b"a", &mut out).unwrap_err();
+        assert!(err.to_string().contains("too long"));
+    }
+
+    #[test]
+    fn test_protected_channel() {
+        let a_dir = tempfile::tempdir().unwrap();
+        let b_dir = tempfile::tempdir().unwrap();
+        let a = init(a_dir.path());
+        let b = init(b_dir.path());
+        let a = a.lock().unwrap();
+        let hash = record(&a, "file", b"a\n");
+        let mut path = a.changes_dir.clone();
+        push_filename(&mut path, &hash);
+        let contents = std::fs::read(&path).unwrap();
+        let mut req =
+            format!("apply stable {} {}\n", hash.to_base32(), contents.len()).into_bytes();
+        req.extend_from_slice(&contents);
+
+        let acl = AccessList {
+            users: [("alice".to_string(), Role::Write)].into(),
+            protected_channels: vec!["stable".to_string()],
+            ..Default::default()
+        };
+        let caller = Caller {
+            user: Some("alice".to_string()),
+            ..Default::default()
+        };
+        let access = Access::resolve("b", &acl, &caller);
+        let mut session = ProtocolSession::new(b, access, RepoSettings::default());
+        let mut out = Vec::new();
+        let err = session.feed(&req, &mut out).unwrap_err();
+        assert!(err.to_string().contains("protected"));
+    }
+
+    #[test]
+    fn test_prove() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let users_dir = tempfile::tempdir().unwrap();
+        let repo = init(repo_dir.path());
+        let settings = RepoSettings {
+            users_dir: users_dir.path().to_path_buf(),
+            audit: AuditConfig {
+                enabled: true,
+                path: users_dir.path().join("audit.log"),
+            },
+            ..Default::default()
+        };
+        let key = SKey::generate(None);
+        let challenge = format!(
+            "challenge {}\n",
+            serde_json::to_string(&key.public_key()).unwrap()
+        );
+
+        // Anonymous clients can't link keys.
+        let access = Access::resolve("repo", &AccessList::default(), &Caller::anonymous());
+        let mut session = ProtocolSession::new(repo.clone(), access, settings.clone());
+        let mut out = Vec::new();
+        session.feed(challenge.as_bytes(), &mut out).unwrap();
+        let signature = key.sign_raw(out.trim_ascii_end()).unwrap();
+        let prove = format!("prove {}\n", signature);
+        assert!(session.feed(prove.as_bytes(), &mut out).is_err());
+
+        let caller = Caller {
+            user: Some("alice".to_string()),
+            ..Default::default()
+        };
+        let access = Access::resolve("repo", &AccessList::default(), &caller);
+        let mut session = ProtocolSession::new(repo, access, settings);
+        let mut out = Vec::new();
+        session.feed(challenge.as_bytes(), &mut out).unwrap();
+        // A signature of another challenge doesn't prove anything.
+        assert!(session.feed(prove.as_bytes(), &mut out).is_err());
+        assert!(session.proven_keys().is_empty());
+
+        out.clear();
+        session.feed(challenge.as_bytes(), &mut out).unwrap();
+        let signature = key.sign_raw(out.trim_ascii_end()).unwrap();
+        let prove = format!("prove {}\n", signature);
+        out.clear();
+        session.feed(prove.as_bytes(), &mut out).unwrap();
+        assert_eq!(session.proven_keys(), &[key.public_key()]);
+
+        // The identity is served to clients.
+        out.clear();
+        session.feed(b"identities\n", &mut out).unwrap();
+        let ids: Vec<serde_json::Value> = out
+            .split(|&c| c == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+        assert_eq!(ids.len(), 1);
+        assert_eq!(ids[0]["username"], "alice");
+        assert_eq!(ids[0]["public_key"]["key"], key.public_key().key);
+        let rev = ids[0]["last_modified"].as_str().unwrap();
+        let rev = rev.parse::<jiff::Timestamp>().unwrap().as_second();
+        out.clear();
+        session
+            .feed(format!("identities {}\n", rev).as_bytes(), &mut out)
+            .unwrap();
+        assert_eq!(out, b"\n");
+    }
+}
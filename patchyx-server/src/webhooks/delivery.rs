@@ -0,0 +1,443 @@
+//! Webhook delivery queue.
+//!
+//! Deliveries are JSON files in the `webhooks` directory of the
+//! repository's `.pijul` directory: pending ones in `pending/`, and
+//! the last [`MAX_LOG`] delivered or abandoned ones in `done/`. File
+//! names start with the creation time, so that sorting them sorts
+//! deliveries chronologically.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use jiff::Timestamp;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::{sign, Event, EventKind, WebhookList};
+use crate::config::{SharedConfig, WebhooksConfig};
+use crate::repo::RepoStore;
+
+/// Name of the deliveries directory, in the repository's `.pijul`
+/// directory.
+pub const DELIVERIES_DIR: &str = "webhooks";
+
+/// Number of finished deliveries kept for each repository.
+pub const MAX_LOG: usize = 200;
+
+/// Longest delay between two attempts.
+const MAX_RETRY: Duration = Duration::from_secs(24 * 3600);
+/// How often the queue is checked for due deliveries.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// State of a delivery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pending,
+    Delivered,
+    /// Abandoned after too many failures
+    Failed,
+}
+
+/// An attempt to send a delivery.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attempt {
+    pub at: Timestamp,
+    /// HTTP status of the response, if any
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+/// An event queued for a webhook.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub id: String,
+    /// Id of the webhook
+    pub webhook: String,
+    pub url: String,
+    pub event: EventKind,
+    pub status: Status,
+    pub created: Timestamp,
+    /// When the next attempt is due, for pending deliveries
+    pub next_attempt: Option<Timestamp>,
+    pub attempts: Vec<Attempt>,
+    /// Body of the request
+    pub payload: serde_json::Value,
+}
+
+/// Body of webhook requests.
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    repository: &'a str,
+    sender: &'a str,
+    timestamp: Timestamp,
+}
+
+fn dir(repo_path: &Path, sub: &str) -> PathBuf {
+    repo_path
+        .join(libpijul::DOT_DIR)
+        .join(DELIVERIES_DIR)
+        .join(sub)
+}
+
+fn write(dir: &Path, delivery: &Delivery) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.json", delivery.id));
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(delivery)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// Read the deliveries of a directory, oldest first.
+fn read_dir(dir: &Path) -> anyhow::Result<Vec<Delivery>> {
+    let mut names = Vec::new();
+    match std::fs::read_dir(dir) {
+        Ok(entries) => {
+            for entry in entries {
+                let name = entry?.file_name();
+                if name.to_str().is_some_and(|n| n.ends_with(".json")) {
+                    names.push(name);
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    names.sort();
+    let mut deliveries = Vec::new();
+    for name in names {
+        match std::fs::read(dir.join(&name)) {
+            Ok(contents) => deliveries.push(serde_json::from_slice(&contents)?),
+            // Moved to `done` in the meantime.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(deliveries)
+}
+
+/// Queue a delivery of `event` to each webhook subscribing to it.
+pub(super) fn enqueue(
+    repo_path: &Path,
+    repo: &str,
+    sender: &str,
+    event: &Event,
+) -> anyhow::Result<()> {
+    let kind = event.kind();
+    let webhooks = WebhookList::load(repo_path)?;
+    let now = Timestamp::now();
+    let payload = serde_json::to_value(Payload {
+        event,
+        repository: repo,
+        sender,
+        timestamp: now,
+    })?;
+    for webhook in webhooks
+        .webhooks
+        .iter()
+        .filter(|w| w.events.contains(&kind))
+    {
+        let delivery = Delivery {
+            id: format!(
+                "{:020}-{:08x}",
+                now.as_nanosecond(),
+                rand::rng().random::<u32>()
+            ),
+            webhook: webhook.id.clone(),
+            url: webhook.url.clone(),
+            event: kind,
+            status: Status::Pending,
+            created: now,
+            next_attempt: Some(now),
+            attempts: Vec::new(),
+            payload: payload.clone(),
+        };
+        write(&dir(repo_path, "pending"), &delivery)?;
+    }
+    Ok(())
+}
+
+/// The last `limit` deliveries to `webhook`, pending or not, newest
+/// first.
+pub fn log(repo_path: &Path, webhook: &str, limit: usize) -> anyhow::Result<Vec<Delivery>> {
+    let mut deliveries = read_dir(&dir(repo_path, "done"))?;
+    deliveries.extend(read_dir(&dir(repo_path, "pending"))?);
+    deliveries.retain(|d| d.webhook == webhook);
+    deliveries.sort_by(|a, b| b.id.cmp(&a.id));
+    deliveries.truncate(limit);
+    Ok(deliveries)
+}
+
+/// Move a finished delivery to the log, pruning old entries.
+fn finish(repo_path: &Path, delivery: &Delivery) -> anyhow::Result<()> {
+    let done = dir(repo_path, "done");
+    write(&done, delivery)?;
+    std::fs::remove_file(dir(repo_path, "pending").join(format!("{}.json", delivery.id)))?;
+    let mut names: Vec<_> = std::fs::read_dir(&done)?
+        .filter_map(|e| e.ok().map(|e| e.file_name()))
+        .filter(|n| n.to_str().is_some_and(|n| n.ends_with(".json")))
+        .collect();
+    if names.len() > MAX_LOG {
+        names.sort();
+        for name in names.iter().take(names.len() - MAX_LOG) {
+            std::fs::remove_file(done.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends queued deliveries.
+pub struct Dispatcher {
+    config: Arc<SharedConfig>,
+    repos: Arc<RepoStore>,
+    client: reqwest::Client,
+}
+
+impl Dispatcher {
+    /// Create a dispatcher for the deliveries of all repositories.
+    pub fn new(config: Arc<SharedConfig>, repos: Arc<RepoStore>) -> Self {
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("patchyx/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("Cannot create HTTP client");
+        Self {
+            config,
+            repos,
+            client,
+        }
+    }
+
+    /// Send due deliveries until the server stops.
+    pub async fn run(self) {
+        loop {
+            self.run_once().await;
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Send the deliveries that are due.
+    pub async fn run_once(&self) {
+        let repos = match self.repos.list() {
+            Ok(repos) => repos,
+            Err(e) => {
+                warn!(error = %e, "Cannot list repositories");
+                return;
+            }
+        };
+        let config = self.config.get();
+        let now = Timestamp::now();
+        for repo in repos {
+            let repo_path = self.repos.path(&repo);
+            let pending = match read_dir(&dir(&repo_path, "pending")) {
+                Ok(pending) => pending,
+                Err(e) => {
+                    warn!(repo = %repo, error = %e, "Cannot read webhook queue");
+                    continue;
+                }
+            };
+            for delivery in pending {
+                if delivery.next_attempt.is_some_and(|t| t <= now) {
+                    let result = self.deliver(&config.webhooks, &repo_path, delivery).await;
+                    if let Err(e) = result {
+                        warn!(repo = %repo, error = %e, "Cannot update webhook delivery");
+                    }
+                }
+            }
+        }
+    }
+
+    async fn deliver(
+        &self,
+        config: &WebhooksConfig,
+        repo_path: &Path,
+        mut delivery: Delivery,
+    ) -> anyhow::Result<()> {
+        let webhooks = WebhookList::load(repo_path)?;
+        let timeout = Duration::from_secs(config.timeout_secs);
+        let attempt = match webhooks.get(&delivery.webhook) {
+            Some(webhook) => self.send(&webhook.secret, &delivery, timeout).await,
+            None => {
+                // The webhook was deleted, give up.
+                delivery.attempts.push(Attempt {
+                    at: Timestamp::now(),
+                    status: None,
+                    error: Some("Webhook deleted".to_string()),
+                });
+                delivery.status = Status::Failed;
+                delivery.next_attempt = None;
+                return finish(repo_path, &delivery);
+            }
+        };
+        debug!(id = %delivery.id, url = %delivery.url, ?attempt, "Webhook delivery attempt");
+        let success = attempt.error.is_none();
+        let at = attempt.at;
+        delivery.attempts.push(attempt);
+        if success {
+            delivery.status = Status::Delivered;
+            delivery.next_attempt = None;
+            finish(repo_path, &delivery)
+        } else if delivery.attempts.len() >= config.max_attempts {
+            warn!(id = %delivery.id, url = %delivery.url, "Webhook delivery abandoned");
+            delivery.status = Status::Failed;
+            delivery.next_attempt = None;
+            finish(repo_path, &delivery)
+        } else {
+            let factor = 2u64.saturating_pow(delivery.attempts.len() as u32 - 1);
+            let delay = Duration::from_secs(config.first_retry_secs.saturating_mul(factor));
+            delivery.next_attempt = Some(at + delay.min(MAX_RETRY));
+            write(&dir(repo_path, "pending"), &delivery)
+        }
+    }
+
+    async fn send(&self, secret: &str, delivery: &Delivery, timeout: Duration) -> Attempt {
+        let at = Timestamp::now();
+        let body = serde_json::to_vec(&delivery.payload).unwrap_or_default();
+        let response = self
+            .client
+            .post(&delivery.url)
+            .timeout(timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Patchyx-Event", delivery.event.as_str())
+            .header("X-Patchyx-Delivery", &delivery.id)
+            .header("X-Patchyx-Signature", sign(secret, &body))
+            .body(body)
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => Attempt {
+                at,
+                status: Some(response.status().as_u16()),
+                error: None,
+            },
+            Ok(response) => Attempt {
+                at,
+                status: Some(response.status().as_u16()),
+                error: Some(format!("Unexpected status {}", response.status())),
+            },
+            Err(e) => Attempt {
+                at,
+                status: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU16, Ordering};
+    use std::sync::Mutex;
+
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+
+    use super::*;
+    use crate::config::{RepoSettings, ServerConfig};
+    use crate::webhooks::{notify, Webhook};
+
+    #[derive(Clone, Default)]
+    struct StandIn {
+        status: Arc<AtomicU16>,
+        received: Arc<Mutex<Vec<(HeaderMap, Bytes)>>>,
+    }
+
+    async fn receive(
+        State(stand_in): State<StandIn>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        stand_in.received.lock().unwrap().push((headers, body));
+        StatusCode::from_u16(stand_in.status.load(Ordering::SeqCst)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_delivery() {
+        let stand_in = StandIn::default();
+        stand_in.status.store(200, Ordering::SeqCst);
+        let app = axum::Router::new()
+            .route("/hook", axum::routing::post(receive))
+            .with_state(stand_in.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let dir = tempfile::tempdir().unwrap();
+        let config: Arc<SharedConfig> = Arc::new(
+            ServerConfig {
+                repos_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            }
+            .into(),
+        );
+        let repos = Arc::new(RepoStore::new(config.clone()));
+        repos.create("a").unwrap();
+        let repo_path = repos.path("a");
+        WebhookList {
+            webhooks: vec![Webhook {
+                id: "w".to_string(),
+                url,
+                secret: "s3cr3t".to_string(),
+                events: vec![EventKind::TagCreate],
+            }],
+        }
+        .save(&repo_path)
+        .unwrap();
+        let dispatcher = Dispatcher::new(config.clone(), repos.clone());
+        let settings = RepoSettings::default();
+        let tag = || Event::TagCreate {
+            channel: "main".to_string(),
+            state: "STATE".to_string(),
+        };
+
+        // Events the webhook doesn't subscribe to aren't queued.
+        let created = Event::ChannelCreate {
+            channel: "main".to_string(),
+        };
+        notify(&settings, &repo_path, "a", "alice", created);
+        assert!(log(&repo_path, "w", 10).unwrap().is_empty());
+
+        // Deliveries are signed.
+        notify(&settings, &repo_path, "a", "alice", tag());
+        dispatcher.run_once().await;
+        {
+            let received = stand_in.received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            let (headers, body) = &received[0];
+            assert_eq!(headers["x-patchyx-event"], "tag_create");
+            assert_eq!(
+                headers["x-patchyx-signature"],
+                sign("s3cr3t", body).as_str()
+            );
+            let payload: serde_json::Value = serde_json::from_slice(body).unwrap();
+            assert_eq!(payload["event"], "tag_create");
+            assert_eq!(payload["repository"], "a");
+            assert_eq!(payload["sender"], "alice");
+            assert_eq!(payload["state"], "STATE");
+        }
+        let deliveries = log(&repo_path, "w", 10).unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].status, Status::Delivered);
+        assert_eq!(deliveries[0].attempts[0].status, Some(200));
+
+        // Failed deliveries are retried later.
+        stand_in.status.store(500, Ordering::SeqCst);
+        notify(&settings, &repo_path, "a", "alice", tag());
+        dispatcher.run_once().await;
+        dispatcher.run_once().await;
+        assert_eq!(stand_in.received.lock().unwrap().len(), 2);
+        let deliveries = log(&repo_path, "w", 10).unwrap();
+        assert_eq!(deliveries.len(), 2);
+        assert_eq!(deliveries[0].status, Status::Pending);
+        assert_eq!(deliveries[0].attempts.len(), 1);
+        assert_eq!(deliveries[0].attempts[0].status, Some(500));
+        let next = deliveries[0].next_attempt.unwrap();
+        let first_retry = Duration::from_secs(config.get().webhooks.first_retry_secs);
+        assert_eq!(next, deliveries[0].attempts[0].at + first_retry);
+    }
+}
@@ -0,0 +1,211 @@
+//! Outgoing webhooks.
+//!
+//! Repository admins register webhooks through the HTTP API; they are
+//! stored in the `webhooks.json` file of the repository's `.pijul`
+//! directory. Each event a webhook subscribes to is queued as a
+//! [`Delivery`], then sent as a JSON `POST` by the [`Dispatcher`],
+//! which retries failed deliveries with exponential backoff.
+//!
+//! Request bodies are signed with the webhook's secret: the
+//! `X-Patchyx-Signature` header is `sha256=` followed by the hex
+//! HMAC-SHA256 of the body.
+
+pub mod delivery;
+
+use std::path::{Path, PathBuf};
+
+use hmac::{Hmac, Mac, NewMac};
+use libpijul::Base32;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::warn;
+
+use crate::config::RepoSettings;
+use crate::error::{Result, ServerError};
+use crate::repo::hooks::PushEvent;
+
+pub use delivery::{Delivery, Dispatcher};
+
+/// Name of the webhooks file, in the repository's `.pijul` directory.
+pub const WEBHOOKS_FILE: &str = "webhooks.json";
+
+/// Kinds of events webhooks can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Push,
+    ChannelCreate,
+    /// Not sent yet: no operation deletes channels
+    ChannelDelete,
+    TagCreate,
+    /// The repository was forked
+    Fork,
+    /// A proposal was opened, merged or closed
+    Proposal,
+    /// A proposal was commented
+    Discussion,
+}
+
+impl EventKind {
+    /// Name of the event, as sent in the `X-Patchyx-Event` header.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Push => "push",
+            EventKind::ChannelCreate => "channel_create",
+            EventKind::ChannelDelete => "channel_delete",
+            EventKind::TagCreate => "tag_create",
+            EventKind::Fork => "fork",
+            EventKind::Proposal => "proposal",
+            EventKind::Discussion => "discussion",
+        }
+    }
+}
+
+/// An event on a repository.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Push {
+        channel: String,
+        old_state: String,
+        new_state: String,
+        changes: Vec<String>,
+    },
+    ChannelCreate {
+        channel: String,
+    },
+    TagCreate {
+        channel: String,
+        state: String,
+    },
+    Fork {
+        /// Name of the new repository
+        fork: String,
+    },
+    Proposal {
+        id: u64,
+        /// `opened`, `merged` or `closed`
+        action: String,
+        title: String,
+    },
+    Discussion {
+        /// Id of the commented proposal
+        proposal: u64,
+        title: String,
+        author: String,
+        body: String,
+    },
+}
+
+impl Event {
+    /// The kind of this event.
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Push { .. } => EventKind::Push,
+            Event::ChannelCreate { .. } => EventKind::ChannelCreate,
+            Event::TagCreate { .. } => EventKind::TagCreate,
+            Event::Fork { .. } => EventKind::Fork,
+            Event::Proposal { .. } => EventKind::Proposal,
+            Event::Discussion { .. } => EventKind::Discussion,
+        }
+    }
+
+    /// The event sent for a push.
+    pub fn push(push: &PushEvent) -> Self {
+        Event::Push {
+            channel: push.channel.clone(),
+            old_state: push.old_state.to_base32(),
+            new_state: push.new_state.to_base32(),
+            changes: push.changes.iter().map(|h| h.to_base32()).collect(),
+        }
+    }
+}
+
+/// A registered webhook.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// Key of the HMAC signing request bodies
+    pub secret: String,
+    /// Events sent to this webhook
+    pub events: Vec<EventKind>,
+}
+
+/// Webhooks of a repository.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WebhookList {
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+}
+
+impl WebhookList {
+    fn path(repo_path: &Path) -> PathBuf {
+        repo_path.join(libpijul::DOT_DIR).join(WEBHOOKS_FILE)
+    }
+
+    /// Load the webhooks of the repository at `repo_path`.
+    pub fn load(repo_path: &Path) -> Result<Self> {
+        match std::fs::read(Self::path(repo_path)) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| ServerError::repository(format!("Invalid {}: {}", WEBHOOKS_FILE, e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the webhooks of the repository at `repo_path`.
+    pub fn save(&self, repo_path: &Path) -> Result<()> {
+        let path = Self::path(repo_path);
+        let tmp = path.with_extension("tmp");
+        let contents =
+            serde_json::to_vec_pretty(self).map_err(|e| ServerError::internal(e.to_string()))?;
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Find a webhook by id.
+    pub fn get(&self, id: &str) -> Option<&Webhook> {
+        self.webhooks.iter().find(|w| w.id == id)
+    }
+}
+
+/// Signature of `body` with `secret`, as sent in the
+/// `X-Patchyx-Signature` header.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    // HMAC accepts keys of any length.
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    format!(
+        "sha256={}",
+        data_encoding::HEXLOWER.encode(&mac.finalize().into_bytes())
+    )
+}
+
+/// Queue deliveries of `event` to the webhooks of the repository at
+/// `repo_path` subscribing to it, unless its `settings` disable
+/// webhooks. Errors are only logged: webhooks never fail the
+/// operation that caused the event.
+pub fn notify(settings: &RepoSettings, repo_path: &Path, repo: &str, sender: &str, event: Event) {
+    if !settings.webhooks {
+        return;
+    }
+    if let Err(e) = delivery::enqueue(repo_path, repo, sender, &event) {
+        warn!(repo = %repo, event = event.kind().as_str(), error = %e, "Cannot queue webhooks");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -4,11 +4,11 @@ use super::load::path;
 use std::io::Write;
 use std::{fs, path::PathBuf};
 
+use crate::interaction::{Confirm, Input, Select};
 use anyhow::{Context, bail};
 use jiff::Timestamp;
 use keyring::Entry;
 use log::{debug, warn};
-use pijul_interaction::{Confirm, Input, Select};
 use thrussh_keys::key::PublicKey;
 
 impl Complete {
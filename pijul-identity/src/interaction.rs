@@ -0,0 +1,119 @@
+//! Prompts, for a process without a terminal.
+//!
+//! The CLI's `pijul_interaction` crate isn't available here, and the
+//! server can't ask anyone anything anyway. These prompts have the
+//! same builder interface, but answering them always fails, so that
+//! only the functions that need an interactive user are unavailable.
+
+use anyhow::bail;
+
+fn no_terminal<T>() -> Result<T, anyhow::Error> {
+    bail!("Interactive prompts are not supported without a terminal")
+}
+
+/// A text prompt.
+pub struct Input;
+
+impl Input {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self)
+    }
+
+    pub fn with_prompt(self, _: impl AsRef<str>) -> Self {
+        self
+    }
+
+    pub fn with_default(self, _: String) -> Self {
+        self
+    }
+
+    pub fn with_allow_empty(self, _: bool) -> Self {
+        self
+    }
+
+    pub fn with_initial_text(self, _: impl AsRef<str>) -> Self {
+        self
+    }
+
+    pub fn with_validator<F, E>(self, _: F) -> Self
+    where
+        F: Fn(&String) -> Result<(), E>,
+    {
+        self
+    }
+
+    pub fn interact(self) -> Result<String, anyhow::Error> {
+        no_terminal()
+    }
+}
+
+/// A yes/no question.
+pub struct Confirm;
+
+impl Confirm {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self)
+    }
+
+    pub fn with_prompt(self, _: impl AsRef<str>) -> Self {
+        self
+    }
+
+    pub fn with_default(self, _: bool) -> Self {
+        self
+    }
+
+    pub fn interact(self) -> Result<bool, anyhow::Error> {
+        no_terminal()
+    }
+}
+
+/// A choice among items.
+pub struct Select;
+
+impl Select {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self)
+    }
+
+    pub fn with_prompt(self, _: impl AsRef<str>) -> Self {
+        self
+    }
+
+    pub fn with_items<T: ToString>(self, _: &[T]) -> Self {
+        self
+    }
+
+    pub fn with_default(self, _: usize) -> Self {
+        self
+    }
+
+    pub fn interact(self) -> Result<usize, anyhow::Error> {
+        no_terminal()
+    }
+}
+
+/// A password prompt.
+pub struct Password;
+
+impl Password {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self)
+    }
+
+    pub fn with_prompt(self, _: impl AsRef<str>) -> Self {
+        self
+    }
+
+    pub fn with_allow_empty(self, _: bool) -> Self {
+        self
+    }
+
+    pub fn with_confirmation(self, _: impl AsRef<str>, _: impl AsRef<str>) -> Self {
+        self
+    }
+
+    pub fn interact(self) -> Result<String, anyhow::Error> {
+        no_terminal()
+    }
+}
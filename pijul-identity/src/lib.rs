@@ -35,6 +35,7 @@
 #![warn(clippy::cargo)]
 
 mod create;
+pub mod interaction;
 mod load;
 mod repair;
 
@@ -42,6 +43,7 @@ pub use load::{choose_identity_name, public_key};
 use log::warn;
 pub use repair::fix_identities;
 
+use interaction::Password;
 use pijul_config as config;
 use pijul_config::Author;
 
@@ -134,7 +136,14 @@ impl Credentials {
                 password_attempt = password;
             }
 
-                return Err(anyhow::anyhow!("Password required for secret key"));
+            // Prompt the user for a password. Without a terminal, as in
+            // the server, this fails.
+            if self.secret_key.load(Some(&password_attempt)).is_err() {
+                writeln!(stderr, "Password for identity `{name}` is required")?;
+                password_attempt = Password::new()?
+                    .with_prompt(format!("Password for identity `{name}`"))
+                    .with_allow_empty(true)
+                    .interact()?;
             }
 
             // Update the password
@@ -249,10 +258,6 @@ impl Complete {
     }
 
     fn change_password(&mut self) -> Result<(), anyhow::Error> {
-        // HEADLESS MODE: Interactive password change is not supported.
-        return Err(anyhow::anyhow!("Interactive password change not supported in server mode"));
-
-        /*
         let (decryped_key, _) = self.decrypt()?;
 
         let user_password = Password::new()?
@@ -260,7 +265,6 @@ impl Complete {
             .with_allow_empty(true)
             .with_confirmation("Confirm password", "Password mismatch")
             .interact()?;
-        */
 
         let password = if user_password.is_empty() {
             OnceLock::new()
@@ -7,8 +7,8 @@ use libpijul::key::{PublicKey, SecretKey};
 use std::fs;
 use std::path::PathBuf;
 
+use crate::interaction::Select;
 use anyhow::bail;
-use pijul_interaction::Select;
 use std::sync::OnceLock;
 
 static CHOSEN_IDENTITY: OnceLock<String> = OnceLock::new();
@@ -7,7 +7,7 @@ use std::io::Write;
 use std::path::PathBuf;
 
 use crate::CS;
-use pijul_interaction::ProgressBar;
+use crate::interaction::ProgressBar;
 
 const USER_AGENT: &str = concat!("pijul-", env!("CARGO_PKG_VERSION"));
 
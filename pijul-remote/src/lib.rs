@@ -39,6 +39,7 @@ mod interaction {
         pub fn new(_: &str) -> anyhow::Result<Self> { Ok(Self) }
         pub fn finish(&self) {}
     }
+    pub use pijul_identity::interaction::Password;
     pub const APPLY_MESSAGE: &str = "Applying";
     pub const COMPLETE_MESSAGE: &str = "Completing";
     pub const DOWNLOAD_MESSAGE: &str = "Downloading";
@@ -8,7 +8,7 @@ use libpijul::*;
 use log::debug;
 
 use crate::CS;
-use pijul_interaction::ProgressBar;
+use crate::interaction::ProgressBar;
 
 #[derive(Clone)]
 pub struct Local {
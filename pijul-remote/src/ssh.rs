@@ -17,7 +17,7 @@ use tokio::sync::Mutex;
 
 use super::parse_line;
 use crate::CS;
-use pijul_interaction::ProgressBar;
+use crate::interaction::{Password, ProgressBar};
 
 pub struct Ssh {
     pub h: thrussh::client::Handle<SshClient>,
@@ -95,6 +95,12 @@ pub fn ssh_remote<'a>(user: Option<&str>, addr: &'a str, with_path: bool) -> Opt
 }
 
 impl<'a> Remote<'a> {
+    /// Authenticate with this private key only, instead of the keys
+    /// configured for the host or the default ones.
+    pub fn set_identity_file(&mut self, file: &str) {
+        self.config.identity_file = Some(file.to_string())
+    }
+
     pub async fn connect(
         &mut self,
         name: &str,
@@ -249,7 +255,7 @@ impl<'a> Remote<'a> {
             let k = match thrussh_keys::load_secret_key(&key_path, None) {
                 Ok(k) => k,
                 Err(thrussh_keys::Error::KeyIsEncrypted) => {
-                    let password = pijul_interaction::Password::new()?
+                    let password = Password::new()?
                         .with_prompt(format!("Password for encrypted private key"))
                         .with_allow_empty(false)
                         .interact()?;
@@ -301,7 +307,7 @@ impl<'a> Remote<'a> {
 
         // Try authenticate using user's password
         if !authenticated {
-            let password = pijul_interaction::Password::new()?
+            let password = Password::new()?
                 .with_prompt(format!("Password for {username}"))
                 .with_allow_empty(true)
                 .interact()?;